@@ -0,0 +1,8 @@
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // a vendored protoc, so building doesn't need one installed
+    env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/chat.proto")?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! A single room chat server whose fan-out is one `tokio::sync::broadcast` channel
+//! instead of a queue per peer as in `chat_room`.
+//!
+//! Broadcasting costs one send however many peers are connected, and a slow peer never
+//! slows the others down: it falls behind, gets `RecvError::Lagged` and is told how many
+//! messages it missed. The price is that every peer sees every message, so filtering
+//! (rooms, ignore lists, the sender itself) happens on the receiving side, and the
+//! capacity is shared, one burst can make all slow peers lag at once.
+//! Run `cargo run --example fanout_bench` to compare both approaches.
+
+use core::fmt;
+use futures::{SinkExt, StreamExt};
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError, Receiver, Sender},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+};
+
+const ADDR: &str = "0.0.0.0:4321";
+const MAX_MESSAGES: usize = 128;
+
+#[derive(Debug)]
+struct ChatRoom {
+    sender: Sender<Envelope>,
+}
+
+/// A message tagged with its author so peers can skip their own.
+#[derive(Debug, Clone)]
+struct Envelope {
+    from: SocketAddr,
+    message: Arc<Message>,
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    Join(String),
+    Leave(String),
+    Chat { from: String, content: String },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let listener = TcpListener::bind(ADDR).await?;
+    info!("Listening on: {}", ADDR);
+
+    let chat_room = Arc::new(ChatRoom::new(MAX_MESSAGES));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Accepted connection from: {}", addr);
+
+        let chat_room = chat_room.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, addr, chat_room).await {
+                warn!("handle client Error: {}", e);
+            }
+            info!("Connection from {} closed", addr);
+        });
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    addr: SocketAddr,
+    chat_room: Arc<ChatRoom>,
+) -> Result<()> {
+    let mut stream = Framed::new(stream, LinesCodec::new());
+    stream.send("Please enter your name: ").await?;
+
+    let name = match stream.next().await {
+        Some(line) => line?.trim().to_string(),
+        None => return Ok(()),
+    };
+    stream.send(format!("Welcome! {}", name)).await?;
+
+    // subscribe before announcing so the peer does not miss anything after its own join
+    let receiver = chat_room.sender.subscribe();
+    chat_room.broadcast(addr, Message::Join(name.clone()));
+    info!("{} joined the chat room", name);
+
+    let (sink, stream) = stream.split();
+    // whichever side finishes first ends the connection
+    let ret = tokio::select! {
+        ret = loop_send_to_client(addr, receiver, sink) => ret,
+        ret = loop_receive_from_client(&name, addr, stream, &chat_room) => ret,
+    };
+
+    chat_room.broadcast(addr, Message::Leave(name.clone()));
+    info!("{} left the chat room", name);
+    ret
+}
+
+impl ChatRoom {
+    fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    fn broadcast(&self, from: SocketAddr, message: Message) {
+        let envelope = Envelope {
+            from,
+            message: Arc::new(message),
+        };
+        // an error only means nobody is listening right now
+        let _ = self.sender.send(envelope);
+    }
+}
+
+async fn loop_send_to_client(
+    addr: SocketAddr,
+    mut receiver: Receiver<Envelope>,
+    mut sink: impl SinkExt<String, Error = tokio_util::codec::LinesCodecError> + Unpin,
+) -> Result<()> {
+    loop {
+        let line = match receiver.recv().await {
+            Ok(envelope) if envelope.from == addr => continue,
+            Ok(envelope) => envelope.message.to_string(),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Peer {} lagged behind by {} messages", addr, skipped);
+                format!("You are lagging behind, {} messages were dropped", skipped)
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        sink.send(line).await?;
+    }
+}
+
+async fn loop_receive_from_client(
+    name: &str,
+    addr: SocketAddr,
+    mut stream: impl StreamExt<Item = Result<String, tokio_util::codec::LinesCodecError>> + Unpin,
+    chat_room: &ChatRoom,
+) -> Result<()> {
+    while let Some(line) = stream.next().await {
+        let line = line?;
+        let content = line.trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        chat_room.broadcast(
+            addr,
+            Message::Chat {
+                from: name.to_string(),
+                content: content.to_string(),
+            },
+        );
+    }
+    Ok(())
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Join(name) => write!(f, "{} joined the chat room", name),
+            Self::Leave(name) => write!(f, "{} left the chat room", name),
+            Self::Chat { from, content } => write!(f, "{}: {}", from, content),
+        }
+    }
+}
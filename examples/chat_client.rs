@@ -0,0 +1,226 @@
+use std::io::{self, Stdout};
+
+use anyhow::Result;
+use clap::Parser;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{SinkExt, StreamExt};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LinesCodec};
+
+const SCROLL_STEP: usize = 5;
+
+/// Terminal client for the chat_room example.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Address of the chat server
+    #[arg(long, default_value = "127.0.0.1:4321")]
+    addr: String,
+}
+
+#[derive(Debug, Default)]
+struct App {
+    lines: Vec<String>,
+    input: String,
+    // number of lines scrolled back from the bottom
+    scroll: usize,
+    closed: bool,
+}
+
+enum Action {
+    None,
+    Send(String),
+    Quit,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let stream = TcpStream::connect(&args.addr).await?;
+    let (mut sink, mut stream) = Framed::new(stream, LinesCodec::new()).split();
+
+    let mut terminal = setup_terminal()?;
+    let mut events = EventStream::new();
+    let mut app = App::default();
+
+    let ret = loop {
+        if let Err(e) = terminal.draw(|frame| render(frame, &app, &args.addr)) {
+            break Err(e.into());
+        }
+
+        tokio::select! {
+            line = stream.next(), if !app.closed => match line {
+                Some(Ok(line)) => app.push(line),
+                Some(Err(e)) => app.push(format!("Connection error: {}", e)),
+                None => {
+                    app.closed = true;
+                    app.push("Connection closed by the server, press Esc to quit".to_string());
+                }
+            },
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => match app.on_key(key) {
+                    Action::None => {}
+                    Action::Send(line) if !app.closed => {
+                        if let Err(e) = sink.send(line).await {
+                            app.push(format!("Failed to send: {}", e));
+                        }
+                    }
+                    Action::Send(_) => app.push("Not connected".to_string()),
+                    Action::Quit => break Ok(()),
+                },
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            },
+        }
+    };
+
+    restore_terminal(&mut terminal)?;
+    ret
+}
+
+fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    Ok(Terminal::new(CrosstermBackend::new(io::stdout()))?)
+}
+
+fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+impl App {
+    fn push(&mut self, line: String) {
+        self.lines.push(line);
+        // keep the view still while the user reads the scrollback
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Action {
+        if key.kind != KeyEventKind::Press {
+            return Action::None;
+        }
+
+        match key.code {
+            KeyCode::Esc => Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Quit,
+            KeyCode::Char(c) => {
+                self.input.push(c);
+                Action::None
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+                Action::None
+            }
+            KeyCode::Enter if !self.input.is_empty() => {
+                self.scroll = 0;
+                Action::Send(std::mem::take(&mut self.input))
+            }
+            KeyCode::PageUp | KeyCode::Up => {
+                self.scroll = (self.scroll + SCROLL_STEP).min(self.lines.len().saturating_sub(1));
+                Action::None
+            }
+            KeyCode::PageDown | KeyCode::Down => {
+                self.scroll = self.scroll.saturating_sub(SCROLL_STEP);
+                Action::None
+            }
+            _ => Action::None,
+        }
+    }
+}
+
+fn render(frame: &mut Frame, app: &App, addr: &str) {
+    let [scrollback, input] =
+        Layout::vertical([Constraint::Min(1), Constraint::Length(3)]).areas(frame.size());
+
+    let height = scrollback.height.saturating_sub(2) as usize;
+    let end = app.lines.len().saturating_sub(app.scroll);
+    let start = end.saturating_sub(height);
+    let lines: Vec<Line> = app.lines[start..end]
+        .iter()
+        .map(|line| style_line(line))
+        .collect();
+
+    let title = match (app.closed, app.scroll) {
+        (true, _) => format!(" {} (disconnected) ", addr),
+        (false, 0) => format!(" {} ", addr),
+        (false, scroll) => format!(" {} (scrolled back {} lines) ", addr, scroll),
+    };
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)),
+        scrollback,
+    );
+
+    frame.render_widget(
+        Paragraph::new(app.input.as_str()).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(" Enter to send, PgUp/PgDn to scroll, Esc to quit "),
+        ),
+        input,
+    );
+    frame.set_cursor(input.x + 1 + app.input.chars().count() as u16, input.y + 1);
+}
+
+/// Color the server's lines: "[room] name joined the room", "[time] [room] from: content"
+/// or a notice.
+fn style_line(line: &str) -> Line<'_> {
+    let muted = Style::default().fg(Color::DarkGray);
+    if line.starts_with("[history") {
+        return Line::styled(line, muted);
+    }
+
+    // rooms always start with '#', anything else in brackets is the time of a chat message
+    let (time, line) = match line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once("] "))
+    {
+        Some((time, rest)) if !time.starts_with('#') => {
+            (Some(Span::styled(format!("{} ", time), muted)), rest)
+        }
+        _ => (None, line),
+    };
+
+    let Some((room, rest)) = line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once("] "))
+    else {
+        return Line::styled(line, Style::default().fg(Color::Yellow));
+    };
+
+    let room = Span::styled(format!("[{}] ", room), Style::default().fg(Color::Cyan));
+    if rest.ends_with(" joined the room") || rest.ends_with(" left the room") {
+        return Line::from(vec![
+            room,
+            Span::styled(rest, muted.add_modifier(Modifier::ITALIC)),
+        ]);
+    }
+
+    let mut spans: Vec<Span> = time.into_iter().collect();
+    spans.push(room);
+    match rest.split_once(": ") {
+        Some((from, content)) => spans.extend([
+            Span::styled(from, Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(": "),
+            Span::raw(content),
+        ]),
+        None => spans.push(Span::raw(rest)),
+    }
+    Line::from(spans)
+}
@@ -0,0 +1,251 @@
+//! Load test for the chat server: simulated clients post to one room at a steady rate and
+//! every client measures how long the others' messages take to reach it.
+//!
+//! `cargo run --release --example chat_loadtest -- --clients 200 --rate 2 --secs 10`
+//!
+//! Without `--addr` a server is started in process. A server started on its own has to
+//! speak the JSON protocol and allow the rate, like `chat_room --protocol json` with a
+//! `rate_limit` above `--rate` in its config.
+//!
+//! Every message should reach every client but its sender, the ones that don't are
+//! reported as dropped: either the server gave up on a slow client or the client itself
+//! fell behind its event stream, which is reported apart.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use ecosystem::chat::{
+    handle_stream, ChatClient, ChatRoom, Message, Protocol, RateLimit, DEFAULT_HISTORY_SIZE,
+};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast::error::RecvError,
+    task::JoinSet,
+    time::{interval, sleep_until, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+// every message starts with this, so the clients can tell them from anything else said
+const TAG: &str = "loadtest";
+
+/// Load test for the chat server.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Address of a running server speaking JSON, one is started in process otherwise
+    #[arg(long)]
+    addr: Option<String>,
+    /// Number of simulated clients
+    #[arg(long, default_value_t = 100)]
+    clients: usize,
+    /// Messages each client sends per second
+    #[arg(long, default_value_t = 1.0)]
+    rate: f64,
+    /// How long the clients send for
+    #[arg(long, default_value_t = 10)]
+    secs: u64,
+    /// How long to wait for messages still on their way once sending stops
+    #[arg(long, default_value_t = 2)]
+    drain_secs: u64,
+    /// Flush interval of the in process server in milliseconds, 0 writes every message
+    #[arg(long, default_value_t = 0)]
+    flush_interval_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    // events a client skipped because it read them too slowly, not the server's doing
+    lagged: AtomicU64,
+    // microseconds from sending to receiving, for every message received
+    latencies: Mutex<Vec<u64>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let shutdown = CancellationToken::new();
+    let addr = match &args.addr {
+        Some(addr) => addr.clone(),
+        None => serve(&args, shutdown.clone()).await?.to_string(),
+    };
+    println!(
+        "server: {}, clients: {}, rate: {}/s each, for {}s",
+        addr, args.clients, args.rate, args.secs
+    );
+
+    let mut clients = Vec::with_capacity(args.clients);
+    for i in 0..args.clients {
+        let client = ChatClient::connect(&addr).await?;
+        client.login(&format!("load{}", i), None).await?;
+        clients.push(client);
+    }
+
+    // everyone is logged in before the first message, so nobody misses one by joining late
+    let started = Instant::now();
+    let sending_until = tokio::time::Instant::now() + Duration::from_secs(args.secs);
+    let counters = Arc::new(Counters::default());
+    let mut tasks = JoinSet::new();
+    for (i, client) in clients.into_iter().enumerate() {
+        let counters = counters.clone();
+        let period = Duration::from_secs_f64(1.0 / args.rate);
+        let drain = Duration::from_secs(args.drain_secs);
+        tasks.spawn(async move {
+            run_client(i, client, started, period, sending_until, drain, &counters).await
+        });
+    }
+    while let Some(ret) = tasks.join_next().await {
+        ret??;
+    }
+    shutdown.cancel();
+
+    report(&args, &counters, started.elapsed());
+    Ok(())
+}
+
+/// Serve a room in process with a rate limit that lets the clients through.
+async fn serve(args: &Args, shutdown: CancellationToken) -> Result<SocketAddr> {
+    let per_second = args.rate.ceil() as u32 * 2;
+    let chat_room = Arc::new(
+        ChatRoom::new(DEFAULT_HISTORY_SIZE)
+            .with_max_connections(args.clients)
+            .with_flush_interval(
+                (args.flush_interval_ms > 0).then(|| Duration::from_millis(args.flush_interval_ms)),
+            )
+            .with_rate_limit(RateLimit {
+                per_second,
+                burst: per_second * 2,
+                mute: Duration::from_secs(1),
+            }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("accept Error: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let chat_room = chat_room.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    handle_stream(stream, addr, Protocol::Json, chat_room, shutdown).await
+                {
+                    eprintln!("handle client Error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Send on every tick until `sending_until`, reading the others' messages all along and
+/// for `drain` after.
+async fn run_client(
+    id: usize,
+    client: ChatClient,
+    started: Instant,
+    period: Duration,
+    sending_until: tokio::time::Instant,
+    drain: Duration,
+    counters: &Counters,
+) -> Result<()> {
+    let mut events = client.subscribe();
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut latencies = Vec::new();
+    let mut seq = 0;
+    loop {
+        tokio::select! {
+            _ = ticks.tick(), if tokio::time::Instant::now() < sending_until => {
+                // the time it was sent rides along, every client measures from the same start
+                let at = started.elapsed().as_micros();
+                client.send(format!("{} {} {} {}", TAG, id, seq, at))?;
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+                seq += 1;
+            }
+            _ = sleep_until(sending_until + drain) => break,
+            event = events.recv() => match event {
+                Ok(message) => {
+                    if let Some(sent_at) = sent_at(&message) {
+                        let now = started.elapsed().as_micros() as u64;
+                        latencies.push(now.saturating_sub(sent_at));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    counters.lagged.fetch_add(skipped, Ordering::Relaxed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    counters
+        .received
+        .fetch_add(latencies.len() as u64, Ordering::Relaxed);
+    counters.latencies.lock().unwrap().extend(latencies);
+    Ok(())
+}
+
+/// When a message of the load test was sent, in microseconds since the start.
+fn sent_at(message: &Message) -> Option<u64> {
+    let Message::Chat(chat) = message else {
+        return None;
+    };
+    let mut words = chat.content.split(' ');
+    if words.next() != Some(TAG) {
+        return None;
+    }
+    words.nth(2)?.parse().ok()
+}
+
+fn report(args: &Args, counters: &Counters, elapsed: Duration) {
+    let sent = counters.sent.load(Ordering::Relaxed);
+    let received = counters.received.load(Ordering::Relaxed);
+    let lagged = counters.lagged.load(Ordering::Relaxed);
+    let expected = sent * (args.clients as u64).saturating_sub(1);
+    let mut latencies = std::mem::take(&mut *counters.latencies.lock().unwrap());
+    latencies.sort_unstable();
+
+    println!(
+        "sent: {}, expected deliveries: {}, received: {}, dropped: {} ({} by lagging clients)",
+        sent,
+        expected,
+        received,
+        expected.saturating_sub(received),
+        lagged
+    );
+    println!(
+        "throughput: {:.0} deliveries/s over {:.1}s",
+        received as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64()
+    );
+    if latencies.is_empty() {
+        return;
+    }
+    let percentile = |p: f64| {
+        let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[index] as f64 / 1000.0
+    };
+    println!(
+        "latency: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+}
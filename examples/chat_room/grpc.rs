@@ -0,0 +1,231 @@
+//! The gRPC interface of the chat server, one bidirectional stream per client.
+//!
+//! Each stream is served by the JSON protocol: client events are turned into its frames
+//! and the messages it sends back into server events, so the gRPC schema only has to map
+//! the existing types rather than reimplement the protocol.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+use ecosystem::chat::{handle_client, ChatRoom, Message, Protocol};
+use futures::{channel::mpsc, future, SinkExt, Stream, StreamExt};
+use serde_json::json;
+use tokio_util::{bytes::Bytes, sync::CancellationToken};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, warn};
+
+use self::proto::{client_event::Event, server_event, ClientEvent, ServerEvent};
+
+pub mod proto {
+    tonic::include_proto!("chat");
+}
+
+pub use self::proto::chat_server::ChatServer;
+
+// server events queued for a stream before the chat room waits on it
+const STREAM_BUFFER: usize = 16;
+// synthetic ports stay below the usual ephemeral range, so they don't clash with a TCP
+// client connecting from the same host
+const MAX_STREAM_PORT: u16 = 32767;
+
+/// Bridges every gRPC stream into the chat room as a JSON client.
+#[derive(Debug)]
+pub struct ChatService {
+    chat_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+    seq: AtomicU16,
+}
+
+impl ChatService {
+    pub fn new(chat_room: Arc<ChatRoom>, shutdown: CancellationToken) -> Self {
+        Self {
+            chat_room,
+            shutdown,
+            seq: AtomicU16::new(0),
+        }
+    }
+
+    /// Streams multiplexed over one HTTP/2 connection share its address, so each stream
+    /// gets the client's IP, which bans apply to, and a port of its own.
+    fn stream_addr(&self, remote: Option<SocketAddr>) -> SocketAddr {
+        let ip = remote.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+        let port = self.seq.fetch_add(1, Ordering::Relaxed) % MAX_STREAM_PORT + 1;
+        SocketAddr::new(ip, port)
+    }
+}
+
+#[tonic::async_trait]
+impl proto::chat_server::Chat for ChatService {
+    type ChatStream = Pin<Box<dyn Stream<Item = Result<ServerEvent, Status>> + Send>>;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<ClientEvent>>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let addr = self.stream_addr(request.remote_addr());
+        info!("Accepted gRPC stream from: {}", addr);
+
+        let stream = request.into_inner().filter_map(|event| {
+            future::ready(match event {
+                Ok(event) => client_frame(event).map(Ok),
+                Err(status) => Some(Err(status.into())),
+            })
+        });
+        let (sink, events) = mpsc::channel::<Bytes>(STREAM_BUFFER);
+        let sink = sink.sink_map_err(anyhow::Error::from);
+
+        let chat_room = self.chat_room.clone();
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_client(sink, stream, addr, Protocol::Json, chat_room, shutdown).await
+            {
+                warn!("handle client Error: {}", e);
+            }
+            info!("gRPC stream from {} closed", addr);
+        });
+
+        // the frames were encoded from messages a moment ago, so they always decode
+        let events = events.filter_map(|frame| {
+            future::ready(match serde_json::from_slice::<Message>(&frame) {
+                Ok(message) => Some(Ok(message.into())),
+                Err(e) => {
+                    warn!("Failed to decode a message for gRPC: {}", e);
+                    None
+                }
+            })
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Render a client event as the JSON protocol frame it stands for, `None` for an empty one.
+fn client_frame(event: ClientEvent) -> Option<String> {
+    let frame = match event.event? {
+        Event::Auth(auth) => json!({"type": "auth", "token": auth.token}),
+        Event::Login(login) => json!({"type": "login", "name": login.name, "token": login.token}),
+        Event::Say(say) => json!({"type": "chat", "content": say.content, "id": say.id}),
+        Event::Command(command) => {
+            json!({"type": "command", "command": command.command, "id": command.id})
+        }
+        Event::Pong(pong) => json!({"type": "pong", "token": pong.token}),
+        Event::Typing(_) => json!({"type": "typing"}),
+        Event::Resume(resume) => json!({"type": "resume", "token": resume.token}),
+    };
+    Some(frame.to_string())
+}
+
+impl From<Message> for ServerEvent {
+    fn from(message: Message) -> Self {
+        use server_event::Event;
+
+        let room_event = |room, name| proto::RoomEvent { room, name };
+        let event = match message {
+            Message::Join { room, name } => Event::Join(room_event(room, name)),
+            Message::Leave { room, name } => Event::Leave(room_event(room, name)),
+            Message::Chat(chat) => Event::Chat(proto::ChatMessage {
+                room: chat.room,
+                from: chat.from,
+                content: chat.content,
+                at: chat.at,
+                mentions: chat.mentions,
+                id: chat.id,
+            }),
+            Message::Action {
+                room,
+                from,
+                content,
+            } => Event::Action(proto::Action {
+                room,
+                from,
+                content,
+            }),
+            Message::Edited {
+                room,
+                from,
+                id,
+                content,
+            } => Event::Edited(proto::Edited {
+                room,
+                from,
+                id,
+                content,
+            }),
+            Message::Deleted { room, from, id } => {
+                Event::Deleted(proto::Deleted { room, from, id })
+            }
+            Message::Welcome { name } => Event::Welcome(proto::Welcome { name }),
+            Message::Joined { room, name } => Event::Joined(room_event(room, name)),
+            Message::Left { room, name } => Event::Left(room_event(room, name)),
+            Message::TopicChanged { room, by, topic } => {
+                Event::TopicChanged(proto::TopicChanged { room, by, topic })
+            }
+            Message::RoomFlagChanged {
+                room,
+                by,
+                flag,
+                enabled,
+            } => Event::RoomFlagChanged(proto::RoomFlagChanged {
+                room,
+                by,
+                flag: flag.to_string(),
+                enabled,
+            }),
+            Message::Typing { room, name } => Event::Typing(room_event(room, name)),
+            Message::Direct {
+                from,
+                to,
+                content,
+                id,
+            } => Event::Direct(proto::Direct {
+                from,
+                to,
+                content,
+                id,
+            }),
+            Message::System { content } => Event::System(proto::System { content }),
+            Message::History { message, at, id } => Event::History(Box::new(proto::History {
+                message: Some(Box::new((*message).into())),
+                at,
+                id,
+            })),
+            Message::Ping { token } => Event::Ping(proto::Ping { token }),
+            Message::Pong { token } => Event::Pong(proto::Pong { token }),
+            Message::FileOffer {
+                id,
+                from,
+                file,
+                size,
+            } => Event::FileOffer(proto::FileOffer {
+                id,
+                from,
+                file,
+                size,
+            }),
+            Message::FileChunk { id, data } => Event::FileChunk(proto::FileChunk { id, data }),
+            Message::FileProgress {
+                id,
+                file,
+                sent,
+                size,
+            } => Event::FileProgress(proto::FileProgress {
+                id,
+                file,
+                sent,
+                size,
+            }),
+            Message::Session { token } => Event::Session(proto::Session { token }),
+            Message::Ack { id, message } => Event::Ack(proto::Ack { id, message }),
+            Message::Nack { id, reason } => Event::Nack(proto::Nack { id, reason }),
+            Message::Delivered { id, to } => Event::Delivered(proto::Delivered { id, to }),
+            Message::Mention { room, from } => Event::Mention(proto::Mention { room, from }),
+        };
+        Self { event: Some(event) }
+    }
+}
@@ -0,0 +1,1284 @@
+mod grpc;
+
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use ecosystem::chat::{
+    handle_client, handle_link, handle_stream, read_proxy_header, AuthProvider, BlockedWordPolicy,
+    ChatRoom, EchoBot, EventLog, Federation, FilterChain, GreeterBot, IdlePolicy, LineSink,
+    LineStream, LongMessagePolicy, Message, Protocol, RateLimit, RedisBackend, Role, RoomBackend,
+    ScheduledAnnouncement, SharedToken, SlowConsumerPolicy, Snapshot, TimestampFormat, TokenFile,
+    Transcripts, Webhook, Webhooks, WordBlocklist, DEFAULT_AUTH_TIMEOUT_SECS,
+    DEFAULT_CHANNEL_CAPACITY, DEFAULT_HISTORY_SIZE, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_FILE_SIZE,
+    DEFAULT_MAX_MESSAGE_LEN, DEFAULT_MUTE_SECS, DEFAULT_RATE_LIMIT, DEFAULT_RESUME_BACKLOG,
+    DEFAULT_TRANSCRIPT_MAX_BYTES,
+};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+use grpc::{ChatServer, ChatService};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use nanoid::nanoid;
+use socket2::{Domain, Socket, Type};
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::BufReader,
+    net::{lookup_host, TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::{self, unix::SignalKind},
+    sync::broadcast::error::RecvError,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+use tokio_util::{bytes::Bytes, sync::CancellationToken};
+use tonic::transport::{server::TcpIncoming, Server};
+use tracing::{debug, info, info_span, level_filters::LevelFilter, warn, Instrument as _};
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+};
+
+const TCP_ADDR: &str = "0.0.0.0:4321";
+const LISTEN_BACKLOG: i32 = 1024;
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const WS_ADDR: &str = "0.0.0.0:4322";
+const TLS_ADDR: &str = "0.0.0.0:4323";
+const DEFAULT_CONFIG_PATH: &str = "chat.toml";
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+const FEDERATION_RETRY: Duration = Duration::from_secs(5);
+const BACKEND_RETRY: Duration = Duration::from_secs(5);
+const REDIS_PREFIX: &str = "chat:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TlsMode {
+    Plain,
+    Tls,
+    Dual,
+}
+
+/// A multi-room chat server speaking plain lines, JSON or WebSocket.
+#[derive(Debug, Parser)]
+struct Args {
+    /// TOML config file, defaults to $CHAT_CONFIG or chat.toml
+    #[arg(long)]
+    config: Option<String>,
+    /// Address of the plain TCP listener
+    #[arg(long)]
+    bind: Option<String>,
+    /// Maximum number of concurrent connections
+    #[arg(long)]
+    max_peers: Option<usize>,
+    /// Number of messages replayed to a peer joining a room
+    #[arg(long)]
+    history: Option<usize>,
+    /// Serve Prometheus metrics on this address
+    #[arg(long)]
+    metrics_addr: Option<String>,
+    /// Wire protocol, text or json
+    #[arg(long)]
+    protocol: Option<Protocol>,
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+}
+
+/// Server settings read from a TOML file, every field falls back to its default.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ChatConfig {
+    tcp_addr: String,
+    // served like tcp_addr, like "[::]:4321" for IPv6 next to "0.0.0.0:4321"
+    tcp_addrs: Vec<String>,
+    // behind a load balancer, take the client address from its PROXY protocol v2 header
+    proxy_protocol: bool,
+    ws_addr: String,
+    tls_addr: String,
+    tls_mode: TlsMode,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    protocol: Protocol,
+    channel_capacity: usize,
+    // a busy peer's messages are written together after this long, 0 writes each right away
+    flush_interval_ms: u64,
+    history_size: usize,
+    max_connections: usize,
+    rate_limit: u32,
+    // lines a peer may send in a row before the rate applies, 0 for twice `rate_limit`
+    rate_burst: u32,
+    mute_secs: u64,
+    // 0 disables the idle timeout
+    idle_timeout_secs: u64,
+    keepalive: bool,
+    // how often peers are pinged to measure their round trip, 0 never pings
+    heartbeat_secs: u64,
+    database_url: Option<String>,
+    event_log: Option<String>,
+    // a log per room and day is written in this directory when set
+    transcripts_dir: Option<String>,
+    // bytes, a full file is continued in the next one, 0 lets files grow
+    transcript_max_bytes: u64,
+    // written by /snapshot, room settings, bans and roles are restored from it at startup
+    snapshot_file: Option<String>,
+    admin_token: Option<String>,
+    metrics_addr: Option<String>,
+    // GET /status answers with uptime, peers, rooms and throughput as JSON when set
+    status_addr: Option<String>,
+    // IRC clients are served on this address when set
+    irc_addr: Option<String>,
+    // binary clients are served on this address when set
+    binary_addr: Option<String>,
+    // rooms are streamed as server-sent events and can be posted to over HTTP when set
+    http_addr: Option<String>,
+    // gRPC clients are served on this address when set
+    grpc_addr: Option<String>,
+    // local clients are served on this socket path when set
+    unix_socket: Option<String>,
+    max_message_len: usize,
+    long_messages: LongMessagePolicy,
+    slow_consumers: SlowConsumerPolicy,
+    slow_consumer_rooms: HashMap<String, SlowConsumerPolicy>,
+    // most members a room takes, admins may change them with /limit
+    room_limits: HashMap<String, usize>,
+    // seconds before an empty room is forgotten with its topic and flags, 0 keeps rooms
+    room_idle_secs: u64,
+    // never forgotten, like the default room
+    persistent_rooms: Vec<String>,
+    blocked_words: Vec<String>,
+    blocked_word_policy: BlockedWordPolicy,
+    // a token shared by everyone, or a file of "user token" lines, asked before the name
+    auth_token: Option<String>,
+    auth_tokens_file: Option<String>,
+    auth_timeout_secs: u64,
+    // roles of users from auth_tokens_file, by user name
+    roles: HashMap<String, Role>,
+    timestamp_format: TimestampFormat,
+    // built-in bots to run: echo, greeter
+    bots: Vec<String>,
+    // other servers link to this address, and this server dials `federation_peers`
+    federation_addr: Option<String>,
+    federation_peers: Vec<String>,
+    federation_token: Option<String>,
+    // identifies this server on federation links, random when unset
+    server_id: Option<String>,
+    // bytes, 0 disables file transfers
+    max_file_size: u64,
+    // how long a dropped client may resume its session, 0 disables session tokens
+    session_grace_secs: u64,
+    // messages per room kept for a dropped client and replayed when it resumes
+    resume_backlog: usize,
+    // instances sharing this Redis and prefix share their rooms
+    redis_url: Option<String>,
+    redis_prefix: String,
+    announcements: Vec<AnnouncementConfig>,
+    webhooks: Vec<Webhook>,
+}
+
+/// A notice announced to everyone on a cron schedule, in UTC.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnnouncementConfig {
+    schedule: String,
+    message: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let layer = Layer::new().with_filter(args.log_level);
+    tracing_subscriber::registry().with(layer).init();
+
+    let mut config =
+        ChatConfig::load(args.config.clone().or_else(|| env::var("CHAT_CONFIG").ok()))?;
+    config.apply_args(args);
+    let protocol = config.protocol;
+    info!("Protocol: {:?}", protocol);
+    let proxy_protocol = config.proxy_protocol;
+
+    let listeners = match config.tls_mode {
+        TlsMode::Tls => Vec::new(),
+        _ => {
+            let addrs: Vec<_> = std::iter::once(&config.tcp_addr)
+                .chain(&config.tcp_addrs)
+                .collect();
+            bind_all(&addrs).await?
+        }
+    };
+
+    let tls_listener = match config.tls_mode {
+        TlsMode::Plain => None,
+        _ => {
+            let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+                return Err(anyhow::anyhow!("tls_cert and tls_key are required for TLS"));
+            };
+            let acceptor = load_tls_acceptor(cert, key)?;
+            let listener = TcpListener::bind(&config.tls_addr).await?;
+            info!("TLS listening on: {}", config.tls_addr);
+            Some((listener, acceptor))
+        }
+    };
+
+    let ws_listener = TcpListener::bind(&config.ws_addr).await?;
+    info!("WebSocket listening on: ws://{}/ws", config.ws_addr);
+
+    let irc_listener = match &config.irc_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("IRC listening on: {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let binary_listener = match &config.binary_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Binary listening on: {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let unix_listener = match &config.unix_socket {
+        Some(path) => {
+            // a socket left behind by a previous run would make the bind fail
+            if std::fs::metadata(path).is_ok() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            info!("Unix socket listening on: {}", path);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let link_listener = match &config.federation_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Federation listening on: {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let history_size = config.history_size();
+    info!("History size: {}", history_size);
+    info!("Max connections: {}", config.max_connections);
+
+    let idle = config.idle_policy();
+    info!("Idle policy: {:?}", idle);
+
+    let rate_limit = config.rate_limit();
+    info!(
+        "Rate limit: {}/s, burst {}, mute {}s",
+        rate_limit.per_second,
+        rate_limit.burst,
+        rate_limit.mute.as_secs()
+    );
+
+    let filters = config.filters();
+    info!(
+        "Message filters: {}, {} blocked words",
+        filters.len(),
+        config.blocked_words.len()
+    );
+
+    let federation = config.federation();
+    if let Some(federation) = &federation {
+        info!(
+            "Federation server id: {}, {} peers to dial",
+            federation.server_id(),
+            config.federation_peers.len()
+        );
+    }
+
+    let auth = config.auth_provider().await?;
+    if auth.is_some() {
+        info!(
+            "Token authentication required, timeout {}s",
+            config.auth_timeout_secs
+        );
+    }
+    if !config.roles.is_empty() {
+        info!("Roles granted to {} users", config.roles.len());
+    }
+
+    let announcements = config.announcements()?;
+    if !announcements.is_empty() {
+        info!("Scheduled announcements: {}", announcements.len());
+    }
+
+    let backend = config.room_backend().await?;
+    if let Some(backend) = &backend {
+        info!("Rooms shared through {:?}", backend);
+    }
+
+    let char_room = match &config.database_url {
+        Some(db_url) => {
+            let chat_room = ChatRoom::try_new_with_db(db_url, history_size).await?;
+            info!("Database connected: {}", db_url);
+            chat_room
+        }
+        None => ChatRoom::new(history_size),
+    }
+    .with_channel_capacity(config.channel_capacity)
+    .with_flush_interval(
+        (config.flush_interval_ms > 0).then(|| Duration::from_millis(config.flush_interval_ms)),
+    )
+    .with_rate_limit(rate_limit)
+    .with_max_connections(config.max_connections)
+    .with_idle_policy(idle)
+    .with_heartbeat((config.heartbeat_secs > 0).then(|| Duration::from_secs(config.heartbeat_secs)))
+    .with_admin_token(config.admin_token.clone())
+    .with_roles(config.roles.clone())
+    .with_message_limit(config.max_message_len, config.long_messages)
+    .with_slow_consumer_policy(config.slow_consumers, config.slow_consumer_rooms.clone())
+    .with_room_limits(config.room_limits.clone())
+    .with_room_idle(
+        (config.room_idle_secs > 0).then(|| Duration::from_secs(config.room_idle_secs)),
+        config.persistent_rooms.clone(),
+    )
+    .with_filters(filters)
+    .with_timestamp_format(config.timestamp_format)
+    .with_federation(federation)
+    .with_backend(backend)
+    .with_announcements(announcements)
+    .with_max_file_size(config.max_file_size)
+    .with_session_grace(
+        (config.session_grace_secs > 0).then(|| Duration::from_secs(config.session_grace_secs)),
+        config.resume_backlog,
+    )
+    .with_auth(auth, Duration::from_secs(config.auth_timeout_secs));
+    info!(
+        "Max message length: {} bytes, {:?} longer ones",
+        config.max_message_len, config.long_messages
+    );
+    let mut char_room = config.register_bots(char_room)?;
+    if !config.bots.is_empty() {
+        info!("Bots: {}", config.bots.join(", "));
+    }
+
+    // the event log is newer than the snapshot, so it goes second
+    if let Some(path) = &config.snapshot_file {
+        match tokio::fs::read_to_string(path).await {
+            Ok(json) => {
+                let snapshot: Snapshot = serde_json::from_str(&json)
+                    .map_err(|e| anyhow::anyhow!("invalid snapshot {}: {}", path, e))?;
+                info!("Restored snapshot {}: {} rooms", path, snapshot.rooms.len());
+                char_room.restore(snapshot);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        char_room = char_room.with_snapshot_file(Some(path.into()));
+    }
+
+    if let Some(path) = config.event_log.clone() {
+        let next_seq = match File::open(&path).await {
+            Ok(file) => char_room.replay(BufReader::new(file)).await?,
+            Err(_) => 0,
+        };
+        char_room = char_room.with_event_log(EventLog::open(&path, next_seq).await?);
+        info!("Event log: {}, next seq: {}", path, next_seq);
+    }
+
+    if let Some(dir) = &config.transcripts_dir {
+        let transcripts = Transcripts::open(dir, config.transcript_max_bytes).await?;
+        char_room = char_room.with_transcripts(Some(transcripts));
+        info!(
+            "Transcripts: {}, up to {} bytes per file",
+            dir, config.transcript_max_bytes
+        );
+    }
+
+    if !config.webhooks.is_empty() {
+        let webhooks = Webhooks::start(config.webhooks.clone())?;
+        char_room = char_room.with_webhooks(Some(webhooks));
+        info!("Webhooks: {}", config.webhooks.len());
+    }
+
+    let char_room = Arc::new(char_room);
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let chat_room = char_room.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!(
+                "Shutting down, {} connections open",
+                chat_room.connection_count()
+            );
+            // queue the notice before the peer loops stop so it is flushed with the rest
+            let message = Message::system("Server is shutting down");
+            chat_room.announce(Arc::new(message)).await;
+            shutdown.cancel();
+        }
+    });
+
+    for addr in &config.federation_peers {
+        tokio::spawn(dial_link(addr.clone(), char_room.clone(), shutdown.clone()));
+    }
+    if config.redis_url.is_some() {
+        tokio::spawn(follow_backend(char_room.clone(), shutdown.clone()));
+    }
+    tokio::spawn({
+        let chat_room = char_room.clone();
+        let shutdown = shutdown.clone();
+        async move { chat_room.run_announcements(shutdown).await }
+    });
+    tokio::spawn({
+        let chat_room = char_room.clone();
+        let shutdown = shutdown.clone();
+        async move { chat_room.run_room_sweeper(shutdown).await }
+    });
+
+    if let Some(addr) = &config.metrics_addr {
+        let handle = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("chat_send_latency_seconds".to_string()),
+                LATENCY_BUCKETS,
+            )?
+            .set_buckets_for_metric(
+                Matcher::Full("chat_ping_rtt_seconds".to_string()),
+                LATENCY_BUCKETS,
+            )?
+            .install_recorder()?;
+        let listener = TcpListener::bind(addr).await?;
+        info!("Metrics listening on: http://{}/metrics", addr);
+        let router = Router::new().route("/metrics", get(move || future::ready(handle.render())));
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let server =
+                axum::serve(listener, router).with_graceful_shutdown(shutdown.cancelled_owned());
+            if let Err(e) = server.await {
+                warn!("Metrics server Error: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = &config.status_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Status listening on: http://{}/status", addr);
+        let chat_room = char_room.clone();
+        let router = Router::new().route(
+            "/status",
+            get(move || future::ready(Json(chat_room.status()))),
+        );
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let server =
+                axum::serve(listener, router).with_graceful_shutdown(shutdown.cancelled_owned());
+            if let Err(e) = server.await {
+                warn!("Status server Error: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = &config.http_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("HTTP gateway listening on: http://{}/rooms", addr);
+        let router = Router::new()
+            .route("/rooms/:name/stream", get(stream_room))
+            .route("/rooms/:name/messages", post(post_message))
+            .with_state((char_room.clone(), shutdown.clone()));
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let server =
+                axum::serve(listener, router).with_graceful_shutdown(shutdown.cancelled_owned());
+            if let Err(e) = server.await {
+                warn!("HTTP gateway Error: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = &config.grpc_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("gRPC listening on: {}", addr);
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+        let service = ChatServer::new(ChatService::new(char_room.clone(), shutdown.clone()));
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let server = Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, shutdown.cancelled_owned());
+            if let Err(e) = server.await {
+                warn!("gRPC server Error: {}", e);
+            }
+        });
+    }
+
+    let router = Router::new().route("/ws", get(ws_handler)).with_state((
+        char_room.clone(),
+        protocol,
+        shutdown.clone(),
+    ));
+    let ws = async {
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(ws_listener, service)
+            .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let plain = async {
+        let serving = listeners.into_iter().map(|listener| {
+            serve_tcp(
+                listener,
+                None,
+                protocol,
+                proxy_protocol,
+                char_room.clone(),
+                shutdown.clone(),
+            )
+        });
+        future::try_join_all(serving).await?;
+        Ok(())
+    };
+    let tls = async {
+        match tls_listener {
+            Some((listener, acceptor)) => {
+                serve_tcp(
+                    listener,
+                    Some(acceptor),
+                    protocol,
+                    proxy_protocol,
+                    char_room.clone(),
+                    shutdown.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let irc = async {
+        match irc_listener {
+            Some(listener) => {
+                serve_tcp(
+                    listener,
+                    None,
+                    Protocol::Irc,
+                    proxy_protocol,
+                    char_room.clone(),
+                    shutdown.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let binary = async {
+        match binary_listener {
+            Some(listener) => {
+                serve_tcp(
+                    listener,
+                    None,
+                    Protocol::Binary,
+                    proxy_protocol,
+                    char_room.clone(),
+                    shutdown.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let unix = async {
+        match unix_listener {
+            Some(listener) => {
+                serve_unix(listener, protocol, char_room.clone(), shutdown.clone()).await
+            }
+            None => Ok(()),
+        }
+    };
+    let links = async {
+        match link_listener {
+            Some(listener) => serve_links(listener, char_room.clone(), shutdown.clone()).await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(plain, tls, ws, irc, binary, unix, links)?;
+
+    // give the peers a moment to drain their queues and close their streams
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while char_room.connection_count() > 0 && Instant::now() < deadline {
+        sleep(Duration::from_millis(50)).await;
+    }
+    if let Some(path) = &config.unix_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove unix socket {}: {}", path, e);
+        }
+    }
+    info!("Server stopped");
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            warn!("Failed to listen for ctrl-c: {}", e);
+            future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match signal::unix::signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Bind every address, an IPv6 one only takes IPv6 when an IPv4 one shares its port, so
+/// "0.0.0.0:4321" and "[::]:4321" can be served side by side.
+async fn bind_all(addrs: &[&String]) -> Result<Vec<TcpListener>> {
+    let mut resolved = Vec::new();
+    for addr in addrs {
+        resolved.extend(lookup_host(addr.as_str()).await?);
+    }
+    resolved
+        .iter()
+        .map(|addr| {
+            let only_v6 = addr.is_ipv6()
+                && resolved
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == addr.port());
+            let listener = bind_tcp(*addr, only_v6)?;
+            info!("Listening on: {}", addr);
+            Ok(listener)
+        })
+        .collect()
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    protocol: Protocol,
+    proxy_protocol: bool,
+    char_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let span = info_span!("listener", addr = %listener.local_addr()?);
+    loop {
+        let (mut stream, mut addr) = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        info!(parent: &span, "Accepted connection from: {}", addr);
+
+        let chat_room = char_room.clone();
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(
+            async move {
+                // the header comes first, even before the TLS handshake
+                if proxy_protocol {
+                    match timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                        Ok(Ok(Some(client))) => {
+                            info!("Connection from {} proxied for {}", addr, client);
+                            addr = client;
+                        }
+                        Ok(Ok(None)) => {}
+                        Ok(Err(e)) => {
+                            warn!("Rejected connection from {}: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("Rejected connection from {}: no PROXY header in time", addr);
+                            return;
+                        }
+                    }
+                }
+                let ret = match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            handle_stream(stream, addr, protocol, chat_room, shutdown).await
+                        }
+                        Err(e) => Err(e.into()),
+                    },
+                    None => handle_stream(stream, addr, protocol, chat_room, shutdown).await,
+                };
+                if let Err(e) = ret {
+                    warn!("handle client Error: {}", e);
+                }
+                info!("Connection from {} closed", addr);
+            }
+            .instrument(span.clone()),
+        );
+    }
+}
+
+async fn serve_unix(
+    listener: UnixListener,
+    protocol: Protocol,
+    char_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut seq: u16 = 0;
+    loop {
+        let (stream, _) = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        seq = seq.wrapping_add(1);
+        let addr = unix_peer_addr(&stream, seq);
+        info!("Accepted unix connection from: {}", addr);
+
+        let chat_room = char_room.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, addr, protocol, chat_room, shutdown).await {
+                warn!("handle client Error: {}", e);
+            }
+            info!("Unix connection from {} closed", addr);
+        });
+    }
+}
+
+/// Unix peers have no network address, so make one up from their credentials.
+///
+/// The uid of the peer becomes a unique local IPv6 address, so a ban applies to the user,
+/// and the connection sequence number the port.
+fn unix_peer_addr(stream: &UnixStream, seq: u16) -> SocketAddr {
+    let uid = match stream.peer_cred() {
+        Ok(cred) => {
+            debug!(uid = cred.uid(), pid = cred.pid(), "unix peer credentials");
+            cred.uid()
+        }
+        Err(e) => {
+            warn!("Failed to read unix peer credentials: {}", e);
+            u32::MAX
+        }
+    };
+    let ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, (uid >> 16) as u16, uid as u16);
+    SocketAddr::new(IpAddr::V6(ip), seq)
+}
+
+/// Overwrite `value` with the parsed environment variable when it is set and valid.
+fn env_override<T: FromStr>(key: &str, value: &mut T) {
+    if let Some(parsed) = env::var(key).ok().and_then(|v| v.parse().ok()) {
+        *value = parsed;
+    }
+}
+
+fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(key_path)?);
+    let key = rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State((chat_room, protocol, shutdown)): State<(Arc<ChatRoom>, Protocol, CancellationToken)>,
+) -> impl IntoResponse {
+    info!("Accepted WebSocket connection from: {}", addr);
+    ws.max_message_size(chat_room.max_frame_len())
+        .on_upgrade(move |socket| async move {
+            let (sink, stream) = split_ws(socket, protocol);
+            if let Err(e) = handle_client(sink, stream, addr, protocol, chat_room, shutdown).await {
+                warn!("handle client Error: {}", e);
+            }
+            info!("WebSocket connection from {} closed", addr);
+        })
+}
+
+/// Body of a message posted through the HTTP gateway.
+#[derive(Debug, Deserialize)]
+struct PostMessage {
+    from: String,
+    content: String,
+}
+
+/// Stream a room as server-sent events, one JSON encoded message per event.
+///
+/// The room is given without its '#', a client that falls behind gets a `lagged` event
+/// with the number of messages it missed.
+async fn stream_room(
+    Path(room): Path<String>,
+    headers: HeaderMap,
+    State((chat_room, shutdown)): State<(Arc<ChatRoom>, CancellationToken)>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, String)> {
+    let user = chat_room
+        .check_token(bearer_token(&headers))
+        .await
+        .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+    let watcher = chat_room
+        .watch(user.as_deref(), &room)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!("Streaming {} over HTTP", room);
+
+    let events = stream::unfold(watcher, |mut watcher| async move {
+        let event = match watcher.recv().await {
+            Ok(message) => Event::default().json_data(message.as_ref()),
+            Err(RecvError::Lagged(missed)) => {
+                Ok(Event::default().event("lagged").data(missed.to_string()))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, watcher))
+    });
+    Ok(Sse::new(events.take_until(shutdown.cancelled_owned())).keep_alive(KeepAlive::default()))
+}
+
+/// Post a chat message to a room, under a name no connected peer holds.
+async fn post_message(
+    Path(room): Path<String>,
+    headers: HeaderMap,
+    State((chat_room, _)): State<(Arc<ChatRoom>, CancellationToken)>,
+    Json(body): Json<PostMessage>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let user = chat_room
+        .check_token(bearer_token(&headers))
+        .await
+        .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+    chat_room
+        .post_as(user.as_deref(), &body.from, &room, body.content)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Adapt a WebSocket into line based halves, every text frame is a line.
+///
+/// The binary protocol uses binary frames instead, each holding one encoded item.
+fn split_ws(socket: WebSocket, protocol: Protocol) -> (impl LineSink, impl LineStream) {
+    let (sink, stream) = socket.split();
+    let sink = sink
+        .sink_map_err(anyhow::Error::from)
+        .with(move |frame: Bytes| {
+            future::ready(match protocol {
+                Protocol::Binary => Ok(WsMessage::Binary(frame.to_vec())),
+                _ => String::from_utf8(frame.to_vec())
+                    .map(WsMessage::Text)
+                    .map_err(anyhow::Error::from),
+            })
+        });
+    let stream = stream.filter_map(move |message| {
+        future::ready(match message {
+            Ok(WsMessage::Text(text)) if protocol != Protocol::Binary => Some(Ok(text)),
+            Ok(WsMessage::Binary(data)) if protocol == Protocol::Binary => {
+                Some(bincode::deserialize(&data).map_err(anyhow::Error::from))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e.into())),
+        })
+    });
+    (sink, stream)
+}
+
+async fn serve_links(
+    listener: TcpListener,
+    chat_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        info!("Accepted federation link from: {}", addr);
+
+        let chat_room = chat_room.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_link(stream, addr, false, chat_room, shutdown).await {
+                warn!("federation link Error: {}", e);
+            }
+            info!("Federation link from {} closed", addr);
+        });
+    }
+}
+
+/// Keep a link to another server up, reconnecting whenever it drops.
+/// Receive the rooms' messages from the other instances, subscribing again when it drops.
+async fn follow_backend(chat_room: Arc<ChatRoom>, shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        if let Err(e) = chat_room.follow_backend(shutdown.clone()).await {
+            warn!("room backend Error: {}", e);
+        }
+        tokio::select! {
+            _ = shutdown.cancelled() => {},
+            _ = sleep(BACKEND_RETRY) => {},
+        }
+    }
+}
+
+async fn dial_link(addr: String, chat_room: Arc<ChatRoom>, shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                let peer_addr = stream.peer_addr().ok();
+                info!("Federation link to {} established", addr);
+                let ret = match peer_addr {
+                    Some(peer_addr) => {
+                        handle_link(stream, peer_addr, true, chat_room.clone(), shutdown.clone())
+                            .await
+                    }
+                    None => Err(anyhow::anyhow!("no peer address")),
+                };
+                if let Err(e) = ret {
+                    warn!("federation link Error: {}", e);
+                }
+                info!("Federation link to {} closed", addr);
+            }
+            Err(e) => warn!("Failed to connect to federation peer {}: {}", addr, e),
+        }
+        tokio::select! {
+            _ = shutdown.cancelled() => {},
+            _ = sleep(FEDERATION_RETRY) => {},
+        }
+    }
+}
+
+impl FromStr for TlsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "tls" => Ok(Self::Tls),
+            "dual" => Ok(Self::Dual),
+            _ => Err(anyhow::anyhow!(
+                "invalid tls mode: {}, expected plain, tls or dual",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            tcp_addr: TCP_ADDR.to_string(),
+            tcp_addrs: Vec::new(),
+            proxy_protocol: false,
+            ws_addr: WS_ADDR.to_string(),
+            tls_addr: TLS_ADDR.to_string(),
+            tls_mode: TlsMode::Plain,
+            tls_cert: None,
+            tls_key: None,
+            protocol: Protocol::Text,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            flush_interval_ms: 0,
+            history_size: DEFAULT_HISTORY_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            rate_burst: 0,
+            mute_secs: DEFAULT_MUTE_SECS,
+            idle_timeout_secs: 0,
+            keepalive: false,
+            heartbeat_secs: 0,
+            database_url: None,
+            event_log: None,
+            transcripts_dir: None,
+            snapshot_file: None,
+            transcript_max_bytes: DEFAULT_TRANSCRIPT_MAX_BYTES,
+            admin_token: None,
+            metrics_addr: None,
+            status_addr: None,
+            irc_addr: None,
+            binary_addr: None,
+            http_addr: None,
+            grpc_addr: None,
+            unix_socket: None,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            long_messages: LongMessagePolicy::Reject,
+            slow_consumers: SlowConsumerPolicy::DropOldest,
+            slow_consumer_rooms: HashMap::new(),
+            room_limits: HashMap::new(),
+            room_idle_secs: 0,
+            persistent_rooms: Vec::new(),
+            blocked_words: Vec::new(),
+            blocked_word_policy: BlockedWordPolicy::Mask,
+            auth_token: None,
+            auth_tokens_file: None,
+            auth_timeout_secs: DEFAULT_AUTH_TIMEOUT_SECS,
+            roles: HashMap::new(),
+            timestamp_format: TimestampFormat::Time,
+            bots: Vec::new(),
+            federation_addr: None,
+            federation_peers: Vec::new(),
+            federation_token: None,
+            server_id: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            session_grace_secs: 0,
+            resume_backlog: DEFAULT_RESUME_BACKLOG,
+            redis_url: None,
+            redis_prefix: REDIS_PREFIX.to_string(),
+            announcements: Vec::new(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl ChatConfig {
+    /// Read the given file (or `chat.toml`) if present, then apply the `CHAT_*` environment overrides.
+    fn load(path: Option<String>) -> Result<Self> {
+        let mut config =
+            match std::fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+                Ok(content) => {
+                    let config = toml::from_str(&content)?;
+                    info!(
+                        "Config loaded from {}",
+                        path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)
+                    );
+                    config
+                }
+                // an explicitly requested file must exist
+                Err(e) if path.is_some() => {
+                    return Err(anyhow::anyhow!(
+                        "failed to read config {}: {}",
+                        path.unwrap_or_default(),
+                        e
+                    ))
+                }
+                Err(_) => Self::default(),
+            };
+        config.apply_env();
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) {
+        env_override("CHAT_TLS_MODE", &mut self.tls_mode);
+        env_override("CHAT_PROXY_PROTOCOL", &mut self.proxy_protocol);
+        env_override("CHAT_HISTORY_SIZE", &mut self.history_size);
+        env_override("CHAT_MAX_CONNECTIONS", &mut self.max_connections);
+        env_override("CHAT_FLUSH_INTERVAL_MS", &mut self.flush_interval_ms);
+        env_override("CHAT_RATE_LIMIT", &mut self.rate_limit);
+        env_override("CHAT_RATE_BURST", &mut self.rate_burst);
+        env_override("CHAT_MUTE_SECS", &mut self.mute_secs);
+        env_override("CHAT_IDLE_TIMEOUT_SECS", &mut self.idle_timeout_secs);
+        env_override("CHAT_KEEPALIVE", &mut self.keepalive);
+        env_override("CHAT_HEARTBEAT_SECS", &mut self.heartbeat_secs);
+        env_override("CHAT_MAX_MESSAGE_LEN", &mut self.max_message_len);
+        env_override("CHAT_LONG_MESSAGES", &mut self.long_messages);
+        env_override("CHAT_SLOW_CONSUMERS", &mut self.slow_consumers);
+        env_override("CHAT_AUTH_TIMEOUT_SECS", &mut self.auth_timeout_secs);
+        env_override("CHAT_TIMESTAMP_FORMAT", &mut self.timestamp_format);
+        env_override("CHAT_MAX_FILE_SIZE", &mut self.max_file_size);
+        env_override("CHAT_SESSION_GRACE_SECS", &mut self.session_grace_secs);
+        env_override("CHAT_RESUME_BACKLOG", &mut self.resume_backlog);
+        env_override("CHAT_ROOM_IDLE_SECS", &mut self.room_idle_secs);
+        env_override("CHAT_TRANSCRIPT_MAX_BYTES", &mut self.transcript_max_bytes);
+        for (key, value) in [
+            ("CHAT_TLS_CERT", &mut self.tls_cert),
+            ("CHAT_TLS_KEY", &mut self.tls_key),
+            ("CHAT_DATABASE_URL", &mut self.database_url),
+            ("CHAT_EVENT_LOG", &mut self.event_log),
+            ("CHAT_TRANSCRIPTS_DIR", &mut self.transcripts_dir),
+            ("CHAT_SNAPSHOT_FILE", &mut self.snapshot_file),
+            ("CHAT_ADMIN_TOKEN", &mut self.admin_token),
+            ("CHAT_METRICS_ADDR", &mut self.metrics_addr),
+            ("CHAT_STATUS_ADDR", &mut self.status_addr),
+            ("CHAT_IRC_ADDR", &mut self.irc_addr),
+            ("CHAT_BINARY_ADDR", &mut self.binary_addr),
+            ("CHAT_HTTP_ADDR", &mut self.http_addr),
+            ("CHAT_GRPC_ADDR", &mut self.grpc_addr),
+            ("CHAT_UNIX_SOCKET", &mut self.unix_socket),
+            ("CHAT_AUTH_TOKEN", &mut self.auth_token),
+            ("CHAT_AUTH_TOKENS_FILE", &mut self.auth_tokens_file),
+            ("CHAT_FEDERATION_ADDR", &mut self.federation_addr),
+            ("CHAT_FEDERATION_TOKEN", &mut self.federation_token),
+            ("CHAT_SERVER_ID", &mut self.server_id),
+            ("CHAT_REDIS_URL", &mut self.redis_url),
+        ] {
+            if let Ok(v) = env::var(key) {
+                *value = Some(v);
+            }
+        }
+    }
+
+    /// Command line flags win over both the file and the environment.
+    fn apply_args(&mut self, args: Args) {
+        if let Some(bind) = args.bind {
+            self.tcp_addr = bind;
+        }
+        if let Some(max_peers) = args.max_peers {
+            self.max_connections = max_peers;
+        }
+        if let Some(history) = args.history {
+            self.history_size = history;
+        }
+        if let Some(metrics_addr) = args.metrics_addr {
+            self.metrics_addr = Some(metrics_addr);
+        }
+        if let Some(protocol) = args.protocol {
+            self.protocol = protocol;
+        }
+    }
+
+    fn history_size(&self) -> usize {
+        // history is queued before the peer starts draining its channel, so it must fit in it
+        let max = self.channel_capacity / 2;
+        if self.history_size > max {
+            warn!(
+                "History size {} is too large, using {}",
+                self.history_size, max
+            );
+            return max;
+        }
+        self.history_size
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        let per_second = self.rate_limit.max(1);
+        RateLimit {
+            per_second,
+            burst: match self.rate_burst {
+                0 => per_second * 2,
+                burst => burst,
+            },
+            mute: Duration::from_secs(self.mute_secs),
+        }
+    }
+
+    fn idle_policy(&self) -> IdlePolicy {
+        IdlePolicy {
+            timeout: (self.idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(self.idle_timeout_secs)),
+            keepalive: self.keepalive,
+        }
+    }
+
+    fn register_bots(&self, mut chat_room: ChatRoom) -> Result<ChatRoom> {
+        for bot in &self.bots {
+            chat_room = match bot.as_str() {
+                "echo" => chat_room.with_bot(EchoBot),
+                "greeter" => chat_room.with_bot(GreeterBot),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "unknown bot: {}, expected echo or greeter",
+                        bot
+                    ))
+                }
+            };
+        }
+        Ok(chat_room)
+    }
+
+    fn announcements(&self) -> Result<Vec<ScheduledAnnouncement>> {
+        self.announcements
+            .iter()
+            .map(|announcement| {
+                ScheduledAnnouncement::new(&announcement.schedule, announcement.message.clone())
+                    .map_err(|e| {
+                        anyhow::anyhow!("Invalid schedule {:?}: {}", announcement.schedule, e)
+                    })
+            })
+            .collect()
+    }
+
+    async fn room_backend(&self) -> Result<Option<Box<dyn RoomBackend>>> {
+        match &self.redis_url {
+            Some(url) => Ok(Some(Box::new(
+                RedisBackend::connect(url, self.redis_prefix.clone()).await?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    async fn auth_provider(&self) -> Result<Option<Box<dyn AuthProvider>>> {
+        match (&self.auth_token, &self.auth_tokens_file) {
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "auth_token and auth_tokens_file can't be used together"
+            )),
+            (Some(token), None) => Ok(Some(Box::new(SharedToken(token.clone())))),
+            (None, Some(path)) => Ok(Some(Box::new(TokenFile::load(path).await?))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn federation(&self) -> Option<Federation> {
+        if self.federation_addr.is_none() && self.federation_peers.is_empty() {
+            return None;
+        }
+        let server_id = self.server_id.clone().unwrap_or_else(|| nanoid!(8));
+        Some(Federation::new(server_id, self.federation_token.clone()))
+    }
+
+    fn filters(&self) -> FilterChain {
+        let mut filters = FilterChain::default();
+        if !self.blocked_words.is_empty() {
+            filters = filters.with(WordBlocklist::new(
+                &self.blocked_words,
+                self.blocked_word_policy,
+            ));
+        }
+        filters
+    }
+}
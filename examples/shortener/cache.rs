@@ -0,0 +1,100 @@
+//! Resolved links shared through Redis, so instances behind one load balancer look each
+//! link up in the database once per TTL between them rather than once each.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use redis::{aio::ConnectionManager, AsyncCommands as _};
+
+use crate::store::ShortenedUrl;
+
+const KEY_PREFIX: &str = "shortener:url:";
+
+pub struct RedisCache {
+    connection: ConnectionManager,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RedisCache {
+    pub async fn connect(url: &str, ttl: Duration) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self {
+            connection,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<ShortenedUrl>> {
+        // the manager reconnects by itself, a clone shares its connection
+        let mut connection = self.connection.clone();
+        let cached: Option<String> = connection.get(key(id)).await?;
+        let Some(cached) = cached else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(serde_json::from_str(&cached)?))
+    }
+
+    /// Kept for the TTL, or until the link expires if that comes first.
+    pub async fn put(&self, url: &ShortenedUrl) -> Result<()> {
+        let mut ttl = self.ttl;
+        if let Some(expires_at) = url.expires_at {
+            let left = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            ttl = ttl.min(left);
+        }
+        if ttl.as_secs() == 0 {
+            return Ok(());
+        }
+        let mut connection = self.connection.clone();
+        connection
+            .set_ex::<_, _, ()>(key(&url.id), serde_json::to_string(url)?, ttl.as_secs())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn invalidate(&self, id: &str) -> Result<()> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(key(id)).await?;
+        Ok(())
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("ttl", &self.ttl)
+            .field("hits", &self.hits)
+            .field("misses", &self.misses)
+            .finish_non_exhaustive()
+    }
+}
+
+fn key(id: &str) -> String {
+    format!("{}{}", KEY_PREFIX, id)
+}
@@ -0,0 +1,411 @@
+//! Settings of the shortener, read from a TOML file when there is one and then from the
+//! environment, which wins. Checked as a whole at startup, so every mistake is reported at
+//! once rather than the first one.
+
+use std::{
+    env,
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use governor::Quota;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+const DEFAULT_CONFIG_PATH: &str = "shortener.toml";
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:4321";
+const DEFAULT_DATABASE_URL: &str = "postgresql://localhost/shortener";
+const DEFAULT_POOL_SIZE: u32 = 10;
+const DEFAULT_ID_LENGTH: usize = 6;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 60;
+const DEFAULT_CLICK_FLUSH_INTERVAL_SECS: u64 = 5;
+const DEFAULT_CREATE_BURST: u32 = 10;
+const DEFAULT_CREATE_REFILL_MS: u64 = 6000;
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+const DEFAULT_REDIS_CACHE_TTL_SECS: u64 = 300;
+// shorter random ids run out, longer ones are no longer short
+const MIN_ID_LENGTH: usize = 4;
+const MAX_ID_LENGTH: usize = 21;
+
+/// Permanent redirects (301) may be kept by browsers and pass search ranking on to the
+/// target, temporary ones (302) bring every click back to be counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectKind {
+    Permanent,
+    Temporary,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_addr: String,
+    // what short urls start with, the listen address when unset
+    pub base_url: Option<String>,
+    // Postgres unless it starts with `sqlite:`
+    pub database_url: String,
+    // in memory SQLite databases keep to one connection whatever this says
+    pub pool_size: u32,
+    // of random ids, aliases are as long as their owners like
+    pub id_length: usize,
+    // for links created without a redirect of their own
+    pub redirect: RedirectKind,
+    // how long requests may finish after SIGTERM or SIGINT, and the clicks be written
+    pub shutdown_grace_secs: u64,
+    // PEM files, listen_addr speaks HTTPS when both are set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // plain HTTP on this address sends everyone to HTTPS, only with TLS
+    pub redirect_addr: Option<String>,
+    pub request_timeout_secs: u64,
+    // how often expired links are deleted and the clicks written
+    pub purge_interval_secs: u64,
+    pub click_flush_interval_secs: u64,
+    // a client may create this many links at once, and one more every refill after that
+    pub create_burst: u32,
+    pub create_refill_ms: u64,
+    // links kept in memory for redirects
+    pub cache_capacity: usize,
+    // shared with the other instances behind the local cache, only when set
+    pub redis_url: Option<String>,
+    pub redis_cache_ttl_secs: u64,
+    // a fixed salt keeps the hashes of an address the same across restarts, random when unset
+    pub ip_salt: Option<String>,
+    // added to `api_keys` at startup, so a fresh database has a key to create links with
+    pub api_key: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: DEFAULT_LISTEN_ADDR.to_string(),
+            base_url: None,
+            database_url: DEFAULT_DATABASE_URL.to_string(),
+            pool_size: DEFAULT_POOL_SIZE,
+            id_length: DEFAULT_ID_LENGTH,
+            redirect: RedirectKind::Temporary,
+            shutdown_grace_secs: DEFAULT_SHUTDOWN_GRACE_SECS,
+            tls_cert: None,
+            tls_key: None,
+            redirect_addr: None,
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+            purge_interval_secs: DEFAULT_PURGE_INTERVAL_SECS,
+            click_flush_interval_secs: DEFAULT_CLICK_FLUSH_INTERVAL_SECS,
+            create_burst: DEFAULT_CREATE_BURST,
+            create_refill_ms: DEFAULT_CREATE_REFILL_MS,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            redis_url: None,
+            redis_cache_ttl_secs: DEFAULT_REDIS_CACHE_TTL_SECS,
+            ip_salt: None,
+            api_key: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the given file (or $SHORTENER_CONFIG, or `shortener.toml`) if present, then
+    /// apply the environment overrides and validate the result.
+    pub fn load(path: Option<String>) -> Result<Self> {
+        let path = path.or_else(|| env::var("SHORTENER_CONFIG").ok());
+        let file = path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+        let mut config = match fs::read_to_string(file) {
+            Ok(content) => {
+                let config = toml::from_str(&content)
+                    .map_err(|e| anyhow!("invalid config {}: {}", file, e))?;
+                info!("Config loaded from {}", file);
+                config
+            }
+            // an explicitly requested file must exist
+            Err(e) if path.is_some() => {
+                return Err(anyhow!("failed to read config {}: {}", file, e))
+            }
+            Err(_) => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_override("SHORTENER_LISTEN_ADDR", &mut self.listen_addr)?;
+        env_override("DATABASE_URL", &mut self.database_url)?;
+        env_override("SHORTENER_POOL_SIZE", &mut self.pool_size)?;
+        env_override("SHORTENER_ID_LENGTH", &mut self.id_length)?;
+        env_override("SHORTENER_REDIRECT", &mut self.redirect)?;
+        env_override(
+            "SHORTENER_SHUTDOWN_GRACE_SECS",
+            &mut self.shutdown_grace_secs,
+        )?;
+        env_override("REQUEST_TIMEOUT_SECS", &mut self.request_timeout_secs)?;
+        env_override("PURGE_INTERVAL_SECS", &mut self.purge_interval_secs)?;
+        env_override(
+            "CLICK_FLUSH_INTERVAL_SECS",
+            &mut self.click_flush_interval_secs,
+        )?;
+        env_override("CREATE_BURST", &mut self.create_burst)?;
+        env_override("CREATE_REFILL_MS", &mut self.create_refill_ms)?;
+        env_override("CACHE_CAPACITY", &mut self.cache_capacity)?;
+        env_override("REDIS_CACHE_TTL_SECS", &mut self.redis_cache_ttl_secs)?;
+        for (key, value) in [
+            ("SHORTENER_BASE_URL", &mut self.base_url),
+            ("SHORTENER_TLS_CERT", &mut self.tls_cert),
+            ("SHORTENER_TLS_KEY", &mut self.tls_key),
+            ("SHORTENER_REDIRECT_ADDR", &mut self.redirect_addr),
+            ("REDIS_URL", &mut self.redis_url),
+            ("CLICK_IP_SALT", &mut self.ip_salt),
+            ("SHORTENER_API_KEY", &mut self.api_key),
+        ] {
+            if let Ok(v) = env::var(key) {
+                *value = Some(v);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.listen_addr.parse::<SocketAddr>() {
+            problems.push(format!(
+                "listen_addr {:?} is not an address like 0.0.0.0:4321: {}",
+                self.listen_addr, e
+            ));
+        }
+        if let Some(base_url) = &self.base_url {
+            match Url::parse(base_url) {
+                Ok(url) if !matches!(url.scheme(), "http" | "https") => problems.push(format!(
+                    "base_url {:?} must be an http or https url",
+                    base_url
+                )),
+                Ok(url) if url.query().is_some() || url.fragment().is_some() => {
+                    problems.push(format!(
+                        "base_url {:?} can't have a query or fragment, ids are appended to it",
+                        base_url
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("base_url {:?} is not a url: {}", base_url, e)),
+            }
+        }
+        let scheme = self.database_url.split(':').next().unwrap_or_default();
+        if !matches!(scheme, "postgres" | "postgresql" | "sqlite") {
+            problems.push(format!(
+                "database_url must start with postgres://, postgresql:// or sqlite:, not {:?}",
+                scheme
+            ));
+        }
+        if self.pool_size == 0 {
+            problems.push("pool_size must be at least 1".to_string());
+        }
+        if !(MIN_ID_LENGTH..=MAX_ID_LENGTH).contains(&self.id_length) {
+            problems.push(format!(
+                "id_length must be between {} and {}, not {}",
+                MIN_ID_LENGTH, MAX_ID_LENGTH, self.id_length
+            ));
+        }
+        for (name, value) in [
+            ("request_timeout_secs", self.request_timeout_secs),
+            ("purge_interval_secs", self.purge_interval_secs),
+            ("click_flush_interval_secs", self.click_flush_interval_secs),
+            ("create_burst", self.create_burst.into()),
+            ("create_refill_ms", self.create_refill_ms),
+            ("cache_capacity", self.cache_capacity as u64),
+            ("redis_cache_ttl_secs", self.redis_cache_ttl_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+        if let Some(redis_url) = &self.redis_url {
+            if let Err(e) = Url::parse(redis_url) {
+                problems.push(format!("redis_url {:?} is not a url: {}", redis_url, e));
+            }
+        }
+        if self.ip_salt.as_deref() == Some("") {
+            problems.push("ip_salt can't be empty, leave it unset for a random one".to_string());
+        }
+        if self.api_key.as_deref() == Some("") {
+            problems.push("api_key can't be empty, leave it unset for none".to_string());
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            problems.push("tls_cert and tls_key go together, set both or neither".to_string());
+        }
+        if let Some(redirect_addr) = &self.redirect_addr {
+            if self.tls().is_none() {
+                problems.push(
+                    "redirect_addr sends clients to HTTPS, it needs tls_cert and tls_key"
+                        .to_string(),
+                );
+            }
+            if let Err(e) = redirect_addr.parse::<SocketAddr>() {
+                problems.push(format!(
+                    "redirect_addr {:?} is not an address like 0.0.0.0:80: {}",
+                    redirect_addr, e
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("invalid config:\n  {}", problems.join("\n  ")))
+    }
+
+    /// Short urls are this followed by the id, without a trailing slash.
+    pub fn base_url(&self) -> String {
+        match &self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None if self.tls().is_some() => format!("https://{}", self.listen_addr),
+            None => format!("http://{}", self.listen_addr),
+        }
+    }
+
+    /// The certificate and key paths, when the server speaks HTTPS.
+    pub fn tls(&self) -> Option<(&str, &str)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn purge_interval(&self) -> Duration {
+        Duration::from_secs(self.purge_interval_secs)
+    }
+
+    pub fn click_flush_interval(&self) -> Duration {
+        Duration::from_secs(self.click_flush_interval_secs)
+    }
+
+    pub fn redis_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.redis_cache_ttl_secs)
+    }
+
+    /// The zeroes `validate` turns away count as one.
+    pub fn create_quota(&self) -> Quota {
+        let burst = NonZeroU32::new(self.create_burst).unwrap_or(NonZeroU32::MIN);
+        let refill = Duration::from_millis(self.create_refill_ms.max(1));
+        Quota::with_period(refill).unwrap().allow_burst(burst)
+    }
+
+    /// The zero `validate` turns away counts as one.
+    pub fn cache_capacity(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.cache_capacity).unwrap_or(NonZeroUsize::MIN)
+    }
+}
+
+impl RedirectKind {
+    /// What a link stores, `None` follows the configured default.
+    pub fn from_permanent(permanent: bool) -> Self {
+        if permanent {
+            Self::Permanent
+        } else {
+            Self::Temporary
+        }
+    }
+}
+
+impl FromStr for RedirectKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "permanent" => Ok(Self::Permanent),
+            "temporary" => Ok(Self::Temporary),
+            _ => Err(format!(
+                "unknown redirect {}, expected permanent or temporary",
+                s
+            )),
+        }
+    }
+}
+
+/// Unlike a missing variable, one that doesn't parse is an error.
+fn env_override<T>(key: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(v) = env::var(key) {
+        *value = v
+            .parse()
+            .map_err(|e| anyhow!("invalid {} {:?}: {}", key, v, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let config = Config {
+            listen_addr: "nowhere".to_string(),
+            base_url: Some("ftp://sho.rt".to_string()),
+            pool_size: 0,
+            id_length: 2,
+            cache_capacity: 0,
+            request_timeout_secs: 0,
+            tls_cert: Some("cert.pem".to_string()),
+            api_key: Some(String::new()),
+            ..Config::default()
+        };
+        let e = config.validate().unwrap_err().to_string();
+        for problem in [
+            "listen_addr \"nowhere\" is not an address",
+            "base_url \"ftp://sho.rt\" must be an http or https url",
+            "pool_size must be at least 1",
+            "id_length must be between 4 and 21, not 2",
+            "cache_capacity must be at least 1",
+            "request_timeout_secs must be at least 1",
+            "tls_cert and tls_key go together",
+            "api_key can't be empty",
+        ] {
+            assert!(e.contains(problem), "{:?} missing from {}", problem, e);
+        }
+        assert!(Config::default().validate().is_ok());
+    }
+
+    // the only test that sets these variables, they are the whole process's
+    #[test]
+    fn the_environment_wins_over_the_file() {
+        let path = env::temp_dir().join(format!("shortener-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "pool_size = 3\nid_length = 8\ncache_capacity = 10\nredirect = \"permanent\"\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap().to_string();
+        env::set_var("SHORTENER_POOL_SIZE", "5");
+        env::set_var("CACHE_CAPACITY", "20");
+
+        let config = Config::load(Some(path.clone()));
+        env::set_var("CACHE_CAPACITY", "lots");
+        let unparsable = Config::load(Some(path.clone()));
+        env::remove_var("SHORTENER_POOL_SIZE");
+        env::remove_var("CACHE_CAPACITY");
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.pool_size, 5);
+        assert_eq!(config.cache_capacity, 20);
+        assert_eq!(config.id_length, 8);
+        assert_eq!(config.redirect, RedirectKind::Permanent);
+        let e = unparsable.unwrap_err().to_string();
+        assert!(e.starts_with("invalid CACHE_CAPACITY \"lots\""), "{}", e);
+        assert!(Config::load(Some(format!("{}.missing", path))).is_err());
+    }
+}
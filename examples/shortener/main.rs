@@ -0,0 +1,2747 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        ConnectInfo, FromRequest, FromRequestParts, MatchedPath, Path, Query, Request, State,
+    },
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH,
+            LOCATION, REFERER, RETRY_AFTER, USER_AGENT,
+        },
+        uri::Authority,
+        HeaderMap, Method, StatusCode, Uri,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    serve, BoxError, Extension, Json, Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use cache::RedisCache;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::Parser;
+use config::{Config, RedirectKind};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, RateLimiter,
+};
+use lru::LruCache;
+use memory::MemoryStore;
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use nanoid::nanoid;
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::{self, Future},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use store::{Click, ShortenedUrl, SqlStore, StoreError, UrlStore};
+use thiserror::Error;
+use tokio::{
+    net::TcpListener,
+    signal::{self, unix::SignalKind},
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    task::JoinSet,
+    time::{interval, sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tower::{timeout::error::Elapsed, ServiceBuilder};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+};
+use url::Url;
+
+mod cache;
+mod config;
+mod memory;
+mod store;
+
+/// URL shortener.
+#[derive(Debug, Parser)]
+struct Args {
+    /// TOML config file, defaults to $SHORTENER_CONFIG or shortener.toml
+    #[arg(long)]
+    config: Option<String>,
+    /// Where the links are kept: database, at $DATABASE_URL, or memory
+    #[arg(long, default_value = "database")]
+    backend: Backend,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Database,
+    Memory,
+}
+
+#[derive(Debug)]
+struct HttpServeState {
+    db: Arc<dyn UrlStore>,
+    cache: Mutex<LruCache<String, ShortenedUrl>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // behind the local cache, shared with the other instances
+    redis: Option<RedisCache>,
+    // redirects per id not yet added to the table
+    clicks: Mutex<HashMap<String, u64>>,
+    // every redirect for the clicks table, written by `record_clicks`
+    analytics: Sender<Click>,
+    analytics_dropped: AtomicU64,
+    // hashed with client addresses so they can be told apart but not read back
+    ip_salt: String,
+    // links each client address may create
+    create_limiter: DefaultKeyedRateLimiter<IpAddr>,
+    // short urls are this and the id
+    base_url: String,
+    id_length: usize,
+    // for links without a redirect of their own
+    redirect: RedirectKind,
+}
+
+#[derive(Debug, FromRequest)]
+#[from_request(via(Json), rejection(ShortenerError))]
+struct AppJson<T>(T);
+
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(Query), rejection(ShortenerError))]
+struct AppQuery<T>(T);
+
+#[derive(Debug, Deserialize)]
+struct RequestBody {
+    url: String,
+    alias: Option<String>,
+    // at most one of the two, links without either never expire
+    expires_at: Option<DateTime<Utc>>,
+    ttl_seconds: Option<u64>,
+    // the configured one when not given
+    redirect: Option<RedirectKind>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateBody {
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseBody {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    // only given to whoever created the link, the table keeps its hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidateResponse {
+    valid: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    // the last id of the previous page
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListResponse {
+    links: Vec<LinkResponse>,
+    // absent on the last page
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct LinkResponse {
+    id: String,
+    url: String,
+    created_at: DateTime<Utc>,
+    clicks: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    // only for links that chose their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect: Option<RedirectKind>,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    // what each dependency answered, only for readiness
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, CheckResponse>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckResponse {
+    status: &'static str,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    id: String,
+    url: String,
+    clicks: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct CreateShortUrlFailed(anyhow::Error);
+
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct GetUrlFailed(anyhow::Error);
+
+#[derive(Debug, Error)]
+enum ShortenerError {
+    #[error("Not found, id: {0}")]
+    NotFound(String),
+    #[error("Expired, id: {0}")]
+    Expired(String),
+    #[error("Create shorten url failed: {0}")]
+    CreateShortUrlFailed(#[from] CreateShortUrlFailed),
+    #[error("Get url failed: {0}")]
+    GetUrlFailed(#[from] GetUrlFailed),
+    #[error("Invalid request body: {0}")]
+    InvalidBody(#[from] JsonRejection),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(#[from] QueryRejection),
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("Invalid alias: {0}")]
+    InvalidAlias(String),
+    #[error("Alias already taken: {0}")]
+    AliasTaken(String),
+    // with the link that has it, unless it is gone by now
+    #[error("Url already shortened: {url}")]
+    UrlTaken { url: String, id: Option<String> },
+    #[error("Invalid expiry: {0}")]
+    InvalidExpiry(String),
+    #[error("Forbidden, id: {0}")]
+    Forbidden(String),
+    #[error("Missing or unknown api key")]
+    Unauthorized,
+    #[error("Too many requests, retry after {0:?}")]
+    RateLimited(Duration),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Internal error: {0}")]
+    Internal(anyhow::Error),
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ErrorResponse {
+    code: u16,
+    message: String,
+}
+
+const MAX_ALIAS_LEN: usize = 32;
+const MAX_ID_ATTEMPTS: usize = 8;
+const RESERVED_ALIASES: &[&str] = &["healthz", "links", "metrics", "readyz", "validate"];
+// a dependency slower than this is as good as down for readiness
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+// redirects should take well under a millisecond from the cache
+const REDIRECT_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+const PERMANENT_REDIRECT_MAX_AGE_SECS: u64 = 86400;
+const TEMPORARY_REDIRECT_CACHE_CONTROL: &str = "private, no-store";
+// the code only depends on the id, a client can keep it for a day
+const QR_CACHE_CONTROL: &str = "public, max-age=86400";
+const QR_MIN_SIZE: u32 = 256;
+// how often addresses that can create again are forgotten
+const LIMITER_SWEEP_SECS: u64 = 60;
+// redirects waiting for the clicks table, beyond which they go unrecorded
+const CLICK_QUEUE: usize = 4096;
+const MAX_CLICK_BATCH: usize = 256;
+// ten years
+const MAX_TTL_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+    let config = Config::load(args.config)?;
+
+    let prometheus = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("shortener_redirect_duration_seconds".to_string()),
+            REDIRECT_BUCKETS,
+        )?
+        .install_recorder()?;
+
+    let addr = &config.listen_addr;
+    let listener = TcpListener::bind(addr).await?;
+    let tls = match config.tls() {
+        Some((cert, key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(|e| anyhow!("failed to load TLS cert {} and key {}: {}", cert, key, e))?,
+        ),
+        None => None,
+    };
+    info!("Listening on: {}, short urls: {}/", addr, config.base_url());
+
+    let db: Arc<dyn UrlStore> = match args.backend {
+        Backend::Database => {
+            let db = SqlStore::connect(&config.database_url, config.pool_size).await?;
+            info!(
+                "Database connected: {} ({}), pool size: {}",
+                config.database_url,
+                db.backend(),
+                db.pool_stats().map_or(config.pool_size, |pool| pool.max)
+            );
+            Arc::new(db)
+        }
+        Backend::Memory => {
+            info!("Links kept in memory, they are lost on exit");
+            Arc::new(MemoryStore::default())
+        }
+    };
+    let redis = match &config.redis_url {
+        Some(url) => {
+            let ttl = config.redis_cache_ttl();
+            let redis = RedisCache::connect(url, ttl).await?;
+            info!("Redis cache: {}, ttl: {:?}", url, ttl);
+            Some(redis)
+        }
+        None => None,
+    };
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down, finishing the requests in flight");
+            shutdown.cancel();
+        }
+    });
+
+    // what still holds clicks when the server stops, waited for before the pool closes
+    let mut writers = JoinSet::new();
+    let (analytics, clicks) = mpsc::channel(CLICK_QUEUE);
+    let state = HttpServeState::try_new(&config, db, redis, analytics).await?;
+    writers.spawn(record_clicks(state.db.clone(), clicks, shutdown.clone()));
+
+    let request_timeout = config.request_timeout();
+    info!("Request timeout: {:?}", request_timeout);
+
+    let state = Arc::new(state);
+    let every = config.purge_interval();
+    info!("Expired urls purged every: {:?}", every);
+    tokio::spawn(purge_expired(state.clone(), every));
+    let every = config.click_flush_interval();
+    info!("Clicks written every: {:?}", every);
+    writers.spawn(flush_clicks(state.clone(), every, shutdown.clone()));
+    tokio::spawn(sweep_create_limiter(state.clone()));
+
+    let router = with_layers(routes(&state), request_timeout, prometheus).with_state(state.clone());
+
+    if let Some(addr) = &config.redirect_addr {
+        let https_port = listener.local_addr()?.port();
+        let listener = TcpListener::bind(addr).await?;
+        info!("Redirecting http://{} to HTTPS", addr);
+        let redirects = https_redirects(https_port);
+        let server =
+            serve(listener, redirects).with_graceful_shutdown(shutdown.clone().cancelled_owned());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("Redirect server failed: {}", e);
+            }
+        });
+    }
+
+    // no new connections once the signal came, the ones open get the grace period
+    let grace = config.shutdown_grace();
+    let app = router.into_make_service_with_connect_info::<SocketAddr>();
+    let server = async {
+        let Some(tls) = tls else {
+            return serve(listener, app)
+                .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+                .await;
+        };
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.cancelled().await;
+                handle.graceful_shutdown(None);
+            }
+        });
+        axum_server::from_tcp_rustls(listener.into_std()?, tls)
+            .handle(handle)
+            .serve(app)
+            .await
+    };
+    let given_up = async {
+        shutdown.cancelled().await;
+        sleep(grace).await;
+    };
+    tokio::select! {
+        ret = server => ret?,
+        _ = given_up => warn!("Requests still running after {:?}, not waiting for them", grace),
+    }
+
+    shutdown.cancel();
+    let written = timeout(grace, async {
+        while writers.join_next().await.is_some() {}
+    });
+    if written.await.is_err() {
+        warn!(
+            "Clicks still being written after {:?}, they are lost",
+            grace
+        );
+    }
+    state.db.close().await;
+    info!("Server stopped");
+
+    Ok(())
+}
+
+/// Every endpoint, without the layers all requests go through.
+fn routes(state: &Arc<HttpServeState>) -> Router<Arc<HttpServeState>> {
+    // editing or deleting a link also takes the secret it was created with, so those
+    // check the key themselves
+    let create = Router::new()
+        .route("/", post(create_url))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_api_key,
+        ))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_creates));
+    // every link and its target, no business of anyone without a key
+    let list =
+        Router::new()
+            .route("/links", get(list_links))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_api_key,
+            ));
+    Router::new()
+        .merge(create)
+        .merge(list)
+        .route("/validate", post(validate_url))
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/:id", get(redirect).put(update_url).delete(delete_url))
+        .route("/:id/stats", get(stats))
+        .route("/:id/qr", get(qr_code))
+}
+
+/// The timeout, the error format the client asked for and the request metrics, around
+/// every route.
+fn with_layers(
+    router: Router<Arc<HttpServeState>>,
+    request_timeout: Duration,
+    prometheus: PrometheusHandle,
+) -> Router<Arc<HttpServeState>> {
+    router
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handle_middleware_error))
+                .timeout(request_timeout),
+        )
+        .layer(middleware::from_fn(negotiate_error_format))
+        .layer(middleware::from_fn(track_requests))
+        .layer(Extension(prometheus))
+}
+
+/// What the plain HTTP listener serves, every path sent to HTTPS.
+fn https_redirects(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+/// Send a plain HTTP request to the same host and path over HTTPS.
+async fn redirect_to_https(
+    State(https_port): State<u16>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Redirect, StatusCode> {
+    // the port the client asked for is the plain one
+    let host = headers
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Authority>().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let target = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    Ok(Redirect::permanent(&target))
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            warn!("Failed to listen for ctrl-c: {}", e);
+            future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match signal::unix::signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn handle_middleware_error(err: BoxError) -> ShortenerError {
+    if err.is::<Elapsed>() {
+        ShortenerError::Timeout
+    } else {
+        ShortenerError::Internal(anyhow!(err))
+    }
+}
+
+async fn negotiate_error_format(request: Request, next: Next) -> Response {
+    let wants_html = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("text/html"));
+
+    let response = next.run(request).await;
+    if !wants_html {
+        return response;
+    }
+
+    match response.extensions().get::<ErrorResponse>() {
+        Some(error) => {
+            let status = response.status();
+            (status, Html(error.to_html(status))).into_response()
+        }
+        None => response,
+    }
+}
+
+/// Count every request by route and status, and time the redirects, as answered after
+/// timeouts and errors were turned into responses.
+async fn track_requests(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    // the route rather than the path, ids would make a series each
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(request).await;
+    if method == Method::GET && route == "/:id" {
+        histogram!("shortener_redirect_duration_seconds").record(started.elapsed());
+    }
+    counter!(
+        "shortener_http_requests_total",
+        "method" => method.to_string(),
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    )
+    .increment(1);
+    response
+}
+
+async fn create_url(
+    State(state): State<Arc<HttpServeState>>,
+    AppJson(mut body): AppJson<RequestBody>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    body.validate()?;
+
+    let (id, expires_at, secret) = state
+        .create_shortened_url(
+            &body.url,
+            body.alias.as_deref(),
+            body.expires_at(),
+            body.redirect,
+        )
+        .await?;
+    // only a new link comes with its secret, an existing one is answered as it is
+    let status = match secret {
+        Some(_) => StatusCode::CREATED,
+        None => StatusCode::OK,
+    };
+
+    Ok((
+        status,
+        Json(ResponseBody::new(state.short_url(&id), expires_at, secret)),
+    ))
+}
+
+async fn validate_url(
+    State(state): State<Arc<HttpServeState>>,
+    AppJson(mut body): AppJson<RequestBody>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    body.validate()?;
+    if let Some(alias) = &body.alias {
+        state.check_alias(alias, &body.url).await?;
+    }
+
+    Ok(Json(ValidateResponse { valid: true }))
+}
+
+async fn redirect(
+    State(state): State<Arc<HttpServeState>>,
+    Path(id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let url = state.get_url(&id).await.map_err(GetUrlFailed)?;
+
+    let url = url.ok_or(ShortenerError::NotFound(id.clone()))?;
+    if url.is_expired() {
+        return Err(ShortenerError::Expired(id));
+    }
+    // stored before urls were normalized, or edited in the table
+    let location = url
+        .url
+        .parse()
+        .map_err(|e| ShortenerError::Internal(anyhow!("invalid target of {}: {}", id, e)))?;
+    state.record_click(&id);
+    state.capture_click(&id, addr.ip(), &headers);
+
+    let mut header = HeaderMap::new();
+    header.append(LOCATION, location);
+    let redirect = url
+        .permanent
+        .map_or(state.redirect, RedirectKind::from_permanent);
+    let status = match redirect {
+        RedirectKind::Permanent => {
+            // kept no longer than the link lives, and re-checked now and then since its
+            // target may be edited
+            let max_age = url
+                .expires_at
+                .map_or(PERMANENT_REDIRECT_MAX_AGE_SECS, |at| {
+                    (at - Utc::now())
+                        .num_seconds()
+                        .clamp(0, PERMANENT_REDIRECT_MAX_AGE_SECS as i64) as u64
+                });
+            header.insert(
+                CACHE_CONTROL,
+                format!("public, max-age={}", max_age).parse().unwrap(),
+            );
+            StatusCode::MOVED_PERMANENTLY
+        }
+        RedirectKind::Temporary => {
+            // every click comes back, to be counted
+            header.insert(
+                CACHE_CONTROL,
+                TEMPORARY_REDIRECT_CACHE_CONTROL.parse().unwrap(),
+            );
+            StatusCode::FOUND
+        }
+    };
+
+    Ok((status, header))
+}
+
+/// Turn a client away once it used up its quota of creates, before anything else is done
+/// for the request.
+async fn limit_creates(
+    State(state): State<Arc<HttpServeState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ShortenerError> {
+    if let Err(not_until) = state.create_limiter.check_key(&addr.ip()) {
+        let wait = not_until.wait_time_from(DefaultClock::default().now());
+        return Err(ShortenerError::RateLimited(wait));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Let the request through only with a bearer token found in `api_keys`.
+async fn require_api_key(
+    State(state): State<Arc<HttpServeState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ShortenerError> {
+    let token = bearer_token(request.headers()).ok_or(ShortenerError::Unauthorized)?;
+    if !state
+        .is_api_key(token)
+        .await
+        .map_err(ShortenerError::Internal)?
+    {
+        return Err(ShortenerError::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Point the link elsewhere, the previous target is kept in `url_edits`. Authorized like
+/// `delete_url`.
+async fn update_url(
+    State(state): State<Arc<HttpServeState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<UpdateBody>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let target = validate_target(&body.url)?;
+    let url = state.fetch_url(&id).await.map_err(GetUrlFailed)?;
+    let url = url.ok_or(ShortenerError::NotFound(id.clone()))?;
+    state.authorize(&url, &headers).await?;
+    state.update_target(&id, &target).await?;
+    info!("Short url {} now points to {}", id, target);
+
+    Ok(Json(ResponseBody::new(
+        state.short_url(&id),
+        url.expires_at,
+        None,
+    )))
+}
+
+/// Takes an api key or the secret the link was created with as a bearer token.
+async fn delete_url(
+    State(state): State<Arc<HttpServeState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let url = state.fetch_url(&id).await.map_err(GetUrlFailed)?;
+    let url = url.ok_or(ShortenerError::NotFound(id.clone()))?;
+    state.authorize(&url, &headers).await?;
+    if !state
+        .delete_shortened_url(&id)
+        .await
+        .map_err(ShortenerError::Internal)?
+    {
+        return Err(ShortenerError::NotFound(id));
+    }
+    info!("Deleted short url {}", id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stats(
+    State(state): State<Arc<HttpServeState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    // the table is read past the cache, it has the clicks
+    let url = state.fetch_url(&id).await.map_err(GetUrlFailed)?;
+    let url = url.ok_or(ShortenerError::NotFound(id))?;
+    let clicks = url.clicks as u64 + state.pending_clicks(&url.id);
+
+    Ok(Json(StatsResponse {
+        id: url.id,
+        url: url.url,
+        clicks,
+        expires_at: url.expires_at,
+    }))
+}
+
+/// Links ordered by id, a page starts after the `cursor` the previous one handed out.
+async fn list_links(
+    State(state): State<Arc<HttpServeState>>,
+    AppQuery(params): AppQuery<ListParams>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    // one more than asked tells whether there is a next page
+    let mut urls = state
+        .list_urls(params.cursor.as_deref(), limit + 1)
+        .await
+        .map_err(GetUrlFailed)?;
+    let next_cursor = if urls.len() > limit as usize {
+        urls.truncate(limit as usize);
+        urls.last().map(|url| url.id.clone())
+    } else {
+        None
+    };
+    let links = urls
+        .into_iter()
+        .map(|url| LinkResponse {
+            clicks: url.clicks as u64 + state.pending_clicks(&url.id),
+            id: url.id,
+            url: url.url,
+            created_at: url.created_at,
+            expires_at: url.expires_at,
+            redirect: url.permanent.map(RedirectKind::from_permanent),
+        })
+        .collect();
+
+    Ok(Json(ListResponse { links, next_cursor }))
+}
+
+/// An SVG QR code of the short url. It never changes for an id, so a client that has it
+/// gets a 304 without the code being drawn again.
+async fn qr_code(
+    State(state): State<Arc<HttpServeState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ShortenerError> {
+    let url = state.get_url(&id).await.map_err(GetUrlFailed)?;
+    let url = url.ok_or(ShortenerError::NotFound(id.clone()))?;
+    if url.is_expired() {
+        return Err(ShortenerError::Expired(id));
+    }
+
+    // what the code encodes, a new base url makes for a new code
+    let short_url = state.short_url(&id);
+    let etag = format!("\"qr-{}\"", &hash_secret(&short_url)[..16]);
+    let cache_headers = [
+        (ETAG, etag.clone()),
+        (CACHE_CONTROL, QR_CACHE_CONTROL.to_string()),
+    ];
+    let cached = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if cached {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let code = QrCode::new(short_url).map_err(|e| ShortenerError::Internal(e.into()))?;
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build();
+
+    Ok((cache_headers, [(CONTENT_TYPE, "image/svg+xml")], image).into_response())
+}
+
+/// Answers as long as the process serves requests, whatever its dependencies do.
+async fn healthz() -> impl IntoResponse {
+    Json(HealthResponse {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Ready when the store, and Redis if there is one, answer. Degraded with 503 otherwise,
+/// with what went wrong.
+async fn readyz(State(state): State<Arc<HttpServeState>>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert(state.db.backend(), check(state.db.ping()).await);
+    if let Some(redis) = &state.redis {
+        checks.insert("redis", check(redis.ping()).await);
+    }
+
+    let ready = checks.values().all(|check| check.status == "ok");
+    let (status, code) = if ready {
+        ("ok", StatusCode::OK)
+    } else {
+        ("degraded", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    (code, Json(HealthResponse { status, checks }))
+}
+
+async fn check(ping: impl Future<Output = Result<()>>) -> CheckResponse {
+    let started = Instant::now();
+    let error = match timeout(READY_CHECK_TIMEOUT, ping).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer within {:?}", READY_CHECK_TIMEOUT)),
+    };
+    CheckResponse {
+        status: if error.is_none() { "ok" } else { "down" },
+        latency_ms: started.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+/// The recorded metrics, with the cache, queue and pool figures kept outside the recorder
+/// brought up to date first.
+async fn metrics(
+    State(state): State<Arc<HttpServeState>>,
+    Extension(prometheus): Extension<PrometheusHandle>,
+) -> impl IntoResponse {
+    let (hits, misses) = state.cache_stats();
+    publish_cache_stats("cache", hits, misses);
+    if let Some(redis) = &state.redis {
+        let (hits, misses) = redis.stats();
+        publish_cache_stats("redis", hits, misses);
+    }
+    counter!("shortener_clicks_dropped_total")
+        .absolute(state.analytics_dropped.load(Ordering::Relaxed));
+
+    if let Some(pool) = state.db.pool_stats() {
+        let in_use = pool.size as f64 - pool.idle as f64;
+        gauge!("shortener_db_connections", "state" => "in_use").set(in_use);
+        gauge!("shortener_db_connections", "state" => "idle").set(pool.idle as f64);
+        gauge!("shortener_db_connections_max").set(pool.max as f64);
+        gauge!("shortener_db_pool_utilization").set(in_use / pool.max as f64);
+    }
+    prometheus.render()
+}
+
+/// Hits and misses of one cache layer, and their ratio once it was looked in at all.
+fn publish_cache_stats(layer: &'static str, hits: u64, misses: u64) {
+    counter!(format!("shortener_{}_hits_total", layer)).absolute(hits);
+    counter!(format!("shortener_{}_misses_total", layer)).absolute(misses);
+    if hits + misses > 0 {
+        gauge!(format!("shortener_{}_hit_ratio", layer)).set(hits as f64 / (hits + misses) as f64);
+    }
+}
+
+impl HttpServeState {
+    async fn try_new(
+        config: &Config,
+        db: Arc<dyn UrlStore>,
+        redis: Option<RedisCache>,
+        analytics: Sender<Click>,
+    ) -> Result<Self> {
+        if let Some(key) = &config.api_key {
+            db.add_api_key(&hash_secret(key), "SHORTENER_API_KEY")
+                .await?;
+        }
+        let cache_capacity = config.cache_capacity();
+        info!("Redirect cache capacity: {}", cache_capacity);
+        let create_quota = config.create_quota();
+        info!("Create quota per client: {:?}", create_quota);
+        info!("Random ids of {} characters", config.id_length);
+        info!("Links redirect by default: {:?}", config.redirect);
+
+        Ok(Self {
+            db,
+            cache: Mutex::new(LruCache::new(cache_capacity)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            redis,
+            clicks: Mutex::new(HashMap::new()),
+            analytics,
+            analytics_dropped: AtomicU64::new(0),
+            ip_salt: config.ip_salt.clone().unwrap_or_else(|| nanoid!()),
+            create_limiter: RateLimiter::keyed(create_quota),
+            base_url: config.base_url(),
+            id_length: config.id_length,
+            redirect: config.redirect,
+        })
+    }
+
+    /// Returns the id, when the link expires and, when this call created the link, the
+    /// secret that deletes it. A link that was there already is left as it is.
+    async fn create_shortened_url(
+        &self,
+        url: &str,
+        alias: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        redirect: Option<RedirectKind>,
+    ) -> Result<(String, Option<DateTime<Utc>>, Option<String>), ShortenerError> {
+        let secret = nanoid!(32);
+        let secret_hash = hash_secret(&secret);
+        let permanent = redirect.map(|redirect| redirect == RedirectKind::Permanent);
+        let (id, expires_at, created) = match alias {
+            Some(alias) => {
+                self.create_aliased_url(alias, url, expires_at, permanent, &secret_hash)
+                    .await?
+            }
+            None => self
+                .create_random_url(url, expires_at, permanent, &secret_hash)
+                .await
+                .map_err(CreateShortUrlFailed)?,
+        };
+        if created {
+            // an expired alias claimed again may still be cached, or have clicks counted
+            self.forget(&id).await;
+            self.clicks.lock().unwrap().remove(&id);
+        }
+        Ok((id, expires_at, created.then_some(secret)))
+    }
+
+    async fn create_aliased_url(
+        &self,
+        alias: &str,
+        url: &str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &str,
+    ) -> Result<(String, Option<DateTime<Utc>>, bool), ShortenerError> {
+        // insert first so two concurrent claims of the same alias can't both succeed, an
+        // expired alias may be claimed again before it is purged
+        let created = match self
+            .db
+            .claim_alias(alias, url, expires_at, permanent, secret_hash)
+            .await
+        {
+            Ok(created) => created,
+            // shortened already, under another id
+            Err(StoreError::Taken) => return Err(self.url_taken(url).await),
+            Err(StoreError::Other(e)) => return Err(CreateShortUrlFailed(e).into()),
+        };
+
+        if created {
+            return Ok((alias.to_string(), expires_at, true));
+        }
+        let Some(existing) = self.check_alias(alias, url).await? else {
+            return Err(ShortenerError::AliasTaken(alias.to_string()));
+        };
+        info!("Alias {} already points to {}", alias, url);
+
+        Ok((alias.to_string(), existing.expires_at, false))
+    }
+
+    /// Returns the link if the alias already points to the url, or an error if it points
+    /// elsewhere.
+    async fn check_alias(
+        &self,
+        alias: &str,
+        url: &str,
+    ) -> Result<Option<ShortenedUrl>, ShortenerError> {
+        let existing = self.fetch_url(alias).await.map_err(CreateShortUrlFailed)?;
+
+        match existing {
+            Some(existing) if existing.url == url => Ok(Some(existing)),
+            Some(_) => Err(ShortenerError::AliasTaken(alias.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn create_random_url(
+        &self,
+        url: &str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &str,
+    ) -> Result<(String, Option<DateTime<Utc>>, bool)> {
+        let length = self.id_length;
+        for _ in 0..MAX_ID_ATTEMPTS {
+            let id = nanoid!(length);
+            // a single upsert per attempt, the primary key decides who owns the id
+            match self
+                .db
+                .create(&id, url, expires_at, permanent, secret_hash)
+                .await
+            {
+                Ok(ret) => {
+                    let created = ret.secret_hash.as_deref() == Some(secret_hash);
+                    return Ok((ret.id, ret.expires_at, created));
+                }
+                Err(StoreError::Taken) => warn!("Id {} already taken, retrying", id),
+                Err(StoreError::Other(e)) => return Err(e),
+            }
+        }
+
+        Err(anyhow!(
+            "no free id found after {} attempts",
+            MAX_ID_ATTEMPTS
+        ))
+    }
+
+    async fn get_url(&self, id: &str) -> Result<Option<ShortenedUrl>> {
+        // an expired copy is looked up again, the alias may have been claimed anew
+        if let Some(url) = self.cache.lock().unwrap().get(id) {
+            if !url.is_expired() {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(url.clone()));
+            }
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        // a failing Redis slows redirects down to the database, it doesn't stop them
+        if let Some(redis) = &self.redis {
+            match redis.get(id).await {
+                Ok(Some(url)) if !url.is_expired() => {
+                    self.cache.lock().unwrap().put(id.to_string(), url.clone());
+                    return Ok(Some(url));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Redis lookup of {} failed: {}", id, e),
+            }
+        }
+
+        let url = self.fetch_url(id).await?;
+        if let Some(url) = &url {
+            self.cache.lock().unwrap().put(id.to_string(), url.clone());
+            if let Some(redis) = &self.redis {
+                if let Err(e) = redis.put(url).await {
+                    warn!("Redis caching of {} failed: {}", id, e);
+                }
+            }
+        }
+        Ok(url)
+    }
+
+    /// Drop the cached copies of a link that changed. Other instances keep theirs in their
+    /// local cache until it is evicted.
+    async fn forget(&self, id: &str) {
+        self.cache.lock().unwrap().pop(id);
+        if let Some(redis) = &self.redis {
+            if let Err(e) = redis.invalidate(id).await {
+                warn!("Redis invalidation of {} failed: {}", id, e);
+            }
+        }
+    }
+
+    /// The conflict of a url another link has, naming that link.
+    async fn url_taken(&self, url: &str) -> ShortenerError {
+        match self.db.find(url).await {
+            Ok(existing) => ShortenerError::UrlTaken {
+                url: url.to_string(),
+                id: existing.map(|existing| existing.id),
+            },
+            Err(e) => ShortenerError::Internal(e),
+        }
+    }
+
+    async fn fetch_url(&self, id: &str) -> Result<Option<ShortenedUrl>> {
+        self.db.resolve(id).await
+    }
+
+    async fn is_api_key(&self, token: &str) -> Result<bool> {
+        self.db.is_api_key(&hash_secret(token)).await
+    }
+
+    /// The bearer token must be an api key or the secret the link was created with.
+    async fn authorize(
+        &self,
+        url: &ShortenedUrl,
+        headers: &HeaderMap,
+    ) -> Result<(), ShortenerError> {
+        let Some(token) = bearer_token(headers) else {
+            return Err(ShortenerError::Forbidden(url.id.clone()));
+        };
+        if url.secret_hash.as_deref() == Some(hash_secret(token).as_str()) {
+            return Ok(());
+        }
+        if !self
+            .is_api_key(token)
+            .await
+            .map_err(ShortenerError::Internal)?
+        {
+            return Err(ShortenerError::Forbidden(url.id.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Change the target, the previous one is kept in `url_edits`.
+    async fn update_target(&self, id: &str, url: &str) -> Result<(), ShortenerError> {
+        match self.db.update(id, url).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ShortenerError::NotFound(id.to_string())),
+            Err(StoreError::Taken) => return Err(self.url_taken(url).await),
+            Err(StoreError::Other(e)) => return Err(ShortenerError::Internal(e)),
+        }
+
+        self.forget(id).await;
+        Ok(())
+    }
+
+    /// Remove the link with its recorded clicks, returns false if it was already gone.
+    async fn delete_shortened_url(&self, id: &str) -> Result<bool> {
+        let deleted = self.db.delete(id).await?;
+
+        self.forget(id).await;
+        self.clicks.lock().unwrap().remove(id);
+        Ok(deleted)
+    }
+
+    async fn list_urls(&self, after: Option<&str>, limit: u32) -> Result<Vec<ShortenedUrl>> {
+        self.db.list(after, limit).await
+    }
+
+    fn record_click(&self, id: &str) {
+        *self
+            .clicks
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default() += 1;
+    }
+
+    /// Queue the details of a redirect for the clicks table, dropping them rather than
+    /// making the redirect wait when the table falls behind.
+    fn capture_click(&self, id: &str, ip: IpAddr, headers: &HeaderMap) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(self.ip_salt.as_bytes());
+        hasher.update(ip.to_string().as_bytes());
+        let click = Click {
+            url_id: id.to_string(),
+            clicked_at: Utc::now(),
+            referrer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            ip_hash: hex::encode(hasher.finalize()),
+        };
+        if let Err(TrySendError::Full(_)) = self.analytics.try_send(click) {
+            self.analytics_dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("Click queue full, click on {} not recorded", id);
+        }
+    }
+
+    fn pending_clicks(&self, id: &str) -> u64 {
+        self.clicks
+            .lock()
+            .unwrap()
+            .get(id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Add the clicks counted since the last time to the table, returns
+    /// how many ids had some. They are kept for the next time when the write fails.
+    async fn write_clicks(&self) -> Result<usize> {
+        let clicks = std::mem::take(&mut *self.clicks.lock().unwrap());
+        if clicks.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.db.add_clicks(&clicks).await {
+            let mut pending = self.clicks.lock().unwrap();
+            for (id, count) in clicks {
+                *pending.entry(id).or_default() += count;
+            }
+            return Err(e);
+        }
+
+        Ok(clicks.len())
+    }
+
+    async fn delete_expired(&self) -> Result<u64> {
+        self.db.delete_expired().await
+    }
+
+    fn short_url(&self, id: &str) -> String {
+        format!("{}/{}", self.base_url, id)
+    }
+
+    fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Delete expired urls from the table every so often, they answer 410 Gone until then.
+async fn purge_expired(state: Arc<HttpServeState>, every: Duration) {
+    let mut ticks = interval(every);
+    loop {
+        ticks.tick().await;
+        match state.delete_expired().await {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {} expired urls", purged),
+            Err(e) => warn!("Failed to purge expired urls: {}", e),
+        }
+    }
+}
+
+/// Forget the clients whose quota filled up again, the limiter would keep every address
+/// it ever saw otherwise.
+async fn sweep_create_limiter(state: Arc<HttpServeState>) {
+    let mut ticks = interval(Duration::from_secs(LIMITER_SWEEP_SECS));
+    loop {
+        ticks.tick().await;
+        state.create_limiter.retain_recent();
+        state.create_limiter.shrink_to_fit();
+    }
+}
+
+/// Insert the clicks queued by redirects, as many as are waiting at once. On shutdown the
+/// ones queued until then are still inserted.
+async fn record_clicks(
+    db: Arc<dyn UrlStore>,
+    mut clicks: Receiver<Click>,
+    shutdown: CancellationToken,
+) {
+    let mut batch = Vec::with_capacity(MAX_CLICK_BATCH);
+    loop {
+        let received = tokio::select! {
+            received = clicks.recv_many(&mut batch, MAX_CLICK_BATCH) => received,
+            _ = shutdown.cancelled() => {
+                clicks.close();
+                clicks.recv_many(&mut batch, MAX_CLICK_BATCH).await
+            }
+        };
+        if received == 0 {
+            break;
+        }
+        let count = batch.len();
+        if let Err(e) = db.record_clicks(std::mem::take(&mut batch)).await {
+            warn!("Failed to record {} clicks: {}", count, e);
+        }
+    }
+}
+
+/// Write the counted clicks to the table every so often, a redirect never waits on it.
+/// Once more on shutdown.
+async fn flush_clicks(state: Arc<HttpServeState>, every: Duration, shutdown: CancellationToken) {
+    let mut ticks = interval(every);
+    let mut stopping = false;
+    while !stopping {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.cancelled() => stopping = true,
+        }
+        if let Err(e) = state.write_clicks().await {
+            warn!("Failed to write clicks: {}", e);
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl RequestBody {
+    /// Check everything a create checks, the url is left as it will be stored.
+    fn validate(&mut self) -> Result<(), ShortenerError> {
+        self.url = validate_target(&self.url)?;
+
+        if let Some(alias) = &self.alias {
+            validate_alias(alias)?;
+        }
+
+        match (self.expires_at, self.ttl_seconds) {
+            (Some(_), Some(_)) => {
+                return Err(ShortenerError::InvalidExpiry(
+                    "set either expires_at or ttl_seconds, not both".to_string(),
+                ))
+            }
+            (Some(expires_at), None) if expires_at <= Utc::now() => {
+                return Err(ShortenerError::InvalidExpiry(
+                    "expires_at is in the past".to_string(),
+                ))
+            }
+            (None, Some(ttl)) if ttl == 0 || ttl > MAX_TTL_SECS => {
+                return Err(ShortenerError::InvalidExpiry(format!(
+                    "ttl_seconds must be between 1 and {}",
+                    MAX_TTL_SECS
+                )))
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// When the link stops redirecting, checked by `validate` first.
+    fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at.or_else(|| {
+            let ttl = TimeDelta::try_seconds(self.ttl_seconds? as i64)?;
+            Some(Utc::now() + ttl)
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "database" => Ok(Self::Database),
+            "memory" => Ok(Self::Memory),
+            _ => Err(format!(
+                "unknown backend {}, expected database or memory",
+                s
+            )),
+        }
+    }
+}
+
+impl ShortenedUrl {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+}
+
+/// Returns the url as it is stored and redirected to, what the client sent may have
+/// characters a `Location` header can't carry.
+fn validate_target(url: &str) -> Result<String, ShortenerError> {
+    let url = Url::parse(url).map_err(|e| ShortenerError::InvalidUrl(e.to_string()))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ShortenerError::InvalidUrl(format!(
+            "unsupported scheme: {}",
+            url.scheme()
+        )));
+    }
+    if url.host_str().is_none() {
+        return Err(ShortenerError::InvalidUrl("missing host".to_string()));
+    }
+
+    Ok(url.into())
+}
+
+fn validate_alias(alias: &str) -> Result<(), ShortenerError> {
+    if alias.is_empty() || alias.len() > MAX_ALIAS_LEN {
+        return Err(ShortenerError::InvalidAlias(format!(
+            "length must be between 1 and {}",
+            MAX_ALIAS_LEN
+        )));
+    }
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ShortenerError::InvalidAlias(
+            "only letters, digits, '-' and '_' are allowed".to_string(),
+        ));
+    }
+    if RESERVED_ALIASES.contains(&alias) {
+        return Err(ShortenerError::InvalidAlias(format!(
+            "{} is reserved",
+            alias
+        )));
+    }
+
+    Ok(())
+}
+
+impl ResponseBody {
+    fn new(url: String, expires_at: Option<DateTime<Utc>>, secret: Option<String>) -> Self {
+        Self {
+            url,
+            expires_at,
+            secret,
+        }
+    }
+}
+
+impl ErrorResponse {
+    fn new(code: u16, message: String) -> Self {
+        Self { code, message }
+    }
+
+    fn create_short_url_failed() -> Self {
+        Self::new(1, "Create short url failed".to_string())
+    }
+
+    fn get_url_failed() -> Self {
+        Self::new(2, "Get url failed".to_string())
+    }
+
+    fn timeout() -> Self {
+        Self::new(3, "Request timed out".to_string())
+    }
+
+    fn internal() -> Self {
+        Self::new(4, "Internal server error".to_string())
+    }
+
+    fn not_found() -> Self {
+        Self::new(5, "Short url not found".to_string())
+    }
+
+    fn invalid_url(reason: &str) -> Self {
+        Self::new(6, format!("Invalid url: {}", reason))
+    }
+
+    fn invalid_alias(reason: &str) -> Self {
+        Self::new(7, format!("Invalid alias: {}", reason))
+    }
+
+    fn alias_taken(alias: &str) -> Self {
+        Self::new(8, format!("Alias already taken: {}", alias))
+    }
+
+    fn invalid_body(reason: &str) -> Self {
+        Self::new(9, format!("Invalid JSON body: {}", reason))
+    }
+
+    fn invalid_expiry(reason: &str) -> Self {
+        Self::new(10, format!("Invalid expiry: {}", reason))
+    }
+
+    fn expired() -> Self {
+        Self::new(11, "Short url expired".to_string())
+    }
+
+    fn forbidden() -> Self {
+        Self::new(12, "Missing or wrong credentials".to_string())
+    }
+
+    fn url_taken(url: &str, id: Option<&str>) -> Self {
+        match id {
+            Some(id) => Self::new(13, format!("Url already shortened as {}: {}", id, url)),
+            None => Self::new(13, format!("Url already shortened: {}", url)),
+        }
+    }
+
+    fn invalid_query(reason: &str) -> Self {
+        Self::new(14, format!("Invalid query: {}", reason))
+    }
+
+    fn unauthorized() -> Self {
+        Self::new(15, "Missing or unknown api key".to_string())
+    }
+
+    fn rate_limited(wait: Duration) -> Self {
+        Self::new(
+            16,
+            format!("Too many requests, retry in {} seconds", retry_secs(wait)),
+        )
+    }
+
+    fn to_html(&self, status: StatusCode) -> String {
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>{status}</title>
+    <style>
+        body {{ font-family: sans-serif; background: #f5f5f5; color: #333; }}
+        main {{ max-width: 480px; margin: 120px auto; padding: 32px; background: #fff; border-radius: 8px; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.1); }}
+        h1 {{ margin-top: 0; font-size: 24px; }}
+        .code {{ color: #999; font-size: 12px; }}
+    </style>
+</head>
+<body>
+    <main>
+        <h1>{status}</h1>
+        <p>{message}</p>
+        <p class="code">Error code: {code}</p>
+    </main>
+</body>
+</html>"#,
+            status = status,
+            message = escape_html(&self.message),
+            code = self.code,
+        )
+    }
+}
+
+impl IntoResponse for ShortenerError {
+    fn into_response(self) -> Response {
+        warn!("{}", self);
+        let retry_after = match self {
+            Self::RateLimited(wait) => Some(retry_secs(wait)),
+            _ => None,
+        };
+        let (status, error) = match self {
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, ErrorResponse::not_found()),
+            Self::Expired(_) => (StatusCode::GONE, ErrorResponse::expired()),
+            Self::Forbidden(_) => (StatusCode::FORBIDDEN, ErrorResponse::forbidden()),
+            Self::Unauthorized => (StatusCode::UNAUTHORIZED, ErrorResponse::unauthorized()),
+            Self::RateLimited(wait) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorResponse::rate_limited(wait),
+            ),
+            Self::CreateShortUrlFailed(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse::create_short_url_failed(),
+            ),
+            Self::GetUrlFailed(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse::get_url_failed(),
+            ),
+            Self::InvalidBody(ref rejection) => (
+                rejection.status(),
+                ErrorResponse::invalid_body(&rejection.body_text()),
+            ),
+            Self::InvalidQuery(ref rejection) => (
+                rejection.status(),
+                ErrorResponse::invalid_query(&rejection.body_text()),
+            ),
+            Self::InvalidUrl(ref reason) => {
+                (StatusCode::BAD_REQUEST, ErrorResponse::invalid_url(reason))
+            }
+            Self::InvalidAlias(ref reason) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse::invalid_alias(reason),
+            ),
+            Self::AliasTaken(ref alias) => {
+                (StatusCode::CONFLICT, ErrorResponse::alias_taken(alias))
+            }
+            Self::UrlTaken { ref url, ref id } => (
+                StatusCode::CONFLICT,
+                ErrorResponse::url_taken(url, id.as_deref()),
+            ),
+            Self::InvalidExpiry(ref reason) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse::invalid_expiry(reason),
+            ),
+            Self::Timeout => (StatusCode::GATEWAY_TIMEOUT, ErrorResponse::timeout()),
+            Self::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::internal()),
+        };
+
+        // keep the error around so the response format can be negotiated later
+        let mut response = (status, Json(error.clone())).into_response();
+        response.extensions_mut().insert(error);
+        if let Some(secs) = retry_after {
+            response.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        response
+    }
+}
+
+/// Messages may quote what the client sent, like a rejected field's value.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whole seconds, rounded up so a client retrying on time isn't turned away again.
+fn retry_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::connect_info::MockConnectInfo};
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use std::{env, sync::OnceLock};
+    use tower::ServiceExt;
+
+    const API_KEY: &str = "test-api-key";
+
+    /// The shortener over an empty `MemoryStore` that knows `API_KEY`.
+    async fn test_state() -> Arc<HttpServeState> {
+        state_over(Arc::new(MemoryStore::default())).await
+    }
+
+    async fn state_over(db: Arc<dyn UrlStore>) -> Arc<HttpServeState> {
+        state_with(Config::default(), db).await
+    }
+
+    /// The key is `API_KEY` whatever `config` says.
+    async fn state_with(config: Config, db: Arc<dyn UrlStore>) -> Arc<HttpServeState> {
+        // nothing records the clicks, they are dropped
+        state_capturing(config, db).await.0
+    }
+
+    /// Along with the queue of clicks `record_clicks` takes from.
+    async fn state_capturing(
+        config: Config,
+        db: Arc<dyn UrlStore>,
+    ) -> (Arc<HttpServeState>, Receiver<Click>) {
+        let config = Config {
+            api_key: Some(API_KEY.to_string()),
+            ..config
+        };
+        let (analytics, clicks) = mpsc::channel(CLICK_QUEUE);
+        let state = HttpServeState::try_new(&config, db, None, analytics)
+            .await
+            .unwrap();
+        (Arc::new(state), clicks)
+    }
+
+    fn app(state: &Arc<HttpServeState>) -> Router {
+        app_with(state, routes(state), Config::default().request_timeout())
+    }
+
+    /// The router as `main` serves it, with every request coming from the same client.
+    fn app_with(
+        state: &Arc<HttpServeState>,
+        router: Router<Arc<HttpServeState>>,
+        request_timeout: Duration,
+    ) -> Router {
+        with_layers(router, request_timeout, prometheus())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))))
+            .with_state(state.clone())
+    }
+
+    /// The metrics macros only reach a global recorder, so the tests share the one installed
+    /// by the first of them.
+    fn prometheus() -> PrometheusHandle {
+        static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+        PROMETHEUS
+            .get_or_init(|| PrometheusBuilder::new().install_recorder().unwrap())
+            .clone()
+    }
+
+    fn request(method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> Request {
+        let mut builder = axum::http::Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        match body {
+            Some(body) => builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }
+        .unwrap()
+    }
+
+    /// The status and the JSON body, `Null` when there is none.
+    async fn send(app: &Router, request: Request) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    /// Create a link with the api key, returns its id and secret.
+    async fn create(app: &Router, body: Value) -> (String, String) {
+        let (status, body) = send(app, request(Method::POST, "/", Some(API_KEY), Some(body))).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        (id.to_string(), body["secret"].as_str().unwrap().to_string())
+    }
+
+    /// Where the id redirects to, if it does.
+    async fn follow(app: &Router, id: &str) -> (StatusCode, Option<String>) {
+        let uri = format!("/{}", id);
+        let response = app
+            .clone()
+            .oneshot(request(Method::GET, &uri, None, None))
+            .await
+            .unwrap();
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out_with_504() {
+        let state = test_state().await;
+        let router = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    sleep(Duration::from_secs(10)).await;
+                    StatusCode::OK
+                }),
+            )
+            .route("/fast", get(|| async { StatusCode::OK }));
+        let app = app_with(&state, router, Duration::from_millis(50));
+
+        let (status, body) = send(&app, request(Method::GET, "/slow", None, None)).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body, json!({"code": 3, "message": "Request timed out"}));
+
+        let (status, _) = send(&app, request(Method::GET, "/fast", None, None)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn errors_are_html_pages_for_browsers_only() {
+        let app = app(&test_state().await);
+
+        let (status, body) = send(&app, request(Method::GET, "/missing", None, None)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, json!({"code": 5, "message": "Short url not found"}));
+
+        let mut browser = request(Method::GET, "/missing", None, None);
+        browser
+            .headers_mut()
+            .insert(ACCEPT, "text/html,application/xhtml+xml".parse().unwrap());
+        let response = app.clone().oneshot(browser).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let page = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("<p>Short url not found</p>"));
+        assert!(page.contains("Error code: 5"));
+    }
+
+    #[tokio::test]
+    async fn html_error_pages_escape_what_the_client_sent() {
+        let app = app(&test_state().await);
+        let body = json!({"url": "https://example.com", "redirect": "<script>alert(1)</script>"});
+        let mut browser = request(Method::POST, "/validate", None, Some(body));
+        browser
+            .headers_mut()
+            .insert(ACCEPT, "text/html".parse().unwrap());
+
+        let response = app.clone().oneshot(browser).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let page = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_links_leave_the_cache_first() {
+        let config = Config {
+            cache_capacity: 2,
+            ..Config::default()
+        };
+        let state = state_with(config, Arc::new(MemoryStore::default())).await;
+        let app = app(&state);
+        let mut ids = Vec::new();
+        for n in 0..3 {
+            let url = format!("https://example.com/{}", n);
+            ids.push(create(&app, json!({"url": url})).await.0);
+        }
+
+        for id in &ids[..2] {
+            follow(&app, id).await;
+        }
+        // the first is used again, the second is now the oldest
+        follow(&app, &ids[0]).await;
+        assert_eq!(state.cache_stats(), (1, 2));
+        follow(&app, &ids[2]).await;
+        {
+            let cache = state.cache.lock().unwrap();
+            assert!(cache.contains(&ids[0]));
+            assert!(!cache.contains(&ids[1]));
+            assert!(cache.contains(&ids[2]));
+        }
+
+        let (status, location) = follow(&app, &ids[1]).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(location.as_deref(), Some("https://example.com/1"));
+        assert_eq!(state.cache_stats(), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn deleted_links_are_not_served_from_the_cache() {
+        let state = test_state().await;
+        let app = app(&state);
+        let (id, secret) = create(&app, json!({"url": "https://example.com/gone"})).await;
+        assert_eq!(follow(&app, &id).await.0, StatusCode::FOUND);
+        assert!(state.cache.lock().unwrap().contains(&id));
+
+        let uri = format!("/{}", id);
+        let (status, _) = send(&app, request(Method::DELETE, &uri, Some(&secret), None)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!state.cache.lock().unwrap().contains(&id));
+        assert_eq!(follow(&app, &id).await, (StatusCode::NOT_FOUND, None));
+    }
+
+    #[tokio::test]
+    async fn validate_checks_like_create_without_creating() {
+        let state = test_state().await;
+        let app = app(&state);
+        create(
+            &app,
+            json!({"url": "https://example.com/taken", "alias": "taken"}),
+        )
+        .await;
+        let validate =
+            |body: Value| send(&app, request(Method::POST, "/validate", None, Some(body)));
+
+        for body in [
+            json!({"url": "https://example.com"}),
+            json!({"url": "http://example.com/a", "alias": "free", "ttl_seconds": 60}),
+            // the alias already points there, creating it again changes nothing
+            json!({"url": "https://example.com/taken", "alias": "taken"}),
+        ] {
+            assert_eq!(
+                validate(body.clone()).await,
+                (StatusCode::OK, json!({"valid": true})),
+                "{}",
+                body
+            );
+        }
+
+        let past = (Utc::now() - TimeDelta::try_hours(1).unwrap()).to_rfc3339();
+        let long = "a".repeat(MAX_ALIAS_LEN + 1);
+        for (body, status, code) in [
+            (json!({"url": "not a url"}), StatusCode::BAD_REQUEST, 6),
+            (
+                json!({"url": "ftp://example.com"}),
+                StatusCode::BAD_REQUEST,
+                6,
+            ),
+            (
+                json!({"url": "https://example.com", "alias": "a/b"}),
+                StatusCode::BAD_REQUEST,
+                7,
+            ),
+            (
+                json!({"url": "https://example.com", "alias": long}),
+                StatusCode::BAD_REQUEST,
+                7,
+            ),
+            (
+                json!({"url": "https://example.com", "alias": "metrics"}),
+                StatusCode::BAD_REQUEST,
+                7,
+            ),
+            (
+                json!({"url": "https://example.com", "alias": "taken"}),
+                StatusCode::CONFLICT,
+                8,
+            ),
+            (
+                json!({"url": "https://example.com", "expires_at": past}),
+                StatusCode::BAD_REQUEST,
+                10,
+            ),
+            (
+                json!({"url": "https://example.com", "ttl_seconds": 0}),
+                StatusCode::BAD_REQUEST,
+                10,
+            ),
+            (
+                json!({"url": "https://example.com", "ttl_seconds": 60, "expires_at": past}),
+                StatusCode::BAD_REQUEST,
+                10,
+            ),
+        ] {
+            let (got, error) = validate(body.clone()).await;
+            assert_eq!(
+                (got, error["code"].as_u64()),
+                (status, Some(code)),
+                "{}",
+                body
+            );
+        }
+
+        // only the link made above
+        assert_eq!(state.db.list(None, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn targets_are_stored_normalized() {
+        let app = app(&test_state().await);
+        // the parser drops the newline, a header couldn't carry it
+        let (id, _) = create(&app, json!({"url": "https://EXAMPLE.com/a\nb c"})).await;
+        assert_eq!(
+            follow(&app, &id).await,
+            (
+                StatusCode::FOUND,
+                Some("https://example.com/ab%20c".to_string())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_are_created_once_per_url() {
+        let app = app(&test_state().await);
+        let alias = |alias: &str, url: &str| {
+            let body = json!({"url": url, "alias": alias});
+            send(&app, request(Method::POST, "/", Some(API_KEY), Some(body)))
+        };
+
+        // free
+        let (status, body) = alias("docs", "https://example.com/docs").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["url"], "http://0.0.0.0:4321/docs");
+        assert!(body["secret"].is_string());
+
+        // the same mapping again, without the secret that came with it the first time
+        let (status, body) = alias("docs", "https://example.com/docs").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"url": "http://0.0.0.0:4321/docs"}));
+
+        // taken by another url
+        let (status, body) = alias("docs", "https://example.com/other").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["message"], "Alias already taken: docs");
+
+        // the url is already another link's
+        let (status, body) = alias("manual", "https://example.com/docs").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            body["message"],
+            "Url already shortened as docs: https://example.com/docs"
+        );
+        assert_eq!(follow(&app, "manual").await.0, StatusCode::NOT_FOUND);
+
+        // malformed or one of the routes
+        let (status, _) = alias("two words", "https://example.com/a").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = alias("links", "https://example.com/a").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("links is reserved"));
+    }
+
+    #[tokio::test]
+    async fn rejected_bodies_get_the_error_envelope() {
+        let app = app(&test_state().await);
+        let raw = |body: &'static str| {
+            let mut request = request(Method::POST, "/", Some(API_KEY), None);
+            *request.body_mut() = Body::from(body);
+            request
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            request
+        };
+
+        let (status, body) = send(&app, raw(r#"{"url": "https://example.com""#)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], 9);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid JSON body: Failed to parse the request body as JSON"));
+
+        let (status, body) = send(&app, raw(r#"{"alias": "docs"}"#)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], 9);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("missing field `url`"));
+
+        let (status, body) = send(&app, request(Method::POST, "/", Some(API_KEY), None)).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["code"], 9);
+    }
+
+    /// Each url gets an id of its own and every one of them is stored, however the creates
+    /// interleave.
+    async fn create_concurrently(db: Arc<dyn UrlStore>) {
+        const CREATES: usize = 200;
+        let state = state_over(db).await;
+        // a database may have the urls of earlier runs
+        let run = nanoid!(8);
+        let mut creates = JoinSet::new();
+        for n in 0..CREATES {
+            let state = state.clone();
+            let run = run.clone();
+            creates.spawn(async move {
+                let url = format!("https://example.com/{}/{}", run, n);
+                let (id, _, secret) = state
+                    .create_shortened_url(&url, None, None, None)
+                    .await
+                    .unwrap();
+                assert!(secret.is_some());
+                (id, url)
+            });
+        }
+
+        let mut created = HashMap::new();
+        while let Some(ret) = creates.join_next().await {
+            let (id, url) = ret.unwrap();
+            assert!(created.insert(id, url).is_none(), "id given out twice");
+        }
+        assert_eq!(created.len(), CREATES);
+        for (id, url) in &created {
+            let stored = state.db.resolve(id).await.unwrap().unwrap();
+            assert_eq!(&stored.url, url);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_creates_in_memory() {
+        create_concurrently(Arc::new(MemoryStore::default())).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_creates_in_sqlite() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        create_concurrently(Arc::new(db)).await;
+    }
+
+    // run with `cargo test -- --ignored` and a database the test may create tables in
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs a Postgres database at SHORTENER_TEST_DATABASE_URL"]
+    async fn concurrent_creates_in_postgres() {
+        let url = env::var("SHORTENER_TEST_DATABASE_URL").expect("SHORTENER_TEST_DATABASE_URL");
+        let db = SqlStore::connect(&url, 10).await.unwrap();
+        create_concurrently(Arc::new(db)).await;
+    }
+
+    #[tokio::test]
+    async fn shortening_a_url_again_keeps_its_redirect() {
+        let app = app(&test_state().await);
+        let url = "https://example.com/moved";
+        let (id, _) = create(&app, json!({"url": url, "redirect": "permanent"})).await;
+        assert_eq!(follow(&app, &id).await.0, StatusCode::MOVED_PERMANENTLY);
+
+        let body = json!({"url": url, "redirect": "temporary"});
+        let (status, body) =
+            send(&app, request(Method::POST, "/", Some(API_KEY), Some(body))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["url"].as_str().unwrap().ends_with(&id));
+        assert_eq!(follow(&app, &id).await.0, StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[tokio::test]
+    async fn shortening_a_url_again_leaves_its_expiry_alone() {
+        let state = test_state().await;
+        let app = app(&state);
+        let shorten =
+            |body: Value| send(&app, request(Method::POST, "/", Some(API_KEY), Some(body)));
+        let url = "https://example.com/sale";
+
+        let (_, first) = shorten(json!({"url": url, "ttl_seconds": 60})).await;
+        let expires_at = first["expires_at"].clone();
+        assert!(expires_at.is_string());
+        for body in [
+            json!({"url": url}),
+            json!({"url": url, "ttl_seconds": 3600}),
+        ] {
+            let (status, again) = shorten(body).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                again,
+                json!({"url": first["url"], "expires_at": expires_at})
+            );
+        }
+        let id = first["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        let stored = state.db.resolve(id).await.unwrap().unwrap();
+        assert_eq!(json!(stored.expires_at), expires_at);
+
+        let aliased = "https://example.com/promo";
+        let (_, first) =
+            shorten(json!({"url": aliased, "alias": "promo", "ttl_seconds": 60})).await;
+        let (status, again) =
+            shorten(json!({"url": aliased, "alias": "promo", "ttl_seconds": 3600})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            again,
+            json!({"url": first["url"], "expires_at": first["expires_at"]})
+        );
+    }
+
+    /// An alias claimed again once expired starts without the clicks and edits of the link
+    /// that had it.
+    async fn reclaim_expired_alias(db: Arc<dyn UrlStore>) {
+        let state = state_over(db).await;
+        let expired = Some(Utc::now() - TimeDelta::try_hours(1).unwrap());
+        let db = &state.db;
+        let old = "https://example.com/old";
+        assert!(db
+            .claim_alias("sale", old, expired, None, "")
+            .await
+            .unwrap());
+        db.update("sale", "https://example.com/older")
+            .await
+            .unwrap();
+        db.add_clicks(&HashMap::from([("sale".to_string(), 5)]))
+            .await
+            .unwrap();
+        db.record_clicks(vec![Click {
+            url_id: "sale".to_string(),
+            clicked_at: Utc::now(),
+            referrer: None,
+            user_agent: None,
+            ip_hash: String::new(),
+        }])
+        .await
+        .unwrap();
+        state.record_click("sale");
+
+        let app = app(&state);
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/new", "alias": "sale"}),
+        )
+        .await;
+        assert_eq!(id, "sale");
+        let (status, stats) = send(&app, request(Method::GET, "/sale/stats", None, None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["clicks"], 0);
+    }
+
+    #[tokio::test]
+    async fn reclaimed_aliases_start_over_in_memory() {
+        reclaim_expired_alias(Arc::new(MemoryStore::default())).await;
+    }
+
+    #[tokio::test]
+    async fn reclaimed_aliases_start_over_in_sqlite() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        let SqlStore::Sqlite(pool) = db.clone() else {
+            unreachable!()
+        };
+        reclaim_expired_alias(Arc::new(db)).await;
+        for table in ["clicks", "url_edits"] {
+            let query = format!("SELECT COUNT(*) FROM {} WHERE url_id = 'sale'", table);
+            let (rows,): (i64,) = sqlx::query_as(&query).fetch_one(&pool).await.unwrap();
+            assert_eq!(rows, 0, "{}", table);
+        }
+    }
+
+    /// Urls whose links expired but weren't purged yet, shortened again.
+    async fn shorten_expired_urls_again(db: Arc<dyn UrlStore>) {
+        let app = app(&state_over(db).await);
+        let soon = Utc::now() + TimeDelta::try_milliseconds(300).unwrap();
+        let (old, _) = create(
+            &app,
+            json!({"url": "https://example.com/a", "expires_at": soon}),
+        )
+        .await;
+        create(
+            &app,
+            json!({"url": "https://example.com/b", "expires_at": soon}),
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let body = json!({"url": "https://example.com/a"});
+        let (status, body) =
+            send(&app, request(Method::POST, "/", Some(API_KEY), Some(body))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(body["secret"].is_string());
+        assert!(body.get("expires_at").is_none());
+        let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        assert_ne!(id, old);
+        assert_eq!(
+            follow(&app, id).await,
+            (StatusCode::FOUND, Some("https://example.com/a".to_string()))
+        );
+
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/b", "alias": "fresh"}),
+        )
+        .await;
+        assert_eq!(id, "fresh");
+        assert_eq!(
+            follow(&app, "fresh").await,
+            (StatusCode::FOUND, Some("https://example.com/b".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_urls_are_shortened_anew_in_memory() {
+        shorten_expired_urls_again(Arc::new(MemoryStore::default())).await;
+    }
+
+    #[tokio::test]
+    async fn expired_urls_are_shortened_anew_in_sqlite() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        shorten_expired_urls_again(Arc::new(db)).await;
+    }
+
+    #[tokio::test]
+    async fn updates_store_the_target_normalized() {
+        let app = app(&test_state().await);
+        let (id, secret) = create(&app, json!({"url": "https://example.com/a"})).await;
+        let (other, _) = create(&app, json!({"url": "https://example.com/c"})).await;
+        let uri = format!("/{}", id);
+        let update = |token, url: &str| {
+            let body = json!({"url": url});
+            send(&app, request(Method::PUT, &uri, token, Some(body)))
+        };
+
+        let (status, _) = update(None, "https://example.com/b").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = update(Some(&secret), "javascript:alert(1)").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = update(Some(&secret), "https://example.com/c").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            body["message"],
+            format!("Url already shortened as {}: https://example.com/c", other)
+        );
+
+        let (status, _) = update(Some(&secret), "https://EXAMPLE.com/b\nc").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            follow(&app, &id).await,
+            (
+                StatusCode::FOUND,
+                Some("https://example.com/bc".to_string())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn listing_links_takes_an_api_key() {
+        let app = app(&test_state().await);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+
+        let (status, _) = send(&app, request(Method::GET, "/links", None, None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&app, request(Method::GET, "/links", Some("nope"), None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = send(&app, request(Method::GET, "/links", Some(API_KEY), None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["links"][0]["id"], id);
+    }
+
+    #[tokio::test]
+    async fn links_redirect_until_they_expire() {
+        let app = app(&test_state().await);
+        let expires_at = Utc::now() + TimeDelta::try_milliseconds(300).unwrap();
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/a", "expires_at": expires_at}),
+        )
+        .await;
+        assert_eq!(
+            follow(&app, &id).await,
+            (StatusCode::FOUND, Some("https://example.com/a".to_string()))
+        );
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        // the cached copy included
+        assert_eq!(follow(&app, &id).await, (StatusCode::GONE, None));
+        assert_eq!(follow(&app, "unknown").await, (StatusCode::NOT_FOUND, None));
+    }
+
+    #[tokio::test]
+    async fn deleting_takes_the_links_secret() {
+        let app = app(&test_state().await);
+        let (id, secret) = create(&app, json!({"url": "https://example.com/a"})).await;
+        let (_, other) = create(&app, json!({"url": "https://example.com/b"})).await;
+        let uri = format!("/{}", id);
+        let delete = |token| send(&app, request(Method::DELETE, &uri, token, None));
+
+        assert_eq!(delete(None).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(delete(Some(&other)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(follow(&app, &id).await.0, StatusCode::FOUND);
+
+        assert_eq!(delete(Some(&secret)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(follow(&app, &id).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(delete(Some(&secret)).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deleted_links_leave_no_analytics_in_sqlite() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        let SqlStore::Sqlite(pool) = db.clone() else {
+            unreachable!()
+        };
+        let state = state_over(Arc::new(db)).await;
+        let app = app(&state);
+        let (id, secret) = create(&app, json!({"url": "https://example.com/a"})).await;
+        state.db.update(&id, "https://example.com/b").await.unwrap();
+        state
+            .db
+            .record_clicks(vec![Click {
+                url_id: id.clone(),
+                clicked_at: Utc::now(),
+                referrer: None,
+                user_agent: None,
+                ip_hash: String::new(),
+            }])
+            .await
+            .unwrap();
+
+        let uri = format!("/{}", id);
+        let (status, _) = send(&app, request(Method::DELETE, &uri, Some(&secret), None)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        for table in ["clicks", "url_edits"] {
+            let query = format!("SELECT COUNT(*) FROM {} WHERE url_id = $1", table);
+            let (rows,): (i64,) = sqlx::query_as(&query)
+                .bind(&id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(rows, 0, "{}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn links_are_listed_a_page_at_a_time() {
+        let app = app(&test_state().await);
+        let mut created = Vec::new();
+        for i in 0..5 {
+            let url = format!("https://example.com/{}", i);
+            created.push(create(&app, json!({ "url": url })).await.0);
+        }
+        created.sort();
+
+        let mut listed = Vec::new();
+        let mut uri = "/links?limit=2".to_string();
+        loop {
+            let (status, body) = send(&app, request(Method::GET, &uri, Some(API_KEY), None)).await;
+            assert_eq!(status, StatusCode::OK);
+            let links = body["links"].as_array().unwrap();
+            assert!(links.len() <= 2);
+            listed.extend(
+                links
+                    .iter()
+                    .map(|link| link["id"].as_str().unwrap().to_string()),
+            );
+            match body["next_cursor"].as_str() {
+                Some(cursor) => uri = format!("/links?limit=2&cursor={}", cursor),
+                None => break,
+            }
+        }
+        assert_eq!(listed, created);
+
+        let (status, _) = send(
+            &app,
+            request(Method::GET, "/links?limit=x", Some(API_KEY), None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn creates_over_the_burst_are_told_when_to_retry() {
+        let config = Config {
+            create_burst: 2,
+            create_refill_ms: 60_000,
+            ..Config::default()
+        };
+        let app = app(&state_with(config, Arc::new(MemoryStore::default())).await);
+        for n in 0..2 {
+            create(&app, json!({ "url": format!("https://example.com/{}", n) })).await;
+        }
+
+        let body = json!({"url": "https://example.com/2"});
+        let response = app
+            .clone()
+            .oneshot(request(Method::POST, "/", Some(API_KEY), Some(body)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{}", retry_after);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["message"],
+            format!("Too many requests, retry in {} seconds", retry_after)
+        );
+
+        // redirects aren't limited
+        assert_eq!(follow(&app, "unknown").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn qr_codes_are_cached_by_what_they_encode() {
+        let state = test_state().await;
+        // the same links under another base url
+        let config = Config {
+            base_url: Some("https://sho.rt".to_string()),
+            ..Config::default()
+        };
+        let moved = app(&state_with(config, state.db.clone()).await);
+        let app = app(&state);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+        let uri = format!("/{}/qr", id);
+        let get = |etag: Option<&str>| {
+            let mut request = request(Method::GET, &uri, None, None);
+            if let Some(etag) = etag {
+                request
+                    .headers_mut()
+                    .insert(IF_NONE_MATCH, etag.parse().unwrap());
+            }
+            app.clone().oneshot(request)
+        };
+
+        let response = get(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(response.headers()[CACHE_CONTROL], QR_CACHE_CONTROL);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(b"<?xml"));
+
+        let response = get(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+        assert_eq!(
+            get(Some("\"other\"")).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        // the same id under another base url is another code
+        let mut request = request(Method::GET, &uri, None, None);
+        request
+            .headers_mut()
+            .insert(IF_NONE_MATCH, etag.parse().unwrap());
+        let response = moved.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn qr_codes_of_expired_links_are_gone() {
+        let app = app(&test_state().await);
+        let soon = Utc::now() + TimeDelta::try_milliseconds(200).unwrap();
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/a", "expires_at": soon}),
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let uri = format!("/{}/qr", id);
+        let (status, _) = send(&app, request(Method::GET, &uri, None, None)).await;
+        assert_eq!(status, StatusCode::GONE);
+        let (status, _) = send(&app, request(Method::GET, "/unknown/qr", None, None)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn readiness_names_the_check_that_failed() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        let app = app(&state_over(Arc::new(db.clone())).await);
+
+        let (status, body) = send(&app, request(Method::GET, "/healthz", None, None)).await;
+        assert_eq!((status, body), (StatusCode::OK, json!({"status": "ok"})));
+        let (status, body) = send(&app, request(Method::GET, "/readyz", None, None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["checks"]["sqlite"]["status"], "ok");
+
+        // the store stops answering, the process goes on
+        db.close().await;
+        let (status, body) = send(&app, request(Method::GET, "/readyz", None, None)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["checks"]["sqlite"]["status"], "down");
+        assert!(body["checks"]["sqlite"]["error"].is_string());
+        let (status, _) = send(&app, request(Method::GET, "/healthz", None, None)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_requests_and_cache_hits() {
+        let app = app(&test_state().await);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+        // a miss that fills the cache, then a hit
+        for _ in 0..2 {
+            assert_eq!(follow(&app, &id).await.0, StatusCode::FOUND);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request(Method::GET, "/metrics", None, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        let series = |name: &str, labels: &[&str]| {
+            metrics.lines().any(|line| {
+                line.starts_with(name) && labels.iter().all(|label| line.contains(label))
+            })
+        };
+        assert!(series(
+            "shortener_http_requests_total{",
+            &[r#"method="POST""#, r#"route="/""#, r#"status="201""#]
+        ));
+        assert!(series(
+            "shortener_http_requests_total{",
+            &[r#"method="GET""#, r#"route="/:id""#, r#"status="302""#]
+        ));
+        assert!(series("shortener_redirect_duration_seconds", &[]));
+        assert!(series("shortener_cache_hits_total 1", &[]), "{}", metrics);
+        assert!(series("shortener_cache_misses_total 1", &[]), "{}", metrics);
+        assert!(series("shortener_cache_hit_ratio 0.5", &[]), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_sent_to_the_same_path_over_https() {
+        let redirect = |https_port, host: Option<&'static str>, uri: &str| {
+            let mut request = request(Method::GET, uri, None, None);
+            if let Some(host) = host {
+                request.headers_mut().insert(HOST, host.parse().unwrap());
+            }
+            https_redirects(https_port).oneshot(request)
+        };
+        let location = |response: &Response| {
+            let location = response.headers().get(LOCATION)?;
+            Some(location.to_str().unwrap().to_string())
+        };
+
+        let response = redirect(443, Some("sho.rt:80"), "/abc?x=1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            location(&response).as_deref(),
+            Some("https://sho.rt/abc?x=1")
+        );
+
+        let response = redirect(8443, Some("sho.rt:8080"), "/").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(location(&response).as_deref(), Some("https://sho.rt:8443/"));
+
+        let response = redirect(443, None, "/abc").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(location(&response), None);
+    }
+
+    /// The status and `Cache-Control` of a redirect.
+    async fn redirect_caching(app: &Router, id: &str) -> (StatusCode, String) {
+        let uri = format!("/{}", id);
+        let response = app
+            .clone()
+            .oneshot(request(Method::GET, &uri, None, None))
+            .await
+            .unwrap();
+        let cache_control = response.headers()[CACHE_CONTROL].to_str().unwrap();
+        (response.status(), cache_control.to_string())
+    }
+
+    #[tokio::test]
+    async fn redirects_are_temporary_unless_a_link_says_otherwise() {
+        let app = app(&test_state().await);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+        assert_eq!(
+            redirect_caching(&app, &id).await,
+            (StatusCode::FOUND, "private, no-store".to_string())
+        );
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/b", "redirect": "permanent"}),
+        )
+        .await;
+        assert_eq!(
+            redirect_caching(&app, &id).await,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                "public, max-age=86400".to_string()
+            )
+        );
+        // no longer than the link lives
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/c", "redirect": "permanent", "ttl_seconds": 600}),
+        )
+        .await;
+        let (status, cache_control) = redirect_caching(&app, &id).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        let max_age: u64 = cache_control
+            .strip_prefix("public, max-age=")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((595..=600).contains(&max_age), "{}", max_age);
+    }
+
+    #[tokio::test]
+    async fn redirects_are_permanent_when_configured_unless_a_link_says_otherwise() {
+        let config = Config {
+            redirect: RedirectKind::Permanent,
+            ..Config::default()
+        };
+        let app = app(&state_with(config, Arc::new(MemoryStore::default())).await);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+        assert_eq!(
+            redirect_caching(&app, &id).await,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                "public, max-age=86400".to_string()
+            )
+        );
+        let (id, _) = create(
+            &app,
+            json!({"url": "https://example.com/b", "redirect": "temporary"}),
+        )
+        .await;
+        assert_eq!(
+            redirect_caching(&app, &id).await,
+            (StatusCode::FOUND, "private, no-store".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_count_in_the_stats_before_and_after_a_flush() {
+        let state = test_state().await;
+        let app = app(&state);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+        let stats = format!("/{}/stats", id);
+        for _ in 0..3 {
+            follow(&app, &id).await;
+        }
+        let (_, body) = send(&app, request(Method::GET, &stats, None, None)).await;
+        assert_eq!(body["clicks"], 3);
+        assert_eq!(state.db.resolve(&id).await.unwrap().unwrap().clicks, 0);
+
+        let shutdown = CancellationToken::new();
+        let flush = tokio::spawn(flush_clicks(
+            state.clone(),
+            Duration::from_millis(10),
+            shutdown.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.db.resolve(&id).await.unwrap().unwrap().clicks, 3);
+        follow(&app, &id).await;
+        // the last ones are written on shutdown
+        shutdown.cancel();
+        flush.await.unwrap();
+        assert_eq!(state.db.resolve(&id).await.unwrap().unwrap().clicks, 4);
+        assert_eq!(state.pending_clicks(&id), 0);
+
+        // counted once, not again as pending
+        let (_, body) = send(&app, request(Method::GET, &stats, None, None)).await;
+        assert_eq!(body["clicks"], 4);
+    }
+
+    #[tokio::test]
+    async fn clicks_keep_the_referrer_and_agent_but_not_the_address() {
+        let db = SqlStore::connect("sqlite::memory:", 1).await.unwrap();
+        let SqlStore::Sqlite(pool) = db.clone() else {
+            unreachable!()
+        };
+        let config = Config {
+            ip_salt: Some("pepper".to_string()),
+            ..Config::default()
+        };
+        let (state, clicks) = state_capturing(config, Arc::new(db)).await;
+        let shutdown = CancellationToken::new();
+        let recording = tokio::spawn(record_clicks(state.db.clone(), clicks, shutdown.clone()));
+        let app = app(&state);
+        let (id, _) = create(&app, json!({"url": "https://example.com/a"})).await;
+
+        let uri = format!("/{}", id);
+        let mut request = request(Method::GET, &uri, None, None);
+        let headers = request.headers_mut();
+        headers.insert(REFERER, "https://news.example.com/".parse().unwrap());
+        headers.insert(USER_AGENT, "curl/8.0".parse().unwrap());
+        let (status, _) = send(&app, request).await;
+        assert_eq!(status, StatusCode::FOUND);
+        follow(&app, &id).await;
+        shutdown.cancel();
+        recording.await.unwrap();
+
+        let rows: Vec<(String, Option<String>, Option<String>, String)> =
+            sqlx::query_as("SELECT url_id, referrer, user_agent, ip_hash FROM clicks ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        // every client comes from 127.0.0.1
+        let ip_hash = hex::encode(Sha256::digest(b"pepper127.0.0.1"));
+        assert_eq!(
+            rows,
+            [
+                (
+                    id.clone(),
+                    Some("https://news.example.com/".to_string()),
+                    Some("curl/8.0".to_string()),
+                    ip_hash.clone()
+                ),
+                (id.clone(), None, None, ip_hash),
+            ]
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! Short urls kept in memory, for trying the shortener out without a database. Everything
+//! is gone when the server stops.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
+use futures::future::{self, BoxFuture};
+
+use crate::store::{Click, ShortenedUrl, StoreError, UrlStore};
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    urls: DashMap<String, ShortenedUrl>,
+    // the id each url is shortened as, unique like the column
+    ids: DashMap<String, String>,
+    // changes take this in turn so `urls` and `ids` agree, lookups don't
+    writes: Mutex<()>,
+    clicks: Mutex<Vec<Click>>,
+    edits: Mutex<Vec<UrlEdit>>,
+    api_keys: DashSet<String>,
+}
+
+// id, previous url, new url and when, like a row of `url_edits`
+type UrlEdit = (String, String, String, DateTime<Utc>);
+
+impl MemoryStore {
+    fn insert(
+        &self,
+        id: &str,
+        url: &str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &str,
+    ) -> Result<ShortenedUrl, StoreError> {
+        let _writes = self.writes.lock().unwrap();
+        if self.urls.contains_key(id) {
+            return Err(StoreError::Taken);
+        }
+        let existing = self.ids.get(url).map(|id| id.value().clone());
+        if let Some(existing) = existing {
+            let row = self.urls.get(&existing).unwrap().clone();
+            if !row.is_expired() {
+                return Ok(row);
+            }
+            // replaced by the new link
+            self.drop_row(&existing);
+        }
+
+        let row = ShortenedUrl {
+            id: id.to_string(),
+            url: url.to_string(),
+            expires_at,
+            clicks: 0,
+            secret_hash: Some(secret_hash.to_string()),
+            created_at: Utc::now(),
+            permanent,
+        };
+        self.ids.insert(url.to_string(), id.to_string());
+        self.urls.insert(id.to_string(), row.clone());
+        Ok(row)
+    }
+
+    fn claim(
+        &self,
+        alias: &str,
+        url: &str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &str,
+    ) -> Result<bool, StoreError> {
+        let _writes = self.writes.lock().unwrap();
+        if self.urls.get(alias).is_some_and(|row| !row.is_expired()) {
+            return Ok(false);
+        }
+        let other = self
+            .ids
+            .get(url)
+            .map(|id| id.value().clone())
+            .filter(|id| id != alias);
+        if let Some(other) = other {
+            // an expired link of the url gives it up
+            if !self.urls.get(&other).unwrap().is_expired() {
+                return Err(StoreError::Taken);
+            }
+            self.drop_row(&other);
+        }
+
+        self.drop_row(alias);
+        self.ids.insert(url.to_string(), alias.to_string());
+        self.urls.insert(
+            alias.to_string(),
+            ShortenedUrl {
+                id: alias.to_string(),
+                url: url.to_string(),
+                expires_at,
+                clicks: 0,
+                secret_hash: Some(secret_hash.to_string()),
+                created_at: Utc::now(),
+                permanent,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Sorts every id for each page, fine for the links a demo has.
+    fn page(&self, after: Option<&str>, limit: u32) -> Vec<ShortenedUrl> {
+        let mut rows: Vec<_> = self
+            .urls
+            .iter()
+            .filter(|row| after.is_none_or(|after| row.id.as_str() > after))
+            .map(|row| row.clone())
+            .collect();
+        rows.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        rows.truncate(limit as usize);
+        rows
+    }
+
+    fn retarget(&self, id: &str, url: &str) -> Result<Option<String>, StoreError> {
+        let _writes = self.writes.lock().unwrap();
+        let Some(old) = self.urls.get(id).map(|row| row.url.clone()) else {
+            return Ok(None);
+        };
+        if old == url {
+            return Ok(Some(old));
+        }
+        if self.ids.contains_key(url) {
+            return Err(StoreError::Taken);
+        }
+
+        self.ids.remove(&old);
+        self.ids.insert(url.to_string(), id.to_string());
+        self.urls.get_mut(id).unwrap().url = url.to_string();
+        self.edits
+            .lock()
+            .unwrap()
+            .push((id.to_string(), old.clone(), url.to_string(), Utc::now()));
+        Ok(Some(old))
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        let _writes = self.writes.lock().unwrap();
+        self.drop_row(id)
+    }
+
+    fn purge(&self) -> u64 {
+        let _writes = self.writes.lock().unwrap();
+        let expired: Vec<_> = self
+            .urls
+            .iter()
+            .filter(|row| row.is_expired())
+            .map(|row| row.id.clone())
+            .collect();
+        for id in &expired {
+            self.drop_row(id);
+        }
+        expired.len() as u64
+    }
+
+    /// Remove the link with its clicks and edits, with `writes` held.
+    fn drop_row(&self, id: &str) -> bool {
+        let Some((_, row)) = self.urls.remove(id) else {
+            return false;
+        };
+        self.ids.remove(&row.url);
+        self.clicks
+            .lock()
+            .unwrap()
+            .retain(|click| click.url_id != id);
+        self.edits.lock().unwrap().retain(|edit| edit.0 != id);
+        true
+    }
+
+    fn count_clicks(&self, clicks: &HashMap<String, u64>) {
+        for (id, count) in clicks {
+            if let Some(mut row) = self.urls.get_mut(id) {
+                row.clicks += *count as i64;
+            }
+        }
+    }
+}
+
+impl UrlStore for MemoryStore {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn create<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<ShortenedUrl, StoreError>> {
+        Box::pin(future::ready(self.insert(
+            id,
+            url,
+            expires_at,
+            permanent,
+            secret_hash,
+        )))
+    }
+
+    fn claim_alias<'a>(
+        &'a self,
+        alias: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(future::ready(self.claim(
+            alias,
+            url,
+            expires_at,
+            permanent,
+            secret_hash,
+        )))
+    }
+
+    fn resolve<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>> {
+        Box::pin(future::ready(Ok(self.urls.get(id).map(|row| row.clone()))))
+    }
+
+    fn find<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>> {
+        let row = self
+            .ids
+            .get(url)
+            .and_then(|id| self.urls.get(id.value()).map(|row| row.clone()));
+        Box::pin(future::ready(Ok(row)))
+    }
+
+    fn list<'a>(
+        &'a self,
+        after: Option<&'a str>,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<ShortenedUrl>>> {
+        Box::pin(future::ready(Ok(self.page(after, limit))))
+    }
+
+    fn update<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        Box::pin(future::ready(self.retarget(id, url)))
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(future::ready(Ok(self.remove(id))))
+    }
+
+    fn delete_expired(&self) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(future::ready(Ok(self.purge())))
+    }
+
+    fn add_clicks<'a>(&'a self, clicks: &'a HashMap<String, u64>) -> BoxFuture<'a, Result<()>> {
+        self.count_clicks(clicks);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn record_clicks(&self, clicks: Vec<Click>) -> BoxFuture<'_, Result<()>> {
+        self.clicks.lock().unwrap().extend(clicks);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn is_api_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(future::ready(Ok(self.api_keys.contains(key_hash))))
+    }
+
+    fn add_api_key<'a>(&'a self, key_hash: &'a str, _name: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.api_keys.insert(key_hash.to_string());
+        Box::pin(future::ready(Ok(())))
+    }
+}
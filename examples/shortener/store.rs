@@ -0,0 +1,746 @@
+//! Where the short urls are kept: any [`UrlStore`], [`SqlStore`] on Postgres or on SQLite
+//! when the database url starts with `sqlite:`, like `sqlite://shortener.db` or
+//! `sqlite::memory:`, or [`MemoryStore`](crate::memory::MemoryStore) with
+//! `--backend memory`.
+
+use std::{collections::HashMap, fmt::Debug, str::FromStr};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    FromRow, PgPool, SqlitePool,
+};
+use thiserror::Error;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct ShortenedUrl {
+    #[sqlx(default)]
+    pub id: String,
+    #[sqlx(default)]
+    pub url: String,
+    #[sqlx(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    pub clicks: i64,
+    // not for caches, only the database is asked who may change the link
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    #[sqlx(default)]
+    pub created_at: DateTime<Utc>,
+    // 301 rather than 302, the configured default when not set
+    #[sqlx(default)]
+    pub permanent: Option<bool>,
+}
+
+/// Connections of a database pool, for the metrics.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+/// One redirect as the clicks table keeps it.
+#[derive(Debug)]
+pub struct Click {
+    pub url_id: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_hash: String,
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    // the id, or the url, is already another link's
+    #[error("already taken")]
+    Taken,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// What the handlers need from the storage of links, the cache and the counting of
+/// clicks are theirs.
+pub trait UrlStore: Debug + Send + Sync {
+    /// Names the backend in the logs.
+    fn backend(&self) -> &'static str;
+
+    /// Whether the backend answers, for the readiness probe.
+    fn ping(&self) -> BoxFuture<'_, Result<()>>;
+
+    /// How the connection pool is used, `None` for backends without one.
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    /// Let go of the connections once nothing is written anymore, on shutdown.
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    /// Shorten `url` as `id`. Shortening a url again gives back its link instead, as it is:
+    /// someone else's expiry isn't changed. An expired link of the url is replaced, with
+    /// its clicks and edits. Whether the secret is `secret_hash` tells the caller which
+    /// happened, `Taken` when `id` is another url's.
+    fn create<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<ShortenedUrl, StoreError>>;
+
+    /// Shorten `url` as `alias`, which may replace an expired link along with its clicks and
+    /// edits, as may an expired link of the url under another id. Returns false if the alias is taken and not expired, by this url or another,
+    /// `Taken` when the url is another link's.
+    fn claim_alias<'a>(
+        &'a self,
+        alias: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>>;
+
+    /// The link, with the clicks counted so far, expired or not.
+    fn resolve<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>>;
+
+    /// The link `url` is shortened as, expired or not.
+    fn find<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>>;
+
+    /// Links ordered by id, starting after `after`.
+    fn list<'a>(
+        &'a self,
+        after: Option<&'a str>,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<ShortenedUrl>>>;
+
+    /// Point the link at `url` and record the change, returns the previous target or
+    /// none if there is no such link. Nothing is recorded when the target stays the same,
+    /// `Taken` when the url is another link's.
+    fn update<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, StoreError>>;
+
+    /// Remove the link with its recorded clicks and edits, returns false if it was already gone.
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    /// Remove the expired links with their clicks and edits, returns how many.
+    fn delete_expired(&self) -> BoxFuture<'_, Result<u64>>;
+
+    /// Add to the click counts of the links.
+    fn add_clicks<'a>(&'a self, clicks: &'a HashMap<String, u64>) -> BoxFuture<'a, Result<()>>;
+
+    /// Keep the details of the redirects.
+    fn record_clicks(&self, clicks: Vec<Click>) -> BoxFuture<'_, Result<()>>;
+
+    fn is_api_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    fn add_api_key<'a>(&'a self, key_hash: &'a str, name: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Links in Postgres or SQLite.
+#[derive(Debug, Clone)]
+pub enum SqlStore {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+/// Run the same statements against whichever pool the store has, for the ones both
+/// databases understand alike.
+macro_rules! on_pool {
+    ($store:expr, $db:ident => $body:expr) => {
+        match $store {
+            SqlStore::Postgres($db) => $body,
+            SqlStore::Sqlite($db) => $body,
+        }
+    };
+}
+
+impl SqlStore {
+    /// Connect to the database the url names and create the tables it lacks.
+    pub async fn connect(url: &str, pool_size: u32) -> Result<Self> {
+        if !url.starts_with("sqlite:") {
+            let db = PgPoolOptions::new()
+                .max_connections(pool_size)
+                .connect(url)
+                .await?;
+            migrate_postgres(&db).await?;
+            return Ok(Self::Postgres(db));
+        }
+
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let mut pool = SqlitePoolOptions::new().max_connections(pool_size);
+        // every connection to an in memory database gets a database of its own, the one
+        // connection is kept for as long as the server runs
+        if url.contains(":memory:") || url.contains("mode=memory") {
+            pool = pool
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        let db = pool.connect_with(options).await?;
+        migrate_sqlite(&db).await?;
+        Ok(Self::Sqlite(db))
+    }
+}
+
+impl UrlStore for SqlStore {
+    fn backend(&self) -> &'static str {
+        match self {
+            Self::Postgres(_) => "postgres",
+            Self::Sqlite(_) => "sqlite",
+        }
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            on_pool!(self, db => {
+                sqlx::query("SELECT 1").execute(db).await?;
+            });
+            Ok(())
+        })
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some(on_pool!(self, db => PoolStats {
+            size: db.size(),
+            idle: db.num_idle(),
+            max: db.options().get_max_connections(),
+        }))
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move { on_pool!(self, db => db.close().await) })
+    }
+
+    fn create<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<ShortenedUrl, StoreError>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let ret = on_pool!(self, db => {
+                let mut tx = db.begin().await?;
+                // an expired link of the url is replaced below, its analytics go with it
+                sqlx::query("DELETE FROM clicks WHERE url_id IN (SELECT id FROM urls WHERE url = $1 AND expires_at <= $2)")
+                    .bind(url)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM url_edits WHERE url_id IN (SELECT id FROM urls WHERE url = $1 AND expires_at <= $2)")
+                    .bind(url)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                // a live link stays as it is, an expired one becomes the new link
+                let ret = sqlx::query_as(
+                    "INSERT INTO urls (id, url, expires_at, secret_hash, created_at, permanent) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(url) DO UPDATE SET id = CASE WHEN urls.expires_at <= $5 THEN EXCLUDED.id ELSE urls.id END, expires_at = CASE WHEN urls.expires_at <= $5 THEN EXCLUDED.expires_at ELSE urls.expires_at END, secret_hash = CASE WHEN urls.expires_at <= $5 THEN EXCLUDED.secret_hash ELSE urls.secret_hash END, created_at = CASE WHEN urls.expires_at <= $5 THEN EXCLUDED.created_at ELSE urls.created_at END, permanent = CASE WHEN urls.expires_at <= $5 THEN EXCLUDED.permanent ELSE urls.permanent END, clicks = CASE WHEN urls.expires_at <= $5 THEN 0 ELSE urls.clicks END RETURNING id, secret_hash, expires_at",
+                )
+                .bind(id)
+                .bind(url)
+                .bind(expires_at)
+                .bind(secret_hash)
+                .bind(now)
+                .bind(permanent)
+                .fetch_one(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                ret
+            });
+
+            Ok(ret)
+        })
+    }
+
+    fn claim_alias<'a>(
+        &'a self,
+        alias: &'a str,
+        url: &'a str,
+        expires_at: Option<DateTime<Utc>>,
+        permanent: Option<bool>,
+        secret_hash: &'a str,
+    ) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            on_pool!(self, db => {
+                let mut tx = db.begin().await?;
+                // an expired link of the url under another id gives the url up
+                sqlx::query("DELETE FROM clicks WHERE url_id IN (SELECT id FROM urls WHERE url = $1 AND id <> $2 AND expires_at <= $3)")
+                    .bind(url)
+                    .bind(alias)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM url_edits WHERE url_id IN (SELECT id FROM urls WHERE url = $1 AND id <> $2 AND expires_at <= $3)")
+                    .bind(url)
+                    .bind(alias)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM urls WHERE url = $1 AND id <> $2 AND expires_at <= $3")
+                    .bind(url)
+                    .bind(alias)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                let ret = sqlx::query(
+                    "INSERT INTO urls (id, url, expires_at, secret_hash, created_at, permanent) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(id) DO UPDATE SET url=EXCLUDED.url, expires_at=EXCLUDED.expires_at, secret_hash=EXCLUDED.secret_hash, created_at=EXCLUDED.created_at, permanent=EXCLUDED.permanent, clicks=0 WHERE urls.expires_at <= $5",
+                )
+                .bind(alias)
+                .bind(url)
+                .bind(expires_at)
+                .bind(secret_hash)
+                .bind(now)
+                .bind(permanent)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                let claimed = ret > 0;
+                // the analytics of the link that had the alias before aren't this one's
+                if claimed {
+                    sqlx::query("DELETE FROM clicks WHERE url_id = $1")
+                        .bind(alias)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query("DELETE FROM url_edits WHERE url_id = $1")
+                        .bind(alias)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                Ok(claimed)
+            })
+        })
+    }
+
+    fn resolve<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>> {
+        Box::pin(async move {
+            let ret = on_pool!(self, db => sqlx::query_as("SELECT * FROM urls WHERE id = $1")
+                .bind(id)
+                .fetch_optional(db)
+                .await?);
+
+            Ok(ret)
+        })
+    }
+
+    fn find<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Option<ShortenedUrl>>> {
+        Box::pin(async move {
+            let ret = on_pool!(self, db => sqlx::query_as("SELECT * FROM urls WHERE url = $1")
+                .bind(url)
+                .fetch_optional(db)
+                .await?);
+
+            Ok(ret)
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        after: Option<&'a str>,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<ShortenedUrl>>> {
+        Box::pin(async move {
+            let ret = on_pool!(self, db => match after {
+                Some(after) => {
+                    sqlx::query_as("SELECT * FROM urls WHERE id > $1 ORDER BY id LIMIT $2")
+                        .bind(after)
+                        .bind(limit as i64)
+                        .fetch_all(db)
+                        .await?
+                }
+                None => {
+                    sqlx::query_as("SELECT * FROM urls ORDER BY id LIMIT $1")
+                        .bind(limit as i64)
+                        .fetch_all(db)
+                        .await?
+                }
+            });
+
+            Ok(ret)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        // the row lock keeps two concurrent edits from recording the same previous target,
+        // SQLite has a single writer anyway
+        let select = match self {
+            Self::Postgres(_) => "SELECT url FROM urls WHERE id = $1 FOR UPDATE",
+            Self::Sqlite(_) => "SELECT url FROM urls WHERE id = $1",
+        };
+        Box::pin(async move {
+            on_pool!(self, db => {
+                let mut tx = db.begin().await?;
+                let old: Option<(String,)> = sqlx::query_as(select)
+                    .bind(id)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                let Some((old,)) = old else {
+                    return Ok(None);
+                };
+                if old != url {
+                    sqlx::query("UPDATE urls SET url = $2 WHERE id = $1")
+                        .bind(id)
+                        .bind(url)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query(
+                        "INSERT INTO url_edits (url_id, old_url, new_url, edited_at) VALUES ($1, $2, $3, $4)",
+                    )
+                    .bind(id)
+                    .bind(&old)
+                    .bind(url)
+                    .bind(Utc::now())
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                Ok(Some(old))
+            })
+        })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            on_pool!(self, db => {
+                let mut tx = db.begin().await?;
+                let ret = sqlx::query("DELETE FROM urls WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM clicks WHERE url_id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM url_edits WHERE url_id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(ret.rows_affected() > 0)
+            })
+        })
+    }
+
+    fn delete_expired(&self) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let ret = on_pool!(self, db => {
+                let mut tx = db.begin().await?;
+                sqlx::query("DELETE FROM clicks WHERE url_id IN (SELECT id FROM urls WHERE expires_at <= $1)")
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM url_edits WHERE url_id IN (SELECT id FROM urls WHERE expires_at <= $1)")
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                let ret = sqlx::query("DELETE FROM urls WHERE expires_at <= $1")
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+                tx.commit().await?;
+                ret
+            });
+
+            Ok(ret)
+        })
+    }
+
+    /// In one statement on Postgres.
+    fn add_clicks<'a>(&'a self, clicks: &'a HashMap<String, u64>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match self {
+                Self::Postgres(db) => {
+                    let (ids, counts): (Vec<String>, Vec<i64>) = clicks
+                        .iter()
+                        .map(|(id, count)| (id.clone(), *count as i64))
+                        .unzip();
+                    sqlx::query(
+                        "UPDATE urls SET clicks = urls.clicks + c.count FROM UNNEST($1::TEXT[], $2::BIGINT[]) AS c(id, count) WHERE urls.id = c.id",
+                    )
+                    .bind(ids)
+                    .bind(counts)
+                    .execute(db)
+                    .await?;
+                }
+                Self::Sqlite(db) => {
+                    let mut tx = db.begin().await?;
+                    for (id, count) in clicks {
+                        sqlx::query("UPDATE urls SET clicks = clicks + $2 WHERE id = $1")
+                            .bind(id)
+                            .bind(*count as i64)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    tx.commit().await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Into the clicks table, in one statement on Postgres.
+    fn record_clicks(&self, clicks: Vec<Click>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            match self {
+                Self::Postgres(db) => {
+                    let mut columns = (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+                    for click in clicks {
+                        columns.0.push(click.url_id);
+                        columns.1.push(click.clicked_at);
+                        columns.2.push(click.referrer);
+                        columns.3.push(click.user_agent);
+                        columns.4.push(click.ip_hash);
+                    }
+                    sqlx::query(
+                        "INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, ip_hash) SELECT * FROM UNNEST($1::VARCHAR[], $2::TIMESTAMPTZ[], $3::TEXT[], $4::TEXT[], $5::TEXT[])",
+                    )
+                    .bind(columns.0)
+                    .bind(columns.1)
+                    .bind(columns.2)
+                    .bind(columns.3)
+                    .bind(columns.4)
+                    .execute(db)
+                    .await?;
+                }
+                Self::Sqlite(db) => {
+                    let mut tx = db.begin().await?;
+                    for click in clicks {
+                        sqlx::query(
+                            "INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, ip_hash) VALUES ($1, $2, $3, $4, $5)",
+                        )
+                        .bind(click.url_id)
+                        .bind(click.clicked_at)
+                        .bind(click.referrer)
+                        .bind(click.user_agent)
+                        .bind(click.ip_hash)
+                        .execute(&mut *tx)
+                        .await?;
+                    }
+                    tx.commit().await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn is_api_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let (found,): (bool,) = on_pool!(self, db => sqlx::query_as(
+                "SELECT EXISTS(SELECT 1 FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL)",
+            )
+            .bind(key_hash)
+            .fetch_one(db)
+            .await?);
+
+            Ok(found)
+        })
+    }
+
+    fn add_api_key<'a>(&'a self, key_hash: &'a str, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            on_pool!(self, db => {
+                sqlx::query(
+                    "INSERT INTO api_keys (key_hash, name, created_at) VALUES ($1, $2, $3) ON CONFLICT(key_hash) DO NOTHING",
+                )
+                .bind(key_hash)
+                .bind(name)
+                .bind(Utc::now())
+                .execute(db)
+                .await?;
+            });
+
+            Ok(())
+        })
+    }
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        let taken = e
+            .as_database_error()
+            .is_some_and(|e| e.is_unique_violation());
+        if taken {
+            Self::Taken
+        } else {
+            Self::Other(e.into())
+        }
+    }
+}
+
+async fn migrate_postgres(db: &PgPool) -> Result<()> {
+    // create table if not exists
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS urls (
+            id VARCHAR(32) PRIMARY KEY,
+            url TEXT NOT NULL UNIQUE
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    // widen ids created before custom aliases were supported
+    sqlx::query("ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32)")
+        .execute(db)
+        .await?;
+    sqlx::query("ALTER TABLE urls ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ")
+        .execute(db)
+        .await?;
+    sqlx::query("ALTER TABLE urls ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0")
+        .execute(db)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS clicks (
+            id BIGSERIAL PRIMARY KEY,
+            url_id VARCHAR(32) NOT NULL,
+            clicked_at TIMESTAMPTZ NOT NULL,
+            referrer TEXT,
+            user_agent TEXT,
+            ip_hash TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS clicks_url_id ON clicks (url_id, clicked_at)")
+        .execute(db)
+        .await?;
+    // links created before this count as created now
+    sqlx::query(
+        "ALTER TABLE urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()",
+    )
+    .execute(db)
+    .await?;
+    // links created before this have none, only an api key deletes them
+    sqlx::query("ALTER TABLE urls ADD COLUMN IF NOT EXISTS secret_hash TEXT")
+        .execute(db)
+        .await?;
+    sqlx::query("ALTER TABLE urls ADD COLUMN IF NOT EXISTS permanent BOOLEAN")
+        .execute(db)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS url_edits (
+            id BIGSERIAL PRIMARY KEY,
+            url_id VARCHAR(32) NOT NULL,
+            old_url TEXT NOT NULL,
+            new_url TEXT NOT NULL,
+            edited_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    // only hashes are kept, a key is shown once to whoever makes it
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_keys (
+            key_hash TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            revoked_at TIMESTAMPTZ
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// The same tables, with the times kept as text since SQLite has no type for them.
+async fn migrate_sqlite(db: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS urls (
+            id TEXT PRIMARY KEY,
+            url TEXT NOT NULL UNIQUE,
+            expires_at TEXT,
+            clicks INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            secret_hash TEXT,
+            permanent BOOLEAN
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    // SQLite can't add a column only if it is missing
+    let (has_permanent,): (bool,) = sqlx::query_as(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('urls') WHERE name = 'permanent'",
+    )
+    .fetch_one(db)
+    .await?;
+    if !has_permanent {
+        sqlx::query("ALTER TABLE urls ADD COLUMN permanent BOOLEAN")
+            .execute(db)
+            .await?;
+    }
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS clicks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url_id TEXT NOT NULL,
+            clicked_at TEXT NOT NULL,
+            referrer TEXT,
+            user_agent TEXT,
+            ip_hash TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS clicks_url_id ON clicks (url_id, clicked_at)")
+        .execute(db)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS url_edits (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url_id TEXT NOT NULL,
+            old_url TEXT NOT NULL,
+            new_url TEXT NOT NULL,
+            edited_at TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_keys (
+            key_hash TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            revoked_at TEXT
+        )
+        "#,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,2899 @@
+//! A multi-room chat server that can be embedded over any transport.
+//!
+//! A [`ChatRoom`] is built once and shared, then every connection is handed to
+//! [`handle_stream`] for byte streams or [`handle_client`] for transports that already
+//! deliver whole frames, like WebSockets.
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, SubsecRound as _, Utc};
+use core::fmt;
+use croner::Cron;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use futures::{
+    future::{self, BoxFuture},
+    SinkExt, StreamExt,
+};
+use lru::LruCache;
+use metrics::{counter, gauge, histogram};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, Notify},
+    time::{interval_at, sleep, timeout, timeout_at, Interval, MissedTickBehavior},
+};
+use tokio_util::{
+    codec::{Framed, LengthDelimitedCodec},
+    sync::CancellationToken,
+};
+use tracing::{debug, field, info, instrument, warn, Instrument as _, Span};
+
+mod commands;
+mod federation;
+mod history;
+mod protocol;
+mod rooms;
+mod transcripts;
+mod transfers;
+mod webhooks;
+
+use commands::{announcement_text, keyword_arg};
+pub use federation::{handle_link, Federation, RedisBackend, RoomBackend};
+pub use history::EventLog;
+use history::History;
+pub use protocol::{
+    read_proxy_header, ChatClient, ChatMessage, LineSink, LineStream, Message, Protocol,
+};
+use protocol::{LineCodec, LineTooLong, CHUNK, PING, PONG, RESUME, TYPING};
+use rooms::{parse_room_name, Invite, Poll};
+pub use rooms::{RoomFlag, RoomSnapshot, Snapshot};
+pub use transcripts::Transcripts;
+use transfers::Transfer;
+pub use webhooks::{Webhook, WebhookEvent, WebhookFormat, Webhooks};
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 128;
+pub const DEFAULT_HISTORY_SIZE: usize = 50;
+const DEFAULT_ROOM: &str = "#general";
+const MAX_NAME_LEN: usize = 32;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1024;
+// frames beyond this many times the message limit are dropped by the codec whatever the policy
+const MAX_FRAME_FACTOR: usize = 4;
+pub const DEFAULT_RATE_LIMIT: u32 = 5;
+pub const DEFAULT_MUTE_SECS: u64 = 10;
+pub const DEFAULT_AUTH_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_TRANSCRIPT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_RESUME_BACKLOG: usize = 100;
+const MESSAGE_ID_LEN: usize = 8;
+// recent chat messages whose sender may still edit or delete them
+const EDITABLE_MESSAGES: usize = 4096;
+// messages written to a peer at once when batching
+const MAX_BATCH: usize = 64;
+// seconds of chat messages counted for the recent throughput
+const THROUGHPUT_WINDOW_SECS: u64 = 60;
+
+/// What to do with a line longer than the configured limit.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongMessagePolicy {
+    Reject,
+    Truncate,
+}
+
+/// What the blocklist filter does with a message containing a blocked word.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockedWordPolicy {
+    #[default]
+    Mask,
+    Reject,
+}
+
+/// How the receipt time of a chat message is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampFormat {
+    Rfc3339,
+    // HH:MM:SS in UTC
+    #[default]
+    Time,
+}
+
+/// What a peer may do, every role can do everything the roles below it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+/// State shared by every connection: peers, names, rooms and history.
+#[derive(Debug)]
+pub struct ChatRoom {
+    peers: DashMap<SocketAddr, PeerHandle>,
+    // lowercased nickname -> owner, claimed through the entry API so two peers can't race
+    names: DashMap<String, SocketAddr>,
+    rooms: DashMap<String, HashSet<SocketAddr>>,
+    // outlives the room so it is still there when someone joins again
+    topics: DashMap<String, String>,
+    // outlives the room like the topic, restored from the event log
+    room_flags: DashSet<(String, RoomFlag)>,
+    // most members a room takes, set from the config or by admins
+    room_limits: DashMap<String, usize>,
+    // who created a room with /create, they may manage its invites
+    room_owners: DashMap<String, Author>,
+    // invite code -> the room it lets a peer into
+    invites: DashMap<String, Invite>,
+    // rooms that exist and when they were left empty, None while anyone is in them
+    known_rooms: DashMap<String, Option<Instant>>,
+    // closed by their creator or an admin, nobody may join them again
+    archived: DashSet<String>,
+    // empty rooms are forgotten after this long, apart from the persistent ones
+    room_idle: Option<Duration>,
+    persistent_rooms: HashSet<String>,
+    history: History,
+    // the running poll of each room
+    polls: DashMap<String, Poll>,
+    started_at: Instant,
+    peak_peers: AtomicUsize,
+    throughput: Throughput,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    event_log: Option<EventLog>,
+    transcripts: Option<Transcripts>,
+    // where /snapshot writes the moderation state
+    snapshot_file: Option<PathBuf>,
+    rate_limit: RateLimit,
+    connections: AtomicUsize,
+    max_connections: usize,
+    idle: IdlePolicy,
+    heartbeat: Option<Duration>,
+    admin_token: Option<String>,
+    // roles of authenticated users, by the user name their token maps to
+    roles: DashMap<String, Role>,
+    banned: DashSet<IpAddr>,
+    // lowercased names whose messages only they get to see, follows renames
+    shadow_banned: DashSet<String>,
+    // lowercased names each peer gets no messages from, follows renames too
+    ignores: DashMap<SocketAddr, HashSet<String>>,
+    channel_capacity: usize,
+    // a busy peer's messages are written together once this has passed, None writes each
+    flush_interval: Option<Duration>,
+    max_message_len: usize,
+    long_messages: LongMessagePolicy,
+    slow_consumers: SlowConsumerPolicy,
+    // per room overrides of `slow_consumers`
+    slow_consumer_rooms: HashMap<String, SlowConsumerPolicy>,
+    filters: FilterChain,
+    auth: Option<Box<dyn AuthProvider>>,
+    auth_timeout: Duration,
+    timestamps: TimestampFormat,
+    bots: Vec<Box<dyn ChatBot>>,
+    federation: Option<Federation>,
+    backend: Option<Box<dyn RoomBackend>>,
+    announcements: Vec<ScheduledAnnouncement>,
+    transfers: DashMap<u64, Transfer>,
+    next_transfer: AtomicU64,
+    max_file_size: u64,
+    // session token -> address of the peer holding it
+    sessions: DashMap<String, SocketAddr>,
+    // peers that dropped but may still resume, by their last address
+    detached: DashMap<SocketAddr, Session>,
+    session_grace: Option<Duration>,
+    // messages kept per room for a detached session
+    resume_backlog: usize,
+    // observers following a room without being members, like the HTTP gateway
+    watchers: DashMap<String, broadcast::Sender<Arc<Message>>>,
+    webhooks: Option<Webhooks>,
+    // chat message id -> room and sender, for /edit and /delete
+    authors: Mutex<LruCache<String, (String, Author)>>,
+}
+
+/// Who may edit or delete a message, manage a room they created or close a poll they started,
+/// and who a vote is counted for: the user the auth provider knows, or else the session,
+/// which outlives a reconnect, or else the connection. Never the name, another peer may take
+/// it once it is changed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Author {
+    User(String),
+    Session(String),
+    Connection(SocketAddr),
+}
+
+/// A peer whose connection dropped, kept until it resumes or the grace period runs out.
+///
+/// The old address stays a member of the peer's rooms, so room messages are kept for it
+/// and replayed room by room once the client is back.
+#[derive(Debug)]
+struct Session {
+    name: String,
+    role: Role,
+    user: Option<String>,
+    token: String,
+    current_room: Option<String>,
+    backlog: HashMap<String, Backlog>,
+}
+
+/// The messages of one room a detached session missed, the latest ones when there were
+/// too many.
+#[derive(Debug, Default)]
+struct Backlog {
+    messages: VecDeque<Arc<Message>>,
+    dropped: usize,
+}
+
+/// A notice made to everyone whenever its cron schedule comes due, like `/announce`.
+#[derive(Debug)]
+pub struct ScheduledAnnouncement {
+    schedule: Cron,
+    text: String,
+}
+
+/// A participant driven by code, called after peer messages, joins and leaves are relayed.
+///
+/// Bots talk back through a [`BotContext`], what they say is not seen by other bots.
+pub trait ChatBot: Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn on_message<'a>(
+        &'a self,
+        _ctx: &'a BotContext<'a>,
+        _message: &'a ChatMessage,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    fn on_join<'a>(
+        &'a self,
+        _ctx: &'a BotContext<'a>,
+        _room: &'a str,
+        _name: &'a str,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    fn on_leave<'a>(
+        &'a self,
+        _ctx: &'a BotContext<'a>,
+        _room: &'a str,
+        _name: &'a str,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+}
+
+/// What a bot may do in the room, on behalf of the bot's name.
+#[derive(Debug)]
+pub struct BotContext<'a> {
+    chat_room: &'a ChatRoom,
+    name: &'a str,
+}
+
+/// Repeats "!echo <text>" back into the room.
+#[derive(Debug)]
+pub struct EchoBot;
+
+/// Welcomes peers joining a room with a direct message.
+#[derive(Debug)]
+pub struct GreeterBot;
+
+/// Checks the token a client presents before picking a name.
+///
+/// Returns the user the token belongs to, empty if it is not tied to one, or `None`
+/// when it is not valid.
+pub trait AuthProvider: Debug + Send + Sync {
+    fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+}
+
+/// Every client presents the same token.
+#[derive(Debug)]
+pub struct SharedToken(pub String);
+
+/// Per user tokens loaded from a file of "user token" lines.
+#[derive(Debug)]
+pub struct TokenFile {
+    // token -> user
+    tokens: HashMap<String, String>,
+}
+
+/// Inspects, and may rewrite, every chat message before it is fanned out.
+pub trait MessageFilter: Debug + Send + Sync {
+    fn filter(&self, message: &mut ChatMessage) -> FilterAction;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FilterAction {
+    Allow,
+    // the message is dropped and the sender gets the reason
+    Reject(String),
+}
+
+/// Filters run in registration order, the first rejection wins.
+#[derive(Debug, Default)]
+pub struct FilterChain(Vec<Box<dyn MessageFilter>>);
+
+/// Masks or rejects messages containing any of the given words, ignoring case.
+#[derive(Debug)]
+pub struct WordBlocklist {
+    words: HashSet<String>,
+    policy: BlockedWordPolicy,
+}
+
+/// When set, peers silent for `timeout` are pinged (if `keepalive`) and then disconnected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdlePolicy {
+    pub timeout: Option<Duration>,
+    pub keepalive: bool,
+}
+
+/// A slot in the connection cap, given back when dropped.
+#[derive(Debug)]
+struct ConnectionGuard {
+    chat_room: Arc<ChatRoom>,
+}
+
+/// How many lines a peer may send per second before being warned and then muted.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+    pub mute: Duration,
+}
+
+/// Token bucket owned by the receive loop of a single connection.
+#[derive(Debug)]
+struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    warned: bool,
+    muted_until: Option<Instant>,
+}
+
+/// Periodic pings of a single connection and the one still waiting for its pong.
+#[derive(Debug)]
+struct Heartbeat {
+    interval: Option<Interval>,
+    seq: u64,
+    pending: Option<(String, Instant)>,
+}
+
+#[derive(Debug, PartialEq)]
+enum RateDecision {
+    Allow,
+    Warn,
+    Mute,
+    // how long the mute still lasts
+    Muted(Duration),
+}
+
+#[derive(Debug)]
+struct PeerHandle {
+    name: String,
+    connected_at: Instant,
+    role: Role,
+    // cancelled on disconnect so the receive loop stops even if the client stays silent
+    closed: CancellationToken,
+    outbox: Arc<Outbox>,
+    current_room: Option<String>,
+    // updated on every line the peer sends
+    last_active: Instant,
+    // round trip of the last ping the peer answered
+    latency: Option<Duration>,
+    away: Option<String>,
+    // token the client can reconnect with, when sessions are enabled
+    session: Option<String>,
+    // the user the auth provider knows the peer as, promotions are kept for it
+    user: Option<String>,
+}
+
+/// Bounded queue of messages waiting to be written to a peer.
+///
+/// A full queue is handled by the room's slow consumer policy, broadcasts only wait on it
+/// under the block policy.
+#[derive(Debug)]
+struct Outbox {
+    queue: Mutex<VecDeque<Arc<Message>>>,
+    capacity: usize,
+    // messages dropped since the peer was last told about it
+    lagged: AtomicU64,
+    closed: AtomicBool,
+    notify: Notify,
+    // woken whenever the send loop takes a message, for broadcasts waiting on a full queue
+    space: Notify,
+}
+
+#[derive(Debug, PartialEq)]
+enum OutboxError {
+    Full,
+    Closed,
+}
+
+/// What to do when a peer's outbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+    // the broadcast waits for room, up to the send timeout, then the peer is disconnected
+    Block,
+}
+
+/// Chat messages delivered, in total and per second over the last minute.
+#[derive(Debug, Default)]
+struct Throughput {
+    total: AtomicU64,
+    // (seconds since the start, messages in that second), oldest first
+    recent: Mutex<VecDeque<(u64, u64)>>,
+}
+
+/// A snapshot of the server for monitoring, like the example's `/status` endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStatus {
+    pub uptime_secs: u64,
+    pub connections: usize,
+    pub peers: usize,
+    pub peak_peers: usize,
+    pub rooms: Vec<RoomStatus>,
+    pub messages_total: u64,
+    pub messages_last_minute: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomStatus {
+    pub name: String,
+    pub members: usize,
+}
+
+/// A logged in client, owned by its connection until it drops.
+#[derive(Debug)]
+pub struct Peer {
+    name: String,
+    addr: SocketAddr,
+    protocol: Protocol,
+    outbox: Arc<Outbox>,
+    closed: CancellationToken,
+}
+
+/// Serve one client over a byte stream, framing it as the protocol requires.
+pub async fn handle_stream<S>(
+    stream: S,
+    addr: SocketAddr,
+    protocol: Protocol,
+    chat_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    if protocol == Protocol::Binary {
+        let codec = LengthDelimitedCodec::builder()
+            .max_frame_length(chat_room.max_frame_len())
+            .new_codec();
+        let (sink, stream) = Framed::new(stream, codec).split();
+        let sink = sink.sink_map_err(anyhow::Error::from);
+        let stream = stream.map(|frame| Ok(bincode::deserialize::<String>(&frame?)?));
+        return handle_client(sink, stream, addr, protocol, chat_room, shutdown).await;
+    }
+
+    let codec = LineCodec::new(chat_room.max_frame_len());
+    let (sink, stream) = Framed::new(stream, codec).split();
+    let sink = sink.sink_map_err(anyhow::Error::from);
+    let stream = stream.map(|line| Ok(line??));
+
+    handle_client(sink, stream, addr, protocol, chat_room, shutdown).await
+}
+
+/// Serve one client over a transport that already yields whole lines.
+///
+/// Runs the login handshake, then the peer's send and receive loops until it leaves.
+#[instrument(
+    name = "client",
+    skip_all,
+    fields(%addr, user = field::Empty, name = field::Empty)
+)]
+pub async fn handle_client(
+    mut sink: impl LineSink,
+    mut stream: impl LineStream,
+    addr: SocketAddr,
+    protocol: Protocol,
+    chat_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let Some(_guard) = chat_room.try_acquire_connection() else {
+        warn!("Server full, rejecting {}", addr);
+        let full = Message::system("Server is full, please try again later");
+        sink.send(protocol.encode(&full)?).await?;
+        sink.close().await?;
+        return Ok(());
+    };
+
+    if chat_room.banned.contains(&addr.ip()) {
+        warn!("Banned address {} rejected", addr);
+        let banned = Message::system("You are banned from this server");
+        sink.send(protocol.encode(&banned)?).await?;
+        sink.close().await?;
+        return Ok(());
+    }
+
+    let mut user = None;
+    if let Some(auth) = &chat_room.auth {
+        let prompt = Message::system("Please enter your access token: ");
+        sink.send(protocol.encode(&prompt)?).await?;
+
+        // lines that decode to nothing, like IRC registration noise, are skipped
+        let read_token = async {
+            while let Some(line) = stream.next().await {
+                let token = protocol.decode(line?)?;
+                if !token.trim().is_empty() {
+                    return Ok(Some(token));
+                }
+            }
+            Ok::<_, anyhow::Error>(None)
+        };
+        let next = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            next = timeout(chat_room.auth_timeout, read_token) => next,
+        };
+        let rejection = match next {
+            Ok(Ok(Some(token))) => match auth.authenticate(token.trim()).await? {
+                Some(authenticated) => {
+                    if !authenticated.is_empty() {
+                        Span::current().record("user", authenticated.as_str());
+                        user = Some(authenticated);
+                    }
+                    None
+                }
+                None => Some("Invalid access token"),
+            },
+            Ok(Err(e)) => return Err(e),
+            Ok(Ok(None)) => return Ok(()),
+            Err(_) => Some("Authentication timed out"),
+        };
+        if let Some(rejection) = rejection {
+            warn!("{}", rejection);
+            sink.send(protocol.encode(&Message::system(rejection))?)
+                .await?;
+            sink.close().await?;
+            return Ok(());
+        }
+    }
+
+    let mut prompt = Some(Message::system("Please enter your name: "));
+    let (peer, replay) = loop {
+        if let Some(prompt) = prompt.take() {
+            sink.send(protocol.encode(&prompt)?).await?;
+        }
+
+        let next = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            next = stream.next() => next,
+        };
+        let name: String = match next {
+            Some(Ok(line)) => protocol.decode(line)?,
+            Some(Err(e)) => {
+                return Err(e);
+            }
+            None => {
+                return Ok(());
+            }
+        };
+
+        // admins log in with "name token", IRC clients with NICK
+        let login = name.trim();
+        if login.is_empty() {
+            continue;
+        }
+        if let Some(token) = login.strip_prefix(RESUME) {
+            match chat_room.resume(addr, token.trim(), protocol) {
+                Ok((peer, replay)) => break (peer, Some(replay)),
+                Err(e) => {
+                    prompt = Some(Message::system(format!("{}, please enter your name: ", e)));
+                    continue;
+                }
+            }
+        }
+        let login = login.strip_prefix("/nick ").unwrap_or(login).trim();
+        let (name, token) = match login.split_once(char::is_whitespace) {
+            Some((name, token)) => (name, Some(token.trim())),
+            None => (login, None),
+        };
+        match chat_room.connect(addr, name.to_string(), token, user.as_deref(), protocol) {
+            Ok(peer) => break (peer, None),
+            Err(e) => {
+                prompt = Some(Message::system(format!(
+                    "{}, please enter another name: ",
+                    e
+                )))
+            }
+        }
+    };
+    Span::current().record("name", peer.name.as_str());
+    chat_room
+        .send_to(
+            addr,
+            Arc::new(Message::Welcome {
+                name: peer.name.clone(),
+            }),
+        )
+        .await;
+    if let Some(token) = chat_room
+        .peers
+        .get(&addr)
+        .and_then(|handle| handle.session.clone())
+    {
+        chat_room
+            .send_to(addr, Arc::new(Message::Session { token }))
+            .await;
+    }
+    match replay {
+        Some(replay) => {
+            for message in replay {
+                chat_room.send_to(addr, message).await;
+            }
+        }
+        None => {
+            if let Err(text) = chat_room
+                .join_room(addr, &peer.name, DEFAULT_ROOM, None)
+                .await
+            {
+                chat_room
+                    .send_to(addr, Arc::new(Message::system(text)))
+                    .await;
+            }
+        }
+    }
+
+    peer.bootstrap(chat_room, sink, stream, shutdown).await?;
+
+    Ok(())
+}
+
+impl Default for ChatRoom {
+    fn default() -> Self {
+        Self {
+            peers: DashMap::new(),
+            names: DashMap::new(),
+            rooms: DashMap::new(),
+            topics: DashMap::new(),
+            room_flags: DashSet::new(),
+            room_limits: DashMap::new(),
+            room_owners: DashMap::new(),
+            invites: DashMap::new(),
+            known_rooms: DashMap::new(),
+            archived: DashSet::new(),
+            room_idle: None,
+            persistent_rooms: HashSet::new(),
+            history: History::memory(DEFAULT_HISTORY_SIZE),
+            polls: DashMap::new(),
+            started_at: Instant::now(),
+            peak_peers: AtomicUsize::new(0),
+            throughput: Throughput::default(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            event_log: None,
+            transcripts: None,
+            snapshot_file: None,
+            rate_limit: RateLimit::default(),
+            connections: AtomicUsize::new(0),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle: IdlePolicy::default(),
+            heartbeat: None,
+            admin_token: None,
+            roles: DashMap::new(),
+            banned: DashSet::new(),
+            shadow_banned: DashSet::new(),
+            ignores: DashMap::new(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            flush_interval: None,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            long_messages: LongMessagePolicy::Reject,
+            slow_consumers: SlowConsumerPolicy::DropOldest,
+            slow_consumer_rooms: HashMap::new(),
+            filters: FilterChain::default(),
+            auth: None,
+            auth_timeout: Duration::from_secs(DEFAULT_AUTH_TIMEOUT_SECS),
+            timestamps: TimestampFormat::Time,
+            bots: Vec::new(),
+            federation: None,
+            backend: None,
+            announcements: Vec::new(),
+            transfers: DashMap::new(),
+            next_transfer: AtomicU64::new(0),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            sessions: DashMap::new(),
+            detached: DashMap::new(),
+            session_grace: None,
+            resume_backlog: DEFAULT_RESUME_BACKLOG,
+            watchers: DashMap::new(),
+            webhooks: None,
+            authors: Mutex::new(LruCache::new(NonZeroUsize::new(EDITABLE_MESSAGES).unwrap())),
+        }
+    }
+}
+
+impl ChatRoom {
+    pub fn new(history_size: usize) -> Self {
+        Self {
+            history: History::memory(history_size),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_rate_limit(self, rate_limit: RateLimit) -> Self {
+        Self { rate_limit, ..self }
+    }
+
+    pub fn with_max_connections(self, max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            ..self
+        }
+    }
+
+    pub fn with_idle_policy(self, idle: IdlePolicy) -> Self {
+        Self { idle, ..self }
+    }
+
+    /// Ping every peer this often to measure its round trip, shown by /who.
+    pub fn with_heartbeat(self, heartbeat: Option<Duration>) -> Self {
+        Self { heartbeat, ..self }
+    }
+
+    pub fn with_channel_capacity(self, channel_capacity: usize) -> Self {
+        Self {
+            channel_capacity: channel_capacity.max(1),
+            ..self
+        }
+    }
+
+    /// Coalesce the messages queued for a busy peer over `flush_interval` into one write,
+    /// a peer with nothing else queued still gets each message right away.
+    pub fn with_flush_interval(self, flush_interval: Option<Duration>) -> Self {
+        Self {
+            flush_interval,
+            ..self
+        }
+    }
+
+    pub fn with_message_limit(
+        self,
+        max_message_len: usize,
+        long_messages: LongMessagePolicy,
+    ) -> Self {
+        Self {
+            max_message_len: max_message_len.max(1),
+            long_messages,
+            ..self
+        }
+    }
+
+    pub fn with_slow_consumer_policy(
+        self,
+        slow_consumers: SlowConsumerPolicy,
+        slow_consumer_rooms: HashMap<String, SlowConsumerPolicy>,
+    ) -> Self {
+        Self {
+            slow_consumers,
+            slow_consumer_rooms,
+            ..self
+        }
+    }
+
+    /// Cap the number of members of some rooms, a limit of 0 is no limit.
+    pub fn with_room_limits(self, room_limits: HashMap<String, usize>) -> Self {
+        Self {
+            room_limits: room_limits
+                .into_iter()
+                .filter(|(_, limit)| *limit > 0)
+                .collect(),
+            ..self
+        }
+    }
+
+    pub fn with_filters(self, filters: FilterChain) -> Self {
+        Self { filters, ..self }
+    }
+
+    pub fn with_timestamp_format(self, timestamps: TimestampFormat) -> Self {
+        Self { timestamps, ..self }
+    }
+
+    pub fn with_bot(mut self, bot: impl ChatBot + 'static) -> Self {
+        self.bots.push(Box::new(bot));
+        self
+    }
+
+    pub fn with_max_file_size(self, max_file_size: u64) -> Self {
+        Self {
+            max_file_size,
+            ..self
+        }
+    }
+
+    /// Keep the rooms of dropped peers for `session_grace`, with up to `resume_backlog`
+    /// messages per room to replay when they resume.
+    pub fn with_session_grace(
+        self,
+        session_grace: Option<Duration>,
+        resume_backlog: usize,
+    ) -> Self {
+        Self {
+            session_grace,
+            resume_backlog,
+            ..self
+        }
+    }
+
+    pub fn with_webhooks(self, webhooks: Option<Webhooks>) -> Self {
+        Self { webhooks, ..self }
+    }
+
+    pub fn with_federation(self, federation: Option<Federation>) -> Self {
+        Self { federation, ..self }
+    }
+
+    /// The file /snapshot writes, the example server restores it at startup.
+    pub fn with_snapshot_file(self, snapshot_file: Option<PathBuf>) -> Self {
+        Self {
+            snapshot_file,
+            ..self
+        }
+    }
+
+    pub fn with_transcripts(self, transcripts: Option<Transcripts>) -> Self {
+        Self {
+            transcripts,
+            ..self
+        }
+    }
+
+    /// Forget rooms left empty for `room_idle`, with [`ChatRoom::run_room_sweeper`] running,
+    /// except the default room and the persistent ones.
+    pub fn with_room_idle(
+        self,
+        room_idle: Option<Duration>,
+        persistent_rooms: Vec<String>,
+    ) -> Self {
+        Self {
+            room_idle,
+            persistent_rooms: persistent_rooms.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Announcements made by [`ChatRoom::run_announcements`] on their schedules.
+    pub fn with_announcements(self, announcements: Vec<ScheduledAnnouncement>) -> Self {
+        Self {
+            announcements,
+            ..self
+        }
+    }
+
+    /// Share room messages with other instances, which needs [`ChatRoom::follow_backend`]
+    /// running to receive theirs.
+    pub fn with_backend(self, backend: Option<Box<dyn RoomBackend>>) -> Self {
+        Self { backend, ..self }
+    }
+
+    /// Append every event from now on to an opened log, usually after replaying it.
+    pub fn with_event_log(self, event_log: EventLog) -> Self {
+        Self {
+            event_log: Some(event_log),
+            ..self
+        }
+    }
+
+    pub fn with_auth(self, auth: Option<Box<dyn AuthProvider>>, auth_timeout: Duration) -> Self {
+        Self {
+            auth,
+            auth_timeout,
+            ..self
+        }
+    }
+
+    fn slow_consumer_policy(&self, room: Option<&str>) -> SlowConsumerPolicy {
+        room.and_then(|room| self.slow_consumer_rooms.get(room))
+            .copied()
+            .unwrap_or(self.slow_consumers)
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_message_len * MAX_FRAME_FACTOR
+    }
+
+    /// Roles granted to users authenticated through the auth provider, by user name.
+    pub fn with_roles(self, roles: HashMap<String, Role>) -> Self {
+        Self {
+            roles: roles.into_iter().collect(),
+            ..self
+        }
+    }
+
+    pub fn with_admin_token(self, admin_token: Option<String>) -> Self {
+        Self {
+            admin_token,
+            ..self
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Peers past the login prompt, connections still authenticating are not counted.
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn status(&self) -> ServerStatus {
+        let uptime = self.started_at.elapsed().as_secs();
+        let mut rooms: Vec<RoomStatus> = self
+            .rooms
+            .iter()
+            .map(|item| RoomStatus {
+                name: item.key().clone(),
+                members: item.value().len(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        ServerStatus {
+            uptime_secs: uptime,
+            connections: self.connection_count(),
+            peers: self.peers.len(),
+            peak_peers: self.peak_peers.load(Ordering::Relaxed),
+            rooms,
+            messages_total: self.throughput.total.load(Ordering::Relaxed),
+            messages_last_minute: self.throughput.last_minute(uptime),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
+    fn try_acquire_connection(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < self.max_connections).then_some(count + 1)
+            })
+            .ok()?;
+        info!(
+            "Connections: {}/{}",
+            self.connection_count(),
+            self.max_connections
+        );
+        Some(ConnectionGuard {
+            chat_room: self.clone(),
+        })
+    }
+
+    pub async fn try_new_with_db(url: &str, history_size: usize) -> Result<Self> {
+        Ok(Self {
+            history: History::try_new_postgres(url, history_size).await?,
+            ..Self::default()
+        })
+    }
+
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        name: String,
+        token: Option<&str>,
+        user: Option<&str>,
+        protocol: Protocol,
+    ) -> Result<Peer, String> {
+        validate_name(&name)?;
+        let granted = user
+            .and_then(|user| self.roles.get(user))
+            .map(|role| *role)
+            .unwrap_or_default();
+        let role = match token {
+            None => granted,
+            Some(token) if self.admin_token.as_deref() == Some(token) => Role::Admin,
+            Some(_) => return Err("Invalid admin token".to_string()),
+        };
+        self.claim_name(addr, &name)?;
+
+        let outbox = Outbox::new(self.channel_capacity);
+        let mut handle = PeerHandle::new(name.clone(), role, outbox.clone());
+        handle.user = user.map(str::to_string);
+        if self.session_grace.is_some() {
+            let token = nanoid!();
+            self.sessions.insert(token.clone(), addr);
+            handle.session = Some(token);
+        }
+        let closed = handle.closed.clone();
+        self.peers.insert(addr, handle);
+        self.peak_peers
+            .fetch_max(self.peers.len(), Ordering::Relaxed);
+        gauge!("chat_connected_peers").set(self.peers.len() as f64);
+        match role {
+            Role::User => info!("{} connected", name),
+            role => info!("{} connected as {}", name, role),
+        }
+        Ok(Peer::new(addr, name, protocol, outbox, closed))
+    }
+
+    async fn disconnect(&self, addr: SocketAddr) {
+        let author = self.author(addr);
+        let Some((_, peer)) = self.peers.remove(&addr) else {
+            return;
+        };
+
+        peer.closed.cancel();
+        peer.outbox.close();
+        gauge!("chat_connected_peers").set(self.peers.len() as f64);
+        if let Some(token) = &peer.session {
+            self.sessions.remove(token);
+        }
+        self.ignores.remove(&addr);
+        let name = peer.name;
+        self.release_name(addr, &name);
+        info!("{} disconnected", name);
+        self.abort_transfers(addr, &name).await;
+        for room in self.rooms_of(addr) {
+            self.leave_room(addr, &name, &room).await;
+            if let Some(author) = &author {
+                self.end_poll_of(author, &room).await;
+            }
+        }
+    }
+
+    /// Take back a detached session on a new connection, returns what to tell the peer: its
+    /// rooms, each followed by the messages it missed there.
+    fn resume(
+        &self,
+        addr: SocketAddr,
+        token: &str,
+        protocol: Protocol,
+    ) -> Result<(Peer, Vec<Arc<Message>>), String> {
+        let expired = || "Unknown or expired session".to_string();
+        let old = self
+            .sessions
+            .get(token)
+            .map(|old| *old)
+            .ok_or_else(expired)?;
+        // racing the expiry, whoever removes the session first wins
+        let (_, session) = self.detached.remove(&old).ok_or_else(expired)?;
+
+        self.sessions.insert(token.to_string(), addr);
+        if let Some((_, ignored)) = self.ignores.remove(&old) {
+            self.ignores.insert(addr, ignored);
+        }
+        if let Some(mut owner) = self.names.get_mut(&session.name.to_lowercase()) {
+            if *owner == old {
+                *owner = addr;
+            }
+        }
+        for mut members in self.rooms.iter_mut() {
+            if members.remove(&old) {
+                members.insert(addr);
+            }
+        }
+
+        let mut rooms = self.rooms_of(addr);
+        rooms.sort();
+        let mut backlog = session.backlog;
+        let mut missed = 0;
+        let mut replay = Vec::new();
+        for room in rooms {
+            let Backlog { messages, dropped } = backlog.remove(&room).unwrap_or_default();
+            missed += messages.len() + dropped;
+            replay.push(Arc::new(Message::Joined {
+                room: room.clone(),
+                name: session.name.clone(),
+            }));
+            if dropped > 0 {
+                let text = format!("{} older messages of {} were dropped", dropped, room);
+                replay.push(Arc::new(Message::system(text)));
+            }
+            replay.extend(messages);
+        }
+        let text = format!(
+            "Session resumed, {} messages arrived while you were away",
+            missed
+        );
+        replay.insert(0, Arc::new(Message::system(text)));
+
+        // the replay is queued before the send loop starts, on top of the usual room
+        let outbox = Outbox::new(self.channel_capacity + replay.len());
+        let mut handle = PeerHandle::new(session.name.clone(), session.role, outbox.clone());
+        handle.current_room = session.current_room;
+        handle.user = session.user;
+        handle.session = Some(session.token);
+        let closed = handle.closed.clone();
+        self.peers.insert(addr, handle);
+        self.peak_peers
+            .fetch_max(self.peers.len(), Ordering::Relaxed);
+        gauge!("chat_connected_peers").set(self.peers.len() as f64);
+        info!(
+            "{} resumed their session, {} missed messages",
+            session.name, missed
+        );
+        let peer = Peer::new(addr, session.name, protocol, outbox, closed);
+        Ok((peer, replay))
+    }
+
+    /// Drop the connection of a peer, keeping its session around when sessions are enabled.
+    async fn detach(self: &Arc<Self>, addr: SocketAddr) {
+        let Some(grace) = self.session_grace else {
+            self.disconnect(addr).await;
+            return;
+        };
+        let Some((_, peer)) = self.peers.remove(&addr) else {
+            return;
+        };
+
+        peer.closed.cancel();
+        peer.outbox.close();
+        gauge!("chat_connected_peers").set(self.peers.len() as f64);
+        self.abort_transfers(addr, &peer.name).await;
+        let Some(token) = peer.session else {
+            return;
+        };
+        info!(
+            "{} dropped, session kept for {}s",
+            peer.name,
+            grace.as_secs()
+        );
+        // the old outbox is closed, so the old send loop finishes
+        let session = Session {
+            name: peer.name,
+            role: peer.role,
+            user: peer.user,
+            token,
+            current_room: peer.current_room,
+            backlog: HashMap::new(),
+        };
+        self.detached.insert(addr, session);
+
+        let chat_room = self.clone();
+        tokio::spawn(
+            async move {
+                sleep(grace).await;
+                chat_room.expire_session(addr).await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    async fn expire_session(&self, addr: SocketAddr) {
+        let Some((_, session)) = self.detached.remove(&addr) else {
+            return;
+        };
+        self.sessions.remove(&session.token);
+        self.ignores.remove(&addr);
+        self.release_name(addr, &session.name);
+        info!("Session of {} expired", session.name);
+        let author = Author::Session(session.token);
+        for room in self.rooms_of(addr) {
+            self.leave_room(addr, &session.name, &room).await;
+            self.end_poll_of(&author, &room).await;
+        }
+    }
+
+    /// Keep a room message for a detached session, dropping its oldest beyond the backlog.
+    fn hold(&self, addr: SocketAddr, room: &str, message: &Arc<Message>) {
+        if message.is_ephemeral() {
+            return;
+        }
+        let Some(mut session) = self.detached.get_mut(&addr) else {
+            return;
+        };
+        let backlog = session.backlog.entry(room.to_string()).or_default();
+        backlog.messages.push_back(message.clone());
+        if backlog.messages.len() > self.resume_backlog {
+            backlog.messages.pop_front();
+            backlog.dropped += 1;
+        }
+    }
+
+    fn claim_name(&self, addr: SocketAddr, name: &str) -> Result<(), String> {
+        if self
+            .bots
+            .iter()
+            .any(|bot| bot.name().eq_ignore_ascii_case(name))
+        {
+            return Err(format!("Name {} is already taken", name));
+        }
+        match self.names.entry(name.to_lowercase()) {
+            Entry::Occupied(_) => Err(format!("Name {} is already taken", name)),
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                Ok(())
+            }
+        }
+    }
+
+    fn release_name(&self, addr: SocketAddr, name: &str) {
+        self.names
+            .remove_if(&name.to_lowercase(), |_, owner| *owner == addr);
+    }
+
+    fn peer_name(&self, addr: SocketAddr) -> Option<String> {
+        self.peers.get(&addr).map(|peer| peer.name.clone())
+    }
+
+    /// Record activity from a peer and return its current name.
+    fn touch(&self, addr: SocketAddr) -> Option<String> {
+        self.peers.get_mut(&addr).map(|mut peer| {
+            peer.last_active = Instant::now();
+            peer.name.clone()
+        })
+    }
+
+    fn record_latency(&self, addr: SocketAddr, latency: Duration) {
+        histogram!("chat_ping_rtt_seconds").record(latency);
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.latency = Some(latency);
+        }
+    }
+
+    fn set_away(&self, addr: SocketAddr, reason: Option<String>) -> Option<String> {
+        let mut peer = self.peers.get_mut(&addr)?;
+        std::mem::replace(&mut peer.away, reason)
+    }
+
+    async fn direct_message(
+        &self,
+        name: &str,
+        target: &str,
+        content: String,
+        id: Option<String>,
+    ) -> Result<String, String> {
+        let target_addr = self
+            .names
+            .get(&target.to_lowercase())
+            .map(|owner| *owner)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        let (target, away) = self
+            .peers
+            .get(&target_addr)
+            .map(|peer| (peer.name.clone(), peer.away.clone()))
+            .ok_or_else(|| format!("No such user: {}", target))?;
+
+        let message = Message::Direct {
+            from: name.to_string(),
+            to: target.clone(),
+            content,
+            id,
+        };
+        if self.is_shadow_banned(name) {
+            debug!(
+                "{} is shadow banned, direct message to {} dropped",
+                name, target
+            );
+        } else if self.is_ignoring(target_addr, name) {
+            debug!("{} ignores {}, direct message dropped", target, name);
+        } else if !self.send_to(target_addr, Arc::new(message)).await {
+            return Err(format!(
+                "Message to {} not delivered, their queue is full",
+                target
+            ));
+        }
+        debug!("{} sent a direct message to {}", name, target);
+        Ok(match away {
+            Some(reason) if reason.is_empty() => format!("Message sent to {}, who is away", target),
+            Some(reason) => format!("Message sent to {}, who is away: {}", target, reason),
+            None => format!("Message sent to {}", target),
+        })
+    }
+
+    fn author(&self, addr: SocketAddr) -> Option<Author> {
+        let peer = self.peers.get(&addr)?;
+        let author = match (&peer.user, &peer.session) {
+            (Some(user), _) => Author::User(user.clone()),
+            (None, Some(session)) => Author::Session(session.clone()),
+            (None, None) => Author::Connection(addr),
+        };
+        Some(author)
+    }
+
+    fn role(&self, addr: SocketAddr) -> Role {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.role)
+            .unwrap_or_default()
+    }
+
+    /// Change the role of another peer, for the rest of its connection or, when it logged in
+    /// through the auth provider, whenever its user logs in.
+    fn promote(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        target: &str,
+        role: Role,
+    ) -> Result<String, String> {
+        let target_addr = self
+            .names
+            .get(&target.to_lowercase())
+            .map(|owner| *owner)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if target_addr == addr {
+            return Err("You cannot change your own role".to_string());
+        }
+        let mut peer = self
+            .peers
+            .get_mut(&target_addr)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if peer.role == role {
+            return Err(format!("{} is already {}", peer.name, role));
+        }
+        peer.role = role;
+        if let Some(user) = &peer.user {
+            self.roles.insert(user.clone(), role);
+        }
+        info!("{} made {} {}", name, peer.name, role);
+        Ok(format!("{} is now {}, by {}", peer.name, role, name))
+    }
+
+    /// Force a peer off the server, banning also blocks their address from reconnecting.
+    async fn kick(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        target: &str,
+        ban: bool,
+    ) -> Result<String, String> {
+        let target_addr = self
+            .names
+            .get(&target.to_lowercase())
+            .map(|owner| *owner)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if target_addr == addr {
+            return Err("You cannot kick yourself".to_string());
+        }
+        let target = self
+            .peer_name(target_addr)
+            .unwrap_or_else(|| target.to_string());
+
+        let action = if ban {
+            self.banned.insert(target_addr.ip());
+            "banned"
+        } else {
+            "kicked"
+        };
+        info!("{} {} {} ({})", name, action, target, target_addr);
+
+        let message = Message::system(format!("You have been {} by {}", action, name));
+        self.send_to(target_addr, Arc::new(message)).await;
+        self.disconnect(target_addr).await;
+        Ok(format!("{} was {} by {}", target, action, name))
+    }
+
+    fn shadow_ban(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        target: &str,
+        banned: bool,
+    ) -> Result<String, String> {
+        let target_addr = self
+            .names
+            .get(&target.to_lowercase())
+            .map(|owner| *owner)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if target_addr == addr {
+            return Err("You cannot shadow ban yourself".to_string());
+        }
+        let target = self
+            .peer_name(target_addr)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        let key = target.to_lowercase();
+        if banned {
+            if !self.shadow_banned.insert(key) {
+                return Err(format!("{} is already shadow banned", target));
+            }
+            info!("{} shadow banned {}", name, target);
+            Ok(format!(
+                "{} is shadow banned, only they see their messages",
+                target
+            ))
+        } else {
+            if self.shadow_banned.remove(&key).is_none() {
+                return Err(format!("{} is not shadow banned", target));
+            }
+            info!("{} lifted the shadow ban of {}", name, target);
+            Ok(format!("{} is no longer shadow banned", target))
+        }
+    }
+
+    fn is_shadow_banned(&self, name: &str) -> bool {
+        self.shadow_banned.contains(&name.to_lowercase())
+    }
+
+    /// Stop or start passing a peer the messages of another, like a shadow ban only the
+    /// peer itself asked for and knows about.
+    fn ignore(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        target: &str,
+        ignored: bool,
+    ) -> Result<String, String> {
+        let key = target.to_lowercase();
+        if key == name.to_lowercase() {
+            return Err("You cannot ignore yourself".to_string());
+        }
+        if !ignored {
+            let removed = self
+                .ignores
+                .get_mut(&addr)
+                .is_some_and(|mut ignored| ignored.remove(&key));
+            if !removed {
+                return Err(format!("You are not ignoring {}", target));
+            }
+            debug!("{} stopped ignoring {}", name, target);
+            return Ok(format!("You hear from {} again", target));
+        }
+        let target = self
+            .names
+            .get(&key)
+            .and_then(|owner| self.peer_name(*owner))
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if !self.ignores.entry(addr).or_default().insert(key) {
+            return Err(format!("You are already ignoring {}", target));
+        }
+        debug!("{} ignores {}", name, target);
+        Ok(format!(
+            "You are ignoring {}, /unignore {} to hear from them again",
+            target, target
+        ))
+    }
+
+    fn describe_ignores(&self, addr: SocketAddr) -> String {
+        let mut ignored: Vec<_> = self
+            .ignores
+            .get(&addr)
+            .map(|ignored| ignored.iter().cloned().collect())
+            .unwrap_or_default();
+        if ignored.is_empty() {
+            return "You are not ignoring anyone".to_string();
+        }
+        ignored.sort();
+        format!("You are ignoring: {}", ignored.join(", "))
+    }
+
+    fn is_ignoring(&self, addr: SocketAddr, name: &str) -> bool {
+        self.ignores
+            .get(&addr)
+            .is_some_and(|ignored| ignored.contains(&name.to_lowercase()))
+    }
+
+    fn rename(&self, addr: SocketAddr, old: &str, new: String) -> Result<String, String> {
+        validate_name(&new)?;
+        if old == new {
+            return Err(format!("You are already known as {}", new));
+        }
+        // a change of case only keeps the same registry entry
+        if old.to_lowercase() != new.to_lowercase() {
+            self.claim_name(addr, &new)?;
+            self.release_name(addr, old);
+        }
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.name = new.clone();
+        }
+        if self.shadow_banned.remove(&old.to_lowercase()).is_some() {
+            self.shadow_banned.insert(new.to_lowercase());
+        }
+        for mut ignored in self.ignores.iter_mut() {
+            if ignored.remove(&old.to_lowercase()) {
+                ignored.insert(new.to_lowercase());
+            }
+        }
+
+        info!("{} renamed to {}", old, new);
+        Ok(format!("{} is now known as {}", old, new))
+    }
+
+    async fn typing(&self, addr: SocketAddr, name: &str) {
+        if let Some(room) = self.current_room(addr) {
+            let message = Message::Typing {
+                room,
+                name: name.to_string(),
+            };
+            self.broadcast(Some(addr), Arc::new(message)).await;
+        }
+    }
+
+    async fn broadcast(&self, from: Option<SocketAddr>, message: Arc<Message>) {
+        self.fan_out(from, message.clone()).await;
+        self.run_bots(&message).await;
+    }
+
+    /// Relay a chat message from a peer to a room, unless a filter rejects it, returns the
+    /// id it was given.
+    async fn post(
+        &self,
+        addr: Option<SocketAddr>,
+        name: &str,
+        room: String,
+        content: String,
+    ) -> Result<String, String> {
+        let mut chat = ChatMessage {
+            room,
+            from: name.to_string(),
+            content,
+            at: self.timestamps.now(),
+            mentions: Vec::new(),
+            id: nanoid!(MESSAGE_ID_LEN),
+        };
+        self.screen(addr, &mut chat)?;
+        // after the filters, a masked word is not a mention
+        chat.mentions = parse_mentions(&chat.content);
+        let id = chat.id.clone();
+        // messages posted from outside a connection can't be edited
+        if let Some(author) = addr.and_then(|addr| self.author(addr)) {
+            self.authors
+                .lock()
+                .unwrap()
+                .put(id.clone(), (chat.room.clone(), author));
+        }
+        let message = Arc::new(Message::Chat(chat));
+        if let Some(addr) = addr.filter(|_| self.is_shadow_banned(name)) {
+            // the sender sees it go through, nobody else gets it
+            debug!(room = message.room(), from = name, "shadow banned message");
+            self.send_to(addr, message).await;
+            return Ok(id);
+        }
+        self.broadcast(addr, message).await;
+        Ok(id)
+    }
+
+    /// Run a message about to be posted through the filters and the room's flags.
+    fn screen(&self, addr: Option<SocketAddr>, chat: &mut ChatMessage) -> Result<(), String> {
+        if let FilterAction::Reject(reason) = self.filters.filter(chat) {
+            debug!(
+                room = chat.room,
+                from = chat.from,
+                "message rejected by a filter"
+            );
+            return Err(reason);
+        }
+        let role = addr.map_or(Role::User, |addr| self.role(addr));
+        if self.has_flag(&chat.room, RoomFlag::ReadOnly) && role < Role::Moderator {
+            return Err(format!(
+                "{} is read-only, only moderators may post",
+                chat.room
+            ));
+        }
+        Ok(())
+    }
+
+    /// Post an action to the peer's current room, screened like a chat message.
+    async fn act(&self, addr: SocketAddr, name: &str, content: String) -> Result<(), String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        let mut chat = ChatMessage {
+            room,
+            from: name.to_string(),
+            content,
+            at: String::new(),
+            mentions: Vec::new(),
+            id: String::new(),
+        };
+        self.screen(Some(addr), &mut chat)?;
+        let message = Arc::new(Message::Action {
+            room: chat.room,
+            from: chat.from,
+            content: chat.content,
+        });
+        if self.is_shadow_banned(name) {
+            self.send_to(addr, message).await;
+            return Ok(());
+        }
+        self.broadcast(Some(addr), message).await;
+        Ok(())
+    }
+
+    /// Change the content of one of the sender's recent messages, or delete it with `None`.
+    ///
+    /// The new content goes through the filters like a new message would.
+    async fn edit(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        id: String,
+        content: Option<String>,
+    ) -> Result<(), String> {
+        let author = self.author(addr);
+        let room = match self.authors.lock().unwrap().peek(&id) {
+            Some((room, by)) if Some(by) == author.as_ref() => room.clone(),
+            _ => return Err(format!("You have no recent message {}", id)),
+        };
+        if self.has_flag(&room, RoomFlag::ReadOnly) && self.role(addr) < Role::Moderator {
+            return Err(format!("{} is read-only, only moderators may post", room));
+        }
+        let message = match content {
+            Some(content) => {
+                let mut chat = ChatMessage {
+                    room,
+                    from: name.to_string(),
+                    content,
+                    at: self.timestamps.now(),
+                    mentions: Vec::new(),
+                    id,
+                };
+                if let FilterAction::Reject(reason) = self.filters.filter(&mut chat) {
+                    return Err(reason);
+                }
+                Message::Edited {
+                    room: chat.room,
+                    from: chat.from,
+                    id: chat.id,
+                    content: chat.content,
+                }
+            }
+            None => {
+                self.authors.lock().unwrap().pop(&id);
+                Message::Deleted {
+                    room,
+                    from: name.to_string(),
+                    id,
+                }
+            }
+        };
+        let message = Arc::new(message);
+        if self.is_shadow_banned(name) {
+            self.send_to(addr, message).await;
+            return Ok(());
+        }
+        // the sender is told too, it is their only sign the change went through
+        self.announce(message).await;
+        Ok(())
+    }
+
+    /// Post a chat message to a room from outside any connection, like the HTTP gateway,
+    /// for the `user` that [`ChatRoom::check_token`] returned.
+    ///
+    /// The sender is a free form name that must not belong to a connected peer, the message
+    /// goes through the same length limit and filters as the ones peers send.
+    pub async fn post_as(
+        &self,
+        user: Option<&str>,
+        from: &str,
+        room: &str,
+        content: String,
+    ) -> Result<(), String> {
+        validate_name(from)?;
+        if self.names.contains_key(&from.to_lowercase()) {
+            return Err(format!("{} is the name of a connected peer", from));
+        }
+        let room = parse_room_name(room)?;
+        if self.archived.contains(&room) {
+            return Err(format!("{} is archived", room));
+        }
+        self.admit_user(user, &room)?;
+        if content.len() > self.max_message_len {
+            return Err(format!(
+                "Message too long ({} bytes), the limit is {} bytes",
+                content.len(),
+                self.max_message_len
+            ));
+        }
+        self.post(None, from, room, content).await.map(|_| ())
+    }
+
+    /// Follow a room without joining it, for the `user` that [`ChatRoom::check_token`]
+    /// returned. Every message fanned out to its members is sent here too, along with the
+    /// announcements made to everyone.
+    ///
+    /// A watcher that falls behind loses the oldest messages, it never slows the room down.
+    pub fn watch(
+        &self,
+        user: Option<&str>,
+        room: &str,
+    ) -> Result<broadcast::Receiver<Arc<Message>>, String> {
+        let room = parse_room_name(room)?;
+        if self.archived.contains(&room) {
+            return Err(format!("{} is archived", room));
+        }
+        self.admit_user(user, &room)?;
+        Ok(self
+            .watchers
+            .entry(room)
+            .or_insert_with(|| broadcast::channel(self.channel_capacity).0)
+            .subscribe())
+    }
+
+    /// Check a token presented outside the line protocols, returns the user it belongs to
+    /// if the auth provider names one. Anything goes without an auth provider.
+    pub async fn check_token(&self, token: Option<&str>) -> Result<Option<String>, String> {
+        let Some(auth) = &self.auth else {
+            return Ok(None);
+        };
+        let Some(token) = token else {
+            return Err("An access token is required".to_string());
+        };
+        match auth.authenticate(token).await {
+            Ok(Some(user)) => Ok(Some(user).filter(|user| !user.is_empty())),
+            Ok(None) => Err("Invalid access token".to_string()),
+            Err(e) => {
+                warn!("Failed to check access token: {}", e);
+                Err("Authentication is unavailable right now".to_string())
+            }
+        }
+    }
+
+    async fn run_bots(&self, message: &Message) {
+        for bot in &self.bots {
+            let ctx = BotContext {
+                chat_room: self,
+                name: bot.name(),
+            };
+            match message {
+                Message::Chat(chat) => bot.on_message(&ctx, chat).await,
+                Message::Join { room, name } => bot.on_join(&ctx, room, name).await,
+                Message::Leave { room, name } => bot.on_leave(&ctx, room, name).await,
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn announce(&self, message: Arc<Message>) {
+        self.fan_out(None, message).await;
+    }
+
+    /// Make the scheduled announcements as they come due, until the server shuts down.
+    pub async fn run_announcements(&self, shutdown: CancellationToken) {
+        let mut last = Utc::now();
+        loop {
+            // the wall clock may lag the timer a little, don't make an announcement twice
+            let now = Utc::now().max(last);
+            let Some(next) = self
+                .announcements
+                .iter()
+                .filter_map(|announcement| announcement.next_after(now))
+                .min()
+            else {
+                return;
+            };
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = sleep(wait) => {}
+            }
+            for announcement in &self.announcements {
+                if announcement.next_after(now) == Some(next) {
+                    info!("Scheduled announcement: {}", announcement.text);
+                    let text = announcement_text(&announcement.text);
+                    self.announce(Arc::new(Message::system(text))).await;
+                }
+            }
+            last = next;
+        }
+    }
+
+    /// Deliver a message to local peers and relay it to the federated servers and the other
+    /// instances.
+    async fn fan_out(&self, skip: Option<SocketAddr>, message: Arc<Message>) {
+        // only the server a message starts on posts it to the webhooks
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(&message);
+        }
+        if let Some(federation) = &self.federation {
+            federation.publish(&message);
+        }
+        if let Some(backend) = self.backend.as_ref().filter(|_| message.is_federated()) {
+            if let Err(e) = backend.publish(&message).await {
+                warn!("Failed to publish message to the backend: {}", e);
+            }
+        }
+        self.fan_out_local(skip, message).await;
+    }
+
+    async fn fan_out_local(&self, skip: Option<SocketAddr>, message: Arc<Message>) {
+        self.record(&message, true).await;
+        self.deliver_local(skip, message).await;
+    }
+
+    async fn record(&self, message: &Arc<Message>, history: bool) {
+        if message.is_ephemeral() {
+            return;
+        }
+        if history {
+            if let Err(e) = self.history.record(message).await {
+                warn!("Failed to record message to history: {}", e);
+            }
+        }
+        if let Some(event_log) = &self.event_log {
+            event_log.append(message);
+        }
+        if let Some(transcripts) = &self.transcripts {
+            transcripts.append(message);
+        }
+    }
+
+    async fn deliver_local(&self, skip: Option<SocketAddr>, message: Arc<Message>) {
+        counter!("chat_messages_broadcast_total").increment(1);
+        if let Message::Chat(_) = *message {
+            self.throughput.record(self.started_at.elapsed().as_secs());
+        }
+
+        // the peers ignoring the sender are passed over, held sessions included
+        let ignored_by = |addr: &SocketAddr| {
+            message
+                .sender()
+                .is_some_and(|sender| self.is_ignoring(*addr, sender))
+        };
+        // collect the recipients first so no map guard is held across an await
+        let recipients: Vec<(SocketAddr, Arc<Outbox>)> = match message.room() {
+            Some(room) => self
+                .rooms
+                .get(room)
+                .map(|members| {
+                    members
+                        .iter()
+                        .filter(|addr| !ignored_by(addr))
+                        .filter_map(|addr| match self.peers.get(addr) {
+                            Some(peer) => Some((*addr, peer.outbox.clone())),
+                            None => {
+                                self.hold(*addr, room, &message);
+                                None
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => self
+                .peers
+                .iter()
+                .filter(|item| !ignored_by(item.key()))
+                .map(|item| (*item.key(), item.value().outbox.clone()))
+                .collect(),
+        };
+
+        let recipients: Vec<_> = recipients
+            .into_iter()
+            .filter(|(addr, _)| Some(*addr) != skip)
+            .collect();
+        debug!(
+            room = message.room(),
+            recipients = recipients.len(),
+            "fan out"
+        );
+
+        let policy = self.slow_consumer_policy(message.room());
+        for (addr, outbox) in recipients {
+            if deliver(addr, &outbox, message.clone(), policy).await == Err(OutboxError::Full)
+                && matches!(
+                    policy,
+                    SlowConsumerPolicy::Disconnect | SlowConsumerPolicy::Block
+                )
+            {
+                // the receive loop notices the cancellation and disconnects the peer
+                if let Some(peer) = self.peers.get(&addr) {
+                    if !peer.closed.is_cancelled() {
+                        warn!("Disconnecting lagging peer {}", addr);
+                        peer.closed.cancel();
+                    }
+                }
+            }
+        }
+        self.notify_watchers(&message);
+        if let Message::Chat(chat) = message.as_ref() {
+            self.notify_mentioned(skip, chat).await;
+        }
+    }
+
+    /// Tell the peers named in a chat message, wherever they are.
+    async fn notify_mentioned(&self, skip: Option<SocketAddr>, chat: &ChatMessage) {
+        for name in &chat.mentions {
+            let Some(addr) = self.names.get(&name.to_lowercase()).map(|owner| *owner) else {
+                continue;
+            };
+            if Some(addr) == skip || self.is_ignoring(addr, &chat.from) {
+                continue;
+            }
+            let message = Message::Mention {
+                room: chat.room.clone(),
+                from: chat.from.clone(),
+            };
+            self.send_to(addr, Arc::new(message)).await;
+        }
+    }
+
+    fn notify_watchers(&self, message: &Arc<Message>) {
+        let rooms: Vec<String> = match message.room() {
+            Some(room) => vec![room.to_string()],
+            None => self
+                .watchers
+                .iter()
+                .map(|item| item.key().clone())
+                .collect(),
+        };
+        for room in rooms {
+            let unwatched = self
+                .watchers
+                .get(&room)
+                .is_some_and(|watchers| watchers.send(message.clone()).is_err());
+            if unwatched {
+                // every receiver is gone, unless one subscribed since the send
+                self.watchers
+                    .remove_if(&room, |_, watchers| watchers.receiver_count() == 0);
+            }
+        }
+    }
+
+    /// Send a message to a single peer, returns false if it could not be delivered.
+    async fn send_to(&self, addr: SocketAddr, message: Arc<Message>) -> bool {
+        let outbox = match self.peers.get(&addr) {
+            Some(item) => item.value().outbox.clone(),
+            None => {
+                warn!("Failed to send message to peer {}: not connected", addr);
+                return false;
+            }
+        };
+        // replies to a single peer never wait on it or get it disconnected, they are also
+        // queued before its send loop starts
+        let policy = match self.slow_consumers {
+            SlowConsumerPolicy::Disconnect | SlowConsumerPolicy::Block => {
+                SlowConsumerPolicy::DropNewest
+            }
+            policy => policy,
+        };
+        deliver(addr, &outbox, message, policy).await.is_ok()
+    }
+
+    /// Tell the sender of a direct message that it was written to the recipient.
+    async fn confirm_delivery(&self, from: &str, to: &str, id: &str) {
+        let Some(addr) = self.names.get(&from.to_lowercase()).map(|owner| *owner) else {
+            return;
+        };
+        let message = Message::Delivered {
+            id: id.to_string(),
+            to: to.to_string(),
+        };
+        self.send_to(addr, Arc::new(message)).await;
+    }
+}
+
+async fn deliver(
+    addr: SocketAddr,
+    outbox: &Outbox,
+    message: Arc<Message>,
+    policy: SlowConsumerPolicy,
+) -> Result<(), OutboxError> {
+    let ret = match policy {
+        SlowConsumerPolicy::Block => outbox.send(message, SEND_TIMEOUT).await,
+        policy => outbox.try_send(message, policy),
+    };
+    match &ret {
+        Ok(()) => {}
+        Err(OutboxError::Full) => {
+            debug!("Outbox of peer {} is full, applying {:?}", addr, policy);
+            counter!("chat_messages_dropped_total").increment(1);
+        }
+        Err(OutboxError::Closed) => {
+            warn!("Failed to send message to peer {}: disconnected", addr);
+            counter!("chat_messages_dropped_total").increment(1);
+        }
+    }
+    ret
+}
+
+impl BotContext<'_> {
+    /// Post a chat message to a room.
+    async fn say(&self, room: &str, content: impl Into<String>) {
+        let content = content.into();
+        let message = Message::Chat(ChatMessage {
+            room: room.to_string(),
+            from: self.name.to_string(),
+            mentions: parse_mentions(&content),
+            content,
+            at: self.chat_room.timestamps.now(),
+            id: nanoid!(MESSAGE_ID_LEN),
+        });
+        self.chat_room.announce(Arc::new(message)).await;
+    }
+
+    /// Send a direct message to a peer, returns false if nobody has that name.
+    async fn tell(&self, name: &str, content: impl Into<String>) -> bool {
+        let Some(addr) = self.chat_room.names.get(&name.to_lowercase()).map(|a| *a) else {
+            return false;
+        };
+        let message = Message::Direct {
+            from: self.name.to_string(),
+            to: name.to_string(),
+            content: content.into(),
+            id: None,
+        };
+        self.chat_room.send_to(addr, Arc::new(message)).await
+    }
+}
+
+impl ChatBot for EchoBot {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_message<'a>(
+        &'a self,
+        ctx: &'a BotContext<'a>,
+        message: &'a ChatMessage,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if let Some(text) = message.content.strip_prefix("!echo ") {
+                ctx.say(&message.room, text).await;
+            }
+        })
+    }
+}
+
+impl ChatBot for GreeterBot {
+    fn name(&self) -> &str {
+        "greeter"
+    }
+
+    fn on_join<'a>(
+        &'a self,
+        ctx: &'a BotContext<'a>,
+        room: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            ctx.tell(name, format!("Hi {}, welcome to {}!", name, room))
+                .await;
+        })
+    }
+}
+
+impl ScheduledAnnouncement {
+    /// Parse a five field cron expression, in UTC, seconds may be given as a sixth field
+    /// in front.
+    pub fn new(schedule: &str, text: impl Into<String>) -> Result<Self> {
+        let schedule = Cron::new(schedule).with_seconds_optional().parse()?;
+        Ok(Self {
+            schedule,
+            text: text.into(),
+        })
+    }
+
+    fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        // on the second, even for schedules with a seconds field
+        self.schedule
+            .find_next_occurrence(&time.trunc_subsecs(0), false)
+            .ok()
+    }
+}
+
+impl AuthProvider for SharedToken {
+    fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(future::ready(Ok((token == self.0).then(String::new))))
+    }
+}
+
+impl TokenFile {
+    pub async fn load(path: &str) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path).await?;
+        let mut tokens = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, token)) = line.split_once(char::is_whitespace) else {
+                return Err(anyhow::anyhow!(
+                    "{}:{}: expected \"user token\"",
+                    path,
+                    i + 1
+                ));
+            };
+            tokens.insert(token.trim().to_string(), user.to_string());
+        }
+        Ok(Self { tokens })
+    }
+}
+
+impl AuthProvider for TokenFile {
+    fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(future::ready(Ok(self.tokens.get(token).cloned())))
+    }
+}
+
+impl FilterChain {
+    pub fn with(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.0.push(Box::new(filter));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl MessageFilter for FilterChain {
+    fn filter(&self, message: &mut ChatMessage) -> FilterAction {
+        for filter in &self.0 {
+            if let FilterAction::Reject(reason) = filter.filter(message) {
+                return FilterAction::Reject(reason);
+            }
+        }
+        FilterAction::Allow
+    }
+}
+
+impl WordBlocklist {
+    pub fn new(words: &[String], policy: BlockedWordPolicy) -> Self {
+        Self {
+            words: words.iter().map(|word| word.to_lowercase()).collect(),
+            policy,
+        }
+    }
+
+    fn is_blocked(&self, word: &str) -> bool {
+        !word.is_empty() && self.words.contains(&word.to_lowercase())
+    }
+}
+
+impl MessageFilter for WordBlocklist {
+    fn filter(&self, message: &mut ChatMessage) -> FilterAction {
+        let mut blocked = false;
+        let masked: Vec<String> = message
+            .content
+            .split(' ')
+            .map(|word| {
+                // compare without surrounding punctuation so "word," is caught too
+                let bare = word.trim_matches(|c: char| !c.is_alphanumeric());
+                if self.is_blocked(bare) {
+                    blocked = true;
+                    word.replacen(bare, &"*".repeat(bare.chars().count()), 1)
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect();
+
+        match (blocked, self.policy) {
+            (false, _) => FilterAction::Allow,
+            (true, BlockedWordPolicy::Reject) => {
+                FilterAction::Reject("Message not sent, it contains a blocked word".to_string())
+            }
+            (true, BlockedWordPolicy::Mask) => {
+                message.content = masked.join(" ");
+                FilterAction::Allow
+            }
+        }
+    }
+}
+
+fn truncate_at_char_boundary(line: &mut String, max_len: usize) {
+    let mut end = max_len.min(line.len());
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line.truncate(end);
+}
+
+/// Names written as `@name` in a message, in order and without repeats, trailing
+/// punctuation is not part of the name.
+fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        let name = name.trim_end_matches([',', '.', ':', ';', '!', '?', ')']);
+        if validate_name(name).is_ok()
+            && !mentions
+                .iter()
+                .any(|mention| mention.to_lowercase() == name.to_lowercase())
+        {
+            mentions.push(name.to_string());
+        }
+    }
+    mentions
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "Name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        ));
+    }
+    if name.starts_with('/') || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("Name may not start with '/' or contain whitespace".to_string());
+    }
+    Ok(())
+}
+
+impl FromStr for LongMessagePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(anyhow::anyhow!(
+                "invalid long message policy: {}, expected reject or truncate",
+                s
+            )),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let count = self.chat_room.connections.fetch_sub(1, Ordering::AcqRel) - 1;
+        info!("Connections: {}/{}", count, self.chat_room.max_connections);
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_second: DEFAULT_RATE_LIMIT,
+            burst: DEFAULT_RATE_LIMIT * 2,
+            mute: Duration::from_secs(DEFAULT_MUTE_SECS),
+        }
+    }
+}
+
+impl Heartbeat {
+    fn new(period: Option<Duration>) -> Self {
+        // the first ping is due a period after connecting, not right away
+        let interval = period.map(|period| {
+            let mut interval = interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self {
+            interval,
+            seq: 0,
+            pending: None,
+        }
+    }
+
+    /// Wait for the next ping to be due, forever without a heartbeat.
+    async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// A ping with a new token, a pong to an earlier one no longer counts.
+    fn ping(&mut self) -> Message {
+        self.seq += 1;
+        let token = self.seq.to_string();
+        self.pending = Some((token.clone(), Instant::now()));
+        Message::Ping { token }
+    }
+
+    /// The round trip of the pending ping, if the pong answers it. Clients that don't echo
+    /// the token answer whichever ping is pending.
+    fn pong(&mut self, token: &str) -> Option<Duration> {
+        match &self.pending {
+            Some((pending, _)) if token.is_empty() || token == pending => {
+                self.pending.take().map(|(_, sent_at)| sent_at.elapsed())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Throughput {
+    fn record(&self, second: u64) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        match recent.back_mut() {
+            Some((last, count)) if *last == second => *count += 1,
+            _ => recent.push_back((second, 1)),
+        }
+        while recent
+            .front()
+            .is_some_and(|(first, _)| first + THROUGHPUT_WINDOW_SECS <= second)
+        {
+            recent.pop_front();
+        }
+    }
+
+    /// Messages in the minute up to `second`, seconds since the start.
+    fn last_minute(&self, second: u64) -> u64 {
+        let recent = self.recent.lock().unwrap();
+        recent
+            .iter()
+            .filter(|(at, _)| at + THROUGHPUT_WINDOW_SECS > second)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: Instant::now(),
+            warned: false,
+            muted_until: None,
+        }
+    }
+
+    /// The first line over the limit earns a warning, the next one a mute.
+    fn check(&mut self) -> RateDecision {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return RateDecision::Muted(until - now);
+            }
+            self.muted_until = None;
+            self.tokens = self.limit.burst as f64;
+            self.refilled_at = now;
+        }
+
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let burst = self.limit.burst as f64;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second as f64).min(burst);
+        self.refilled_at = now;
+        if self.tokens >= burst {
+            self.warned = false;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return RateDecision::Allow;
+        }
+        if !self.warned {
+            self.warned = true;
+            return RateDecision::Warn;
+        }
+
+        self.warned = false;
+        self.muted_until = Some(now + self.limit.mute);
+        RateDecision::Mute
+    }
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            lagged: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            notify: Notify::new(),
+            space: Notify::new(),
+        })
+    }
+
+    fn try_send(
+        &self,
+        message: Arc<Message>,
+        policy: SlowConsumerPolicy,
+    ) -> Result<(), OutboxError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(OutboxError::Closed);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        let ret = if queue.len() < self.capacity {
+            queue.push_back(message);
+            Ok(())
+        } else {
+            self.lagged.fetch_add(1, Ordering::Relaxed);
+            if policy == SlowConsumerPolicy::DropOldest {
+                queue.pop_front();
+                queue.push_back(message);
+            }
+            Err(OutboxError::Full)
+        };
+        drop(queue);
+
+        self.notify.notify_one();
+        ret
+    }
+
+    /// Queue a message, waiting up to `wait` for the peer to make room.
+    async fn send(&self, message: Arc<Message>, wait: Duration) -> Result<(), OutboxError> {
+        let queued = async {
+            loop {
+                // registered before looking at the queue so a message taken meanwhile wakes it
+                let space = self.space.notified();
+                tokio::pin!(space);
+                space.as_mut().enable();
+                if self.closed.load(Ordering::Acquire) {
+                    return Err(OutboxError::Closed);
+                }
+                let pushed = {
+                    let mut queue = self.queue.lock().unwrap();
+                    let room = queue.len() < self.capacity;
+                    if room {
+                        queue.push_back(message.clone());
+                    }
+                    room
+                };
+                if pushed {
+                    self.notify.notify_one();
+                    return Ok(());
+                }
+                space.await;
+            }
+        };
+        timeout(wait, queued).await.unwrap_or_else(|_| {
+            self.lagged.fetch_add(1, Ordering::Relaxed);
+            Err(OutboxError::Full)
+        })
+    }
+
+    /// Wait for the next message, queued messages are still handed out after `close`.
+    async fn recv(&self) -> Option<Arc<Message>> {
+        loop {
+            if let Some(message) = self.queue.lock().unwrap().pop_front() {
+                self.space.notify_waiters();
+                return Some(message);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Take up to `max` of the messages already queued, without waiting for more.
+    fn take_queued(&self, max: usize) -> Vec<Arc<Message>> {
+        let taken: Vec<_> = {
+            let mut queue = self.queue.lock().unwrap();
+            let len = queue.len().min(max);
+            queue.drain(..len).collect()
+        };
+        if !taken.is_empty() {
+            self.space.notify_waiters();
+        }
+        taken
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+        self.space.notify_waiters();
+    }
+}
+
+impl TimestampFormat {
+    fn now(self) -> String {
+        let now = Utc::now();
+        match self {
+            Self::Rfc3339 => now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            Self::Time => now.format("%H:%M:%S").to_string(),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(Self::User),
+            "moderator" => Ok(Self::Moderator),
+            "admin" => Ok(Self::Admin),
+            _ => Err(anyhow::anyhow!(
+                "invalid role: {}, expected user, moderator or admin",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User => write!(f, "user"),
+            Self::Moderator => write!(f, "moderator"),
+            Self::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rfc3339" => Ok(Self::Rfc3339),
+            "time" => Ok(Self::Time),
+            _ => Err(anyhow::anyhow!(
+                "invalid timestamp format: {}, expected rfc3339 or time",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            "block" => Ok(Self::Block),
+            _ => Err(anyhow::anyhow!(
+                "invalid slow consumer policy: {}, expected drop-oldest, drop-newest, disconnect or block",
+                s
+            )),
+        }
+    }
+}
+
+impl PeerHandle {
+    fn new(name: String, role: Role, outbox: Arc<Outbox>) -> Self {
+        Self {
+            name,
+            connected_at: Instant::now(),
+            role,
+            closed: CancellationToken::new(),
+            outbox,
+            current_room: None,
+            last_active: Instant::now(),
+            latency: None,
+            away: None,
+            session: None,
+            user: None,
+        }
+    }
+}
+
+impl Peer {
+    fn new(
+        addr: SocketAddr,
+        name: String,
+        protocol: Protocol,
+        outbox: Arc<Outbox>,
+        closed: CancellationToken,
+    ) -> Self {
+        Self {
+            addr,
+            name,
+            protocol,
+            outbox,
+            closed,
+        }
+    }
+
+    async fn bootstrap(
+        self,
+        chat_room: Arc<ChatRoom>,
+        sender: impl LineSink,
+        receiver: impl LineStream,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let addr = self.addr;
+        let protocol = self.protocol;
+        let closed = self.closed;
+        let chat_room_cloned = chat_room.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = loop_receive_from_client(
+                    addr,
+                    protocol,
+                    receiver,
+                    &chat_room_cloned,
+                    shutdown,
+                    closed,
+                )
+                .await
+                {
+                    warn!("Failed to receive message from client: {}", e);
+                }
+                chat_room_cloned.detach(addr).await;
+            }
+            .in_current_span(),
+        );
+
+        if let Err(e) = loop_send_to_client(self.outbox, sender, protocol, &chat_room).await {
+            warn!("Failed to send message to client: {}", e);
+        }
+        chat_room.detach(addr).await;
+
+        Ok(())
+    }
+}
+
+async fn loop_send_to_client(
+    outbox: Arc<Outbox>,
+    mut sender: impl LineSink,
+    protocol: Protocol,
+    chat_room: &Arc<ChatRoom>,
+) -> Result<()> {
+    while let Some(message) = outbox.recv().await {
+        let mut batch = vec![message];
+        if let Some(flush_interval) = chat_room.flush_interval {
+            // more already queued means a busy room, give the rest a moment to arrive too
+            if !outbox.is_empty() {
+                sleep(flush_interval).await;
+            }
+            batch.extend(outbox.take_queued(MAX_BATCH - 1));
+        }
+        histogram!("chat_send_batch_size").record(batch.len() as f64);
+
+        let lagged = outbox.lagged.swap(0, Ordering::Relaxed);
+        if lagged > 0 {
+            let notice = Message::system(format!(
+                "You are lagging behind, {} messages were dropped",
+                lagged
+            ));
+            sender.feed(protocol.encode(&notice)?).await?;
+        }
+
+        let started = Instant::now();
+        let mut len = 0;
+        for message in &batch {
+            if protocol != Protocol::Json && message.is_ephemeral() {
+                continue;
+            }
+            let line = protocol.encode(message)?;
+            // count the trailing newline added by the codec as well
+            len += line.len() as u64 + 1;
+            // a peer that stops reading must not pin its connection forever
+            timeout(SEND_TIMEOUT, sender.feed(line))
+                .await
+                .map_err(|_| anyhow::anyhow!("write timed out"))??;
+        }
+        // the whole batch goes out in one write
+        timeout(SEND_TIMEOUT, sender.flush())
+            .await
+            .map_err(|_| anyhow::anyhow!("write timed out"))??;
+        histogram!("chat_send_latency_seconds").record(started.elapsed());
+        chat_room.bytes_sent.fetch_add(len, Ordering::Relaxed);
+
+        for message in &batch {
+            if let Message::Direct {
+                from,
+                to,
+                id: Some(id),
+                ..
+            } = message.as_ref()
+            {
+                chat_room.confirm_delivery(from, to, id).await;
+            }
+        }
+    }
+    // the queue is drained once the peer is disconnected, close the transport cleanly
+    sender.close().await?;
+    Ok(())
+}
+
+async fn loop_receive_from_client(
+    addr: SocketAddr,
+    protocol: Protocol,
+    mut receiver: impl LineStream,
+    chat_room: &Arc<ChatRoom>,
+    shutdown: CancellationToken,
+    closed: CancellationToken,
+) -> Result<()> {
+    let mut limiter = RateLimiter::new(chat_room.rate_limit);
+    let mut heartbeat = Heartbeat::new(chat_room.heartbeat);
+    let mut pinged = false;
+    let mut typed_at: Option<Instant> = None;
+    // only lines from the peer push the deadline back, not the pings sent meanwhile
+    let idle_deadline = || {
+        chat_room
+            .idle
+            .timeout
+            .map(|idle_timeout| tokio::time::Instant::now() + idle_timeout)
+    };
+    let mut deadline = idle_deadline();
+    loop {
+        let read = async {
+            match deadline {
+                Some(deadline) => timeout_at(deadline, receiver.next()).await,
+                None => Ok(receiver.next().await),
+            }
+        };
+        let next = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = closed.cancelled() => break,
+            _ = heartbeat.tick() => {
+                chat_room.send_to(addr, Arc::new(heartbeat.ping())).await;
+                continue;
+            }
+            next = read => next,
+        };
+        let next = match next {
+            Ok(next) => next,
+            Err(_) if chat_room.idle.keepalive && !pinged => {
+                pinged = true;
+                deadline = idle_deadline();
+                chat_room.send_to(addr, Arc::new(heartbeat.ping())).await;
+                continue;
+            }
+            Err(_) => {
+                info!("{} idle for too long, disconnecting", addr);
+                let message = Message::system("Disconnected for inactivity");
+                chat_room.send_to(addr, Arc::new(message)).await;
+                break;
+            }
+        };
+        let Some(line) = next else {
+            break;
+        };
+        pinged = false;
+        deadline = idle_deadline();
+
+        let line = match line {
+            Ok(line) => line,
+            // the codec skips the rest of an oversized line, so the connection stays usable
+            Err(e) if e.is::<LineTooLong>() => {
+                let message = Message::system(format!(
+                    "Message dropped, lines are limited to {} bytes",
+                    chat_room.max_message_len
+                ));
+                chat_room.send_to(addr, Arc::new(message)).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        chat_room
+            .bytes_received
+            .fetch_add(line.len() as u64 + 1, Ordering::Relaxed);
+
+        let (line, id) = match protocol.decode_frame(line) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                let message = Message::system(format!("Invalid frame: {}", e));
+                chat_room.send_to(addr, Arc::new(message)).await;
+                continue;
+            }
+        };
+
+        let line = line.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(token) = keyword_arg(&line, PONG) {
+            if let Some(latency) = heartbeat.pong(token) {
+                chat_room.record_latency(addr, latency);
+            }
+            continue;
+        }
+        // a client checking on the server, IRC clients do it regularly
+        if let Some(token) = keyword_arg(&line, PING) {
+            let message = Message::Pong {
+                token: token.to_string(),
+            };
+            chat_room.send_to(addr, Arc::new(message)).await;
+            continue;
+        }
+
+        // the name can change through /nick, so look it up for every line
+        let Some(name) = chat_room.touch(addr) else {
+            break;
+        };
+        let name = name.as_str();
+
+        // a file is sent as a burst of chunks, bounded by the size of accepted transfers instead
+        if line.starts_with(CHUNK) {
+            let ret = chat_room.handle_command(addr, name, &line, None).await;
+            chat_room.acknowledge(addr, id, ret.map(|()| None)).await;
+            continue;
+        }
+
+        // typing events are frequent and never stored, so throttle instead of rate limiting
+        if line.eq_ignore_ascii_case(TYPING) {
+            if typed_at.is_none_or(|at| at.elapsed() >= TYPING_THROTTLE) {
+                typed_at = Some(Instant::now());
+                chat_room.typing(addr, name).await;
+            }
+            continue;
+        }
+
+        let notice = match limiter.check() {
+            RateDecision::Allow => None,
+            RateDecision::Muted(left) => Some(format!(
+                "You are muted for another {}s, your message was not sent",
+                left.as_secs_f64().ceil()
+            )),
+            RateDecision::Warn => Some("You are sending messages too fast, slow down".to_string()),
+            RateDecision::Mute => {
+                warn!("{} muted for flooding", name);
+                let notice = Message::system(format!(
+                    "{} was muted for {}s for flooding",
+                    name,
+                    chat_room.rate_limit.mute.as_secs()
+                ));
+                chat_room.fan_out_local(Some(addr), Arc::new(notice)).await;
+                Some(format!(
+                    "You are muted for {}s for sending messages too fast",
+                    chat_room.rate_limit.mute.as_secs()
+                ))
+            }
+        };
+        if let Some(notice) = notice {
+            chat_room
+                .send_to(addr, Arc::new(Message::system(notice.clone())))
+                .await;
+            chat_room.acknowledge(addr, id, Err(notice)).await;
+            continue;
+        }
+
+        let mut line = line;
+        if line.len() > chat_room.max_message_len {
+            let limit = chat_room.max_message_len;
+            let text = match chat_room.long_messages {
+                LongMessagePolicy::Reject => {
+                    format!(
+                        "Message too long ({} bytes), the limit is {} bytes",
+                        line.len(),
+                        limit
+                    )
+                }
+                LongMessagePolicy::Truncate => {
+                    truncate_at_char_boundary(&mut line, limit);
+                    format!("Message truncated to {} bytes", limit)
+                }
+            };
+            chat_room
+                .send_to(addr, Arc::new(Message::system(text.clone())))
+                .await;
+            if chat_room.long_messages == LongMessagePolicy::Reject {
+                chat_room.acknowledge(addr, id, Err(text)).await;
+                continue;
+            }
+        }
+
+        if line.starts_with('/') {
+            let ret = chat_room
+                .handle_command(addr, name, &line, id.as_deref())
+                .await;
+            chat_room.acknowledge(addr, id, ret.map(|()| None)).await;
+            continue;
+        }
+
+        let Some(room) = chat_room.current_room(addr) else {
+            let text = "You are not in any room, use /join #room first".to_string();
+            chat_room
+                .send_to(addr, Arc::new(Message::system(text.clone())))
+                .await;
+            chat_room.acknowledge(addr, id, Err(text)).await;
+            continue;
+        };
+
+        let ret = chat_room.post(Some(addr), name, room, line).await;
+        if let Err(reason) = &ret {
+            chat_room
+                .send_to(addr, Arc::new(Message::system(reason.clone())))
+                .await;
+        }
+        chat_room.acknowledge(addr, id, ret.map(Some)).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
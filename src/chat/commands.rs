@@ -0,0 +1,1213 @@
+//! The slash commands peers send, how they are parsed and how the room carries them out.
+
+use super::{
+    history::{DEFAULT_HISTORY_PAGE, MAX_HISTORY_PAGE, MAX_SEARCH_RESULTS},
+    protocol::Message,
+    rooms::{parse_room_name, RoomFlag, MAX_INVITE_MINUTES},
+    ChatRoom, Role,
+};
+use anyhow::Result;
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+// /who only mentions idle time past this
+const SHOW_IDLE_AFTER: Duration = Duration::from_secs(60);
+const MAX_FILE_NAME_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Command {
+    Join {
+        room: String,
+        // needed for invite-only rooms
+        invite: Option<String>,
+    },
+    Leave(Option<String>),
+    // a room other than the current one, and a page of messages older than `before`
+    History {
+        room: Option<String>,
+        before: Option<u64>,
+        count: usize,
+    },
+    Search(String),
+    Poll {
+        question: String,
+        options: Vec<String>,
+    },
+    Vote(usize),
+    PollResult,
+    PollClose,
+    Stats {
+        uptime_only: bool,
+    },
+    Who,
+    Away(Option<String>),
+    Back,
+    Msg {
+        to: String,
+        content: String,
+    },
+    // post to one of the joined rooms other than the current one
+    Say {
+        room: String,
+        content: String,
+    },
+    Me(String),
+    Create(String),
+    // None archives the current room
+    Archive(Option<String>),
+    Rooms,
+    Edit {
+        id: String,
+        content: String,
+    },
+    Delete(String),
+    Quit,
+    // None queries the topic of the current room, an empty topic clears it
+    Topic(Option<String>),
+    // None lists the flags of the current room, otherwise the flag is set or cleared
+    Flag(Option<(RoomFlag, bool)>),
+    // None queries the member limit of the current room, 0 lifts it
+    Limit(Option<usize>),
+    // None lasts DEFAULT_INVITE_MINUTES
+    Invite {
+        single_use: bool,
+        minutes: Option<u64>,
+    },
+    Nick(String),
+    Kick(String),
+    Ban(String),
+    SendFile {
+        to: String,
+        file: String,
+        size: u64,
+    },
+    Accept(u64),
+    // the recipient rejecting an offer or either side aborting a transfer
+    Cancel(u64),
+    Chunk {
+        id: u64,
+        data: String,
+    },
+    // None lists every command
+    Help(Option<String>),
+    Announce(String),
+    Promote {
+        name: String,
+        role: Role,
+    },
+    ShadowBan {
+        name: String,
+        banned: bool,
+    },
+    // None lists the ignored names
+    Ignore(Option<String>),
+    Unignore(String),
+    Snapshot,
+}
+
+/// A slash command: its names, how to parse its arguments and what /help says about it.
+///
+/// Adding a command takes a `Command` variant, an entry in `COMMANDS` and its handler in
+/// `ChatRoom::run_command`.
+#[derive(Debug)]
+struct CommandSpec {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    usage: &'static str,
+    // commands without help text are for clients, not people, and left out of /help
+    help: &'static str,
+    args: ArgStyle,
+    parse: fn(&[String]) -> Result<Command, ArgError>,
+}
+
+/// How the text after a command name is split into arguments.
+#[derive(Debug, PartialEq)]
+enum ArgStyle {
+    // whitespace separated, double quotes group words
+    Words,
+    // a target, then the rest of the line verbatim, quotes included
+    TargetAndText,
+    // the rest of the line verbatim
+    Text,
+}
+
+#[derive(Debug)]
+pub(super) struct CommandRegistry(&'static [CommandSpec]);
+
+#[derive(Debug)]
+enum ArgError {
+    // the arguments don't fit the command, answered with its usage
+    Usage,
+    Invalid(String),
+}
+
+pub(super) static COMMANDS: CommandRegistry = CommandRegistry(&[
+    CommandSpec {
+        name: "/help",
+        aliases: &[],
+        usage: "/help [command]",
+        help: "List the commands, or explain one of them",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Help(None)),
+            [topic] => Ok(Command::Help(Some(topic.clone()))),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/join",
+        aliases: &[],
+        usage: "/join #room [invite code]",
+        help: "Join a room, or switch to it when already a member",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [room] => Ok(Command::Join {
+                room: parse_room_name(room)?,
+                invite: None,
+            }),
+            [room, invite] => Ok(Command::Join {
+                room: parse_room_name(room)?,
+                invite: Some(invite.clone()),
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/leave",
+        aliases: &[],
+        usage: "/leave [#room]",
+        help: "Leave a room, the current one by default",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Leave(None)),
+            [room] => Ok(Command::Leave(Some(parse_room_name(room)?))),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/create",
+        aliases: &[],
+        usage: "/create #room",
+        help: "Make a new room and join it, you may then set its flags and invites",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [room] => Ok(Command::Create(parse_room_name(room)?)),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/archive",
+        aliases: &[],
+        usage: "/archive [#room]",
+        help: "Close a room you created for good, admins may close any",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Archive(None)),
+            [room] => Ok(Command::Archive(Some(parse_room_name(room)?))),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/rooms",
+        aliases: &[],
+        usage: "/rooms",
+        help: "List the rooms with their members and topics",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::Rooms),
+    },
+    CommandSpec {
+        name: "/me",
+        aliases: &[],
+        usage: "/me <action>",
+        help: "Say what you are doing, /me waves shows * name waves",
+        args: ArgStyle::Text,
+        parse: |args| match args {
+            [text] => Ok(Command::Me(text.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/say",
+        aliases: &[],
+        usage: "/say #room <message>",
+        help: "Post to one of your rooms without switching to it",
+        args: ArgStyle::TargetAndText,
+        parse: |args| match args {
+            [room, content] => Ok(Command::Say {
+                room: parse_room_name(room)?,
+                content: content.clone(),
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/edit",
+        aliases: &[],
+        usage: "/edit <id> <new text>",
+        help: "Change one of your recent messages",
+        args: ArgStyle::TargetAndText,
+        parse: |args| match args {
+            [id, content] => Ok(Command::Edit {
+                id: id.clone(),
+                content: content.clone(),
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/delete",
+        aliases: &[],
+        usage: "/delete <id>",
+        help: "Delete one of your recent messages",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [id] => Ok(Command::Delete(id.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/msg",
+        aliases: &[],
+        usage: "/msg <name> <message>",
+        help: "Send a private message",
+        args: ArgStyle::TargetAndText,
+        parse: |args| match args {
+            [to, content] => Ok(Command::Msg {
+                to: to.clone(),
+                content: content.clone(),
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/history",
+        aliases: &[],
+        usage: "/history [1-100] | /history #room [before-id] [1-100]",
+        help: "Replay the latest messages of a room, or the page before a message id",
+        args: ArgStyle::Words,
+        parse: |args| {
+            let count = |count: Option<&String>| match count.map(|count| typed_arg(count)) {
+                None => Ok(DEFAULT_HISTORY_PAGE),
+                Some(Ok(count)) if (1..=MAX_HISTORY_PAGE).contains(&count) => Ok(count),
+                Some(_) => Err(ArgError::Usage),
+            };
+            match args {
+                [] => Ok(Command::History {
+                    room: None,
+                    before: None,
+                    count: DEFAULT_HISTORY_PAGE,
+                }),
+                [page] if !page.starts_with('#') => Ok(Command::History {
+                    room: None,
+                    before: None,
+                    count: count(Some(page))?,
+                }),
+                [room, rest @ ..] if rest.len() <= 2 => Ok(Command::History {
+                    room: Some(parse_room_name(room)?),
+                    before: rest.first().map(|before| typed_arg(before)).transpose()?,
+                    count: count(rest.get(1))?,
+                }),
+                _ => Err(ArgError::Usage),
+            }
+        },
+    },
+    CommandSpec {
+        name: "/search",
+        aliases: &[],
+        usage: "/search <term>",
+        help: "Find the latest messages of the current room containing the words",
+        args: ArgStyle::Text,
+        parse: |args| match args {
+            [term] => Ok(Command::Search(term.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/who",
+        aliases: &[],
+        usage: "/who",
+        help: "List who is online",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::Who),
+    },
+    CommandSpec {
+        name: "/ignore",
+        aliases: &[],
+        usage: "/ignore [name]",
+        help: "Stop getting someone's messages, or list who you ignore",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Ignore(None)),
+            [name] => Ok(Command::Ignore(Some(name.clone()))),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/unignore",
+        aliases: &[],
+        usage: "/unignore <name>",
+        help: "Get someone's messages again",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::Unignore(name.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/away",
+        aliases: &[],
+        usage: "/away [reason]",
+        help: "Mark yourself as away",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Away(None)),
+            words => Ok(Command::Away(Some(words.join(" ")))),
+        },
+    },
+    CommandSpec {
+        name: "/back",
+        aliases: &[],
+        usage: "/back",
+        help: "Clear your away status",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::Back),
+    },
+    CommandSpec {
+        name: "/nick",
+        aliases: &[],
+        usage: "/nick <name>",
+        help: "Change your name",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::Nick(name.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/topic",
+        aliases: &[],
+        usage: "/topic [text]",
+        help: "Show the topic of the current room, moderators may change it",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Topic(None)),
+            words => Ok(Command::Topic(Some(words.join(" ")))),
+        },
+    },
+    CommandSpec {
+        name: "/flag",
+        aliases: &[],
+        usage: "/flag [read_only|invite_only on|off]",
+        help: "Show the current room's flags, admins may set them, room creators invite_only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Flag(None)),
+            [flag, switch] => {
+                let enabled = match switch.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(ArgError::Usage),
+                };
+                Ok(Command::Flag(Some((typed_arg(flag)?, enabled))))
+            }
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/limit",
+        aliases: &[],
+        usage: "/limit [members|off]",
+        help: "Show the member limit of the current room, admins may change it",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Limit(None)),
+            [off] if off == "off" => Ok(Command::Limit(Some(0))),
+            [limit] => Ok(Command::Limit(Some(typed_arg(limit)?))),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/invite",
+        aliases: &[],
+        usage: "/invite [once] [minutes]",
+        help: "Make a code to join the current room, for its creator and admins",
+        args: ArgStyle::Words,
+        parse: |args| {
+            let (single_use, minutes) = match args {
+                [] => (false, None),
+                [once] if once == "once" => (true, None),
+                [once, minutes] if once == "once" => (true, Some(minutes)),
+                [minutes] => (false, Some(minutes)),
+                _ => return Err(ArgError::Usage),
+            };
+            let minutes = minutes.map(|minutes| typed_arg(minutes)).transpose()?;
+            if minutes.is_some_and(|minutes| !(1..=MAX_INVITE_MINUTES).contains(&minutes)) {
+                return Err(ArgError::Invalid(format!(
+                    "An invite lasts 1 to {} minutes",
+                    MAX_INVITE_MINUTES
+                )));
+            }
+            Ok(Command::Invite {
+                single_use,
+                minutes,
+            })
+        },
+    },
+    CommandSpec {
+        name: "/poll",
+        aliases: &[],
+        usage: "/poll \"question\" option1 option2 ...",
+        help: "Start a poll in the current room, quote arguments that have spaces",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [question, options @ ..] if options.len() >= 2 => Ok(Command::Poll {
+                question: question.clone(),
+                options: options.to_vec(),
+            }),
+            [_, ..] => Err(ArgError::Invalid(
+                "A poll needs at least two options".to_string(),
+            )),
+            [] => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/vote",
+        aliases: &[],
+        usage: "/vote <n>",
+        help: "Vote for an option of the current room's poll",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [choice] => Ok(Command::Vote(typed_arg(choice)?)),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/pollresult",
+        aliases: &[],
+        usage: "/pollresult",
+        help: "Show the votes so far",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::PollResult),
+    },
+    CommandSpec {
+        name: "/pollclose",
+        aliases: &[],
+        usage: "/pollclose",
+        help: "Close your poll and announce the result",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::PollClose),
+    },
+    CommandSpec {
+        name: "/stats",
+        aliases: &[],
+        usage: "/stats [uptime]",
+        help: "Show server statistics",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Stats { uptime_only: false }),
+            [kind] if kind == "uptime" => Ok(Command::Stats { uptime_only: true }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/send",
+        aliases: &[],
+        usage: "/send <name> <file> <size>",
+        help: "Offer a file of the given size in bytes to someone",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [to, file, size] => Ok(Command::SendFile {
+                to: to.clone(),
+                file: parse_file_name(file)?,
+                size: typed_arg(size)?,
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/accept",
+        aliases: &[],
+        usage: "/accept <transfer>",
+        help: "Accept a file offered to you",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [id] => Ok(Command::Accept(typed_arg(id)?)),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/cancel",
+        aliases: &["/reject"],
+        usage: "/cancel <transfer>",
+        help: "Reject a file offer or abort a transfer",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [id] => Ok(Command::Cancel(typed_arg(id)?)),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/chunk",
+        aliases: &[],
+        usage: "/chunk <transfer> <base64>",
+        help: "",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [id, data] => Ok(Command::Chunk {
+                id: typed_arg(id)?,
+                data: data.clone(),
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/kick",
+        aliases: &[],
+        usage: "/kick <name>",
+        help: "Disconnect someone, moderators only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::Kick(name.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/ban",
+        aliases: &[],
+        usage: "/ban <name>",
+        help: "Disconnect someone and refuse their address, admins only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::Ban(name.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/announce",
+        aliases: &[],
+        usage: "/announce <message>",
+        help: "Send a notice to everyone on the server, admins only",
+        args: ArgStyle::Text,
+        parse: |args| match args {
+            [text] => Ok(Command::Announce(text.clone())),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/promote",
+        aliases: &[],
+        usage: "/promote <name> <user|moderator|admin>",
+        help: "Change someone's role until they disconnect, admins only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name, role] => Ok(Command::Promote {
+                name: name.clone(),
+                role: typed_arg(role)?,
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/shadowban",
+        aliases: &[],
+        usage: "/shadowban <name>",
+        help: "Hide someone's messages from everyone but them, admins only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::ShadowBan {
+                name: name.clone(),
+                banned: true,
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/unshadowban",
+        aliases: &[],
+        usage: "/unshadowban <name>",
+        help: "Let everyone see someone's messages again, admins only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [name] => Ok(Command::ShadowBan {
+                name: name.clone(),
+                banned: false,
+            }),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/snapshot",
+        aliases: &[],
+        usage: "/snapshot",
+        help: "Save room settings, bans and roles to be restored at startup, admins only",
+        args: ArgStyle::Words,
+        parse: |args| match args {
+            [] => Ok(Command::Snapshot),
+            _ => Err(ArgError::Usage),
+        },
+    },
+    CommandSpec {
+        name: "/quit",
+        aliases: &[],
+        usage: "/quit",
+        help: "Leave the server",
+        args: ArgStyle::Words,
+        parse: |_| Ok(Command::Quit),
+    },
+]);
+
+#[derive(Debug)]
+enum Reply {
+    Everyone(String),
+    // told to the members of a room only
+    Room { room: String, text: String },
+    Sender(String),
+}
+
+impl ChatRoom {
+    /// Run a slash command, errors are told to the peer and returned for acknowledgements.
+    pub(super) async fn handle_command(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        line: &str,
+        id: Option<&str>,
+    ) -> Result<(), String> {
+        let command = match Command::parse(line) {
+            Ok(command) => command,
+            Err(e) => {
+                self.send_to(addr, Arc::new(Message::system(e.clone())))
+                    .await;
+                return Err(e);
+            }
+        };
+
+        let required = command.required_role();
+        let reply = if self.role(addr) < required {
+            Err(format!(
+                "Permission denied, this command needs the {} role",
+                required
+            ))
+        } else {
+            self.run_command(addr, name, command, id).await
+        };
+
+        match reply {
+            Ok(None) => Ok(()),
+            Ok(Some(Reply::Everyone(text))) => {
+                self.announce(Arc::new(Message::system(text))).await;
+                Ok(())
+            }
+            Ok(Some(Reply::Room { room, text })) => {
+                self.tell_room(&room, Arc::new(Message::system(text))).await;
+                Ok(())
+            }
+            Ok(Some(Reply::Sender(text))) => {
+                self.send_to(addr, Arc::new(Message::system(text))).await;
+                Ok(())
+            }
+            Err(text) => {
+                self.send_to(addr, Arc::new(Message::system(text.clone())))
+                    .await;
+                Err(text)
+            }
+        }
+    }
+
+    /// Execute a parsed command, `None` when it already told the peer everything.
+    async fn run_command(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        command: Command,
+        id: Option<&str>,
+    ) -> Result<Option<Reply>, String> {
+        let reply = match command {
+            Command::Join { room, invite } => {
+                self.join_room(addr, name, &room, invite.as_deref()).await?;
+                return Ok(None);
+            }
+            Command::Leave(room) => {
+                let room = room
+                    .or_else(|| self.current_room(addr))
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                let author = self.author(addr);
+                if !self.leave_room(addr, name, &room).await {
+                    return Err(format!("You are not in {}", room));
+                }
+                if let Some(author) = author {
+                    self.end_poll_of(&author, &room).await;
+                }
+                let message = Message::Left {
+                    room,
+                    name: name.to_string(),
+                };
+                self.send_to(addr, Arc::new(message)).await;
+                return Ok(None);
+            }
+            Command::History {
+                room,
+                before,
+                count,
+            } => {
+                let room = match room {
+                    Some(room) => {
+                        if !self
+                            .rooms
+                            .get(&room)
+                            .is_some_and(|members| members.contains(&addr))
+                        {
+                            return Err(format!("You are not in {}", room));
+                        }
+                        room
+                    }
+                    None => self
+                        .current_room(addr)
+                        .ok_or_else(|| "You are not in any room".to_string())?,
+                };
+                let page = match self.history.page(&room, before, count).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!("Failed to load chat history: {}", e);
+                        return Err("History is unavailable right now".to_string());
+                    }
+                };
+                let oldest = page.first().and_then(Message::history_id);
+                let full = page.len() == count;
+                if page.is_empty() {
+                    return Ok(Some(Reply::Sender(format!("No more history for {}", room))));
+                }
+                for message in page {
+                    self.send_to(addr, Arc::new(message)).await;
+                }
+                match oldest {
+                    Some(oldest) if full => Reply::Sender(format!(
+                        "Older messages: /history {} {} {}",
+                        room, oldest, count
+                    )),
+                    _ => return Ok(None),
+                }
+            }
+            Command::Search(term) => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                let found = match self.history.search(&room, &term, MAX_SEARCH_RESULTS).await {
+                    Ok(found) => found,
+                    Err(e) => {
+                        warn!("Failed to search chat history: {}", e);
+                        return Err("Search is unavailable right now".to_string());
+                    }
+                };
+                let count = found.len();
+                for message in found {
+                    self.send_to(addr, Arc::new(message)).await;
+                }
+                Reply::Sender(match count {
+                    0 => format!("No messages in {} match \"{}\"", room, term),
+                    MAX_SEARCH_RESULTS => {
+                        format!("Showing the latest {} matches in {}", count, room)
+                    }
+                    1 => format!("1 message in {} matches", room),
+                    count => format!("{} messages in {} match", count, room),
+                })
+            }
+            Command::Poll { question, options } => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                let text = self.start_poll(addr, name, &room, question, options)?;
+                Reply::Room { room, text }
+            }
+            Command::Vote(choice) => Reply::Sender(self.vote(addr, choice)?),
+            Command::PollResult => Reply::Sender(self.poll_result(addr)?),
+            Command::PollClose => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                let text = self.close_poll(addr, &room)?;
+                Reply::Room { room, text }
+            }
+            Command::Stats { uptime_only } => Reply::Sender(self.stats(uptime_only)),
+            Command::Who => Reply::Sender(self.who()),
+            Command::Away(reason) => {
+                let text = match &reason {
+                    Some(reason) => format!("You are marked as away: {}", reason),
+                    None => "You are marked as away".to_string(),
+                };
+                self.set_away(addr, Some(reason.unwrap_or_default()));
+                Reply::Sender(text)
+            }
+            Command::Back => match self.set_away(addr, None) {
+                Some(_) => Reply::Sender("Welcome back".to_string()),
+                None => return Err("You are not away".to_string()),
+            },
+            Command::Msg { to, content } => Reply::Sender(
+                self.direct_message(name, &to, content, id.map(str::to_string))
+                    .await?,
+            ),
+            Command::Say { room, content } => {
+                if !self
+                    .rooms
+                    .get(&room)
+                    .is_some_and(|members| members.contains(&addr))
+                {
+                    return Err(format!("You are not in {}", room));
+                }
+                self.post(Some(addr), name, room, content).await?;
+                return Ok(None);
+            }
+            Command::Me(content) => {
+                self.act(addr, name, content).await?;
+                return Ok(None);
+            }
+            Command::Create(room) => {
+                self.create_room(addr, name, room).await?;
+                return Ok(None);
+            }
+            Command::Archive(room) => Reply::Sender(self.archive(addr, name, room).await?),
+            Command::Rooms => Reply::Sender(self.list_rooms()),
+            Command::Edit { id, content } => {
+                self.edit(addr, name, id, Some(content)).await?;
+                return Ok(None);
+            }
+            Command::Delete(id) => {
+                self.edit(addr, name, id, None).await?;
+                return Ok(None);
+            }
+            Command::Quit => {
+                info!("{} quit", name);
+                self.send_to(addr, Arc::new(Message::system("Goodbye")))
+                    .await;
+                self.disconnect(addr).await;
+                return Ok(None);
+            }
+            Command::Topic(None) => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                Reply::Sender(match self.topic(&room) {
+                    Some(topic) => format!("Topic for {}: {}", room, topic),
+                    None => format!("No topic is set for {}", room),
+                })
+            }
+            Command::Topic(Some(topic)) => {
+                self.change_topic(addr, name, topic).await?;
+                return Ok(None);
+            }
+            Command::Flag(None) => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                Reply::Sender(self.describe_flags(&room))
+            }
+            Command::Flag(Some((flag, enabled))) => {
+                self.change_flag(addr, name, flag, enabled).await?;
+                return Ok(None);
+            }
+            Command::Limit(None) => {
+                let room = self
+                    .current_room(addr)
+                    .ok_or_else(|| "You are not in any room".to_string())?;
+                Reply::Sender(self.describe_limit(&room))
+            }
+            Command::Limit(Some(limit)) => Reply::Everyone(self.change_limit(addr, name, limit)?),
+            Command::Invite {
+                single_use,
+                minutes,
+            } => Reply::Sender(self.invite(addr, name, single_use, minutes)?),
+            Command::Nick(new) => Reply::Everyone(self.rename(addr, name, new)?),
+            Command::Kick(target) => Reply::Everyone(self.kick(addr, name, &target, false).await?),
+            Command::Ban(target) => Reply::Everyone(self.kick(addr, name, &target, true).await?),
+            Command::SendFile { to, file, size } => {
+                Reply::Sender(self.offer_file(addr, name, &to, file, size).await?)
+            }
+            Command::Accept(id) => Reply::Sender(self.accept_transfer(addr, name, id).await?),
+            Command::Cancel(id) => Reply::Sender(self.cancel_transfer(addr, name, id).await?),
+            Command::Chunk { id, data } => {
+                self.receive_chunk(addr, id, data).await?;
+                return Ok(None);
+            }
+            Command::Help(topic) => Reply::Sender(COMMANDS.help(topic.as_deref())?),
+            Command::Announce(text) => {
+                info!("{} announced: {}", name, text);
+                Reply::Everyone(announcement_text(&text))
+            }
+            Command::Promote { name: target, role } => {
+                Reply::Everyone(self.promote(addr, name, &target, role)?)
+            }
+            // only the admin is told, the point is that the target doesn't find out
+            Command::ShadowBan {
+                name: target,
+                banned,
+            } => Reply::Sender(self.shadow_ban(addr, name, &target, banned)?),
+            Command::Ignore(None) => Reply::Sender(self.describe_ignores(addr)),
+            Command::Ignore(Some(target)) => Reply::Sender(self.ignore(addr, name, &target, true)?),
+            Command::Unignore(target) => Reply::Sender(self.ignore(addr, name, &target, false)?),
+            Command::Snapshot => Reply::Sender(self.write_snapshot(name).await?),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Tell a peer whether the frame it tagged with `id` was accepted, and the id of the chat
+    /// message it posted.
+    pub(super) async fn acknowledge(
+        &self,
+        addr: SocketAddr,
+        id: Option<String>,
+        result: Result<Option<String>, String>,
+    ) {
+        let Some(id) = id else {
+            return;
+        };
+        let message = match result {
+            Ok(message) => Message::Ack { id, message },
+            Err(reason) => Message::Nack { id, reason },
+        };
+        self.send_to(addr, Arc::new(message)).await;
+    }
+
+    fn stats(&self, uptime_only: bool) -> String {
+        let uptime = format!("Uptime: {}", format_duration(self.started_at.elapsed()));
+        if uptime_only {
+            return uptime;
+        }
+
+        format!(
+            "{}, connections: {}, peers: {} (peak {}), rooms: {}, bytes sent: {}, bytes received: {}",
+            uptime,
+            self.connection_count(),
+            self.peers.len(),
+            self.peak_peers.load(Ordering::Relaxed),
+            self.rooms.len(),
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.bytes_received.load(Ordering::Relaxed),
+        )
+    }
+
+    fn who(&self) -> String {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut status = format!("online {}", format_duration(peer.connected_at.elapsed()));
+                let idle = peer.last_active.elapsed();
+                if idle >= SHOW_IDLE_AFTER {
+                    status.push_str(&format!(", idle {}", format_duration(idle)));
+                }
+                if let Some(latency) = peer.latency {
+                    status.push_str(&format!(", ping {}ms", latency.as_millis()));
+                }
+                match peer.away.as_deref() {
+                    Some("") => status.push_str(", away"),
+                    Some(reason) => status.push_str(&format!(", away: {}", reason)),
+                    None => {}
+                }
+                (peer.name.clone(), status)
+            })
+            .collect();
+        peers.sort();
+
+        let names: Vec<_> = peers
+            .into_iter()
+            .map(|(name, status)| format!("{} ({})", name, status))
+            .collect();
+        format!("Online ({}): {}", names.len(), names.join(", "))
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{}d {}h {}m {}s",
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+pub(super) fn announcement_text(text: &str) -> String {
+    format!("[announcement] {}", text)
+}
+
+impl Command {
+    fn required_role(&self) -> Role {
+        match self {
+            Self::Kick(_) | Self::Topic(Some(_)) => Role::Moderator,
+            Self::Ban(_)
+            | Self::Announce(_)
+            | Self::Promote { .. }
+            | Self::ShadowBan { .. }
+            | Self::Snapshot
+            | Self::Flag(Some((RoomFlag::ReadOnly, _)))
+            | Self::Limit(Some(_)) => Role::Admin,
+            _ => Role::User,
+        }
+    }
+
+    pub(super) fn parse(line: &str) -> Result<Self, String> {
+        COMMANDS.parse(line)
+    }
+}
+
+impl CommandRegistry {
+    fn find(&self, name: &str) -> Option<&CommandSpec> {
+        self.0
+            .iter()
+            .find(|spec| spec.name == name || spec.aliases.contains(&name))
+    }
+
+    pub(super) fn parse(&self, line: &str) -> Result<Command, String> {
+        // the text after the target is taken verbatim, quotes included
+        let (head, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let (spec, args) = match self.find(head).filter(|spec| spec.args != ArgStyle::Words) {
+            Some(spec) => {
+                let rest = rest.trim();
+                let args = match spec.args {
+                    ArgStyle::TargetAndText => rest
+                        .split_once(char::is_whitespace)
+                        .map(|(target, text)| vec![target.to_string(), text.trim().to_string()]),
+                    _ => (!rest.is_empty()).then(|| vec![rest.to_string()]),
+                };
+                (spec, args)
+            }
+            None => {
+                let mut args = split_args(line)?.into_iter();
+                let command = args.next().ok_or_else(|| "Empty command".to_string())?;
+                let spec = self
+                    .find(&command)
+                    .ok_or_else(|| format!("Unknown command: {}, try /help", command))?;
+                (spec, Some(args.collect()))
+            }
+        };
+
+        args.ok_or(ArgError::Usage)
+            .and_then(|args| (spec.parse)(&args))
+            .map_err(|e| match e {
+                ArgError::Usage => format!("Usage: {}", spec.usage),
+                ArgError::Invalid(e) => e,
+            })
+    }
+
+    /// One line listing every command, or the usage and description of one of them.
+    pub(super) fn help(&self, topic: Option<&str>) -> Result<String, String> {
+        match topic {
+            None => {
+                let names: Vec<&str> = self
+                    .0
+                    .iter()
+                    .filter(|spec| !spec.help.is_empty())
+                    .map(|spec| spec.name)
+                    .collect();
+                Ok(format!(
+                    "Commands: {}, try /help <command>",
+                    names.join(", ")
+                ))
+            }
+            Some(topic) => {
+                let name = format!("/{}", topic.trim_start_matches('/'));
+                match self.find(&name) {
+                    Some(spec) if !spec.help.is_empty() => {
+                        Ok(format!("{} - {}", spec.usage, spec.help))
+                    }
+                    _ => Err(format!("Unknown command: {}", name)),
+                }
+            }
+        }
+    }
+}
+
+impl From<String> for ArgError {
+    fn from(e: String) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+/// The argument of a line made of a keyword like PING, in any case, and an optional
+/// argument, `None` for other lines.
+pub(super) fn keyword_arg<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = line.get(..keyword.len())?;
+    let rest = &line[keyword.len()..];
+    (head.eq_ignore_ascii_case(keyword) && (rest.is_empty() || rest.starts_with(' ')))
+        .then(|| rest.trim())
+}
+
+/// Parse one argument, a malformed value is a usage error.
+fn typed_arg<T: FromStr>(arg: &str) -> Result<T, ArgError> {
+    arg.parse().map_err(|_| ArgError::Usage)
+}
+
+/// Keep only the last component of a path, the recipient decides where the file goes.
+fn parse_file_name(path: &str) -> Result<String, String> {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or_default();
+    if name.is_empty() || name == ".." || name.len() > MAX_FILE_NAME_LEN {
+        return Err(format!(
+            "File name must be between 1 and {} characters",
+            MAX_FILE_NAME_LEN
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Split a command line by whitespace, double quotes group words into a single argument.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+
+    if in_quotes {
+        return Err("Unterminated quote in command".to_string());
+    }
+    if has_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn every_command_is_documented_once() {
+        let mut names = HashSet::new();
+        for spec in COMMANDS.0 {
+            for name in std::iter::once(&spec.name).chain(spec.aliases) {
+                assert!(names.insert(*name), "{} registered twice", name);
+            }
+            assert!(spec.usage.starts_with(spec.name), "{}", spec.usage);
+            if !spec.help.is_empty() {
+                assert!(COMMANDS.help(Some(spec.name)).is_ok());
+                assert!(COMMANDS.help(None).unwrap().contains(spec.name));
+            }
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Sharing rooms beyond one server: links to federated servers and the backend that
+//! instances behind a load balancer publish to.
+
+use super::{protocol::Message, ChatRoom, SEND_TIMEOUT};
+use anyhow::Result;
+use core::fmt;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{
+    future::{self, BoxFuture},
+    stream::BoxStream,
+    SinkExt, StreamExt,
+};
+use lru::LruCache;
+use metrics::counter;
+use nanoid::nanoid;
+use redis::{aio::ConnectionManager, AsyncCommands as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{self, Sender},
+    time::timeout,
+};
+use tokio_util::{
+    codec::{Framed, LinesCodec},
+    sync::CancellationToken,
+};
+use tracing::{debug, field, info, instrument, warn, Span};
+
+const FEDERATION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// ids of relayed messages remembered to drop copies arriving over another link
+const FEDERATION_SEEN_IDS: usize = 4096;
+
+/// Links to other chat servers, room messages are relayed across all of them.
+#[derive(Debug)]
+pub struct Federation {
+    server_id: String,
+    // both sides of a link present it in their hello when set
+    token: Option<String>,
+    // remote server id -> lines queued for that link
+    links: DashMap<String, Sender<String>>,
+    seen: Mutex<LruCache<String, ()>>,
+}
+
+/// Shares room messages between instances of the same server, so peers connected to any
+/// of them see the same rooms.
+///
+/// Unlike federation, the instances are one server behind a load balancer: they share a
+/// database if they have one, and only messages reach the other instances, not names.
+pub trait RoomBackend: Debug + Send + Sync {
+    fn publish<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<()>>;
+
+    /// Messages published by the other instances, never the ones published by this one.
+    fn subscribe(&self) -> BoxFuture<'_, Result<BoxStream<'static, Message>>>;
+}
+
+/// A [`RoomBackend`] over Redis pub/sub, every room is a channel under a common prefix.
+pub struct RedisBackend {
+    client: redis::Client,
+    connection: ConnectionManager,
+    prefix: String,
+    // tags what this instance publishes, so it can skip its own messages
+    instance: String,
+}
+
+/// What goes through a backend channel.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackendFrame {
+    instance: String,
+    message: Message,
+}
+
+/// Frames exchanged over a federation link, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LinkFrame {
+    Hello {
+        server: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    // `origin` is the server the message was first broadcast on
+    Relay {
+        id: String,
+        origin: String,
+        message: Message,
+    },
+}
+
+/// Run a federation link: exchange hellos, then relay room messages both ways.
+///
+/// The dialing side says hello first, so a listener never reveals its token to an
+/// unauthenticated server.
+#[instrument(name = "link", skip_all, fields(%addr, server = field::Empty))]
+pub async fn handle_link<S>(
+    stream: S,
+    addr: SocketAddr,
+    dialed: bool,
+    chat_room: Arc<ChatRoom>,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let Some(federation) = &chat_room.federation else {
+        return Ok(());
+    };
+    let codec = LinesCodec::new_with_max_length(chat_room.max_frame_len() * 2);
+    let (mut sink, mut stream) = Framed::new(stream, codec).split();
+    let hello = serde_json::to_string(&LinkFrame::Hello {
+        server: federation.server_id.clone(),
+        token: federation.token.clone(),
+    })?;
+
+    if dialed {
+        sink.send(hello.clone()).await?;
+    }
+    let remote = match timeout(FEDERATION_HANDSHAKE_TIMEOUT, stream.next()).await {
+        Ok(Some(line)) => match serde_json::from_str(&line?)? {
+            LinkFrame::Hello { server, token } => {
+                federation.check_hello(&server, token.as_deref())?;
+                server
+            }
+            LinkFrame::Relay { .. } => return Err(anyhow::anyhow!("expected a hello")),
+        },
+        Ok(None) => return Ok(()),
+        Err(_) => return Err(anyhow::anyhow!("federation handshake timed out")),
+    };
+    if !dialed {
+        sink.send(hello).await?;
+    }
+    Span::current().record("server", remote.as_str());
+
+    let (sender, mut receiver) = mpsc::channel(chat_room.channel_capacity);
+    federation.link(&remote, sender.clone())?;
+    info!("Federated with {}", remote);
+
+    let write = async {
+        while let Some(line) = receiver.recv().await {
+            timeout(SEND_TIMEOUT, sink.send(line))
+                .await
+                .map_err(|_| anyhow::anyhow!("write timed out"))??;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let read = async {
+        while let Some(line) = stream.next().await {
+            match serde_json::from_str(&line?)? {
+                LinkFrame::Relay {
+                    id,
+                    origin,
+                    message,
+                } => {
+                    chat_room
+                        .receive_relayed(&remote, id, origin, message)
+                        .await
+                }
+                LinkFrame::Hello { .. } => warn!("Unexpected hello from {}", remote),
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let ret = tokio::select! {
+        _ = shutdown.cancelled() => Ok(()),
+        ret = write => ret,
+        ret = read => ret,
+    };
+    federation.unlink(&remote, &sender);
+    ret
+}
+
+impl ChatRoom {
+    /// Deliver the messages other instances publish until the subscription drops or the
+    /// server shuts down, the caller decides whether to subscribe again.
+    pub async fn follow_backend(&self, shutdown: CancellationToken) -> Result<()> {
+        let Some(backend) = &self.backend else {
+            return Ok(());
+        };
+        let mut messages = backend.subscribe().await?;
+        loop {
+            let message = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                message = messages.next() => message,
+            };
+            let Some(message) = message else {
+                return Err(anyhow::anyhow!("backend subscription closed"));
+            };
+            if !message.is_federated() {
+                continue;
+            }
+            counter!("chat_messages_shared_total").increment(1);
+            self.apply_room_state(&message);
+            let message = Arc::new(message);
+            // the publishing instance already stored it when the database is shared
+            self.record(&message, !self.history.is_shared()).await;
+            self.deliver_local(None, message).await;
+        }
+    }
+
+    /// Deliver a message relayed by another server, then pass it on to the other links.
+    async fn receive_relayed(&self, link: &str, id: String, origin: String, message: Message) {
+        let Some(federation) = &self.federation else {
+            return;
+        };
+        // a link is trusted with room messages only
+        if !message.is_federated() || !federation.first_sighting(&id, &origin) {
+            debug!(%origin, %id, "relayed message dropped");
+            return;
+        }
+        counter!("chat_messages_relayed_total").increment(1);
+
+        let frame = LinkFrame::Relay {
+            id,
+            origin,
+            message: message.clone(),
+        };
+        federation.forward(Some(link), &frame);
+        self.apply_room_state(&message);
+        self.fan_out_local(None, Arc::new(message)).await;
+    }
+}
+
+impl Federation {
+    pub fn new(server_id: String, token: Option<String>) -> Self {
+        Self {
+            server_id,
+            token,
+            links: DashMap::new(),
+            seen: Mutex::new(LruCache::new(
+                NonZeroUsize::new(FEDERATION_SEEN_IDS).unwrap(),
+            )),
+        }
+    }
+
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    fn check_hello(&self, server: &str, token: Option<&str>) -> Result<()> {
+        if self.token.is_some() && token != self.token.as_deref() {
+            return Err(anyhow::anyhow!("invalid federation token from {}", server));
+        }
+        if server == self.server_id {
+            return Err(anyhow::anyhow!("refusing to link with itself"));
+        }
+        Ok(())
+    }
+
+    fn link(&self, server: &str, sender: Sender<String>) -> Result<()> {
+        match self.links.entry(server.to_string()) {
+            Entry::Occupied(_) => Err(anyhow::anyhow!("already linked with {}", server)),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+                Ok(())
+            }
+        }
+    }
+
+    fn unlink(&self, server: &str, sender: &Sender<String>) {
+        self.links
+            .remove_if(server, |_, link| link.same_channel(sender));
+    }
+
+    /// True the first time a message is seen, messages that went around back home never are.
+    fn first_sighting(&self, id: &str, origin: &str) -> bool {
+        origin != self.server_id && self.seen.lock().unwrap().put(id.to_string(), ()).is_none()
+    }
+
+    /// Relay a message broadcast on this server to every link.
+    pub(super) fn publish(&self, message: &Message) {
+        if !message.is_federated() || self.links.is_empty() {
+            return;
+        }
+        let id = nanoid!();
+        self.seen.lock().unwrap().put(id.clone(), ());
+        let frame = LinkFrame::Relay {
+            id,
+            origin: self.server_id.clone(),
+            message: message.clone(),
+        };
+        self.forward(None, &frame);
+    }
+
+    fn forward(&self, skip: Option<&str>, frame: &LinkFrame) {
+        let line = match serde_json::to_string(frame) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode relayed message: {}", e);
+                return;
+            }
+        };
+        // like broadcasts to peers, a link that can't keep up loses messages instead of blocking
+        for link in self.links.iter() {
+            if Some(link.key().as_str()) == skip {
+                continue;
+            }
+            if let Err(e) = link.value().try_send(line.clone()) {
+                warn!("Failed to relay message to {}: {}", link.key(), e);
+                counter!("chat_messages_dropped_total").increment(1);
+            }
+        }
+    }
+}
+
+impl RedisBackend {
+    /// Connect for publishing, subscriptions open their own connection.
+    pub async fn connect(url: &str, prefix: impl Into<String>) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self {
+            client,
+            connection,
+            prefix: prefix.into(),
+            instance: nanoid!(),
+        })
+    }
+}
+
+impl fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisBackend")
+            .field("client", &self.client)
+            .field("prefix", &self.prefix)
+            .field("instance", &self.instance)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RoomBackend for RedisBackend {
+    fn publish<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let Some(room) = message.room() else {
+                return Ok(());
+            };
+            let frame = BackendFrame {
+                instance: self.instance.clone(),
+                message: message.clone(),
+            };
+            // the manager reconnects by itself, a clone shares its connection
+            let mut connection = self.connection.clone();
+            connection
+                .publish::<_, _, ()>(
+                    format!("{}{}", self.prefix, room),
+                    serde_json::to_string(&frame)?,
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<BoxStream<'static, Message>>> {
+        Box::pin(async move {
+            let mut pubsub = self.client.get_async_pubsub().await?;
+            pubsub.psubscribe(format!("{}*", self.prefix)).await?;
+            let instance = self.instance.clone();
+            let messages = pubsub.into_on_message().filter_map(move |message| {
+                let frame = message
+                    .get_payload::<String>()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|payload| Ok(serde_json::from_str::<BackendFrame>(&payload)?));
+                future::ready(match frame {
+                    Ok(frame) if frame.instance != instance => Some(frame.message),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("Dropped an invalid backend message: {}", e);
+                        None
+                    }
+                })
+            });
+            Ok(messages.boxed())
+        })
+    }
+}
@@ -0,0 +1,714 @@
+//! What the server remembers of the rooms: the history kept in memory or Postgres and the
+//! event log replayed on start.
+
+use super::{
+    parse_mentions,
+    protocol::{ChatMessage, Message},
+    ChatRoom, DEFAULT_ROOM,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, UnboundedSender},
+};
+use tracing::warn;
+
+pub(super) const DEFAULT_HISTORY_PAGE: usize = 20;
+pub(super) const MAX_HISTORY_PAGE: usize = 100;
+// /search returns the latest matches only
+pub(super) const MAX_SEARCH_RESULTS: usize = 20;
+
+/// Append-only JSON lines file of room events, replayed into the history on startup.
+#[derive(Debug)]
+pub struct EventLog {
+    seq: AtomicU64,
+    sender: UnboundedSender<LoggedEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct LoggedEvent {
+    seq: u64,
+    message: Message,
+}
+
+/// An event as the log wrote it before messages were tagged inline, still replayed.
+#[derive(Debug, Deserialize)]
+struct LegacyLoggedEvent {
+    seq: u64,
+    message: LegacyMessage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+enum LegacyMessage {
+    Join(LegacyPresence),
+    Leave(LegacyPresence),
+    Chat {
+        // none before there were rooms
+        room: Option<String>,
+        from: String,
+        content: String,
+    },
+    System(serde::de::IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LegacyPresence {
+    Name(String),
+    InRoom { room: String, name: String },
+}
+
+/// A remembered message with the id `/history` pages by.
+type Numbered = (u64, Arc<Message>);
+
+#[derive(Debug)]
+pub(super) enum History {
+    Memory {
+        size: usize,
+        // numbered like the rows of the database, across every room
+        rooms: Mutex<HashMap<String, VecDeque<Numbered>>>,
+        next_id: AtomicU64,
+    },
+    Postgres {
+        size: usize,
+        db: PgPool,
+    },
+}
+
+#[derive(Debug, FromRow)]
+struct StoredMessage {
+    #[sqlx(default)]
+    id: Option<i64>,
+    room: String,
+    kind: String,
+    sender: String,
+    content: String,
+    #[sqlx(default)]
+    message_id: Option<String>,
+    #[sqlx(default)]
+    created_at: Option<String>,
+}
+
+impl ChatRoom {
+    /// Rebuild the in-memory history from an event log, returns the next sequence number.
+    ///
+    /// Lines that can't be read, like the last one of a log cut short by a crash, are
+    /// skipped with a warning rather than keeping the server from starting.
+    pub async fn replay(&self, reader: impl AsyncBufRead + Unpin) -> Result<u64> {
+        let mut lines = reader.split(b'\n');
+        let mut events = Vec::new();
+        let mut line_number = 0;
+        let mut skipped = 0;
+        while let Some(line) = lines.next_segment().await? {
+            line_number += 1;
+            let line = match String::from_utf8(line) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Skipping line {} of the event log: {}", line_number, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match LoggedEvent::parse(&line) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Skipping line {} of the event log: {}", line_number, e);
+                    skipped += 1;
+                }
+            }
+        }
+        if skipped > 0 {
+            warn!("Skipped {} unreadable lines of the event log", skipped);
+        }
+
+        // events are appended by a writer task, so concurrent broadcasts may land out of order
+        events.sort_by_key(|event| event.seq);
+        let next_seq = events.last().map_or(0, |event| event.seq + 1);
+
+        for event in &events {
+            self.apply_room_state(&event.message);
+        }
+
+        // a database backed history is already durable
+        if let History::Memory { .. } = self.history {
+            for event in events {
+                self.history.record(&Arc::new(event.message)).await?;
+            }
+        }
+
+        Ok(next_seq)
+    }
+
+    pub(super) async fn recent_history(&self, room: &str, limit: usize) -> Vec<Message> {
+        match self.history.page(room, None, limit).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                warn!("Failed to load chat history: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl LoggedEvent {
+    /// A line of the log, in the current shape or the legacy one. `None` for the legacy
+    /// events replay has no use for.
+    pub(super) fn parse(line: &str) -> serde_json::Result<Option<Self>> {
+        let e = match serde_json::from_str::<Self>(line) {
+            Ok(event) => return Ok(Some(event)),
+            Err(e) => e,
+        };
+        // the error of the current shape is the one worth reporting
+        let Ok(legacy) = serde_json::from_str::<LegacyLoggedEvent>(line) else {
+            return Err(e);
+        };
+        Ok(legacy.message.upgrade().map(|message| Self {
+            seq: legacy.seq,
+            message,
+        }))
+    }
+}
+
+impl LegacyMessage {
+    fn upgrade(self) -> Option<Message> {
+        let message = match self {
+            Self::Join(presence) => {
+                let (room, name) = presence.in_room();
+                Message::join(room, name)
+            }
+            Self::Leave(presence) => {
+                let (room, name) = presence.in_room();
+                Message::leave(room, name)
+            }
+            Self::Chat {
+                room,
+                from,
+                content,
+            } => Message::Chat(ChatMessage {
+                room: room.unwrap_or_else(|| DEFAULT_ROOM.to_string()),
+                from,
+                content,
+                at: String::new(),
+                mentions: Vec::new(),
+                id: String::new(),
+            }),
+            // never part of the history
+            Self::System(_) => return None,
+        };
+        Some(message)
+    }
+}
+
+impl LegacyPresence {
+    fn in_room(self) -> (String, String) {
+        match self {
+            Self::Name(name) => (DEFAULT_ROOM.to_string(), name),
+            Self::InRoom { room, name } => (room, name),
+        }
+    }
+}
+
+impl EventLog {
+    pub async fn open(path: &str, next_seq: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (sender, mut receiver) = mpsc::unbounded_channel::<LoggedEvent>();
+
+        tokio::spawn(async move {
+            let mut writer = BufWriter::new(file);
+            while let Some(event) = receiver.recv().await {
+                if let Err(e) = write_event(&mut writer, &event).await {
+                    warn!("Failed to write event {} to log: {}", event.seq, e);
+                }
+            }
+        });
+
+        Ok(Self {
+            seq: AtomicU64::new(next_seq),
+            sender,
+        })
+    }
+
+    pub(super) fn append(&self, message: &Message) {
+        if let Message::Direct { .. }
+        | Message::System { .. }
+        | Message::History { .. }
+        | Message::Ping { .. }
+        | Message::FileOffer { .. }
+        | Message::FileChunk { .. }
+        | Message::FileProgress { .. }
+        | Message::Session { .. }
+        | Message::Ack { .. }
+        | Message::Nack { .. }
+        | Message::Delivered { .. }
+        | Message::Mention { .. } = message
+        {
+            return;
+        }
+
+        let event = LoggedEvent {
+            seq: self.seq.fetch_add(1, Ordering::SeqCst),
+            message: message.clone(),
+        };
+        if let Err(e) = self.sender.send(event) {
+            warn!("Failed to append event to log: {}", e);
+        }
+    }
+}
+
+async fn write_event(writer: &mut BufWriter<File>, event: &LoggedEvent) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+impl History {
+    pub(super) fn memory(size: usize) -> Self {
+        Self::Memory {
+            size,
+            rooms: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub(super) async fn try_new_postgres(url: &str, size: usize) -> Result<Self> {
+        let db = PgPool::connect(url).await?;
+        // create table if not exists
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                id BIGSERIAL PRIMARY KEY,
+                room TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(&db)
+        .await?;
+        // messages stored before rooms existed belong to the default room
+        sqlx::query(&format!(
+            "ALTER TABLE messages ADD COLUMN IF NOT EXISTS room TEXT NOT NULL DEFAULT '{}'",
+            DEFAULT_ROOM
+        ))
+        .execute(&db)
+        .await?;
+        // pages are read by id within a room, from the newest down
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id)")
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS messages_content_search ON messages \
+                USING GIN (to_tsvector('simple', content))",
+        )
+        .execute(&db)
+        .await?;
+        // chat messages stored before they had ids can't be edited
+        sqlx::query("ALTER TABLE messages ADD COLUMN IF NOT EXISTS message_id TEXT")
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS messages_room_message_id ON messages (room, message_id)",
+        )
+        .execute(&db)
+        .await?;
+
+        Ok(Self::Postgres { size, db })
+    }
+
+    /// Whether other instances write to the same store.
+    pub(super) fn is_shared(&self) -> bool {
+        matches!(self, Self::Postgres { .. })
+    }
+
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Self::Memory { size, .. } | Self::Postgres { size, .. } => *size,
+        }
+    }
+
+    /// Drop the messages kept of a room, a database keeps them.
+    pub(super) fn forget(&self, room: &str) {
+        if let Self::Memory { rooms, .. } = self {
+            rooms.lock().unwrap().remove(room);
+        }
+    }
+
+    pub(super) async fn record(&self, message: &Arc<Message>) -> Result<()> {
+        match self {
+            Self::Memory {
+                size,
+                rooms,
+                next_id,
+            } => {
+                let Some(room) = message.room() else {
+                    return Ok(());
+                };
+                if let Message::Edited { .. } | Message::Deleted { .. } = message.as_ref() {
+                    if let Some(buffer) = rooms.lock().unwrap().get_mut(room) {
+                        amend(buffer, message);
+                    }
+                    return Ok(());
+                }
+                if *size == 0 {
+                    return Ok(());
+                }
+                let mut rooms = rooms.lock().unwrap();
+                let buffer = rooms.entry(room.to_string()).or_default();
+                if buffer.len() == *size {
+                    buffer.pop_front();
+                }
+                let id = next_id.fetch_add(1, Ordering::Relaxed);
+                buffer.push_back((id, message.clone()));
+            }
+            Self::Postgres { db, .. } => match message.as_ref() {
+                Message::Edited {
+                    room, id, content, ..
+                } => {
+                    sqlx::query(
+                        "UPDATE messages SET content = $1 WHERE room = $2 AND message_id = $3",
+                    )
+                    .bind(content)
+                    .bind(room)
+                    .bind(id)
+                    .execute(db)
+                    .await?;
+                }
+                Message::Deleted { room, id, .. } => {
+                    sqlx::query("DELETE FROM messages WHERE room = $1 AND message_id = $2")
+                        .bind(room)
+                        .bind(id)
+                        .execute(db)
+                        .await?;
+                }
+                _ => {
+                    let Some(stored) = StoredMessage::from_message(message) else {
+                        return Ok(());
+                    };
+                    sqlx::query(
+                        "INSERT INTO messages (room, kind, sender, content, message_id) \
+                            VALUES ($1, $2, $3, $4, $5)",
+                    )
+                    .bind(stored.room)
+                    .bind(stored.kind)
+                    .bind(stored.sender)
+                    .bind(stored.content)
+                    .bind(stored.message_id)
+                    .execute(db)
+                    .await?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Load up to `limit` messages of a room older than `before`, or the latest ones,
+    /// oldest first and wrapped to be replayed to a peer.
+    ///
+    /// Pages are keyed by message id rather than offset, so going back stays cheap however
+    /// long the room's history is.
+    pub(super) async fn page(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        match self {
+            Self::Memory { rooms, .. } => Ok(rooms
+                .lock()
+                .unwrap()
+                .get(room)
+                .map(|buffer| {
+                    // ids only grow, so everything before the cursor is a prefix
+                    let end = match before {
+                        Some(before) => buffer.partition_point(|(id, _)| *id < before),
+                        None => buffer.len(),
+                    };
+                    buffer
+                        .range(end.saturating_sub(limit)..end)
+                        .map(|(id, message)| Message::history(message.as_ref().clone(), None, *id))
+                        .collect()
+                })
+                .unwrap_or_default()),
+            Self::Postgres { db, .. } => {
+                let rows: Vec<StoredMessage> = sqlx::query_as(
+                    r#"
+                    SELECT id, room, kind, sender, content, message_id,
+                        to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at
+                    FROM messages WHERE room = $1 AND ($2::BIGINT IS NULL OR id < $2)
+                    ORDER BY id DESC LIMIT $3
+                    "#,
+                )
+                .bind(room)
+                .bind(before.map(|before| before as i64))
+                .bind(limit as i64)
+                .fetch_all(db)
+                .await?;
+
+                Ok(rows
+                    .into_iter()
+                    .rev()
+                    .filter_map(|row| {
+                        let (at, id) = (row.created_at.clone(), row.id.unwrap_or_default());
+                        row.into_message()
+                            .map(|message| Message::history(message, at, id as u64))
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Find the latest chat messages of a room containing every word of `term`, oldest
+    /// first and wrapped like history.
+    ///
+    /// The database matches words with full-text search, the memory buffer by
+    /// case-insensitive substrings.
+    pub(super) async fn search(
+        &self,
+        room: &str,
+        term: &str,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        match self {
+            Self::Memory { rooms, .. } => {
+                let words: Vec<String> = term.split_whitespace().map(str::to_lowercase).collect();
+                let rooms = rooms.lock().unwrap();
+                let Some(buffer) = rooms.get(room) else {
+                    return Ok(Vec::new());
+                };
+                let mut found: Vec<Message> = buffer
+                    .iter()
+                    .rev()
+                    .filter(|(_, message)| match message.as_ref() {
+                        Message::Chat(chat) => {
+                            let content = chat.content.to_lowercase();
+                            words.iter().all(|word| content.contains(word.as_str()))
+                        }
+                        _ => false,
+                    })
+                    .take(limit)
+                    .map(|(id, message)| Message::history(message.as_ref().clone(), None, *id))
+                    .collect();
+                found.reverse();
+                Ok(found)
+            }
+            Self::Postgres { db, .. } => {
+                let rows: Vec<StoredMessage> = sqlx::query_as(
+                    r#"
+                    SELECT id, room, kind, sender, content, message_id,
+                        to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at
+                    FROM messages WHERE room = $1 AND kind = 'chat'
+                        AND to_tsvector('simple', content) @@ plainto_tsquery('simple', $2)
+                    ORDER BY id DESC LIMIT $3
+                    "#,
+                )
+                .bind(room)
+                .bind(term)
+                .bind(limit as i64)
+                .fetch_all(db)
+                .await?;
+
+                Ok(rows
+                    .into_iter()
+                    .rev()
+                    .filter_map(|row| {
+                        let (at, id) = (row.created_at.clone(), row.id.unwrap_or_default());
+                        row.into_message()
+                            .map(|message| Message::history(message, at, id as u64))
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Apply an edit or deletion to the remembered copy of the message it refers to.
+fn amend(buffer: &mut VecDeque<Numbered>, change: &Message) {
+    let (Message::Edited { id, .. } | Message::Deleted { id, .. }) = change else {
+        return;
+    };
+    let Some(index) = buffer
+        .iter()
+        .position(|(_, message)| matches!(message.as_ref(), Message::Chat(chat) if chat.id == *id))
+    else {
+        return;
+    };
+    match change {
+        Message::Edited { content, .. } => {
+            let (_, message) = &mut buffer[index];
+            if let Message::Chat(chat) = message.as_ref() {
+                let mut chat = chat.clone();
+                chat.mentions = parse_mentions(content);
+                chat.content = content.clone();
+                *message = Arc::new(Message::Chat(chat));
+            }
+        }
+        _ => {
+            buffer.remove(index);
+        }
+    }
+}
+
+impl StoredMessage {
+    fn from_message(message: &Message) -> Option<Self> {
+        let message_id = match message {
+            Message::Chat(chat) if !chat.id.is_empty() => Some(chat.id.clone()),
+            _ => None,
+        };
+        let (room, kind, sender, content) = match message {
+            Message::Join { room, name } => (room, "join", name.as_str(), ""),
+            Message::Leave { room, name } => (room, "leave", name.as_str(), ""),
+            Message::Chat(chat) => (
+                &chat.room,
+                "chat",
+                chat.from.as_str(),
+                chat.content.as_str(),
+            ),
+            Message::Action {
+                room,
+                from,
+                content,
+            } => (room, "action", from.as_str(), content.as_str()),
+            Message::TopicChanged { room, by, topic } => {
+                (room, "topic", by.as_str(), topic.as_str())
+            }
+            Message::RoomFlagChanged { .. }
+            | Message::Edited { .. }
+            | Message::Deleted { .. }
+            | Message::Typing { .. }
+            | Message::Welcome { .. }
+            | Message::Joined { .. }
+            | Message::Left { .. }
+            | Message::Direct { .. }
+            | Message::System { .. }
+            | Message::History { .. }
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::FileOffer { .. }
+            | Message::FileChunk { .. }
+            | Message::FileProgress { .. }
+            | Message::Session { .. }
+            | Message::Ack { .. }
+            | Message::Nack { .. }
+            | Message::Delivered { .. }
+            | Message::Mention { .. } => return None,
+        };
+        Some(Self {
+            id: None,
+            created_at: None,
+            room: room.clone(),
+            kind: kind.to_string(),
+            sender: sender.to_string(),
+            content: content.to_string(),
+            message_id,
+        })
+    }
+
+    fn into_message(self) -> Option<Message> {
+        match self.kind.as_str() {
+            "join" => Some(Message::join(self.room, self.sender)),
+            "leave" => Some(Message::leave(self.room, self.sender)),
+            "chat" => {
+                let mut message = Message::chat_message(self.room, self.sender, self.content);
+                if let (Message::Chat(chat), Some(id)) = (&mut message, self.message_id) {
+                    chat.id = id;
+                }
+                Some(message)
+            }
+            "action" => Some(Message::Action {
+                room: self.room,
+                from: self.sender,
+                content: self.content,
+            }),
+            "topic" => Some(Message::TopicChanged {
+                room: self.room,
+                by: self.sender,
+                topic: self.content,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_skips_unreadable_lines_and_reads_legacy_events() {
+        let mut log = String::new();
+        // as the log was first written, before rooms
+        log.push_str(r#"{"seq":0,"message":{"type":"join","data":"alice"}}"#);
+        log.push('\n');
+        log.push_str(
+            r#"{"seq":1,"message":{"type":"chat","data":{"from":"alice","content":"hi"}}}"#,
+        );
+        log.push('\n');
+        log.push_str(r#"{"seq":2,"message":{"type":"system","data":"restarting"}}"#);
+        log.push('\n');
+        // then with rooms, still adjacently tagged
+        log.push_str(
+            r##"{"seq":3,"message":{"type":"join","data":{"room":"#rust","name":"bob"}}}"##,
+        );
+        log.push('\n');
+        log.push_str("not json\n");
+        let current = LoggedEvent {
+            seq: 4,
+            message: Message::chat_message("#rust", "bob", "hello"),
+        };
+        log.push_str(&serde_json::to_string(&current).unwrap());
+        log.push('\n');
+        // cut short by a crash
+        log.push_str(r##"{"seq":5,"message":{"type":"chat","room":"#ru"##);
+        let mut log = log.into_bytes();
+        log.extend(b"\n\xff\xfe\n");
+
+        let chat_room = ChatRoom::new(10);
+        assert_eq!(chat_room.replay(&log[..]).await.unwrap(), 5);
+
+        let lines = |history: Vec<Message>| -> Vec<String> {
+            history
+                .into_iter()
+                .map(|message| match message {
+                    Message::History { message, .. } => match *message {
+                        Message::Chat(chat) => format!("<{}> {}", chat.from, chat.content),
+                        Message::Join { name, .. } => format!("{} joined", name),
+                        message => panic!("unexpected message in history: {:?}", message),
+                    },
+                    message => panic!("history not wrapped: {:?}", message),
+                })
+                .collect()
+        };
+        assert_eq!(
+            lines(chat_room.recent_history(DEFAULT_ROOM, 10).await),
+            ["alice joined", "<alice> hi"]
+        );
+        assert_eq!(
+            lines(chat_room.recent_history("#rust", 10).await),
+            ["bob joined", "<bob> hello"]
+        );
+    }
+}
@@ -0,0 +1,951 @@
+//! What goes over the wire: the messages, the client frames of each protocol and the codecs
+//! that cut a byte stream into lines.
+
+use super::{parse_mentions, rooms::RoomFlag, truncate_at_char_boundary};
+use anyhow::Result;
+use core::fmt;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, UnboundedSender},
+    },
+};
+use tokio_util::{
+    bytes::{Buf as _, Bytes, BytesMut},
+    codec::{Decoder, Encoder, Framed, LinesCodec},
+    sync::{CancellationToken, DropGuard},
+};
+use tracing::warn;
+
+pub(super) const PING: &str = "PING";
+pub(super) const PONG: &str = "PONG";
+pub(super) const TYPING: &str = "/typing";
+pub(super) const CHUNK: &str = "/chunk ";
+pub(super) const RESUME: &str = "/resume ";
+// server name used as the prefix of IRC replies
+const IRC_SERVER: &str = "chat";
+// every PROXY protocol v2 header starts with these
+pub(super) const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// telnet's "interpret as command" byte and the commands that matter to the codec
+const TELNET_IAC: u8 = 255;
+const TELNET_SB: u8 = 250;
+const TELNET_SE: u8 = 240;
+const TELNET_WILL: u8 = 251;
+const TELNET_DONT: u8 = 254;
+// events a ChatClient subscriber may fall behind by before losing the oldest
+const CLIENT_EVENT_CAPACITY: usize = 256;
+
+/// Outgoing half of a client transport, one encoded message per item.
+pub trait LineSink: Sink<Bytes, Error = anyhow::Error> + Send + Unpin + 'static {}
+
+impl<T> LineSink for T where T: Sink<Bytes, Error = anyhow::Error> + Send + Unpin + 'static {}
+
+/// Incoming half of a client transport, one line per item.
+pub trait LineStream: Stream<Item = Result<String>> + Send + Unpin + 'static {}
+
+impl<T> LineStream for T where T: Stream<Item = Result<String>> + Send + Unpin + 'static {}
+
+/// How lines from and messages to a client are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Text,
+    Json,
+    // a minimal subset of IRC: PASS, NICK, JOIN, PART, PRIVMSG, PING and QUIT
+    Irc,
+    // length-delimited frames, bincode strings from clients and bincode messages from the server
+    Binary,
+}
+
+/// Frames sent by clients speaking the JSON protocol.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Auth {
+        token: String,
+    },
+    Login {
+        name: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    // an id asks for an ack or nack, and a delivery receipt for direct messages
+    Chat {
+        content: String,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    Command {
+        command: String,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    Pong {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Typing,
+    Resume {
+        token: String,
+    },
+}
+
+/// Lines codec that reports an oversized line as an item instead of ending the stream.
+///
+/// It takes what telnet and putty send as well: CRLF line endings, the NUL telnet sends
+/// after a bare CR and the IAC sequences of option negotiation are dropped, invalid UTF-8
+/// is replaced rather than closing the connection.
+#[derive(Debug)]
+pub(super) struct LineCodec {
+    max_length: usize,
+    // the line read so far, without the bytes dropped
+    line: Vec<u8>,
+    telnet: TelnetState,
+    // the rest of an oversized line is skipped up to its newline
+    discarding: bool,
+}
+
+/// Where the codec is in a telnet command, which can span reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TelnetState {
+    Data,
+    // after IAC
+    Command,
+    // after WILL, WONT, DO or DONT, the option byte is next
+    Option,
+    // inside SB ... IAC SE, which may carry any byte, newlines included
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("line longer than {0} bytes")]
+pub(super) struct LineTooLong(usize);
+
+/// A connection to a chat server speaking the JSON protocol, for bots and tests that want
+/// typed events instead of lines.
+///
+/// Events are only kept for subscribers, so subscribe before logging in to see everything
+/// from the welcome on. Pings from the server are answered without being passed on.
+#[derive(Debug)]
+pub struct ChatClient {
+    frames: UnboundedSender<String>,
+    // only resubscribed, never read, so subscribers see the end of the connection
+    events: broadcast::Receiver<Arc<Message>>,
+    closed: CancellationToken,
+    // stops reading when the client is dropped
+    _guard: DropGuard,
+}
+
+/// A line posted to a room, as seen by bots and filters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub room: String,
+    pub from: String,
+    pub content: String,
+    // UTC receipt time, empty for messages restored without one
+    #[serde(default)]
+    pub at: String,
+    // names written as @name in the content, each once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    // what /edit and /delete refer to, empty for messages restored without one
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+}
+
+/// Everything the server sends to clients, encoded per connection by its protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Join {
+        room: String,
+        name: String,
+    },
+    Leave {
+        room: String,
+        name: String,
+    },
+    Chat(ChatMessage),
+    // "/me waves", told in the third person
+    Action {
+        room: String,
+        from: String,
+        content: String,
+    },
+    // a chat message changed or withdrawn by its sender
+    Edited {
+        room: String,
+        from: String,
+        id: String,
+        content: String,
+    },
+    Deleted {
+        room: String,
+        from: String,
+        id: String,
+    },
+    Welcome {
+        name: String,
+    },
+    // the peer's own joins and leaves, told only to the peer
+    Joined {
+        room: String,
+        name: String,
+    },
+    Left {
+        room: String,
+        name: String,
+    },
+    TopicChanged {
+        room: String,
+        by: String,
+        topic: String,
+    },
+    RoomFlagChanged {
+        room: String,
+        by: String,
+        flag: RoomFlag,
+        enabled: bool,
+    },
+    // ephemeral, never stored and only sent to JSON clients
+    Typing {
+        room: String,
+        name: String,
+    },
+    // sent to a single peer and never stored
+    Direct {
+        from: String,
+        to: String,
+        content: String,
+        // set when the sender asked for a delivery receipt
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    System {
+        content: String,
+    },
+    History {
+        message: Box<Message>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        at: Option<String>,
+        // where to page from with /history, when the store numbers its messages
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    // answered by a pong with the same token
+    Ping {
+        #[serde(default)]
+        token: String,
+    },
+    Pong {
+        token: String,
+    },
+    // file transfers are sent to their two peers only and never stored
+    FileOffer {
+        id: u64,
+        from: String,
+        file: String,
+        size: u64,
+    },
+    FileChunk {
+        id: u64,
+        // base64 encoded
+        data: String,
+    },
+    FileProgress {
+        id: u64,
+        file: String,
+        sent: u64,
+        size: u64,
+    },
+    // told only to the peer, never stored
+    Session {
+        token: String,
+    },
+    // replies to JSON frames carrying an id, never stored
+    Ack {
+        id: String,
+        // the id given to the chat message the frame posted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    Nack {
+        id: String,
+        reason: String,
+    },
+    // a direct message was written to the recipient's connection
+    Delivered {
+        id: String,
+        to: String,
+    },
+    // told to a peer named in a chat message, which members of the room also receive
+    Mention {
+        room: String,
+        from: String,
+    },
+}
+
+/// Read the PROXY protocol v2 header a load balancer sends ahead of a connection and
+/// return the client address it carries.
+///
+/// `None` is a connection the proxy makes on its own, like a health check, or one from
+/// outside TCP over IPv4 or IPv6; the address of the socket applies to it.
+pub async fn read_proxy_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>> {
+    let mut header = [0; 16];
+    stream.read_exact(&mut header).await?;
+    if &header[..12] != PROXY_V2_SIGNATURE {
+        return Err(anyhow::anyhow!("missing PROXY protocol v2 header"));
+    }
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(anyhow::anyhow!(
+            "unsupported PROXY protocol version {}",
+            version
+        ));
+    }
+    // the addresses are followed by optional TLVs, all of it is read so the stream is left
+    // at the client's first byte
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(anyhow::anyhow!("unsupported PROXY command {}", command)),
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    // source address, destination address, source port, destination port
+    let addr = match header[13] >> 4 {
+        1 if len >= 12 => {
+            let ip: [u8; 4] = payload[..4].try_into()?;
+            SocketAddr::from((Ipv4Addr::from(ip), port(8)))
+        }
+        2 if len >= 36 => {
+            let ip: [u8; 16] = payload[..16].try_into()?;
+            SocketAddr::from((Ipv6Addr::from(ip), port(32)))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+impl Protocol {
+    pub(super) fn encode(self, message: &Message) -> Result<Bytes> {
+        match self {
+            Self::Text => Ok(message.to_string().into()),
+            Self::Json => Ok(serde_json::to_vec(message)?.into()),
+            Self::Irc => Ok(encode_irc(message).into()),
+            // bincode writes the variant name as the "type" field, then the variant's fields
+            Self::Binary => Ok(bincode::serialize(message)?.into()),
+        }
+    }
+
+    pub(super) fn decode(self, line: String) -> Result<String> {
+        Ok(self.decode_frame(line)?.0)
+    }
+
+    /// Decode a line along with the id a JSON client tagged it with, if any.
+    pub(super) fn decode_frame(self, line: String) -> Result<(String, Option<String>)> {
+        match self {
+            Self::Text | Self::Binary => Ok((line, None)),
+            Self::Json => Ok(match serde_json::from_str(&line)? {
+                ClientFrame::Auth { token } => (token, None),
+                ClientFrame::Login { name, token: None } => (name, None),
+                ClientFrame::Login {
+                    name,
+                    token: Some(token),
+                } => (format!("{} {}", name, token), None),
+                ClientFrame::Chat { content, id } => (content, id),
+                ClientFrame::Command { command, id } => (command, id),
+                ClientFrame::Pong { token: None } => (PONG.to_string(), None),
+                ClientFrame::Pong { token: Some(token) } => (format!("{} {}", PONG, token), None),
+                ClientFrame::Typing => (TYPING.to_string(), None),
+                ClientFrame::Resume { token } => (format!("{}{}", RESUME, token), None),
+            }),
+            Self::Irc => Ok((decode_irc(&line), None)),
+        }
+    }
+}
+
+/// Render a message as a line from an IRC server.
+pub(super) fn encode_irc(message: &Message) -> String {
+    let user = |name: &str| format!("{}!{}@{}", name, name, IRC_SERVER);
+    match message {
+        Message::Welcome { name } => format!(
+            ":{} 001 {} :Welcome to the chat, {}",
+            IRC_SERVER, name, name
+        ),
+        Message::Join { room, name } | Message::Joined { room, name } => {
+            format!(":{} JOIN {}", user(name), room)
+        }
+        Message::Leave { room, name } | Message::Left { room, name } => {
+            format!(":{} PART {}", user(name), room)
+        }
+        Message::Chat(chat) => format!(
+            ":{} PRIVMSG {} :{}",
+            user(&chat.from),
+            chat.room,
+            chat.content
+        ),
+        Message::Action {
+            room,
+            from,
+            content,
+        } => format!(
+            ":{} PRIVMSG {} :\x01ACTION {}\x01",
+            user(from),
+            room,
+            content
+        ),
+        Message::Direct {
+            from, to, content, ..
+        } => {
+            format!(":{} PRIVMSG {} :{}", user(from), to, content)
+        }
+        Message::TopicChanged { room, by, topic } => {
+            format!(":{} TOPIC {} :{}", user(by), room, topic)
+        }
+        Message::Ping { token } if token.is_empty() => format!("PING :{}", IRC_SERVER),
+        Message::Ping { token } => format!("PING :{}", token),
+        Message::Pong { token } => format!(":{} PONG {} :{}", IRC_SERVER, IRC_SERVER, token),
+        // everything else is informational
+        Message::RoomFlagChanged { .. }
+        | Message::Edited { .. }
+        | Message::Deleted { .. }
+        | Message::Typing { .. }
+        | Message::System { .. }
+        | Message::History { .. }
+        | Message::FileOffer { .. }
+        | Message::FileChunk { .. }
+        | Message::FileProgress { .. }
+        | Message::Session { .. }
+        | Message::Ack { .. }
+        | Message::Nack { .. }
+        | Message::Delivered { .. }
+        | Message::Mention { .. } => {
+            format!(":{} NOTICE * :{}", IRC_SERVER, message)
+        }
+    }
+}
+
+/// Translate an IRC client command into the line protocol, unsupported ones become empty.
+pub(super) fn decode_irc(line: &str) -> String {
+    let line = line.trim();
+    // a prefix sent by a client is ignored, as most servers do
+    let line = match line.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ').map_or("", |(_, rest)| rest),
+        None => line,
+    };
+    let (params, trailing) = match line.split_once(" :") {
+        Some((params, trailing)) => (params, Some(trailing)),
+        None => (line, None),
+    };
+    let mut params = params.split_whitespace();
+    let command = params.next().unwrap_or_default().to_ascii_uppercase();
+    let mut args: Vec<&str> = params.collect();
+    args.extend(trailing);
+
+    // channel lists are comma separated, only the first one is used
+    let first = |targets: &str| targets.split(',').next().unwrap_or_default().to_string();
+    match (command.as_str(), args.as_slice()) {
+        ("PASS", [token, ..]) => token.to_string(),
+        ("NICK", [nick, ..]) => format!("/nick {}", nick),
+        ("JOIN", [rooms, keys, ..]) => format!("/join {} {}", first(rooms), first(keys)),
+        ("JOIN", [rooms, ..]) => format!("/join {}", first(rooms)),
+        ("PART", [rooms, ..]) => format!("/leave {}", first(rooms)),
+        ("PRIVMSG", [target, text]) if target.starts_with('#') => {
+            format!("/say {} {}", target, text)
+        }
+        ("PRIVMSG", [target, text]) => format!("/msg {} {}", target, text),
+        ("QUIT", _) => "/quit".to_string(),
+        ("PING", [token, ..]) => format!("{} {}", PING, token),
+        ("PING", []) => PING.to_string(),
+        // "PONG :token" or "PONG server :token"
+        ("PONG", [.., token]) => format!("{} {}", PONG, token),
+        ("PONG", []) => PONG.to_string(),
+        _ => String::new(),
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "irc" => Ok(Self::Irc),
+            "binary" => Ok(Self::Binary),
+            _ => Err(anyhow::anyhow!(
+                "invalid protocol: {}, expected text, json, irc or binary",
+                s
+            )),
+        }
+    }
+}
+
+impl LineCodec {
+    pub(super) fn new(max_length: usize) -> Self {
+        Self {
+            max_length,
+            line: Vec::new(),
+            telnet: TelnetState::Data,
+            discarding: false,
+        }
+    }
+
+    fn take_line(&mut self) -> String {
+        let mut line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        // a replacement character is longer than the byte it replaces
+        truncate_at_char_boundary(&mut line, self.max_length);
+        line
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = Result<String, LineTooLong>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut read = 0;
+        let mut item = None;
+        for &byte in buf.iter() {
+            read += 1;
+            self.telnet = match (self.telnet, byte) {
+                (TelnetState::Data, TELNET_IAC) => TelnetState::Command,
+                (TelnetState::Data, b'\n') if self.discarding => {
+                    self.discarding = false;
+                    TelnetState::Data
+                }
+                (TelnetState::Data, b'\n') => {
+                    item = Some(Ok(self.take_line()));
+                    break;
+                }
+                // the CR of a CRLF, or of the CR NUL telnet sends for a bare CR
+                (TelnetState::Data, b'\r' | 0) => TelnetState::Data,
+                (TelnetState::Data, _) if self.discarding => TelnetState::Data,
+                (TelnetState::Data, _) if self.line.len() >= self.max_length => {
+                    self.line.clear();
+                    self.discarding = true;
+                    item = Some(Err(LineTooLong(self.max_length)));
+                    break;
+                }
+                (TelnetState::Data, byte) => {
+                    self.line.push(byte);
+                    TelnetState::Data
+                }
+                (TelnetState::Command, TELNET_SB) => TelnetState::Subnegotiation,
+                (TelnetState::Command, TELNET_WILL..=TELNET_DONT) => TelnetState::Option,
+                // an escaped 255 is not UTF-8 either, so it goes like every other command
+                (TelnetState::Command | TelnetState::Option, _) => TelnetState::Data,
+                (TelnetState::Subnegotiation, TELNET_IAC) => TelnetState::SubnegotiationIac,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationIac, TELNET_SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+            };
+        }
+        buf.advance(read);
+        Ok(item)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(item) = self.decode(buf)? {
+            return Ok(Some(item));
+        }
+        // the last line may not end with a newline
+        if self.line.is_empty() || self.discarding {
+            return Ok(None);
+        }
+        Ok(Some(Ok(self.take_line())))
+    }
+}
+
+impl Encoder<Bytes> for LineCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, line: Bytes, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.reserve(line.len() + 1);
+        buf.extend_from_slice(&line);
+        buf.extend_from_slice(b"\n");
+        Ok(())
+    }
+}
+
+impl Message {
+    pub(super) fn join(room: impl Into<String>, name: impl Into<String>) -> Self {
+        Self::Join {
+            room: room.into(),
+            name: name.into(),
+        }
+    }
+
+    pub(super) fn leave(room: impl Into<String>, name: impl Into<String>) -> Self {
+        Self::Leave {
+            room: room.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::System {
+            content: content.into(),
+        }
+    }
+
+    pub(super) fn history(message: Message, at: Option<String>, id: u64) -> Self {
+        Self::History {
+            message: Box::new(message),
+            at,
+            id: Some(id),
+        }
+    }
+
+    pub(super) fn history_id(&self) -> Option<u64> {
+        match self {
+            Self::History { id, .. } => *id,
+            _ => None,
+        }
+    }
+
+    pub(super) fn chat_message(
+        room: impl Into<String>,
+        from: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        let content = content.into();
+        Self::Chat(ChatMessage {
+            room: room.into(),
+            from: from.into(),
+            mentions: parse_mentions(&content),
+            content,
+            at: String::new(),
+            id: String::new(),
+        })
+    }
+
+    pub(super) fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Typing { .. })
+    }
+
+    /// Who a message comes from, for the peers ignoring them.
+    pub(super) fn sender(&self) -> Option<&str> {
+        match self {
+            Self::Chat(chat) => Some(&chat.from),
+            Self::Action { from, .. }
+            | Self::Direct { from, .. }
+            | Self::Edited { from, .. }
+            | Self::Deleted { from, .. }
+            | Self::Mention { from, .. } => Some(from),
+            Self::Typing { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Room messages that are relayed to federated servers.
+    pub(super) fn is_federated(&self) -> bool {
+        matches!(
+            self,
+            Self::Join { .. }
+                | Self::Leave { .. }
+                | Self::Chat(_)
+                | Self::Action { .. }
+                | Self::Edited { .. }
+                | Self::Deleted { .. }
+                | Self::TopicChanged { .. }
+                | Self::RoomFlagChanged { .. }
+        )
+    }
+
+    pub(super) fn room(&self) -> Option<&str> {
+        match self {
+            Self::Join { room, .. } | Self::Leave { room, .. } => Some(room),
+            Self::Chat(message) => Some(&message.room),
+            Self::Action { room, .. }
+            | Self::Edited { room, .. }
+            | Self::Deleted { room, .. }
+            | Self::TopicChanged { room, .. }
+            | Self::RoomFlagChanged { room, .. }
+            | Self::Typing { room, .. } => Some(room),
+            Self::Welcome { .. }
+            | Self::Joined { .. }
+            | Self::Left { .. }
+            | Self::Direct { .. }
+            | Self::System { .. }
+            | Self::History { .. }
+            | Self::Ping { .. }
+            | Self::Pong { .. }
+            | Self::FileOffer { .. }
+            | Self::FileChunk { .. }
+            | Self::FileProgress { .. }
+            | Self::Session { .. }
+            | Self::Ack { .. }
+            | Self::Nack { .. }
+            | Self::Delivered { .. }
+            | Self::Mention { .. } => None,
+        }
+    }
+}
+
+impl ChatClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::new(stream))
+    }
+
+    /// Talk to a server over any transport, like a TLS stream or one end of a pipe.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut sink, mut lines) = Framed::new(stream, LinesCodec::new()).split();
+        let (frames, mut outgoing) = mpsc::unbounded_channel::<String>();
+        let (events, receiver) = broadcast::channel(CLIENT_EVENT_CAPACITY);
+        let closed = CancellationToken::new();
+
+        let pongs = frames.clone();
+        let reading = closed.clone();
+        tokio::spawn(async move {
+            loop {
+                let line = tokio::select! {
+                    _ = reading.cancelled() => break,
+                    line = lines.next() => line,
+                };
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    Some(Err(e)) => {
+                        warn!("Chat client read Error: {}", e);
+                        break;
+                    }
+                    None => break,
+                };
+                let message = match serde_json::from_str::<Message>(&line) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Chat client received an invalid frame: {}", e);
+                        continue;
+                    }
+                };
+                if let Message::Ping { token } = message {
+                    let pong = ClientFrame::Pong { token: Some(token) };
+                    if let Ok(frame) = serde_json::to_string(&pong) {
+                        let _ = pongs.send(frame);
+                    }
+                    continue;
+                }
+                // nobody may be subscribed at the moment
+                let _ = events.send(Arc::new(message));
+            }
+            reading.cancel();
+        });
+
+        // ends once the client and the reading task are gone, after the frames left to send
+        let writing = closed.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = outgoing.recv().await {
+                if let Err(e) = sink.send(frame).await {
+                    warn!("Chat client write Error: {}", e);
+                    break;
+                }
+            }
+            writing.cancel();
+            let _ = sink.close().await;
+        });
+
+        Self {
+            frames,
+            events: receiver,
+            _guard: closed.clone().drop_guard(),
+            closed,
+        }
+    }
+
+    /// Every event from now on, until the connection ends.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Message>> {
+        self.events.resubscribe()
+    }
+
+    /// Answer a server that asks for a token before the name.
+    pub fn authenticate(&self, token: impl Into<String>) -> Result<()> {
+        self.send_frame(ClientFrame::Auth {
+            token: token.into(),
+        })
+    }
+
+    /// Log in and wait for the welcome, returning the name the server settled on.
+    ///
+    /// A name the server turns down is an error and the server asks for another one, so
+    /// `login` can be called again.
+    pub async fn login(&self, name: &str, token: Option<&str>) -> Result<String> {
+        let mut events = self.subscribe();
+        self.send_frame(ClientFrame::Login {
+            name: name.to_string(),
+            token: token.map(str::to_string),
+        })?;
+        let mut last = None;
+        loop {
+            let message = match events.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    let reason = last.unwrap_or_else(|| "connection closed".to_string());
+                    return Err(anyhow::anyhow!(reason));
+                }
+            };
+            match &*message {
+                Message::Welcome { name } => return Ok(name.clone()),
+                // the server gives the reason and then prompts again
+                Message::System { content } => match content.split_once(", please enter") {
+                    Some((reason, _)) => return Err(anyhow::anyhow!(reason.to_string())),
+                    None => last = Some(content.clone()),
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a line as the peer would type it, a chat message or a slash command.
+    pub fn send(&self, content: impl Into<String>) -> Result<()> {
+        self.send_frame(ClientFrame::Chat {
+            content: content.into(),
+            id: None,
+        })
+    }
+
+    pub fn command(&self, command: impl Into<String>) -> Result<()> {
+        self.send_frame(ClientFrame::Command {
+            command: command.into(),
+            id: None,
+        })
+    }
+
+    /// Resolves once the connection has ended.
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+
+    fn send_frame(&self, frame: ClientFrame) -> Result<()> {
+        if self.closed.is_cancelled() {
+            return Err(anyhow::anyhow!("connection closed"));
+        }
+        self.frames.send(serde_json::to_string(&frame)?)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Join { room, name } => write!(f, "[{}] {} joined the room", room, name),
+            Self::Leave { room, name } => write!(f, "[{}] {} left the room", room, name),
+            Self::Chat(message) if message.at.is_empty() => write!(
+                f,
+                "[{}] {}: {}",
+                message.room, message.from, message.content
+            ),
+            Self::Chat(message) => write!(
+                f,
+                "[{}] [{}] {}: {}",
+                message.at, message.room, message.from, message.content
+            ),
+            Self::Action {
+                room,
+                from,
+                content,
+            } => write!(f, "[{}] * {} {}", room, from, content),
+            Self::Edited {
+                room,
+                from,
+                id,
+                content,
+            } => write!(f, "[{}] {} edited {}: {}", room, from, id, content),
+            Self::Deleted { room, from, id } => write!(f, "[{}] {} deleted {}", room, from, id),
+            Self::TopicChanged { room, by, topic } if topic.is_empty() => {
+                write!(f, "[{}] {} cleared the topic", room, by)
+            }
+            Self::TopicChanged { room, by, topic } => {
+                write!(f, "[{}] {} changed the topic to: {}", room, by, topic)
+            }
+            Self::RoomFlagChanged {
+                room,
+                by,
+                flag,
+                enabled,
+            } => {
+                let action = if *enabled { "set" } else { "cleared" };
+                write!(f, "[{}] {} {} {}", room, by, action, flag)
+            }
+            Self::Typing { room, name } => write!(f, "[{}] {} is typing...", room, name),
+            Self::Welcome { name } => write!(f, "Welcome! {}", name),
+            Self::Joined { room, .. } => write!(f, "You joined {}", room),
+            Self::Left { room, .. } => write!(f, "You left {}", room),
+            Self::Direct { from, content, .. } => write!(f, "[pm] {}: {}", from, content),
+            Self::System { content } => write!(f, "{}", content),
+            Self::Ping { token } if token.is_empty() => write!(f, "{}", PING),
+            Self::Ping { token } => write!(f, "{} {}", PING, token),
+            Self::Pong { token } if token.is_empty() => write!(f, "{}", PONG),
+            Self::Pong { token } => write!(f, "{} {}", PONG, token),
+            Self::FileOffer {
+                id,
+                from,
+                file,
+                size,
+            } => write!(
+                f,
+                "{} offers you {} ({} bytes), /accept {} or /cancel {}",
+                from, file, size, id, id
+            ),
+            Self::FileChunk { id, data } => write!(f, "[file {}] {}", id, data),
+            Self::Ack { id, .. } => write!(f, "Message {} accepted", id),
+            Self::Nack { id, reason } => write!(f, "Message {} rejected: {}", id, reason),
+            Self::Delivered { id, to } => write!(f, "Message {} delivered to {}", id, to),
+            Self::Mention { room, from } => write!(f, "[{}] {} mentioned you", room, from),
+            Self::Session { token } => write!(
+                f,
+                "Your session token is {}, reconnect with {}{} to pick up where you left",
+                token, RESUME, token
+            ),
+            Self::FileProgress {
+                id,
+                file,
+                sent,
+                size,
+            } => write!(
+                f,
+                "Transfer {} of {}: {}/{} bytes ({}%)",
+                id,
+                file,
+                sent,
+                size,
+                sent * 100 / size
+            ),
+            Self::History { message, at, id } => {
+                write!(f, "[history")?;
+                if let Some(id) = id {
+                    write!(f, " {}", id)?;
+                }
+                if let Some(at) = at {
+                    write!(f, " {}", at)?;
+                }
+                write!(f, "] {}", message)
+            }
+        }
+    }
+}
@@ -0,0 +1,907 @@
+//! Room state: who is in which room, topics, flags, limits, invites, polls and the snapshots
+//! that carry them over to a new server.
+
+use super::{protocol::Message, Author, ChatRoom, Role, DEFAULT_ROOM};
+use anyhow::Result;
+use core::fmt;
+use dashmap::mapref::entry::Entry;
+use metrics::counter;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+const INVITE_CODE_LEN: usize = 10;
+const DEFAULT_INVITE_MINUTES: u64 = 24 * 60;
+pub(super) const MAX_INVITE_MINUTES: u64 = 30 * 24 * 60;
+// how often idle rooms are looked for, half the idle period within these bounds
+const MIN_ROOM_SWEEP: Duration = Duration::from_millis(100);
+const MAX_ROOM_SWEEP: Duration = Duration::from_secs(60);
+
+/// A setting of a room that changes who may do what in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomFlag {
+    // only moderators and admins may post
+    ReadOnly,
+    // joining takes an invite code from the room's creator or an admin
+    InviteOnly,
+}
+
+/// A code letting its holder into an invite-only room.
+#[derive(Debug)]
+pub(super) struct Invite {
+    room: String,
+    expires_at: Instant,
+    single_use: bool,
+}
+
+/// Moderation state that outlives a restart without a database, written by /snapshot.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub rooms: BTreeMap<String, RoomSnapshot>,
+    #[serde(default)]
+    pub banned: BTreeSet<IpAddr>,
+    // lowercased names
+    #[serde(default)]
+    pub shadow_banned: BTreeSet<String>,
+    // by the user name of the auth provider, as in `with_roles`
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+    #[serde(default)]
+    pub archived: BTreeSet<String>,
+}
+
+/// The settings of a room, whether or not anyone is in it.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomSnapshot {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<RoomFlag>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    // user name of the room's creator, when they logged in through the auth provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug)]
+pub(super) struct Poll {
+    creator: Author,
+    question: String,
+    options: Vec<String>,
+    votes: HashMap<Author, usize>,
+}
+
+impl ChatRoom {
+    pub(super) async fn join_room(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        room: &str,
+        invite: Option<&str>,
+    ) -> Result<(), String> {
+        let history = self.recent_history(room, self.history.size()).await;
+        let admitted = {
+            // checked and joined under the entry's lock, so two peers can't take the last seat
+            let mut members = self.rooms.entry(room.to_string()).or_default();
+            if members.contains(&addr) {
+                Ok(false)
+            } else {
+                self.admit(addr, room, members.len(), invite)
+                    .map(|()| members.insert(addr))
+            }
+        };
+        let joined = match admitted {
+            Ok(joined) => joined,
+            Err(text) => {
+                self.rooms.remove_if(room, |_, members| members.is_empty());
+                return Err(text);
+            }
+        };
+        self.known_rooms.insert(room.to_string(), None);
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.current_room = Some(room.to_string());
+        }
+
+        if !joined {
+            let message = Message::system(format!("Switched to {}", room));
+            self.send_to(addr, Arc::new(message)).await;
+            return Ok(());
+        }
+
+        info!(%room, "{} joined", name);
+        let message = Message::Joined {
+            room: room.to_string(),
+            name: name.to_string(),
+        };
+        self.send_to(addr, Arc::new(message)).await;
+        if let Some(topic) = self.topic(room) {
+            let message = Message::system(format!("Topic for {}: {}", room, topic));
+            self.send_to(addr, Arc::new(message)).await;
+        }
+        for message in history {
+            self.send_to(addr, Arc::new(message)).await;
+        }
+        self.broadcast(Some(addr), Arc::new(Message::join(room, name)))
+            .await;
+        Ok(())
+    }
+
+    pub(super) async fn leave_room(&self, addr: SocketAddr, name: &str, room: &str) -> bool {
+        let left = match self.rooms.get_mut(room) {
+            Some(mut members) => members.remove(&addr),
+            None => false,
+        };
+        if !left {
+            return false;
+        }
+        if self
+            .rooms
+            .remove_if(room, |_, members| members.is_empty())
+            .is_some()
+        {
+            self.known_rooms
+                .insert(room.to_string(), Some(Instant::now()));
+        }
+
+        let fallback = self.rooms_of(addr).into_iter().next();
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            if peer.current_room.as_deref() == Some(room) {
+                peer.current_room = fallback;
+            }
+        }
+
+        info!(%room, "{} left", name);
+        self.broadcast(Some(addr), Arc::new(Message::leave(room, name)))
+            .await;
+        true
+    }
+
+    pub(super) fn topic(&self, room: &str) -> Option<String> {
+        self.topics.get(room).map(|topic| topic.clone())
+    }
+
+    fn set_topic(&self, room: &str, topic: String) {
+        if topic.is_empty() {
+            self.topics.remove(room);
+        } else {
+            self.topics.insert(room.to_string(), topic);
+        }
+    }
+
+    /// Check whether a peer may join a room that has `members` already, taking up an invite
+    /// when the room needs one.
+    fn admit(
+        &self,
+        addr: SocketAddr,
+        room: &str,
+        members: usize,
+        invite: Option<&str>,
+    ) -> Result<(), String> {
+        if self.archived.contains(room) {
+            return Err(format!("{} is archived", room));
+        }
+        if let Some(limit) = self.room_limit(room) {
+            if members >= limit {
+                return Err(format!(
+                    "{} is full ({} members), try again later",
+                    room, limit
+                ));
+            }
+        }
+        if !self.has_flag(room, RoomFlag::InviteOnly) || self.manages(addr, room) {
+            return Ok(());
+        }
+        let Some(code) = invite else {
+            return Err(format!(
+                "{} is invite-only, join with /join {} <code>",
+                room, room
+            ));
+        };
+        let invalid = || format!("Invalid or expired invite code for {}", room);
+        let Entry::Occupied(entry) = self.invites.entry(code.to_string()) else {
+            return Err(invalid());
+        };
+        if entry.get().expires_at <= Instant::now() {
+            entry.remove();
+            return Err(invalid());
+        }
+        if entry.get().room != room {
+            return Err(invalid());
+        }
+        if entry.get().single_use {
+            entry.remove();
+        }
+        Ok(())
+    }
+
+    /// Make a room that doesn't exist yet and join it, its creator manages it. Rooms are
+    /// only ever owned this way, joining an empty room gives no rights over it.
+    pub(super) async fn create_room(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        room: String,
+    ) -> Result<(), String> {
+        if self.archived.contains(&room) {
+            return Err(format!("{} is archived", room));
+        }
+        if room == DEFAULT_ROOM || self.persistent_rooms.contains(&room) {
+            return Err(format!(
+                "{} is kept by the server, /join {} instead",
+                room, room
+            ));
+        }
+        let author = self
+            .author(addr)
+            .ok_or_else(|| "You are not logged in".to_string())?;
+        match self.known_rooms.entry(room.clone()) {
+            Entry::Occupied(_) => {
+                return Err(format!("{} already exists, /join {} instead", room, room))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Some(Instant::now()));
+            }
+        }
+        self.room_owners.insert(room.clone(), author);
+        info!(%room, "{} created the room", name);
+        self.join_room(addr, name, &room, None).await
+    }
+
+    /// Close a room for good, its members are moved out and nobody may join it again.
+    ///
+    /// Its history stays, only the room goes.
+    pub(super) async fn archive(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        room: Option<String>,
+    ) -> Result<String, String> {
+        let room = room
+            .or_else(|| self.current_room(addr))
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        // rooms the server keeps have no creator to decide their fate
+        if room == DEFAULT_ROOM || self.persistent_rooms.contains(&room) {
+            return Err(format!("{} cannot be archived", room));
+        }
+        if !self.known_rooms.contains_key(&room) {
+            return Err(format!("No such room: {}", room));
+        }
+        if !self.manages(addr, &room) {
+            return Err(format!(
+                "Only the creator of {} or an admin may archive it",
+                room
+            ));
+        }
+        // joins are refused from here on, so the room empties for good
+        self.archived.insert(room.clone());
+        let text = format!("{} archived {}", name, room);
+        let members: Vec<SocketAddr> = self
+            .rooms
+            .get(&room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default();
+        for member in members {
+            let member_name = self.peer_name(member).or_else(|| {
+                self.detached
+                    .get(&member)
+                    .map(|session| session.name.clone())
+            });
+            let Some(member_name) = member_name else {
+                continue;
+            };
+            self.leave_room(member, &member_name, &room).await;
+            let message = Message::Left {
+                room: room.clone(),
+                name: member_name,
+            };
+            self.send_to(member, Arc::new(message)).await;
+            if member != addr {
+                self.send_to(member, Arc::new(Message::system(text.clone())))
+                    .await;
+            }
+        }
+        self.known_rooms.remove(&room);
+        self.room_owners.remove(&room);
+        self.polls.remove(&room);
+        self.invites.retain(|_, invite| invite.room != room);
+        info!(%room, "{} archived the room", name);
+        Ok(text)
+    }
+
+    pub(super) fn list_rooms(&self) -> String {
+        let mut rooms: Vec<String> = self
+            .known_rooms
+            .iter()
+            .map(|room| room.key().clone())
+            .collect();
+        if rooms.is_empty() {
+            return "No rooms yet, /create one".to_string();
+        }
+        rooms.sort();
+        let rooms: Vec<_> = rooms
+            .into_iter()
+            .map(|room| {
+                let members = self.rooms.get(&room).map_or(0, |members| members.len());
+                let mut line = format!("{} ({} members", room, members);
+                if self.has_flag(&room, RoomFlag::InviteOnly) {
+                    line.push_str(", invite-only");
+                }
+                line.push(')');
+                if let Some(topic) = self.topic(&room) {
+                    line.push_str(&format!(": {}", topic));
+                }
+                line
+            })
+            .collect();
+        format!("Rooms ({}): {}", rooms.len(), rooms.join(", "))
+    }
+
+    /// Forget the rooms left empty for the idle period every so often, until shutdown.
+    pub async fn run_room_sweeper(&self, shutdown: CancellationToken) {
+        let Some(idle) = self.room_idle else {
+            return;
+        };
+        let mut ticks = interval((idle / 2).clamp(MIN_ROOM_SWEEP, MAX_ROOM_SWEEP));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticks.tick() => self.sweep_rooms(idle),
+            }
+        }
+    }
+
+    /// Drop the topic, flags, creator, invites and kept history of the rooms empty for
+    /// `idle`, the default and persistent rooms stay.
+    fn sweep_rooms(&self, idle: Duration) {
+        let idle_rooms: Vec<String> = self
+            .known_rooms
+            .iter()
+            .filter(|room| room.value().is_some_and(|at| at.elapsed() >= idle))
+            .map(|room| room.key().clone())
+            .filter(|room| room != DEFAULT_ROOM && !self.persistent_rooms.contains(room))
+            .collect();
+        for room in idle_rooms {
+            // someone may have joined since
+            let removed = self.known_rooms.remove_if(&room, |_, emptied_at| {
+                emptied_at.is_some() && !self.rooms.contains_key(&room)
+            });
+            if removed.is_none() {
+                continue;
+            }
+            self.topics.remove(&room);
+            self.room_flags.retain(|(flagged, _)| *flagged != room);
+            self.room_owners.remove(&room);
+            self.invites.retain(|_, invite| invite.room != room);
+            self.history.forget(&room);
+            counter!("chat_rooms_swept_total").increment(1);
+            info!(%room, "Forgot the idle room");
+        }
+    }
+
+    /// Whether a caller from outside any connection may read and post in a room. Invite-only
+    /// rooms take a user who created the room or is an admin, nobody outside holds a code.
+    pub(super) fn admit_user(&self, user: Option<&str>, room: &str) -> Result<(), String> {
+        if !self.has_flag(room, RoomFlag::InviteOnly) {
+            return Ok(());
+        }
+        let allowed = user.is_some_and(|user| {
+            self.roles
+                .get(user)
+                .is_some_and(|role| *role >= Role::Admin)
+                || self
+                    .room_owners
+                    .get(room)
+                    .is_some_and(|owner| *owner == Author::User(user.to_string()))
+        });
+        if !allowed {
+            return Err(format!(
+                "{} is invite-only, only its creator or an admin may follow it",
+                room
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a peer may manage a room's invites: whoever created it and admins.
+    fn manages(&self, addr: SocketAddr, room: &str) -> bool {
+        if self.role(addr) >= Role::Admin {
+            return true;
+        }
+        let Some(author) = self.author(addr) else {
+            return false;
+        };
+        self.room_owners
+            .get(room)
+            .is_some_and(|owner| *owner == author)
+    }
+
+    /// Make an invite code for the current room, returns the reply telling how to use it.
+    pub(super) fn invite(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        single_use: bool,
+        minutes: Option<u64>,
+    ) -> Result<String, String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        if !self.manages(addr, &room) {
+            return Err(format!(
+                "Only the creator of {} or an admin may invite",
+                room
+            ));
+        }
+        let now = Instant::now();
+        self.invites.retain(|_, invite| invite.expires_at > now);
+
+        let minutes = minutes.unwrap_or(DEFAULT_INVITE_MINUTES);
+        let code = nanoid!(INVITE_CODE_LEN);
+        let invite = Invite {
+            room: room.clone(),
+            expires_at: now + Duration::from_secs(minutes * 60),
+            single_use,
+        };
+        info!(%room, "{} made an invite for {} minutes", name, minutes);
+        self.invites.insert(code.clone(), invite);
+        let mut text = format!(
+            "Join {} with /join {} {} within {} minutes",
+            room, room, code, minutes
+        );
+        if single_use {
+            text.push_str(", the code works once");
+        }
+        if !self.has_flag(&room, RoomFlag::InviteOnly) {
+            text.push_str(&format!(", {} is open to everyone for now", room));
+        }
+        Ok(text)
+    }
+
+    fn room_limit(&self, room: &str) -> Option<usize> {
+        self.room_limits.get(room).map(|limit| *limit)
+    }
+
+    pub(super) fn describe_limit(&self, room: &str) -> String {
+        match self.room_limit(room) {
+            Some(limit) => format!("{} takes at most {} members", room, limit),
+            None => format!("No member limit is set for {}", room),
+        }
+    }
+
+    /// Set or lift (with 0) the member limit of the admin's current room.
+    ///
+    /// Members beyond a lowered limit stay, only new joins are refused.
+    pub(super) fn change_limit(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        limit: usize,
+    ) -> Result<String, String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        if limit == 0 {
+            return match self.room_limits.remove(&room) {
+                Some(_) => {
+                    info!(%room, "{} lifted the member limit", name);
+                    Ok(format!("{} lifted the member limit of {}", name, room))
+                }
+                None => Err(format!("No member limit is set for {}", room)),
+            };
+        }
+        info!(%room, "{} set the member limit to {}", name, limit);
+        self.room_limits.insert(room.clone(), limit);
+        Ok(format!("{} limited {} to {} members", name, room, limit))
+    }
+
+    pub(super) fn has_flag(&self, room: &str, flag: RoomFlag) -> bool {
+        self.room_flags.contains(&(room.to_string(), flag))
+    }
+
+    /// The rooms' topics, flags, limits and creators, the bans and the roles of users, as
+    /// they are now.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut rooms = BTreeMap::<String, RoomSnapshot>::new();
+        for topic in self.topics.iter() {
+            rooms.entry(topic.key().clone()).or_default().topic = Some(topic.value().clone());
+        }
+        for flag in self.room_flags.iter() {
+            let (room, flag) = flag.key();
+            rooms.entry(room.clone()).or_default().flags.push(*flag);
+        }
+        for limit in self.room_limits.iter() {
+            rooms.entry(limit.key().clone()).or_default().limit = Some(*limit.value());
+        }
+        // sessions and connections don't outlive the server, only users are kept
+        for owner in self.room_owners.iter() {
+            if let Author::User(user) = owner.value() {
+                rooms.entry(owner.key().clone()).or_default().owner = Some(user.clone());
+            }
+        }
+        for room in rooms.values_mut() {
+            room.flags.sort_by_key(|flag| flag.to_string());
+        }
+        Snapshot {
+            rooms,
+            banned: self.banned.iter().map(|ip| *ip).collect(),
+            shadow_banned: self.shadow_banned.iter().map(|name| name.clone()).collect(),
+            roles: self
+                .roles
+                .iter()
+                .map(|role| (role.key().clone(), *role.value()))
+                .collect(),
+            archived: self.archived.iter().map(|room| room.clone()).collect(),
+        }
+    }
+
+    /// Take over the state of a snapshot, over what the config set.
+    pub fn restore(&self, snapshot: Snapshot) {
+        for (name, room) in snapshot.rooms {
+            // empty since the snapshot, as far as the sweeper is concerned
+            self.known_rooms
+                .entry(name.clone())
+                .or_insert_with(|| Some(Instant::now()));
+            if let Some(topic) = room.topic {
+                self.set_topic(&name, topic);
+            }
+            for flag in room.flags {
+                self.room_flags.insert((name.clone(), flag));
+            }
+            if let Some(limit) = room.limit.filter(|limit| *limit > 0) {
+                self.room_limits.insert(name.clone(), limit);
+            }
+            if let Some(owner) = room.owner {
+                self.room_owners.insert(name, Author::User(owner));
+            }
+        }
+        for ip in snapshot.banned {
+            self.banned.insert(ip);
+        }
+        for name in snapshot.shadow_banned {
+            self.shadow_banned.insert(name.to_lowercase());
+        }
+        for (user, role) in snapshot.roles {
+            self.roles.insert(user, role);
+        }
+        for room in snapshot.archived {
+            self.known_rooms.remove(&room);
+            self.archived.insert(room);
+        }
+    }
+
+    /// Write a snapshot to the snapshot file, through a temporary file so a crash never
+    /// leaves half of one.
+    pub(super) async fn write_snapshot(&self, name: &str) -> Result<String, String> {
+        let path = self
+            .snapshot_file
+            .as_ref()
+            .ok_or_else(|| "No snapshot file is configured".to_string())?;
+        let snapshot = self.snapshot();
+        let json = serde_json::to_vec_pretty(&snapshot).map_err(|e| e.to_string())?;
+        let partial = path.with_extension("partial");
+        let written = async {
+            tokio::fs::write(&partial, json).await?;
+            tokio::fs::rename(&partial, path).await
+        };
+        if let Err(e) = written.await {
+            warn!("Failed to write snapshot {}: {}", path.display(), e);
+            return Err(format!("Failed to write the snapshot: {}", e));
+        }
+        info!("{} wrote a snapshot to {}", name, path.display());
+        Ok(format!(
+            "Snapshot of {} rooms, {} bans and {} roles written to {}",
+            snapshot.rooms.len(),
+            snapshot.banned.len() + snapshot.shadow_banned.len(),
+            snapshot.roles.len(),
+            path.display()
+        ))
+    }
+
+    /// Keep the topic and flags in step with a message that changes them, wherever the
+    /// message came from.
+    pub(super) fn apply_room_state(&self, message: &Message) {
+        match message {
+            Message::TopicChanged { room, topic, .. } => self.set_topic(room, topic.clone()),
+            Message::RoomFlagChanged {
+                room,
+                flag,
+                enabled: true,
+                ..
+            } => {
+                self.room_flags.insert((room.clone(), *flag));
+            }
+            Message::RoomFlagChanged {
+                room,
+                flag,
+                enabled: false,
+                ..
+            } => {
+                self.room_flags.remove(&(room.clone(), *flag));
+            }
+            _ => {}
+        }
+    }
+
+    pub(super) fn describe_flags(&self, room: &str) -> String {
+        let mut flags: Vec<String> = self
+            .room_flags
+            .iter()
+            .filter(|item| item.0 == room)
+            .map(|item| item.1.to_string())
+            .collect();
+        if flags.is_empty() {
+            return format!("No flags are set for {}", room);
+        }
+        flags.sort();
+        format!("Flags of {}: {}", room, flags.join(", "))
+    }
+
+    pub(super) async fn change_flag(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        flag: RoomFlag,
+        enabled: bool,
+    ) -> Result<(), String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        if flag == RoomFlag::InviteOnly && !self.manages(addr, &room) {
+            return Err(format!(
+                "Only the creator of {} or an admin may change {}",
+                room, flag
+            ));
+        }
+        if self.has_flag(&room, flag) == enabled {
+            let state = if enabled { "set" } else { "not set" };
+            return Err(format!("{} is already {} for {}", flag, state, room));
+        }
+
+        info!(%room, "{} set {} to {}", name, flag, enabled);
+        let message = Message::RoomFlagChanged {
+            room,
+            by: name.to_string(),
+            flag,
+            enabled,
+        };
+        self.apply_room_state(&message);
+        self.announce(Arc::new(message)).await;
+        Ok(())
+    }
+
+    pub(super) async fn change_topic(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        topic: String,
+    ) -> Result<(), String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+
+        info!(%room, "{} changed the topic to {:?}", name, topic);
+        self.set_topic(&room, topic.clone());
+        let message = Message::TopicChanged {
+            room,
+            by: name.to_string(),
+            topic,
+        };
+        self.announce(Arc::new(message)).await;
+        Ok(())
+    }
+
+    pub(super) fn rooms_of(&self, addr: SocketAddr) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|item| item.value().contains(&addr))
+            .map(|item| item.key().clone())
+            .collect()
+    }
+
+    pub(super) fn current_room(&self, addr: SocketAddr) -> Option<String> {
+        self.peers
+            .get(&addr)
+            .and_then(|peer| peer.current_room.clone())
+    }
+
+    pub(super) fn start_poll(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        room: &str,
+        question: String,
+        options: Vec<String>,
+    ) -> Result<String, String> {
+        let creator = self
+            .author(addr)
+            .ok_or_else(|| "You are not logged in".to_string())?;
+        let created = Poll::new(creator, question, options);
+        let text = format!(
+            "{} started a poll: {} {} (vote with /vote <n>)",
+            name,
+            created.question,
+            created.numbered_options()
+        );
+        match self.polls.entry(room.to_string()) {
+            Entry::Occupied(_) => Err(format!(
+                "A poll is already active in {}, close it with /pollclose first",
+                room
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(created);
+                Ok(text)
+            }
+        }
+    }
+
+    pub(super) fn vote(&self, addr: SocketAddr, choice: usize) -> Result<String, String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        let voter = self
+            .author(addr)
+            .ok_or_else(|| "You are not logged in".to_string())?;
+        let mut poll = self
+            .polls
+            .get_mut(&room)
+            .ok_or_else(|| no_active_poll(&room))?;
+        let option = choice
+            .checked_sub(1)
+            .and_then(|index| poll.options.get(index))
+            .ok_or_else(|| format!("Invalid choice, pick 1 to {}", poll.options.len()))?
+            .clone();
+
+        // a peer may change their mind, the last vote wins
+        poll.votes.insert(voter, choice - 1);
+        Ok(format!("You voted for: {}", option))
+    }
+
+    pub(super) fn poll_result(&self, addr: SocketAddr) -> Result<String, String> {
+        let room = self
+            .current_room(addr)
+            .ok_or_else(|| "You are not in any room".to_string())?;
+        let poll = self.polls.get(&room).ok_or_else(|| no_active_poll(&room))?;
+        Ok(format!("Current results for {}", poll.tally()))
+    }
+
+    pub(super) fn close_poll(&self, addr: SocketAddr, room: &str) -> Result<String, String> {
+        let author = self.author(addr);
+        let closed = self
+            .polls
+            .remove_if(room, |_, poll| Some(&poll.creator) == author.as_ref());
+        match closed {
+            Some((_, poll)) => Ok(format!("Poll closed, final results for {}", poll.tally())),
+            None if self.polls.contains_key(room) => {
+                Err("Only the creator of the poll can close it".to_string())
+            }
+            None => Err(no_active_poll(room)),
+        }
+    }
+
+    /// Close the poll of a room its creator left, nobody else could.
+    pub(super) async fn end_poll_of(&self, author: &Author, room: &str) {
+        let Some((_, poll)) = self
+            .polls
+            .remove_if(room, |_, poll| poll.creator == *author)
+        else {
+            return;
+        };
+        let text = format!(
+            "Poll closed as its creator left, final results for {}",
+            poll.tally()
+        );
+        self.tell_room(room, Arc::new(Message::system(text))).await;
+    }
+
+    /// Send a message to the connected members of a room only, like the replies to poll
+    /// commands.
+    pub(super) async fn tell_room(&self, room: &str, message: Arc<Message>) {
+        let members: Vec<SocketAddr> = self
+            .rooms
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default();
+        for member in members {
+            self.send_to(member, message.clone()).await;
+        }
+    }
+}
+
+fn no_active_poll(room: &str) -> String {
+    format!("There is no active poll in {}", room)
+}
+
+impl Poll {
+    pub(super) fn new(creator: Author, question: String, options: Vec<String>) -> Self {
+        Self {
+            creator,
+            question,
+            options,
+            votes: HashMap::new(),
+        }
+    }
+
+    fn numbered_options(&self) -> String {
+        self.options
+            .iter()
+            .enumerate()
+            .map(|(i, option)| format!("{}) {}", i + 1, option))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn tally(&self) -> String {
+        let mut counts = vec![0; self.options.len()];
+        for choice in self.votes.values() {
+            counts[*choice] += 1;
+        }
+
+        let results = self
+            .options
+            .iter()
+            .zip(counts)
+            .enumerate()
+            .map(|(i, (option, count))| format!("{}) {}: {}", i + 1, option, count))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("{}: {}", self.question, results)
+    }
+}
+
+pub(super) fn parse_room_name(name: &str) -> Result<String, String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_ROOM_NAME_LEN {
+        return Err(format!(
+            "Room name must be between 1 and {} characters",
+            MAX_ROOM_NAME_LEN
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("Room name may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(format!("#{}", name))
+}
+
+impl FromStr for RoomFlag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read_only" => Ok(Self::ReadOnly),
+            "invite_only" => Ok(Self::InviteOnly),
+            _ => Err(anyhow::anyhow!(
+                "invalid room flag: {}, expected read_only or invite_only",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RoomFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadOnly => write!(f, "read_only"),
+            Self::InviteOnly => write!(f, "invite_only"),
+        }
+    }
+}
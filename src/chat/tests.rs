@@ -0,0 +1,536 @@
+use super::{
+    commands::Command,
+    history::{DEFAULT_HISTORY_PAGE, MAX_HISTORY_PAGE},
+    protocol::{decode_irc, encode_irc, PROXY_V2_SIGNATURE},
+    rooms::MAX_INVITE_MINUTES,
+    *,
+};
+use proptest::prelude::*;
+use std::net::Ipv6Addr;
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+// any argument split_args can carry once quoted, it has no escapes for quotes themselves
+const ARG: &str = "[^\"]{0,16}";
+const ROOM: &str = "#[A-Za-z0-9_-]{1,32}";
+const WORD: &str = "[^\\s\"]{1,16}";
+// a colon would start the trailing parameter instead
+const IRC_TARGET: &str = "[^\\s\":]{1,16}";
+
+/// Render a command the way a client would type it, quoting every free form argument.
+fn render(command: &Command) -> String {
+    let quote = |arg: &str| format!("\"{}\"", arg);
+    match command {
+        Command::Join { room, invite: None } => format!("/join {}", room),
+        Command::Join {
+            room,
+            invite: Some(invite),
+        } => format!("/join {} {}", room, quote(invite)),
+        Command::Leave(None) => "/leave".to_string(),
+        Command::Leave(Some(room)) => format!("/leave {}", room),
+        Command::History {
+            room: None,
+            before: _,
+            count,
+        } => format!("/history {}", count),
+        Command::History {
+            room: Some(room),
+            before: None,
+            count,
+        } if *count == DEFAULT_HISTORY_PAGE => format!("/history {}", room),
+        Command::History {
+            room: Some(room),
+            before,
+            count,
+        } => format!("/history {} {} {}", room, before.unwrap_or(u64::MAX), count),
+        Command::Search(term) => format!("/search {}", term),
+        Command::ShadowBan { name, banned: true } => format!("/shadowban {}", quote(name)),
+        Command::ShadowBan {
+            name,
+            banned: false,
+        } => format!("/unshadowban {}", quote(name)),
+        Command::Poll { question, options } => {
+            let options: Vec<String> = options.iter().map(|option| quote(option)).collect();
+            format!("/poll {} {}", quote(question), options.join(" "))
+        }
+        Command::Vote(choice) => format!("/vote {}", choice),
+        Command::PollResult => "/pollresult".to_string(),
+        Command::PollClose => "/pollclose".to_string(),
+        Command::Stats { uptime_only: false } => "/stats".to_string(),
+        Command::Stats { uptime_only: true } => "/stats uptime".to_string(),
+        Command::Who => "/who".to_string(),
+        Command::Away(None) => "/away".to_string(),
+        Command::Away(Some(reason)) => format!("/away {}", quote(reason)),
+        Command::Back => "/back".to_string(),
+        Command::Msg { to, content } => format!("/msg {} {}", to, content),
+        Command::Say { room, content } => format!("/say {} {}", room, content),
+        Command::Me(text) => format!("/me {}", text),
+        Command::Create(room) => format!("/create {}", room),
+        Command::Archive(None) => "/archive".to_string(),
+        Command::Archive(Some(room)) => format!("/archive {}", room),
+        Command::Rooms => "/rooms".to_string(),
+        Command::Edit { id, content } => format!("/edit {} {}", id, content),
+        Command::Delete(id) => format!("/delete {}", quote(id)),
+        Command::Quit => "/quit".to_string(),
+        Command::Topic(None) => "/topic".to_string(),
+        Command::Topic(Some(topic)) => format!("/topic {}", quote(topic)),
+        Command::Flag(None) => "/flag".to_string(),
+        Command::Flag(Some((flag, enabled))) => {
+            format!("/flag {} {}", flag, if *enabled { "on" } else { "off" })
+        }
+        Command::Limit(None) => "/limit".to_string(),
+        Command::Limit(Some(limit)) => format!("/limit {}", limit),
+        Command::Invite {
+            single_use,
+            minutes,
+        } => {
+            let mut line = "/invite".to_string();
+            if *single_use {
+                line.push_str(" once");
+            }
+            if let Some(minutes) = minutes {
+                line.push_str(&format!(" {}", minutes));
+            }
+            line
+        }
+        Command::Nick(name) => format!("/nick {}", quote(name)),
+        Command::Kick(name) => format!("/kick {}", quote(name)),
+        Command::Ban(name) => format!("/ban {}", quote(name)),
+        Command::SendFile { to, file, size } => {
+            format!("/send {} {} {}", quote(to), quote(file), size)
+        }
+        Command::Accept(id) => format!("/accept {}", id),
+        Command::Cancel(id) => format!("/cancel {}", id),
+        Command::Chunk { id, data } => format!("/chunk {} {}", id, quote(data)),
+        Command::Help(None) => "/help".to_string(),
+        Command::Help(Some(topic)) => format!("/help {}", quote(topic)),
+        Command::Announce(text) => format!("/announce {}", text),
+        Command::Promote { name, role } => format!("/promote {} {}", quote(name), role),
+        Command::Ignore(None) => "/ignore".to_string(),
+        Command::Ignore(Some(name)) => format!("/ignore {}", quote(name)),
+        Command::Unignore(name) => format!("/unignore {}", quote(name)),
+        Command::Snapshot => "/snapshot".to_string(),
+    }
+}
+
+fn content() -> impl Strategy<Value = String> {
+    "[^\n]{1,32}".prop_filter_map("blank content", |content| {
+        let content = content.trim();
+        (!content.is_empty()).then(|| content.to_string())
+    })
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        (ROOM, proptest::option::of(WORD))
+            .prop_map(|(room, invite)| Command::Join { room, invite }),
+        proptest::option::of(ROOM).prop_map(Command::Leave),
+        (1..=MAX_HISTORY_PAGE).prop_map(|count| Command::History {
+            room: None,
+            before: None,
+            count
+        }),
+        (ROOM, any::<u64>(), 1..=MAX_HISTORY_PAGE).prop_map(|(room, before, count)| {
+            Command::History {
+                room: Some(room),
+                before: Some(before),
+                count,
+            }
+        }),
+        content().prop_map(Command::Search),
+        (ARG, any::<bool>()).prop_map(|(name, banned)| Command::ShadowBan { name, banned }),
+        (ARG, prop::collection::vec(ARG, 2..5))
+            .prop_map(|(question, options)| Command::Poll { question, options }),
+        any::<usize>().prop_map(Command::Vote),
+        Just(Command::PollResult),
+        Just(Command::PollClose),
+        any::<bool>().prop_map(|uptime_only| Command::Stats { uptime_only }),
+        Just(Command::Who),
+        proptest::option::of(ARG).prop_map(Command::Away),
+        Just(Command::Back),
+        (WORD, content()).prop_map(|(to, content)| Command::Msg { to, content }),
+        (ROOM, content()).prop_map(|(room, content)| Command::Say { room, content }),
+        content().prop_map(Command::Me),
+        ROOM.prop_map(Command::Create),
+        proptest::option::of(ROOM).prop_map(Command::Archive),
+        Just(Command::Rooms),
+        (WORD, content()).prop_map(|(id, content)| Command::Edit { id, content }),
+        ARG.prop_map(Command::Delete),
+        Just(Command::Quit),
+        Just(Command::Snapshot),
+        proptest::option::of(ARG).prop_map(Command::Ignore),
+        ARG.prop_map(Command::Unignore),
+        proptest::option::of(ARG).prop_map(Command::Topic),
+        proptest::option::of((
+            prop_oneof![Just(RoomFlag::ReadOnly), Just(RoomFlag::InviteOnly)],
+            any::<bool>()
+        ))
+        .prop_map(Command::Flag),
+        (any::<bool>(), proptest::option::of(1..=MAX_INVITE_MINUTES)).prop_map(
+            |(single_use, minutes)| Command::Invite {
+                single_use,
+                minutes
+            }
+        ),
+        proptest::option::of(any::<usize>()).prop_map(Command::Limit),
+        ARG.prop_map(Command::Nick),
+        ARG.prop_map(Command::Kick),
+        ARG.prop_map(Command::Ban),
+        (ARG, "[^\"/\\\\]{1,16}", any::<u64>())
+            .prop_filter("parent directory", |(_, file, _)| file != "..")
+            .prop_map(|(to, file, size)| Command::SendFile { to, file, size }),
+        any::<u64>().prop_map(Command::Accept),
+        any::<u64>().prop_map(Command::Cancel),
+        (any::<u64>(), ARG).prop_map(|(id, data)| Command::Chunk { id, data }),
+        proptest::option::of(ARG).prop_map(Command::Help),
+        content().prop_map(Command::Announce),
+        (
+            ARG,
+            prop_oneof![Just(Role::User), Just(Role::Moderator), Just(Role::Admin)]
+        )
+            .prop_map(|(name, role)| Command::Promote { name, role }),
+    ]
+}
+
+#[test]
+fn announcements_come_due_on_schedule() {
+    let announcement = ScheduledAnnouncement::new("*/15 9-17 * * MON-FRI", "standup").unwrap();
+    let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+    // a Wednesday
+    assert_eq!(
+        announcement.next_after(at("2024-05-15T09:07:30.5Z")),
+        Some(at("2024-05-15T09:15:00Z"))
+    );
+    assert_eq!(
+        announcement.next_after(at("2024-05-15T09:15:00Z")),
+        Some(at("2024-05-15T09:30:00Z"))
+    );
+    assert_eq!(
+        announcement.next_after(at("2024-05-17T17:45:00Z")),
+        Some(at("2024-05-20T09:00:00Z"))
+    );
+    assert!(ScheduledAnnouncement::new("every monday", "standup").is_err());
+}
+
+#[test]
+fn mentions_are_parsed_once_without_punctuation() {
+    assert_eq!(
+        parse_mentions("@bob, ask @Carol: is @BOB here? mail me@example.com @ @/quit"),
+        vec!["bob".to_string(), "Carol".to_string()]
+    );
+    assert!(parse_mentions("no mentions here").is_empty());
+}
+
+#[test]
+fn codec_drops_telnet_negotiation_and_carriage_returns() {
+    let mut codec = LineCodec::new(16);
+    // DO SUPPRESS-GO-AHEAD, a window size subnegotiation with a newline in it, a NOP
+    let mut buf = BytesMut::from(&b"\xff\xfd\x03\xff\xfa\x1f\x00\x0a\x00\x18\xff\xf0ali"[..]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(b"\xff\xf1ce\r\nhi\r\x00there\r\n\xff");
+    let mut lines = Vec::new();
+    while let Some(line) = codec.decode(&mut buf).unwrap() {
+        lines.push(line.unwrap());
+    }
+    assert_eq!(lines, ["alice", "hithere"]);
+    // the IAC at the end of the read is still part of a command
+    buf.extend_from_slice(b"\xfbbye");
+    assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap().unwrap(), "ye");
+}
+
+#[test]
+fn irc_registration_and_messages_become_line_commands() {
+    let decoded = [
+        ("PASS s3cret", "s3cret"),
+        ("NICK alice", "/nick alice"),
+        (
+            ":alice!alice@host JOIN #dev,#ops key,other",
+            "/join #dev key",
+        ),
+        ("join #dev", "/join #dev"),
+        ("PRIVMSG #dev :hello there", "/say #dev hello there"),
+        ("PRIVMSG bob :psst", "/msg bob psst"),
+        ("PART #dev :bye", "/leave #dev"),
+        ("PONG chat :42", "PONG 42"),
+        ("QUIT :gone", "/quit"),
+        ("USER alice 0 * :Alice", ""),
+        ("", ""),
+    ];
+    for (line, expected) in decoded {
+        assert_eq!(decode_irc(line), expected, "{:?}", line);
+    }
+}
+
+#[test]
+fn irc_replies_name_the_sender_like_a_server_would() {
+    let chat = Message::Chat(ChatMessage {
+        room: "#dev".to_string(),
+        from: "bob".to_string(),
+        content: "hello there".to_string(),
+        at: String::new(),
+        mentions: Vec::new(),
+        id: String::new(),
+    });
+    let encoded = [
+        (
+            Message::Welcome {
+                name: "alice".to_string(),
+            },
+            ":chat 001 alice :Welcome to the chat, alice",
+        ),
+        (
+            Message::Joined {
+                room: "#dev".to_string(),
+                name: "alice".to_string(),
+            },
+            ":alice!alice@chat JOIN #dev",
+        ),
+        (chat, ":bob!bob@chat PRIVMSG #dev :hello there"),
+        (
+            Message::Direct {
+                from: "bob".to_string(),
+                to: "alice".to_string(),
+                content: "psst".to_string(),
+                id: None,
+            },
+            ":bob!bob@chat PRIVMSG alice :psst",
+        ),
+        (
+            Message::Ping {
+                token: "42".to_string(),
+            },
+            "PING :42",
+        ),
+        (Message::system("Goodbye"), ":chat NOTICE * :Goodbye"),
+    ];
+    for (message, expected) in encoded {
+        assert_eq!(encode_irc(&message), expected);
+    }
+}
+
+#[tokio::test]
+async fn proxy_headers_carry_the_client_address() {
+    let header = |command: u8, family: u8, addresses: &[u8]| {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header.extend(b"alice\n");
+        header
+    };
+    // 203.0.113.7:51000 to 10.0.0.1:4321, then a TLV the reader skips
+    let v4 = header(
+        1,
+        0x11,
+        &[
+            203, 0, 113, 7, 10, 0, 0, 1, 0xc7, 0x38, 0x10, 0xe1, 3, 0, 1, 0,
+        ],
+    );
+    let mut stream = &v4[..];
+    let addr = read_proxy_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("203.0.113.7:51000".parse().unwrap()));
+    assert_eq!(stream, b"alice\n");
+
+    let mut v6 = [0; 36];
+    v6[..16].copy_from_slice(&"2001:db8::5".parse::<Ipv6Addr>().unwrap().octets());
+    v6[32..34].copy_from_slice(&4242u16.to_be_bytes());
+    let addr = read_proxy_header(&mut &header(1, 0x21, &v6)[..]).await;
+    assert_eq!(addr.unwrap(), Some("[2001:db8::5]:4242".parse().unwrap()));
+
+    // a health check from the proxy itself
+    let local = header(0, 0x00, &[]);
+    assert_eq!(read_proxy_header(&mut &local[..]).await.unwrap(), None);
+    assert!(read_proxy_header(&mut &b"alice\nhello there\n"[..])
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn transcripts_rotate_at_the_size_cap() {
+    let dir = std::env::temp_dir().join(format!("transcripts-{}", nanoid!(8)));
+    let transcripts = Transcripts::open(dir.to_str().unwrap(), 64).await.unwrap();
+    for n in 1..=3 {
+        let message = Message::chat_message("#general", "alice", format!("message {}", n));
+        transcripts.append(&message);
+    }
+    transcripts.append(&Message::join("#rust", "bob"));
+    // typing and messages to a single peer are not part of a room's transcript
+    transcripts.append(&Message::Typing {
+        room: "#rust".to_string(),
+        name: "bob".to_string(),
+    });
+
+    let today = Utc::now().date_naive();
+    let parts = [
+        dir.join(format!("general-{}.log", today)),
+        dir.join(format!("general-{}.1.log", today)),
+        dir.join(format!("rust-{}.log", today)),
+    ];
+    let read = async {
+        loop {
+            let contents: Vec<String> = parts
+                .iter()
+                .map(|path| std::fs::read_to_string(path).unwrap_or_default())
+                .collect();
+            if contents[1].lines().count() == 1 && !contents[2].is_empty() {
+                return contents;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let contents = timeout(Duration::from_secs(2), read).await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let first: Vec<&str> = contents[0].lines().collect();
+    assert_eq!(first.len(), 2);
+    assert!(first[0].ends_with(" <alice> message 1"));
+    assert!(contents[1].ends_with(" <alice> message 3\n"));
+    assert!(contents[2].ends_with(" * bob joined\n"));
+    assert_eq!(contents[2].lines().count(), 1);
+}
+
+#[tokio::test]
+async fn blocking_send_waits_for_room() {
+    let outbox = Outbox::new(1);
+    let message = Arc::new(Message::system("hello"));
+    outbox.send(message.clone(), SEND_TIMEOUT).await.unwrap();
+
+    let wait = Duration::from_millis(50);
+    assert_eq!(
+        outbox.send(message.clone(), wait).await,
+        Err(OutboxError::Full)
+    );
+    assert_eq!(outbox.lagged.load(Ordering::Relaxed), 1);
+
+    let reader = outbox.clone();
+    let recv = tokio::spawn(async move {
+        sleep(wait).await;
+        reader.recv().await
+    });
+    outbox.send(message.clone(), SEND_TIMEOUT).await.unwrap();
+    assert!(recv.await.unwrap().is_some());
+
+    outbox.close();
+    assert_eq!(
+        outbox.send(message, SEND_TIMEOUT).await,
+        Err(OutboxError::Closed)
+    );
+}
+
+// run with `cargo test -- --ignored` and a database the test may create tables in
+#[tokio::test]
+#[ignore = "needs a Postgres database at CHAT_TEST_DATABASE_URL"]
+async fn history_in_postgres_outlives_the_chat_room() {
+    let url = std::env::var("CHAT_TEST_DATABASE_URL").expect("CHAT_TEST_DATABASE_URL");
+    // the table outlives the test too, a room of its own keeps runs apart
+    let room = format!("#persist-{}", nanoid!(8));
+    let chat_room = ChatRoom::try_new_with_db(&url, 10).await.unwrap();
+    for n in 1..=3 {
+        chat_room
+            .post_as(None, "alice", &room, format!("message {}", n))
+            .await
+            .unwrap();
+    }
+    drop(chat_room);
+
+    let chat_room = ChatRoom::try_new_with_db(&url, 10).await.unwrap();
+    let replayed: Vec<String> = chat_room
+        .recent_history(&room, 10)
+        .await
+        .into_iter()
+        .map(|message| match message {
+            Message::History { message, .. } => match *message {
+                Message::Chat(chat) => format!("<{}> {}", chat.from, chat.content),
+                message => panic!("unexpected message in history: {:?}", message),
+            },
+            message => panic!("history not wrapped: {:?}", message),
+        })
+        .collect();
+    assert_eq!(
+        replayed,
+        [
+            "<alice> message 1",
+            "<alice> message 2",
+            "<alice> message 3"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn send_to_reports_peers_it_cannot_reach() {
+    let chat_room = ChatRoom::new(10);
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let peer = chat_room
+        .connect(addr, "alice".to_string(), None, None, Protocol::Text)
+        .unwrap();
+    let message = Arc::new(Message::system("hello"));
+    assert!(chat_room.send_to(addr, message.clone()).await);
+    assert!(peer.outbox.recv().await.is_some());
+
+    // the peer's send loop is gone, nothing is queued for it anymore
+    peer.outbox.close();
+    assert!(!chat_room.send_to(addr, message.clone()).await);
+    assert!(peer.outbox.is_empty());
+
+    let unknown = "127.0.0.1:40001".parse().unwrap();
+    assert!(!chat_room.send_to(unknown, message).await);
+}
+
+proptest! {
+    #[test]
+    fn valid_commands_round_trip(command in command()) {
+        let line = render(&command);
+        prop_assert_eq!(Command::parse(&line), Ok(command), "line: {:?}", line);
+    }
+
+    #[test]
+    fn parse_never_panics(line in "/?\\PC{0,64}") {
+        let _ = Command::parse(&line);
+    }
+
+    #[test]
+    fn decoders_never_panic(line in any::<String>()) {
+        for protocol in [Protocol::Text, Protocol::Json, Protocol::Irc, Protocol::Binary] {
+            if let Ok((line, _)) = protocol.decode_frame(line.clone()) {
+                let _ = Command::parse(&line);
+            }
+        }
+    }
+
+    #[test]
+    fn irc_messages_become_commands(target in IRC_TARGET, text in content()) {
+        let line = format!("PRIVMSG {} :{}", target, text);
+        let decoded = decode_irc(&line);
+        let expected = match parse_room_name(&target) {
+            Ok(room) if target.starts_with('#') => Ok(Command::Say { room, content: text }),
+            Err(e) if target.starts_with('#') => Err(e),
+            _ => Ok(Command::Msg { to: target, content: text }),
+        };
+        prop_assert_eq!(Command::parse(&decoded), expected);
+    }
+
+    #[test]
+    fn codec_survives_arbitrary_bytes(
+        chunks in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 0..8),
+        max_len in 1..32usize,
+    ) {
+        let mut codec = LineCodec::new(max_len);
+        let mut buf = BytesMut::new();
+        for chunk in chunks {
+            buf.extend_from_slice(&chunk);
+            while let Ok(Some(line)) = codec.decode(&mut buf) {
+                if let Ok(line) = line {
+                    prop_assert!(line.len() <= max_len);
+                }
+            }
+        }
+        while let Ok(Some(_)) = codec.decode_eof(&mut buf) {}
+    }
+
+    #[test]
+    fn truncation_keeps_valid_utf8(mut line in any::<String>(), max_len in 0..64usize) {
+        let original = line.clone();
+        truncate_at_char_boundary(&mut line, max_len);
+        prop_assert!(line.len() <= max_len);
+        prop_assert!(original.starts_with(&line));
+    }
+}
@@ -0,0 +1,135 @@
+//! Daily transcripts of every room written to disk.
+
+use super::protocol::Message;
+use anyhow::Result;
+use chrono::{NaiveDate, Utc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, UnboundedSender},
+};
+use tracing::warn;
+
+/// Human readable logs of every room, one file per room and UTC day in a directory.
+///
+/// Files are named `<room>-<date>.log`, a file reaching the size cap is continued in
+/// `<room>-<date>.1.log` and so on.
+#[derive(Debug)]
+pub struct Transcripts {
+    sender: UnboundedSender<(String, String)>,
+}
+
+/// The file a room's transcript is currently written to.
+#[derive(Debug)]
+struct TranscriptFile {
+    date: NaiveDate,
+    part: u32,
+    size: u64,
+    writer: BufWriter<File>,
+}
+
+impl Transcripts {
+    /// Write transcripts under `dir`, a `max_file_size` of 0 lets a day's file grow as large
+    /// as it needs.
+    pub async fn open(dir: &str, max_file_size: u64) -> Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let dir = PathBuf::from(dir);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(String, String)>();
+
+        tokio::spawn(async move {
+            let mut files: HashMap<String, TranscriptFile> = HashMap::new();
+            while let Some((room, line)) = receiver.recv().await {
+                if let Err(e) =
+                    write_transcript(&dir, max_file_size, &mut files, &room, &line).await
+                {
+                    warn!("Failed to write transcript of {}: {}", room, e);
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    pub(super) fn append(&self, message: &Message) {
+        let time = Utc::now().format("%H:%M:%S");
+        let (room, line) = match message {
+            Message::Chat(chat) => (
+                &chat.room,
+                format!("{} <{}> {}", time, chat.from, chat.content),
+            ),
+            Message::Action {
+                room,
+                from,
+                content,
+            } => (room, format!("{} * {} {}", time, from, content)),
+            Message::Join { room, name } => (room, format!("{} * {} joined", time, name)),
+            Message::Leave { room, name } => (room, format!("{} * {} left", time, name)),
+            Message::TopicChanged { room, by, topic } => (
+                room,
+                format!("{} * {} set the topic to: {}", time, by, topic),
+            ),
+            _ => return,
+        };
+        if let Err(e) = self.sender.send((room.clone(), line)) {
+            warn!("Failed to append to transcript: {}", e);
+        }
+    }
+}
+
+/// Append a line to a room's transcript, moving to a new file on a new day or at the cap.
+async fn write_transcript(
+    dir: &Path,
+    max_file_size: u64,
+    files: &mut HashMap<String, TranscriptFile>,
+    room: &str,
+    line: &str,
+) -> Result<()> {
+    let line = format!("{}\n", line);
+    let today = Utc::now().date_naive();
+    let full =
+        |size: u64| max_file_size > 0 && size > 0 && size + line.len() as u64 > max_file_size;
+
+    let (mut part, current) = match files.remove(room) {
+        Some(file) if file.date != today => (0, None),
+        Some(file) if full(file.size) => (file.part + 1, None),
+        Some(file) => (file.part, Some(file)),
+        None => (0, None),
+    };
+    let mut file = match current {
+        Some(file) => file,
+        None => loop {
+            // a restart continues the day's last file, unless it is already full
+            let name = room.trim_start_matches('#');
+            let path = match part {
+                0 => dir.join(format!("{}-{}.log", name, today)),
+                part => dir.join(format!("{}-{}.{}.log", name, today, part)),
+            };
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            let size = file.metadata().await?.len();
+            if !full(size) {
+                break TranscriptFile {
+                    date: today,
+                    part,
+                    size,
+                    writer: BufWriter::new(file),
+                };
+            }
+            part += 1;
+        },
+    };
+
+    file.writer.write_all(line.as_bytes()).await?;
+    file.writer.flush().await?;
+    file.size += line.len() as u64;
+    files.insert(room.to_string(), file);
+    Ok(())
+}
@@ -0,0 +1,219 @@
+//! File transfers between two peers, relayed in base64 chunks once the recipient accepts.
+
+use super::{protocol::Message, ChatRoom};
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+use tracing::{debug, info};
+
+/// A file streamed from one peer to another as base64 chunks relayed by the server.
+#[derive(Debug)]
+pub(super) struct Transfer {
+    from: SocketAddr,
+    to: SocketAddr,
+    file: String,
+    size: u64,
+    sent: u64,
+    // chunks are refused until the recipient accepts the offer
+    accepted: bool,
+}
+
+impl ChatRoom {
+    pub(super) async fn offer_file(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        target: &str,
+        file: String,
+        size: u64,
+    ) -> Result<String, String> {
+        if self.max_file_size == 0 {
+            return Err("File transfers are disabled on this server".to_string());
+        }
+        if size == 0 || size > self.max_file_size {
+            return Err(format!(
+                "Files must be between 1 and {} bytes",
+                self.max_file_size
+            ));
+        }
+        let target_addr = self
+            .names
+            .get(&target.to_lowercase())
+            .map(|owner| *owner)
+            .ok_or_else(|| format!("No such user: {}", target))?;
+        if target_addr == addr {
+            return Err("You cannot send a file to yourself".to_string());
+        }
+        let target = self
+            .peer_name(target_addr)
+            .unwrap_or_else(|| target.to_string());
+
+        let id = self.next_transfer.fetch_add(1, Ordering::Relaxed) + 1;
+        self.transfers.insert(
+            id,
+            Transfer {
+                from: addr,
+                to: target_addr,
+                file: file.clone(),
+                size,
+                sent: 0,
+                accepted: false,
+            },
+        );
+        let offer = Message::FileOffer {
+            id,
+            from: name.to_string(),
+            file: file.clone(),
+            size,
+        };
+        self.send_to(target_addr, Arc::new(offer)).await;
+        info!("{} offered {} ({} bytes) to {}", name, file, size, target);
+        Ok(format!(
+            "Offered {} to {} as transfer {}, waiting for them to accept",
+            file, target, id
+        ))
+    }
+
+    pub(super) async fn accept_transfer(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        id: u64,
+    ) -> Result<String, String> {
+        let (from, file) = match self.transfers.get_mut(&id) {
+            Some(mut transfer) if transfer.to == addr && !transfer.accepted => {
+                transfer.accepted = true;
+                (transfer.from, transfer.file.clone())
+            }
+            _ => return Err(format!("No pending transfer {} for you", id)),
+        };
+
+        let message = Message::system(format!(
+            "{} accepted transfer {}, send {} with /chunk {} <base64>",
+            name, id, file, id
+        ));
+        self.send_to(from, Arc::new(message)).await;
+        Ok(format!("Accepted transfer {}, receiving {}", id, file))
+    }
+
+    pub(super) async fn cancel_transfer(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        id: u64,
+    ) -> Result<String, String> {
+        let Some((_, transfer)) = self.transfers.remove_if(&id, |_, transfer| {
+            transfer.from == addr || transfer.to == addr
+        }) else {
+            return Err(format!("No such transfer: {}", id));
+        };
+
+        let other = if transfer.from == addr {
+            transfer.to
+        } else {
+            transfer.from
+        };
+        let message = Message::system(format!(
+            "{} cancelled transfer {} of {}",
+            name, id, transfer.file
+        ));
+        self.send_to(other, Arc::new(message)).await;
+        Ok(format!("Transfer {} cancelled", id))
+    }
+
+    /// Relay a chunk to the recipient, both sides hear about every quarter of the file.
+    pub(super) async fn receive_chunk(
+        &self,
+        addr: SocketAddr,
+        id: u64,
+        data: String,
+    ) -> Result<(), String> {
+        let len = BASE64
+            .decode(&data)
+            .map_err(|_| "Invalid chunk, expected base64 data".to_string())?
+            .len() as u64;
+
+        let (to, file, size, before, sent) = match self.transfers.get_mut(&id) {
+            Some(mut transfer) if transfer.from == addr && transfer.accepted => {
+                let before = transfer.sent;
+                transfer.sent += len;
+                (
+                    transfer.to,
+                    transfer.file.clone(),
+                    transfer.size,
+                    before,
+                    transfer.sent,
+                )
+            }
+            _ => return Err(format!("No accepted transfer {} to send", id)),
+        };
+
+        if sent > size {
+            self.transfers.remove(&id);
+            let message = Message::system(format!("Transfer {} of {} failed", id, file));
+            self.send_to(to, Arc::new(message)).await;
+            return Err(format!(
+                "Transfer {} aborted, more than {} bytes sent",
+                id, size
+            ));
+        }
+
+        if !self
+            .send_to(to, Arc::new(Message::FileChunk { id, data }))
+            .await
+        {
+            // a dropped chunk would corrupt the file, so give up instead
+            self.transfers.remove(&id);
+            let message = Message::system(format!("Transfer {} of {} failed", id, file));
+            self.send_to(to, Arc::new(message)).await;
+            return Err(format!(
+                "Transfer {} failed, the recipient is not keeping up",
+                id
+            ));
+        }
+
+        if sent == size {
+            self.transfers.remove(&id);
+            debug!("transfer {} of {} completed", id, file);
+        }
+        if sent == size || before * 4 / size != sent * 4 / size {
+            let progress = Arc::new(Message::FileProgress {
+                id,
+                file,
+                sent,
+                size,
+            });
+            self.send_to(addr, progress.clone()).await;
+            self.send_to(to, progress).await;
+        }
+        Ok(())
+    }
+
+    /// Cancel the transfers of a peer leaving the server, telling the other side.
+    pub(super) async fn abort_transfers(&self, addr: SocketAddr, name: &str) {
+        let ids: Vec<u64> = self
+            .transfers
+            .iter()
+            .filter(|item| item.from == addr || item.to == addr)
+            .map(|item| *item.key())
+            .collect();
+        for id in ids {
+            if let Some((_, transfer)) = self.transfers.remove(&id) {
+                let other = if transfer.from == addr {
+                    transfer.to
+                } else {
+                    transfer.from
+                };
+                let message = Message::system(format!(
+                    "Transfer {} of {} cancelled, {} disconnected",
+                    id, transfer.file, name
+                ));
+                self.send_to(other, Arc::new(message)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Room events posted to HTTP endpoints, signed when the webhook has a secret.
+
+use super::protocol::Message;
+use anyhow::Result;
+use hmac::{Hmac, Mac as _};
+use http_body_util::Full;
+use hyper::{
+    header::{CONTENT_TYPE, HOST},
+    Request, StatusCode, Uri,
+};
+use hyper_util::rt::TokioIo;
+use metrics::counter;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc::{self, Sender},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tokio_util::bytes::Bytes;
+use tracing::warn;
+
+// events waiting for a webhook, beyond which new ones are dropped
+const WEBHOOK_QUEUE: usize = 1024;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_SIGNATURE: &str = "x-chat-signature";
+
+/// An HTTP endpoint told about room events, JSON POSTed to it as they happen.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    // every kind of event when empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    // every room when empty
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    // signs bodies, the X-Chat-Signature header is "sha256=" and the hex HMAC-SHA256
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Join,
+    Leave,
+    Chat,
+}
+
+/// The body a webhook is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    // the event as the JSON protocol sends it
+    #[default]
+    Json,
+    // {"text": ...} as Slack's incoming webhooks and their clones take it
+    Slack,
+}
+
+/// Webhooks posted to from a task each, so a slow endpoint never holds up a room.
+#[derive(Debug)]
+pub struct Webhooks {
+    hooks: Vec<(Webhook, Sender<Arc<Message>>)>,
+}
+
+impl Webhooks {
+    /// Check the URLs and start posting, a hook falling `WEBHOOK_QUEUE` events behind
+    /// misses the next ones.
+    pub fn start(hooks: Vec<Webhook>) -> Result<Self> {
+        let tls = TlsConnector::from(Arc::new(webhook_tls_config()));
+        let hooks = hooks
+            .into_iter()
+            .map(|hook| {
+                let uri: Uri = hook.url.parse()?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+                    return Err(anyhow::anyhow!(
+                        "invalid webhook url: {}, expected http or https",
+                        hook.url
+                    ));
+                }
+                let (sender, mut receiver) = mpsc::channel::<Arc<Message>>(WEBHOOK_QUEUE);
+                let (task_hook, tls) = (hook.clone(), tls.clone());
+                tokio::spawn(async move {
+                    while let Some(message) = receiver.recv().await {
+                        let posted = post_webhook(&task_hook, &uri, &tls, &message);
+                        let ret = match timeout(WEBHOOK_TIMEOUT, posted).await {
+                            Ok(Ok(status)) if status.is_success() => Ok(()),
+                            Ok(Ok(status)) => Err(format!("answered {}", status)),
+                            Ok(Err(e)) => Err(e.to_string()),
+                            Err(_) => Err("timed out".to_string()),
+                        };
+                        if let Err(e) = ret {
+                            counter!("chat_webhook_failures_total").increment(1);
+                            warn!(url = task_hook.url, "Failed to post to webhook: {}", e);
+                        }
+                    }
+                });
+                Ok((hook, sender))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { hooks })
+    }
+
+    pub(super) fn notify(&self, message: &Arc<Message>) {
+        let (event, room) = match message.as_ref() {
+            Message::Join { room, .. } => (WebhookEvent::Join, room),
+            Message::Leave { room, .. } => (WebhookEvent::Leave, room),
+            Message::Chat(chat) => (WebhookEvent::Chat, &chat.room),
+            Message::Action { room, .. } => (WebhookEvent::Chat, room),
+            _ => return,
+        };
+        for (hook, sender) in &self.hooks {
+            if hook.wants(event, room) && sender.try_send(message.clone()).is_err() {
+                counter!("chat_webhook_dropped_total").increment(1);
+                warn!(url = hook.url, "Webhook queue full, event dropped");
+            }
+        }
+    }
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent, room: &str) -> bool {
+        (self.events.is_empty() || self.events.contains(&event))
+            && (self.rooms.is_empty() || self.rooms.iter().any(|wanted| wanted == room))
+    }
+
+    fn body(&self, message: &Message) -> Result<Vec<u8>> {
+        let body = match self.format {
+            WebhookFormat::Json => serde_json::to_vec(message)?,
+            WebhookFormat::Slack => {
+                serde_json::to_vec(&serde_json::json!({ "text": message.to_string() }))?
+            }
+        };
+        Ok(body)
+    }
+
+    fn signature(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(body);
+        Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+}
+
+/// Trust the Mozilla roots bundled with webpki-roots, webhooks go to public endpoints.
+fn webhook_tls_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// POST an event to a webhook over a connection of its own, returns the response status.
+async fn post_webhook(
+    hook: &Webhook,
+    uri: &Uri,
+    tls: &TlsConnector,
+    message: &Message,
+) -> Result<StatusCode> {
+    let body = hook.body(message)?;
+    let mut request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(authority) = uri.authority() {
+        request = request.header(HOST, authority.as_str());
+    }
+    if let Some(signature) = hook.signature(&body) {
+        request = request.header(WEBHOOK_SIGNATURE, signature);
+    }
+    let request = request.body(Full::new(Bytes::from(body)))?;
+
+    // IPv6 hosts keep their brackets in the URL
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']');
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let stream = TcpStream::connect((host, port)).await?;
+    if https {
+        let stream = tls.connect(ServerName::try_from(host)?, stream).await?;
+        send_request(stream, request).await
+    } else {
+        send_request(stream, request).await
+    }
+}
+
+async fn send_request<S>(stream: S, request: Request<Full<Bytes>>) -> Result<StatusCode>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    // driven until the sender is dropped with the response in
+    tokio::spawn(connection);
+    Ok(sender.send_request(request).await?.status())
+}
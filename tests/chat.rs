@@ -0,0 +1,1559 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use ecosystem::chat::{
+    handle_link, handle_stream, ChatClient, ChatMessage, ChatRoom, EchoBot, Federation, GreeterBot,
+    LongMessagePolicy, Message, Protocol, RateLimit, Role, RoomBackend, RoomFlag, Snapshot,
+    TokenFile, Webhook, WebhookEvent, WebhookFormat, Webhooks,
+};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    SinkExt, StreamExt,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream},
+    net::TcpListener,
+    sync::broadcast,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_util::{
+    codec::{Framed, LinesCodec},
+    sync::CancellationToken,
+};
+
+const WAIT: Duration = Duration::from_secs(2);
+
+/// A simulated client talking to the room over an in-memory pipe.
+struct Client {
+    lines: Framed<DuplexStream, LinesCodec>,
+    server: JoinHandle<Result<()>>,
+}
+
+impl Client {
+    async fn connect(chat_room: &Arc<ChatRoom>, port: u16, shutdown: &CancellationToken) -> Self {
+        let (client, server) = io::duplex(4096);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let server = tokio::spawn(handle_stream(
+            server,
+            addr,
+            Protocol::Text,
+            chat_room.clone(),
+            shutdown.clone(),
+        ));
+        Self {
+            lines: Framed::new(client, LinesCodec::new()),
+            server,
+        }
+    }
+
+    async fn login(chat_room: &Arc<ChatRoom>, port: u16, name: &str) -> Self {
+        Self::answer_prompt(chat_room, port, name, name).await
+    }
+
+    /// Log in with a token after the name, like the admin token.
+    async fn login_with_token(
+        chat_room: &Arc<ChatRoom>,
+        port: u16,
+        name: &str,
+        token: &str,
+    ) -> Self {
+        let line = format!("{} {}", name, token);
+        Self::answer_prompt(chat_room, port, name, &line).await
+    }
+
+    /// Log in through the auth provider, the access token comes before the name.
+    async fn login_as(chat_room: &Arc<ChatRoom>, port: u16, name: &str, token: &str) -> Self {
+        let mut client = Self::connect(chat_room, port, &CancellationToken::new()).await;
+        client.expect("Please enter your access token").await;
+        client.send(token).await;
+        client.expect("Please enter your name").await;
+        client.send(name).await;
+        client.expect(&format!("Welcome! {}", name)).await;
+        client.expect("You joined #general").await;
+        client
+    }
+
+    async fn answer_prompt(chat_room: &Arc<ChatRoom>, port: u16, name: &str, line: &str) -> Self {
+        let mut client = Self::connect(chat_room, port, &CancellationToken::new()).await;
+        client.expect("Please enter your name").await;
+        client.send(line).await;
+        client.expect(&format!("Welcome! {}", name)).await;
+        client.expect("You joined #general").await;
+        client
+    }
+
+    async fn send(&mut self, line: &str) {
+        self.lines.send(line).await.unwrap();
+    }
+
+    /// Read lines until one contains `needle`, failing on timeout or end of stream.
+    async fn expect(&mut self, needle: &str) -> String {
+        let read = async {
+            while let Some(line) = self.lines.next().await {
+                let line = line.unwrap();
+                if line.contains(needle) {
+                    return line;
+                }
+            }
+            panic!("stream closed before {:?}", needle);
+        };
+        timeout(WAIT, read)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {:?}", needle))
+    }
+
+    /// Read whatever arrives within a short window.
+    async fn drain(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(Some(line)) = timeout(Duration::from_millis(100), self.lines.next()).await {
+            lines.push(line.unwrap());
+        }
+        lines
+    }
+}
+
+/// One instance of a cluster whose backend is an in-process channel.
+#[derive(Debug)]
+struct Bus {
+    instance: usize,
+    sender: broadcast::Sender<(usize, Message)>,
+    // taken by the first subscription, made up front so nothing published is missed
+    receiver: Mutex<Option<broadcast::Receiver<(usize, Message)>>>,
+}
+
+impl Bus {
+    fn cluster(size: usize) -> Vec<Bus> {
+        let (sender, _) = broadcast::channel(64);
+        (0..size)
+            .map(|instance| Bus {
+                instance,
+                sender: sender.clone(),
+                receiver: Mutex::new(Some(sender.subscribe())),
+            })
+            .collect()
+    }
+}
+
+impl RoomBackend for Bus {
+    fn publish<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<()>> {
+        let _ = self.sender.send((self.instance, message.clone()));
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<BoxStream<'static, Message>>> {
+        let receiver = self.receiver.lock().unwrap().take().unwrap();
+        let instance = self.instance;
+        let messages = stream::unfold(receiver, move |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok((from, message)) if from != instance => return Some((message, receiver)),
+                    Ok(_) => continue,
+                    Err(_) => return None,
+                }
+            }
+        });
+        Box::pin(future::ready(Ok(messages.boxed())))
+    }
+}
+
+/// The far end of a federation link, speaking the frames by hand.
+struct Link {
+    lines: Framed<DuplexStream, LinesCodec>,
+    server: JoinHandle<Result<()>>,
+}
+
+impl Link {
+    fn open(chat_room: &Arc<ChatRoom>, port: u16) -> Self {
+        let (client, stream) = io::duplex(4096);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let server = tokio::spawn(handle_link(
+            stream,
+            addr,
+            false,
+            chat_room.clone(),
+            CancellationToken::new(),
+        ));
+        Self {
+            lines: Framed::new(client, LinesCodec::new()),
+            server,
+        }
+    }
+
+    /// Dial the room as `server`, presenting `token` in the hello.
+    async fn dial(chat_room: &Arc<ChatRoom>, port: u16, server: &str, token: &str) -> Self {
+        let mut link = Self::open(chat_room, port);
+        let hello = serde_json::json!({ "type": "hello", "server": server, "token": token });
+        link.send(hello).await;
+        link
+    }
+
+    async fn send(&mut self, frame: serde_json::Value) {
+        self.lines.send(frame.to_string()).await.unwrap();
+    }
+
+    async fn relay(&mut self, id: &str, origin: &str, message: Message) {
+        let message = serde_json::to_value(message).unwrap();
+        let frame = serde_json::json!({
+            "type": "relay",
+            "id": id,
+            "origin": origin,
+            "message": message,
+        });
+        self.send(frame).await;
+    }
+
+    async fn next(&mut self) -> serde_json::Value {
+        let line = timeout(WAIT, self.lines.next())
+            .await
+            .expect("timed out waiting for a frame")
+            .expect("link closed")
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Whatever frames arrive within a short window.
+    async fn drain(&mut self) -> Vec<serde_json::Value> {
+        let mut frames = Vec::new();
+        while let Ok(Some(line)) = timeout(Duration::from_millis(100), self.lines.next()).await {
+            frames.push(serde_json::from_str(&line.unwrap()).unwrap());
+        }
+        frames
+    }
+}
+
+fn chat(room: &str, from: &str, content: &str) -> Message {
+    Message::Chat(ChatMessage {
+        room: room.to_string(),
+        from: from.to_string(),
+        content: content.to_string(),
+        at: String::new(),
+        mentions: Vec::new(),
+        id: String::new(),
+    })
+}
+
+async fn wait_for_peers(chat_room: &ChatRoom, count: usize) {
+    let wait = async {
+        while chat_room.peer_count() != count {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(WAIT, wait)
+        .await
+        .unwrap_or_else(|_| panic!("{} peers left, expected {}", chat_room.peer_count(), count));
+}
+
+#[tokio::test]
+async fn messages_are_broadcast_to_the_room() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+    wait_for_peers(&chat_room, 3).await;
+
+    alice.send("hello everyone").await;
+    assert!(bob.expect("alice: hello").await.ends_with("hello everyone"));
+    assert!(carol
+        .expect("alice: hello")
+        .await
+        .ends_with("hello everyone"));
+    // the sender does not get its own message back
+    assert!(alice
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("hello everyone")));
+}
+
+#[tokio::test]
+async fn abrupt_disconnect_cleans_up_the_peer() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    // no /quit, the pipe just goes away
+    drop(alice.lines);
+    bob.expect("alice left the room").await;
+    wait_for_peers(&chat_room, 1).await;
+    timeout(WAIT, alice.server).await.unwrap().unwrap().unwrap();
+    assert_eq!(chat_room.connection_count(), 1);
+
+    // the name is free again
+    let mut alice = Client::login(&chat_room, 3, "alice").await;
+    bob.expect("alice joined the room").await;
+    alice.send("/quit").await;
+    alice.expect("Goodbye").await;
+    wait_for_peers(&chat_room, 1).await;
+}
+
+#[tokio::test]
+async fn duplicate_names_are_refused() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let _alice = Client::login(&chat_room, 1, "alice").await;
+
+    let mut other = Client::connect(&chat_room, 2, &CancellationToken::new()).await;
+    other.expect("Please enter your name").await;
+    other.send("Alice").await;
+    other.expect("please enter another name").await;
+    assert_eq!(chat_room.peer_count(), 1);
+}
+
+#[tokio::test]
+async fn full_servers_turn_connections_away() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_max_connections(2));
+    let _alice = Client::login(&chat_room, 1, "alice").await;
+    let bob = Client::login(&chat_room, 2, "bob").await;
+
+    let mut turned_away = Client::connect(&chat_room, 3, &CancellationToken::new()).await;
+    turned_away
+        .expect("Server is full, please try again later")
+        .await;
+    assert!(timeout(WAIT, turned_away.lines.next())
+        .await
+        .unwrap()
+        .is_none());
+    timeout(WAIT, turned_away.server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(chat_room.connection_count(), 2);
+
+    // a slot frees up once a peer leaves
+    drop(bob);
+    wait_for_peers(&chat_room, 1).await;
+    let wait = async {
+        while chat_room.connection_count() != 1 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(WAIT, wait).await.unwrap();
+    Client::login(&chat_room, 4, "carol").await;
+}
+
+#[tokio::test]
+async fn shutdown_closes_every_client() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let shutdown = CancellationToken::new();
+    let mut clients = Vec::new();
+    for port in 1..=3 {
+        let mut client = Client::connect(&chat_room, port, &shutdown).await;
+        client.expect("Please enter your name").await;
+        client.send(&format!("user{}", port)).await;
+        client.expect("Welcome!").await;
+        clients.push(client);
+    }
+    wait_for_peers(&chat_room, 3).await;
+
+    shutdown.cancel();
+    for client in clients {
+        timeout(WAIT, client.server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+    wait_for_peers(&chat_room, 0).await;
+    assert_eq!(chat_room.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn flooding_mutes_the_sender() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_rate_limit(RateLimit {
+        per_second: 1,
+        burst: 3,
+        mute: Duration::from_secs(60),
+    }));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    for i in 0..5 {
+        alice.send(&format!("spam {}", i)).await;
+    }
+    alice.expect("slow down").await;
+    alice.expect("You are muted for 60s").await;
+    bob.expect("alice was muted for 60s for flooding").await;
+
+    alice.send("let me talk").await;
+    alice.expect("You are muted for another").await;
+    let seen = bob.drain().await;
+    assert!(seen.iter().all(|line| !line.contains("let me talk")));
+}
+
+#[tokio::test]
+async fn long_messages_are_rejected_or_truncated_as_configured() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_message_limit(10, LongMessagePolicy::Reject));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    alice.send("0123456789abc").await;
+    alice
+        .expect("Message too long (13 bytes), the limit is 10 bytes")
+        .await;
+    alice.send("0123456789").await;
+    let line = bob.expect("alice: ").await;
+    assert!(line.ends_with("alice: 0123456789"), "{}", line);
+
+    let chat_room = Arc::new(ChatRoom::new(10).with_message_limit(10, LongMessagePolicy::Truncate));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    alice.send("0123456789abc").await;
+    alice.expect("Message truncated to 10 bytes").await;
+    let line = bob.expect("alice: ").await;
+    assert!(line.ends_with("alice: 0123456789"), "{}", line);
+    // never in the middle of a character
+    alice.send("012345678é").await;
+    alice.expect("Message truncated to 10 bytes").await;
+    let line = bob.expect("alice: ").await;
+    assert!(line.ends_with("alice: 012345678"), "{}", line);
+}
+
+#[tokio::test]
+async fn roles_gate_moderation_commands() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_admin_token(Some("secret".to_string())));
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+    wait_for_peers(&chat_room, 3).await;
+
+    bob.send("/kick carol").await;
+    bob.expect("needs the moderator role").await;
+
+    alice.send("/promote bob moderator").await;
+    carol.expect("bob is now moderator, by alice").await;
+    bob.send("/ban carol").await;
+    bob.expect("needs the admin role").await;
+    bob.send("/kick carol").await;
+    carol.expect("You have been kicked by bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    alice.send("/announce  maintenance at noon").await;
+    bob.expect("[announcement] maintenance at noon").await;
+}
+
+#[tokio::test]
+async fn history_pages_back_by_message_id() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    for n in 1..=5 {
+        alice.send(&format!("message {}", n)).await;
+        bob.expect(&format!("message {}", n)).await;
+    }
+
+    bob.send("/history 2").await;
+    bob.expect("message 4").await;
+    bob.expect("message 5").await;
+    let more = bob.expect("Older messages: ").await;
+    let next = &more[more.find("/history").unwrap()..];
+    assert!(next.starts_with("/history #general "));
+
+    bob.send(next).await;
+    let page = bob.drain().await;
+    assert!(page[0].contains("message 2"), "{:?}", page);
+    assert!(page[1].contains("message 3"), "{:?}", page);
+    assert!(page.iter().all(|line| !line.contains("message 4")));
+}
+
+#[tokio::test]
+async fn watchers_follow_a_room_without_joining() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut watcher = chat_room.watch(None, "general").unwrap();
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    wait_for_peers(&chat_room, 1).await;
+
+    alice.send("hello watchers").await;
+    let seen = async {
+        loop {
+            if let Message::Chat(chat) = watcher.recv().await.unwrap().as_ref() {
+                return chat.clone();
+            }
+        }
+    };
+    let chat = timeout(WAIT, seen).await.unwrap();
+    assert_eq!(
+        (chat.from.as_str(), chat.content.as_str()),
+        ("alice", "hello watchers")
+    );
+    // watching is not membership
+    assert_eq!(chat_room.peer_count(), 1);
+
+    chat_room
+        .post_as(None, "dashboard", "#general", "hi from outside".to_string())
+        .await
+        .unwrap();
+    assert!(alice
+        .expect("dashboard: ")
+        .await
+        .ends_with("hi from outside"));
+    assert!(chat_room
+        .post_as(None, "alice", "#general", "impostor".to_string())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn instances_share_rooms_through_the_backend() {
+    let shutdown = CancellationToken::new();
+    let instances: Vec<_> = Bus::cluster(2)
+        .into_iter()
+        .map(|bus| {
+            let chat_room = Arc::new(ChatRoom::new(10).with_backend(Some(Box::new(bus))));
+            tokio::spawn({
+                let chat_room = chat_room.clone();
+                let shutdown = shutdown.clone();
+                async move { chat_room.follow_backend(shutdown).await }
+            });
+            chat_room
+        })
+        .collect();
+    let mut alice = Client::login(&instances[0], 1, "alice").await;
+    let mut bob = Client::login(&instances[1], 2, "bob").await;
+    bob.expect("alice joined").await;
+
+    alice.send("hello from the first instance").await;
+    assert!(bob
+        .expect("alice: ")
+        .await
+        .ends_with("hello from the first instance"));
+    bob.send("/join #rust").await;
+    alice.send("/join #rust").await;
+    bob.expect("alice joined").await;
+    // nothing comes back to the instance it was published on
+    assert!(alice
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("hello from the first instance")));
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn search_finds_matching_messages_for_the_sender_only() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    for line in ["Rust is fast", "lunch?", "rust and tokio"] {
+        alice.send(line).await;
+        bob.expect(line).await;
+    }
+
+    bob.send("/search RUST").await;
+    let found = bob.drain().await;
+    let matches: Vec<_> = found
+        .iter()
+        .filter(|line| line.contains("alice: "))
+        .collect();
+    assert_eq!(matches.len(), 2, "{:?}", found);
+    assert!(matches[0].ends_with("Rust is fast"));
+    assert!(matches[1].ends_with("rust and tokio"));
+    assert!(found
+        .iter()
+        .any(|line| line.contains("2 messages in #general match")));
+    assert!(alice
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("match")));
+
+    bob.send("/search dinner").await;
+    bob.expect("No messages in #general match \"dinner\"").await;
+}
+
+#[tokio::test]
+async fn shadow_banned_messages_reach_only_their_sender() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_admin_token(Some("secret".to_string())));
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    bob.send("/shadowban alice").await;
+    bob.expect("needs the admin role").await;
+    alice.send("/shadowban bob").await;
+    alice.expect("bob is shadow banned").await;
+
+    bob.send("/nick robert").await;
+    alice.expect("bob is now known as robert").await;
+    bob.send("anyone there?").await;
+    assert!(bob.expect("robert: ").await.ends_with("anyone there?"));
+    bob.send("/msg alice psst").await;
+    bob.expect("Message sent to alice").await;
+    assert!(alice
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("anyone there?") && !line.contains("psst")));
+
+    alice.send("/unshadowban robert").await;
+    alice.expect("robert is no longer shadow banned").await;
+    bob.send("back again").await;
+    assert!(alice.expect("robert: ").await.ends_with("back again"));
+}
+
+#[tokio::test]
+async fn mentioned_peers_are_notified() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+    carol.send("/join #elsewhere").await;
+    carol.send("/leave #general").await;
+    alice.expect("carol left").await;
+    wait_for_peers(&chat_room, 3).await;
+
+    alice.send("hey @Bob, and @carol!").await;
+    bob.expect("alice: hey @Bob").await;
+    bob.expect("[#general] alice mentioned you").await;
+    // carol hears of the mention, not of what was said in a room carol isn't in
+    let lines = carol.drain().await;
+    assert!(lines.contains(&"[#general] alice mentioned you".to_string()));
+    assert!(lines.iter().all(|line| !line.contains("hey")));
+    // the sender is not notified of its own mentions
+    alice.send("note to self @alice").await;
+    assert!(alice
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("mentioned you")));
+}
+
+#[tokio::test]
+async fn read_only_rooms_take_posts_from_moderators_only() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_admin_token(Some("secret".to_string())));
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+    wait_for_peers(&chat_room, 3).await;
+
+    bob.send("/flag read_only on").await;
+    bob.expect("needs the admin role").await;
+    alice.send("/flag read_only on").await;
+    carol.expect("[#general] alice set read_only").await;
+    bob.send("/flag").await;
+    bob.expect("Flags of #general: read_only").await;
+
+    bob.send("can I still talk?").await;
+    bob.expect("#general is read-only, only moderators may post")
+        .await;
+    alice.send("/promote bob moderator").await;
+    bob.expect("moderator").await;
+    bob.send("now I can").await;
+    assert!(carol.expect("bob: ").await.ends_with("now I can"));
+    assert!(carol
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("can I still talk?")));
+
+    alice.send("/flag read_only off").await;
+    carol.expect("[#general] alice cleared read_only").await;
+    carol.send("hello again").await;
+    assert!(bob.expect("carol: ").await.ends_with("hello again"));
+}
+
+#[tokio::test]
+async fn full_rooms_refuse_joins_until_the_limit_changes() {
+    let chat_room = Arc::new(
+        ChatRoom::new(10)
+            .with_admin_token(Some("secret".to_string()))
+            .with_room_limits(HashMap::from([("#small".to_string(), 2)])),
+    );
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+
+    alice.send("/join #small").await;
+    alice.expect("You joined #small").await;
+    bob.send("/join #small").await;
+    bob.expect("You joined #small").await;
+    carol.send("/join #small").await;
+    carol
+        .expect("#small is full (2 members), try again later")
+        .await;
+    // already a member, switching back is not a join
+    bob.send("/join #general").await;
+    bob.send("/join #small").await;
+    bob.expect("Switched to #small").await;
+
+    carol.send("/limit 3").await;
+    carol.expect("needs the admin role").await;
+    alice.send("/limit 3").await;
+    carol.expect("alice limited #small to 3 members").await;
+    carol.send("/join #small").await;
+    carol.expect("You joined #small").await;
+    carol.send("/limit").await;
+    carol.expect("#small takes at most 3 members").await;
+    alice.send("/limit off").await;
+    carol
+        .expect("alice lifted the member limit of #small")
+        .await;
+}
+
+#[tokio::test]
+async fn invite_only_rooms_take_invite_codes_from_their_creator() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+
+    alice.send("/create #secret").await;
+    alice.expect("You joined #secret").await;
+    bob.send("/flag invite_only on").await;
+    bob.expect("Only the creator of #general or an admin").await;
+    alice.send("/flag invite_only on").await;
+    alice.expect("[#secret] alice set invite_only").await;
+
+    bob.send("/join #secret").await;
+    bob.expect("#secret is invite-only, join with /join #secret <code>")
+        .await;
+    alice.send("/invite once 5").await;
+    let reply = alice.expect("within 5 minutes, the code works once").await;
+    let code = reply.split_whitespace().nth(5).unwrap().to_string();
+
+    bob.send("/join #secret not-a-code").await;
+    bob.expect("Invalid or expired invite code for #secret")
+        .await;
+    bob.send(&format!("/join #secret {}", code)).await;
+    bob.expect("You joined #secret").await;
+    bob.send("/invite").await;
+    bob.expect("Only the creator of #secret or an admin may invite")
+        .await;
+    carol.send(&format!("/join #secret {}", code)).await;
+    carol
+        .expect("Invalid or expired invite code for #secret")
+        .await;
+}
+
+#[tokio::test]
+async fn invite_only_rooms_are_followed_over_http_only_by_their_creator() {
+    let path = std::env::temp_dir().join(format!("tokens-{}.txt", std::process::id()));
+    std::fs::write(&path, "alice alice-token\nbob bob-token\n").unwrap();
+    let tokens = TokenFile::load(path.to_str().unwrap()).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let chat_room = Arc::new(ChatRoom::new(10).with_auth(Some(Box::new(tokens)), WAIT));
+    let mut alice = Client::login_as(&chat_room, 1, "alice", "alice-token").await;
+
+    alice.send("/create #secret").await;
+    alice.expect("You joined #secret").await;
+    alice.send("/flag invite_only on").await;
+    alice.expect("[#secret] alice set invite_only").await;
+    let owner = chat_room.snapshot().rooms["#secret"].owner.clone();
+    assert_eq!(owner.as_deref(), Some("alice"));
+
+    let bob = chat_room.check_token(Some("bob-token")).await.unwrap();
+    assert_eq!(bob.as_deref(), Some("bob"));
+    for user in [None, bob.as_deref()] {
+        let error = chat_room.watch(user, "#secret").unwrap_err();
+        assert!(error.contains("#secret is invite-only"), "{}", error);
+        let error = chat_room
+            .post_as(user, "dashboard", "#secret", "let me in".to_string())
+            .await
+            .unwrap_err();
+        assert!(error.contains("#secret is invite-only"), "{}", error);
+    }
+
+    let user = chat_room.check_token(Some("alice-token")).await.unwrap();
+    let mut watcher = chat_room.watch(user.as_deref(), "#secret").unwrap();
+    chat_room
+        .post_as(
+            user.as_deref(),
+            "dashboard",
+            "#secret",
+            "from outside".to_string(),
+        )
+        .await
+        .unwrap();
+    alice.expect("[#secret] dashboard: from outside").await;
+    let seen = timeout(WAIT, watcher.recv()).await.unwrap().unwrap();
+    assert!(seen.to_string().contains("from outside"), "{}", seen);
+}
+
+#[tokio::test]
+async fn polls_belong_to_a_room_and_close_when_their_creator_leaves() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+    carol.send("/join #dev").await;
+    carol.send("/leave #general").await;
+    carol.expect("You left #general").await;
+
+    alice.send("/poll \"Lunch?\" pizza sushi").await;
+    bob.expect("alice started a poll: Lunch? 1) pizza 2) sushi (vote with /vote <n>)")
+        .await;
+    carol.send("/vote 1").await;
+    carol.expect("There is no active poll in #dev").await;
+    bob.send("/vote 1").await;
+    bob.expect("You voted for: pizza").await;
+    bob.send("/vote 2").await;
+    bob.expect("You voted for: sushi").await;
+    bob.send("/pollresult").await;
+    bob.expect("Current results for Lunch?: 1) pizza: 0 2) sushi: 1")
+        .await;
+    bob.send("/pollclose").await;
+    bob.expect("Only the creator of the poll can close it")
+        .await;
+    alice.send("/pollclose").await;
+    bob.expect("Poll closed, final results for Lunch?: 1) pizza: 0 2) sushi: 1")
+        .await;
+    bob.send("/vote 1").await;
+    bob.expect("There is no active poll in #general").await;
+    assert!(carol
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("Lunch?")));
+
+    bob.send("/poll \"Again?\" yes no").await;
+    alice.expect("bob started a poll: Again?").await;
+    alice.send("/vote 1").await;
+    alice.expect("You voted for: yes").await;
+    drop(bob);
+    alice
+        .expect("Poll closed as its creator left, final results for Again?: 1) yes: 1 2) no: 0")
+        .await;
+    alice.send("/vote 2").await;
+    alice.expect("There is no active poll in #general").await;
+}
+
+#[tokio::test]
+async fn files_reach_only_the_peer_that_accepted_them() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_max_file_size(16));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+
+    alice.send("/send bob notes.txt 8").await;
+    alice
+        .expect("Offered notes.txt to bob as transfer 1, waiting for them to accept")
+        .await;
+    bob.expect("alice offers you notes.txt (8 bytes), /accept 1 or /cancel 1")
+        .await;
+    alice.send("/chunk 1 YWJjZA==").await;
+    alice.expect("No accepted transfer 1 to send").await;
+    carol.send("/accept 1").await;
+    carol.expect("No pending transfer 1 for you").await;
+    bob.send("/accept 1").await;
+    bob.expect("Accepted transfer 1, receiving notes.txt").await;
+    alice
+        .expect("bob accepted transfer 1, send notes.txt with /chunk 1 <base64>")
+        .await;
+
+    alice.send("/chunk 1 YWJjZA==").await;
+    bob.expect("[file 1] YWJjZA==").await;
+    bob.expect("Transfer 1 of notes.txt: 4/8 bytes (50%)").await;
+    alice.send("/chunk 1 ZWZnaA==").await;
+    bob.expect("[file 1] ZWZnaA==").await;
+    bob.expect("Transfer 1 of notes.txt: 8/8 bytes (100%)")
+        .await;
+    alice
+        .expect("Transfer 1 of notes.txt: 4/8 bytes (50%)")
+        .await;
+    alice
+        .expect("Transfer 1 of notes.txt: 8/8 bytes (100%)")
+        .await;
+    assert!(carol
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.contains("[file")));
+
+    // more than offered ends the transfer on both sides
+    alice.send("/send bob big.bin 17").await;
+    alice.expect("Files must be between 1 and 16 bytes").await;
+    alice.send("/send bob small.bin 2").await;
+    bob.send("/accept 2").await;
+    bob.expect("Accepted transfer 2, receiving small.bin").await;
+    alice.send("/chunk 2 YWJj").await;
+    alice
+        .expect("Transfer 2 aborted, more than 2 bytes sent")
+        .await;
+    bob.expect("Transfer 2 of small.bin failed").await;
+
+    alice.send("/send bob last.bin 4").await;
+    bob.expect("alice offers you last.bin (4 bytes)").await;
+    drop(alice);
+    bob.expect("Transfer 3 of last.bin cancelled, alice disconnected")
+        .await;
+    bob.send("/accept 3").await;
+    bob.expect("No pending transfer 3 for you").await;
+}
+
+#[tokio::test]
+async fn federation_links_must_say_hello_with_the_token() {
+    let federation = Federation::new("home".to_string(), Some("s3cret".to_string()));
+    let chat_room = Arc::new(ChatRoom::new(10).with_federation(Some(federation)));
+
+    let refused = [
+        ("away", "wrong", "invalid federation token from away"),
+        ("home", "s3cret", "refusing to link with itself"),
+    ];
+    for (port, (server, token, error)) in (1..).zip(refused) {
+        let link = Link::dial(&chat_room, port, server, token).await;
+        let ret = timeout(WAIT, link.server).await.unwrap().unwrap();
+        assert_eq!(ret.unwrap_err().to_string(), error);
+    }
+
+    let mut link = Link::open(&chat_room, 3);
+    link.relay("1", "away", chat("#general", "mallory", "hi"))
+        .await;
+    let ret = timeout(WAIT, link.server).await.unwrap().unwrap();
+    assert_eq!(ret.unwrap_err().to_string(), "expected a hello");
+
+    let mut link = Link::dial(&chat_room, 4, "away", "s3cret").await;
+    let hello = link.next().await;
+    assert_eq!(
+        hello,
+        serde_json::json!({ "type": "hello", "server": "home", "token": "s3cret" })
+    );
+}
+
+#[tokio::test]
+async fn relayed_room_messages_are_delivered_and_passed_on_once() {
+    let federation = Federation::new("home".to_string(), None);
+    let chat_room = Arc::new(ChatRoom::new(10).with_federation(Some(federation)));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut east = Link::dial(&chat_room, 2, "east", "").await;
+    east.next().await;
+    let mut west = Link::dial(&chat_room, 3, "west", "").await;
+    west.next().await;
+
+    east.relay("m1", "east", chat("#general", "bob", "hello from east"))
+        .await;
+    alice.expect("bob: hello from east").await;
+    let passed_on = west.next().await;
+    assert_eq!(
+        (passed_on["id"].as_str(), passed_on["origin"].as_str()),
+        (Some("m1"), Some("east"))
+    );
+    assert_eq!(passed_on["message"]["content"], "hello from east");
+
+    // seen before, back home, or not a room message: nobody hears of it
+    west.relay("m1", "east", chat("#general", "bob", "hello from east"))
+        .await;
+    east.relay("m2", "home", chat("#general", "bob", "went around"))
+        .await;
+    let system = Message::System {
+        content: "you are an admin now".to_string(),
+    };
+    east.relay("m3", "east", system).await;
+    assert!(alice.drain().await.is_empty());
+    assert!(east.drain().await.is_empty());
+    assert!(west.drain().await.is_empty());
+
+    alice.send("hello from home").await;
+    for link in [&mut east, &mut west] {
+        let relayed = link.next().await;
+        assert_eq!(relayed["origin"], "home");
+        assert_eq!(relayed["message"]["content"], "hello from home");
+    }
+}
+
+#[tokio::test]
+async fn resumed_sessions_rejoin_their_rooms_and_replay_the_backlog() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_session_grace(Some(WAIT), 2));
+    let mut alice = Client::connect(&chat_room, 1, &CancellationToken::new()).await;
+    alice.expect("Please enter your name").await;
+    alice.send("alice").await;
+    let line = alice.expect("Your session token is").await;
+    let token = line
+        .split_whitespace()
+        .nth(4)
+        .unwrap()
+        .trim_end_matches(',');
+    let resume = format!("/resume {}", token);
+    alice.send("/join #dev").await;
+    alice.expect("You joined #dev").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    bob.send("/join #dev").await;
+    bob.expect("You joined #dev").await;
+
+    drop(alice);
+    wait_for_peers(&chat_room, 1).await;
+    for n in 1..=3 {
+        bob.send(&format!("dev {}", n)).await;
+    }
+    bob.send("/say #general still around?").await;
+    sleep(Duration::from_millis(50)).await;
+
+    let mut alice = Client::connect(&chat_room, 3, &CancellationToken::new()).await;
+    alice.expect("Please enter your name").await;
+    alice.send(&resume).await;
+    alice
+        .expect("Session resumed, 4 messages arrived while you were away")
+        .await;
+    let replay = alice.drain().await;
+    let expected = [
+        "You joined #dev",
+        "1 older messages of #dev were dropped",
+        "[#dev] bob: dev 2",
+        "[#dev] bob: dev 3",
+        "You joined #general",
+        "[#general] bob: still around?",
+    ];
+    assert_eq!(replay.len(), expected.len(), "{:?}", replay);
+    for (line, expected) in replay.iter().zip(expected) {
+        assert!(line.ends_with(expected), "{:?} is not {:?}", line, expected);
+    }
+}
+
+#[tokio::test]
+async fn heartbeat_pings_measure_the_round_trip() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_heartbeat(Some(Duration::from_millis(200))));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    alice.send("/who").await;
+    assert!(!alice.expect("Online (1)").await.contains("ping"));
+
+    let ping = alice.expect("PING ").await;
+    let token = ping.trim_start_matches("PING ");
+    alice.send(&format!("PONG {}", token)).await;
+    alice.send("/who").await;
+    assert!(alice.expect("Online (1)").await.contains(", ping "));
+}
+
+#[tokio::test]
+async fn status_counts_peers_rooms_and_messages() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    bob.send("/join #dev").await;
+    bob.expect("You joined #dev").await;
+    alice.send("hello").await;
+    alice.send("anyone?").await;
+    bob.expect("anyone?").await;
+
+    let status = chat_room.status();
+    assert_eq!(status.peers, 2);
+    let rooms: Vec<_> = status
+        .rooms
+        .iter()
+        .map(|room| (room.name.as_str(), room.members))
+        .collect();
+    assert_eq!(rooms, [("#dev", 1), ("#general", 2)]);
+    assert_eq!(status.messages_total, 2);
+    assert_eq!(status.messages_last_minute, 2);
+}
+
+#[tokio::test]
+async fn stats_keep_the_peak_of_peers_and_count_bytes() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let carol = Client::login(&chat_room, 3, "carol").await;
+    drop(carol);
+    wait_for_peers(&chat_room, 2).await;
+
+    let before = chat_room.status();
+    assert_eq!((before.peers, before.peak_peers), (2, 3));
+    alice.send("hello").await;
+    bob.expect("alice: hello").await;
+    let after = chat_room.status();
+    // the line and its newline
+    assert_eq!(after.bytes_received - before.bytes_received, 6);
+    assert!(after.bytes_sent > before.bytes_sent);
+
+    alice.send("/stats").await;
+    let stats = alice.expect("peers: 2 (peak 3)").await;
+    let received = format!("bytes received: {}", after.bytes_received + 7);
+    assert!(stats.ends_with(&received), "{}", stats);
+    alice.send("/stats uptime").await;
+    let uptime = alice.expect("Uptime: ").await;
+    assert!(!uptime.contains("peers"), "{}", uptime);
+}
+
+#[tokio::test]
+async fn chat_clients_log_in_and_receive_typed_events() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let connect = |port: u16| {
+        let (client, server) = io::duplex(4096);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        tokio::spawn(handle_stream(
+            server,
+            addr,
+            Protocol::Json,
+            chat_room.clone(),
+            CancellationToken::new(),
+        ));
+        ChatClient::new(client)
+    };
+    let alice = connect(1);
+    let bob = connect(2);
+    assert_eq!(alice.login("alice", None).await.unwrap(), "alice");
+    assert!(bob.login("Alice", None).await.is_err());
+    let mut events = bob.subscribe();
+    assert_eq!(bob.login("bob", None).await.unwrap(), "bob");
+
+    alice.send("hi @bob").unwrap();
+    let chat = timeout(WAIT, async {
+        loop {
+            if let Message::Chat(chat) = &*events.recv().await.unwrap() {
+                break chat.clone();
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        (chat.from.as_str(), chat.content.as_str()),
+        ("alice", "hi @bob")
+    );
+    assert_eq!(chat.mentions, ["bob"]);
+
+    bob.command("/quit").unwrap();
+    timeout(WAIT, bob.closed()).await.unwrap();
+    assert!(bob.send("still there?").is_err());
+}
+
+#[tokio::test]
+async fn tagged_json_frames_are_acknowledged_or_refused() {
+    // the next reply to a tagged frame, skipping the room traffic
+    async fn reply(lines: &mut Framed<DuplexStream, LinesCodec>) -> Message {
+        let read = async {
+            loop {
+                let line = lines.next().await.unwrap().unwrap();
+                let message: Message = serde_json::from_str(&line).unwrap();
+                if let Message::Ack { .. } | Message::Nack { .. } | Message::Delivered { .. } =
+                    message
+                {
+                    return message;
+                }
+            }
+        };
+        timeout(WAIT, read).await.unwrap()
+    }
+
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let (client, server) = io::duplex(4096);
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+    tokio::spawn(handle_stream(
+        server,
+        addr,
+        Protocol::Json,
+        chat_room.clone(),
+        CancellationToken::new(),
+    ));
+    let mut alice = Framed::new(client, LinesCodec::new());
+    let frames = [
+        serde_json::json!({ "type": "login", "name": "alice" }),
+        serde_json::json!({ "type": "chat", "content": "hello", "id": "c1" }),
+        serde_json::json!({ "type": "command", "command": "/leave #nowhere", "id": "c2" }),
+        serde_json::json!({ "type": "command", "command": "/msg bob psst", "id": "c3" }),
+    ];
+    for frame in frames {
+        alice.send(frame.to_string()).await.unwrap();
+    }
+
+    let Message::Ack { id, message } = reply(&mut alice).await else {
+        panic!("the chat message was not accepted");
+    };
+    assert_eq!(id, "c1");
+    assert!(message.is_some_and(|message| !message.is_empty()));
+    bob.expect("alice: hello").await;
+    let Message::Nack { id, reason } = reply(&mut alice).await else {
+        panic!("leaving a room alice is not in was accepted");
+    };
+    assert_eq!(id, "c2");
+    assert_eq!(reason, "You are not in #nowhere");
+    let Message::Ack { id, message: None } = reply(&mut alice).await else {
+        panic!("the direct message was not accepted");
+    };
+    assert_eq!(id, "c3");
+    bob.expect("psst").await;
+    let Message::Delivered { id, to } = reply(&mut alice).await else {
+        panic!("the direct message was not delivered");
+    };
+    assert_eq!((id.as_str(), to.as_str()), ("c3", "bob"));
+}
+
+#[tokio::test]
+async fn senders_edit_and_delete_their_own_messages() {
+    // joins are not what this is about
+    async fn next(events: &mut broadcast::Receiver<Arc<Message>>) -> Message {
+        let event = async {
+            loop {
+                match &*events.recv().await.unwrap() {
+                    Message::Join { .. } => continue,
+                    message => break message.clone(),
+                }
+            }
+        };
+        timeout(WAIT, event).await.unwrap()
+    }
+
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let (client, server) = io::duplex(4096);
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 2));
+    tokio::spawn(handle_stream(
+        server,
+        addr,
+        Protocol::Json,
+        chat_room.clone(),
+        CancellationToken::new(),
+    ));
+    let bob = ChatClient::new(client);
+    bob.login("bob", None).await.unwrap();
+    let mut events = bob.subscribe();
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    alice.send("hello").await;
+    let Message::Chat(chat) = next(&mut events).await else {
+        panic!("expected a chat message");
+    };
+    assert_eq!(chat.id.len(), 8);
+
+    bob.command(format!("/delete {}", chat.id)).unwrap();
+    let refused = next(&mut events).await;
+    assert!(
+        matches!(&refused, Message::System { content } if content.starts_with("You have no recent")),
+        "{:?}",
+        refused
+    );
+
+    alice.send(&format!("/edit {} hello again", chat.id)).await;
+    alice.expect("alice edited").await;
+    match next(&mut events).await {
+        Message::Edited { id, content, .. } => {
+            assert_eq!((id, content.as_str()), (chat.id.clone(), "hello again"))
+        }
+        other => panic!("expected an edit, got {:?}", other),
+    }
+    alice.send("/history").await;
+    alice.expect("alice: hello again").await;
+
+    alice.send(&format!("/delete {}", chat.id)).await;
+    alice.expect("alice deleted").await;
+    assert!(matches!(next(&mut events).await, Message::Deleted { id, .. } if id == chat.id));
+    alice.send("/history").await;
+    assert!(!alice
+        .drain()
+        .await
+        .iter()
+        .any(|line| line.contains("hello")));
+}
+
+#[tokio::test]
+async fn edits_belong_to_the_connection_not_the_name() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut events = chat_room.watch(None, "#general").unwrap();
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut mallory = Client::login(&chat_room, 2, "mallory").await;
+    alice.send("mine").await;
+    let id = timeout(WAIT, async {
+        loop {
+            if let Message::Chat(chat) = &*events.recv().await.unwrap() {
+                break chat.id.clone();
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    // the name is anyone's once alice changes hers
+    alice.send("/nick alicia").await;
+    mallory.expect("alice is now known as alicia").await;
+    mallory.send("/nick alice").await;
+    mallory.expect("mallory is now known as alice").await;
+    mallory.send(&format!("/edit {} not yours", id)).await;
+    mallory
+        .expect(&format!("You have no recent message {}", id))
+        .await;
+
+    alice.send(&format!("/edit {} still mine", id)).await;
+    alice.expect("alicia edited").await;
+}
+
+#[tokio::test]
+async fn batched_messages_arrive_complete_and_in_order() {
+    let chat_room = Arc::new(
+        ChatRoom::new(10)
+            .with_flush_interval(Some(Duration::from_millis(20)))
+            .with_rate_limit(RateLimit {
+                per_second: 100,
+                burst: 100,
+                mute: Duration::from_secs(60),
+            }),
+    );
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    wait_for_peers(&chat_room, 2).await;
+
+    for i in 0..30 {
+        alice.send(&format!("burst {}", i)).await;
+    }
+    for i in 0..30 {
+        assert!(bob
+            .expect("alice: burst")
+            .await
+            .ends_with(&format!("burst {}", i)));
+    }
+}
+
+#[tokio::test]
+async fn webhooks_receive_signed_events_of_their_rooms() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook = Webhook {
+        url: format!("http://{}/hook", listener.local_addr().unwrap()),
+        events: vec![WebhookEvent::Chat],
+        rooms: vec!["#dev".to_string()],
+        secret: Some("s3cret".to_string()),
+        format: WebhookFormat::Json,
+    };
+    let webhooks = Webhooks::start(vec![hook]).unwrap();
+    let chat_room = Arc::new(ChatRoom::new(10).with_webhooks(Some(webhooks)));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    alice.send("not for the hook").await;
+    alice.send("/join #dev").await;
+    alice.expect("You joined #dev").await;
+    alice.send("hello hooks").await;
+
+    let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut headers = HashMap::new();
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("POST /hook HTTP/1.1"), "{}", line);
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+        let Some((name, value)) = line.trim_end().split_once(": ") else {
+            break;
+        };
+        headers.insert(name.to_lowercase(), value.to_string());
+    }
+    let mut body = vec![0; headers["content-length"].parse().unwrap()];
+    stream.read_exact(&mut body).await.unwrap();
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+    mac.update(&body);
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    assert_eq!(headers["x-chat-signature"], signature);
+    let Message::Chat(chat) = serde_json::from_slice(&body).unwrap() else {
+        panic!("expected a chat: {}", String::from_utf8_lossy(&body));
+    };
+    assert_eq!(
+        (chat.room.as_str(), chat.content.as_str()),
+        ("#dev", "hello hooks")
+    );
+    // neither the join nor the chat in #general was posted
+    assert!(timeout(Duration::from_millis(100), listener.accept())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn snapshots_carry_room_settings_and_bans_to_a_new_server() {
+    let path = std::env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+    let chat_room = Arc::new(
+        ChatRoom::new(10)
+            .with_admin_token(Some("secret".to_string()))
+            .with_roles(HashMap::from([("dana".to_string(), Role::Moderator)]))
+            .with_snapshot_file(Some(path.clone())),
+    );
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+
+    bob.send("/snapshot").await;
+    bob.expect("needs the admin role").await;
+    alice.send("/create #ops").await;
+    alice.send("/topic Deploys only").await;
+    alice.send("/flag read_only on").await;
+    alice.send("/limit 5").await;
+    alice.send("/shadowban bob").await;
+    alice.expect("bob is shadow banned").await;
+    alice.send("/snapshot").await;
+    alice
+        .expect("Snapshot of 1 rooms, 1 bans and 1 roles written to")
+        .await;
+
+    let snapshot: Snapshot = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let ops = &snapshot.rooms["#ops"];
+    assert_eq!(ops.topic.as_deref(), Some("Deploys only"));
+    assert_eq!(ops.flags, [RoomFlag::ReadOnly]);
+    // alice logged in without a user, so nobody keeps #ops on the next server
+    assert_eq!((ops.limit, ops.owner.as_deref()), (Some(5), None));
+    assert_eq!(snapshot.roles["dana"], Role::Moderator);
+
+    let restored = Arc::new(ChatRoom::new(10));
+    restored.restore(snapshot);
+    assert_eq!(restored.snapshot(), chat_room.snapshot());
+    let mut carol = Client::login(&restored, 3, "carol").await;
+    carol.send("/join #ops").await;
+    carol.send("/topic").await;
+    carol.expect("Topic for #ops: Deploys only").await;
+    carol.send("/limit").await;
+    carol.expect("#ops takes at most 5 members").await;
+    carol.send("posting anyway").await;
+    carol.expect("#ops is read-only").await;
+}
+
+#[tokio::test]
+async fn bots_answer_in_the_room_and_greet_newcomers() {
+    let chat_room = Arc::new(ChatRoom::new(10).with_bot(EchoBot).with_bot(GreeterBot));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+
+    let mut impostor = Client::connect(&chat_room, 3, &CancellationToken::new()).await;
+    impostor.expect("Please enter your name").await;
+    impostor.send("Echo").await;
+    impostor.expect("Name Echo is already taken").await;
+
+    bob.send("/join #dev").await;
+    bob.expect("Hi bob, welcome to #dev!").await;
+    alice.send("!echo ping").await;
+    bob.expect("echo: ping").await;
+    alice.expect("echo: ping").await;
+    // what a bot says doesn't reach the bots
+    alice.send("!echo !echo again").await;
+    bob.expect("echo: !echo again").await;
+    assert!(bob
+        .drain()
+        .await
+        .iter()
+        .all(|line| !line.ends_with("echo: again")));
+}
+
+#[tokio::test]
+async fn ignored_users_reach_only_the_peers_not_ignoring_them() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+    let mut carol = Client::login(&chat_room, 3, "carol").await;
+
+    carol.send("/ignore bob").await;
+    carol
+        .expect("You are ignoring bob, /unignore bob to hear from them again")
+        .await;
+    carol.send("/ignore carol").await;
+    carol.expect("You cannot ignore yourself").await;
+    bob.send("spam spam spam").await;
+    alice.expect("bob: spam spam spam").await;
+    bob.send("/msg carol psst").await;
+    bob.expect("Message sent to carol").await;
+    // renamed, bob is still ignored
+    bob.send("/nick robert").await;
+    alice.expect("bob is now known as robert").await;
+    bob.send("@carol more spam").await;
+    alice.expect("robert: @carol more spam").await;
+    let lines = carol.drain().await;
+    assert!(
+        lines.iter().all(|line| ["spam", "psst", "mentioned you"]
+            .iter()
+            .all(|word| !line.contains(word))),
+        "{:?}",
+        lines
+    );
+    carol.send("/ignore").await;
+    carol.expect("You are ignoring: robert").await;
+
+    carol.send("/unignore robert").await;
+    carol.expect("You hear from robert again").await;
+    bob.send("hello again").await;
+    carol.expect("robert: hello again").await;
+}
+
+#[tokio::test]
+async fn actions_are_told_in_the_third_person() {
+    let chat_room = Arc::new(ChatRoom::new(10));
+    let mut alice = Client::login(&chat_room, 1, "alice").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+
+    alice.send("/me waves").await;
+    bob.expect("[#general] * alice waves").await;
+    alice.send("/me").await;
+    alice.expect("Usage: /me <action>").await;
+
+    let action = Message::Action {
+        room: "#general".to_string(),
+        from: "alice".to_string(),
+        content: "waves".to_string(),
+    };
+    let json = serde_json::to_value(&action).unwrap();
+    assert_eq!(json["type"], "action");
+    let decoded: Message = serde_json::from_value(json).unwrap();
+    assert_eq!(decoded.to_string(), action.to_string());
+}
+
+#[tokio::test]
+async fn created_rooms_are_archived_or_forgotten_once_empty() {
+    let chat_room = Arc::new(
+        ChatRoom::new(10)
+            .with_admin_token(Some("secret".to_string()))
+            .with_room_idle(Some(Duration::from_millis(200)), vec!["#lobby".to_string()]),
+    );
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let chat_room = chat_room.clone();
+        let shutdown = shutdown.clone();
+        async move { chat_room.run_room_sweeper(shutdown).await }
+    });
+    let mut alice = Client::login_with_token(&chat_room, 1, "alice", "secret").await;
+    let mut bob = Client::login(&chat_room, 2, "bob").await;
+
+    alice.send("/create #dev").await;
+    alice.expect("You joined #dev").await;
+    alice.send("/topic Builds and deploys").await;
+    bob.send("/create #dev").await;
+    bob.expect("#dev already exists, /join #dev instead").await;
+    bob.send("/join #dev").await;
+    bob.expect("Topic for #dev: Builds and deploys").await;
+    bob.send("/rooms").await;
+    bob.expect("Rooms (2): #dev (2 members): Builds and deploys, #general (2 members)")
+        .await;
+    bob.send("/archive").await;
+    bob.expect("Only the creator of #dev or an admin may archive it")
+        .await;
+    alice.send("/archive").await;
+    bob.expect("alice archived #dev").await;
+    bob.send("/join #dev").await;
+    bob.expect("#dev is archived").await;
+    assert_eq!(
+        chat_room.watch(None, "#dev").unwrap_err(),
+        "#dev is archived"
+    );
+    let posted = chat_room
+        .post_as(None, "dashboard", "#dev", "anyone here?".to_string())
+        .await;
+    assert_eq!(posted.unwrap_err(), "#dev is archived");
+
+    // emptied rooms outlive the idle period only when persistent
+    alice.send("/create #tmp").await;
+    alice.send("/topic scratch").await;
+    alice.send("/join #lobby").await;
+    alice.send("/topic welcome").await;
+    alice.send("/leave #tmp").await;
+    alice.expect("You left #tmp").await;
+    alice.send("/leave #lobby").await;
+    alice.expect("You left #lobby").await;
+    sleep(Duration::from_millis(500)).await;
+    alice.send("/rooms").await;
+    alice
+        .expect("Rooms (2): #general (2 members), #lobby (0 members): welcome")
+        .await;
+    alice.send("/join #tmp").await;
+    alice.send("/topic").await;
+    alice.expect("No topic is set for #tmp").await;
+    // not even admins archive the rooms the server keeps
+    let mut dana = Client::login_with_token(&chat_room, 3, "dana", "secret").await;
+    dana.send("/archive #lobby").await;
+    dana.expect("#lobby cannot be archived").await;
+    shutdown.cancel();
+}